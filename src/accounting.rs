@@ -0,0 +1,140 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! `accounting_file`: the traffic of each port, added up across connections, sessions and restarts. The pipes only
+//! add to atomic counters as they go, a thread of its own writes them out every `accounting_interval` and when
+//! smugglrs stops. The file is a CSV, `port,sent,received,connections`, from the point of view of the tunnel like
+//! the line logged when a connection closes: sent through it, and received from it
+
+use crate::config::Accounting;
+use crate::error::Failure;
+use crate::{info, signal, warn};
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::{fs, thread};
+
+const HEADER : &str = "port,sent,received,connections";
+
+static LEDGER : OnceLock<Ledger> = OnceLock::new();
+
+/// What went through a port, since accounting started
+#[derive(Default)]
+pub struct PortTraffic {
+    pub sent: AtomicU64,
+    pub received: AtomicU64,
+    pub connections: AtomicU64
+}
+
+struct Ledger {
+    file: PathBuf,
+    ports: Mutex<BTreeMap<u16, Arc<PortTraffic>>>,
+    // Held while writing, the periodic flush and the one on termination may happen together
+    writing: Mutex<()>
+}
+
+impl Ledger {
+    fn totals(&self) -> Vec<(u16, u64, u64, u64)> {
+        self.ports.lock().unwrap().iter()
+            .map(|(port, traffic)| (*port, traffic.sent.load(Ordering::Relaxed), traffic.received.load(Ordering::Relaxed), traffic.connections.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /* Through a temporary file, so that a crash while writing doesn't lose the totals */
+    fn flush(&self) -> Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        let mut csv = format!("{HEADER}\n");
+        for (port, sent, received, connections) in self.totals() {
+            csv += &format!("{port},{sent},{received},{connections}\n");
+        }
+        let mut temporary = self.file.clone().into_os_string();
+        temporary.push(".tmp");
+        fs::write(&temporary, csv).with_context(|| format!("Failed to write {}", Path::new(&temporary).display()))?;
+        fs::rename(&temporary, &self.file).with_context(|| format!("Failed to replace {}", self.file.display()))
+    }
+}
+
+/* The totals of the previous runs, nothing if the file doesn't exist yet */
+fn load(file: &Path) -> Result<BTreeMap<u16, Arc<PortTraffic>>> {
+    let csv = match fs::read_to_string(file) {
+        Ok(csv) => csv,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {}", file.display()))
+    };
+    let mut ports = BTreeMap::new();
+    for (i, line) in csv.lines().enumerate().filter(|(_, line)| !line.trim().is_empty() && line.trim() != HEADER) {
+        let parsed : Option<(u16, u64, u64, u64)> = match line.split(',').map(str::trim).collect::<Vec<&str>>().as_slice() {
+            [port, sent, received, connections] => (|| Some((port.parse().ok()?, sent.parse().ok()?, received.parse().ok()?, connections.parse().ok()?)))(),
+            _ => None
+        };
+        let (port, sent, received, connections) = parsed.ok_or_else(|| anyhow!("{}, line {}: expected {HEADER}, got {line}", file.display(), i + 1))?;
+        let traffic : &mut Arc<PortTraffic> = ports.entry(port).or_default();
+        traffic.sent.fetch_add(sent, Ordering::Relaxed);
+        traffic.received.fetch_add(received, Ordering::Relaxed);
+        traffic.connections.fetch_add(connections, Ordering::Relaxed);
+    }
+    Ok(ports)
+}
+
+/// Writes the totals once the run is over, when it ends without a signal
+pub struct Flusher;
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        if let Some(ledger) = LEDGER.get() {
+            if let Err(err) = ledger.flush() {
+                warn!("Failed to write the traffic accounting, reason: {err:#}");
+            }
+        }
+    }
+}
+
+/// Before anything spawns a thread, as SIGTERM is blocked to write the totals before exiting
+pub fn start(accounting: &Option<Accounting>) -> Result<Option<Flusher>> {
+    let Some(Accounting { file, interval }) = accounting.clone() else { return Ok(None) };
+    let ports = load(&file).context("Failed to load the traffic accounting").context(Failure::Config)?;
+    let written = file.clone();
+    if LEDGER.set(Ledger { file, ports: Mutex::new(ports), writing: Mutex::default() }).is_err() {
+        return Err(anyhow!("The traffic accounting was already started"));
+    }
+    signal::on_termination(move || {
+        info!("Terminated, writing the traffic accounting to {}", written.display());
+        drop(Flusher);
+    })?;
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Some(Err(err)) = LEDGER.get().map(Ledger::flush) {
+            warn!("Failed to write the traffic accounting, reason: {err:#}");
+        }
+    });
+    Ok(Some(Flusher))
+}
+
+/// The counters of a port, for a new connection through it, if accounting is on
+pub fn port(port: u16) -> Option<Arc<PortTraffic>> {
+    let traffic = LEDGER.get()?.ports.lock().unwrap().entry(port).or_default().clone();
+    traffic.connections.fetch_add(1, Ordering::Relaxed);
+    Some(traffic)
+}
+
+/// `traffic` on the admin socket
+pub fn traffic_command() -> Result<String> {
+    let ledger = LEDGER.get().ok_or_else(|| anyhow!("Accounting is off, set accounting_file"))?;
+    let totals = ledger.totals();
+    if totals.is_empty() {
+        return Ok("no traffic yet".to_string());
+    }
+    Ok(totals.iter()
+        .map(|(port, sent, received, connections)| format!("port={port} sent={sent} received={received} connections={connections}"))
+        .collect::<Vec<String>>().join("; "))
+}
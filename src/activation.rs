@@ -0,0 +1,221 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+/* systemd socket activation: when systemd owns the listening sockets, they are passed
+   as fds 3 and up, described by LISTEN_PID, LISTEN_FDS and LISTEN_FDNAMES (see sd_listen_fds(3)).
+   With Type=notify, systemd also waits for us to say we are ready on NOTIFY_SOCKET (see sd_notify(3)) */
+
+use anyhow::Result;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::warn;
+
+/// Name of the socket the servers connect to, as given by `FileDescriptorName=`
+pub const CONTROL_SOCKET : &str = "control";
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use anyhow::{anyhow, Context};
+    use std::env;
+    use std::io;
+    use std::mem::{size_of, MaybeUninit};
+    use std::os::fd::{FromRawFd, RawFd};
+
+    const LISTEN_FDS_START : RawFd = 3;
+
+    fn socket_option(fd: RawFd, option: libc::c_int) -> io::Result<libc::c_int> {
+        let mut value : libc::c_int = 0;
+        let mut length = size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: `value` and `length` outlive the call, and `length` is the size of `value`
+        let ret = unsafe {
+            libc::getsockopt(fd, libc::SOL_SOCKET, option, &mut value as *mut _ as *mut libc::c_void, &mut length)
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(value)
+    }
+
+    /* Only a listening TCP socket can be turned into a TcpListener */
+    fn check_listener(fd: RawFd) -> Result<()> {
+        let mut stat = MaybeUninit::<libc::stat>::uninit();
+        // SAFETY: `stat` has room for the struct fstat writes
+        if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error()).context("fstat failed");
+        }
+        // SAFETY: fstat succeeded, so it wrote the whole struct
+        if unsafe { stat.assume_init() }.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+            return Err(anyhow!("it is not a socket"));
+        }
+        if socket_option(fd, libc::SO_TYPE).context("Failed to get the socket type")? != libc::SOCK_STREAM {
+            return Err(anyhow!("it is not a stream socket"));
+        }
+        let mut address = MaybeUninit::<libc::sockaddr_storage>::zeroed();
+        let mut length = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        // SAFETY: `address` and `length` outlive the call, and `length` is the size of `address`
+        if unsafe { libc::getsockname(fd, address.as_mut_ptr() as *mut libc::sockaddr, &mut length) } != 0 {
+            return Err(io::Error::last_os_error()).context("getsockname failed");
+        }
+        // SAFETY: zeroed, and written by getsockname besides
+        let family = unsafe { address.assume_init() }.ss_family as libc::c_int;
+        if family != libc::AF_INET && family != libc::AF_INET6 {
+            return Err(anyhow!("it is not an IPv4 or IPv6 socket"));
+        }
+        if socket_option(fd, libc::SO_ACCEPTCONN).context("Failed to check whether the socket listens")? == 0 {
+            return Err(anyhow!("it is not listening"));
+        }
+        Ok(())
+    }
+
+    /* Which of the `count` sockets systemd passed is `name`, going by LISTEN_FDNAMES */
+    fn position(name: &str, count: RawFd, names: Option<&str>) -> Result<RawFd> {
+        let names : Vec<&str> = names.map_or(Vec::new(), |names| names.split(':').collect());
+        match names.iter().position(|x| *x == name) {
+            Some(index) if (index as RawFd) < count => Ok(index as RawFd),
+            Some(_) => Err(anyhow!("LISTEN_FDNAMES names more sockets than LISTEN_FDS")),
+            None if count == 1 && names.iter().all(|x| x.is_empty() || *x == "unknown") => Ok(0),
+            None => Err(anyhow!("None of the {count} sockets passed by systemd is named {name}, set FileDescriptorName={name}"))
+        }
+    }
+
+    /* Only owned once checked, a descriptor that can't be used is left as it is */
+    fn adopt(fd: RawFd) -> Result<TcpListener> {
+        check_listener(fd)?;
+        // Not to leak it into the processes we may spawn
+        // SAFETY: no pointers are passed, an invalid `fd` only makes fcntl fail
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to set FD_CLOEXEC");
+        }
+        // SAFETY: `fd` was passed to us by systemd, checked to be a listening TCP socket, and nothing else owns it
+        Ok(unsafe { TcpListener::from_raw_fd(fd) })
+    }
+
+    /// The listener systemd passed under `name`, if we were socket activated.
+    /// A single unnamed socket is taken whatever the name asked
+    pub fn listener(name: &str) -> Result<Option<TcpListener>> {
+        // The variables may have been inherited by a process they were not meant for
+        match env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) {
+            Some(pid) if pid == std::process::id() => {}
+            _ => return Ok(None)
+        }
+        let count : RawFd = env::var("LISTEN_FDS").context("LISTEN_PID is set but not LISTEN_FDS")?
+            .parse().context("LISTEN_FDS should be a number")?;
+        let fd = LISTEN_FDS_START + position(name, count, env::var("LISTEN_FDNAMES").ok().as_deref())?;
+        adopt(fd).map(Some).with_context(|| format!("File descriptor {fd} passed by systemd can't be used"))
+    }
+
+    /// A datagram of `KEY=value` lines to NOTIFY_SOCKET, when systemd set it. A name starting with '@'
+    /// is in the abstract namespace
+    pub fn notify(message: &str) -> Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::net::UnixDatagram;
+        let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+            return Ok(());
+        };
+        let socket = UnixDatagram::unbound().context("Failed to create the notification socket")?;
+        match path.as_bytes().strip_prefix(b"@") {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Some(name) => {
+                #[cfg(target_os = "linux")]
+                use std::os::linux::net::SocketAddrExt;
+                #[cfg(target_os = "android")]
+                use std::os::android::net::SocketAddrExt;
+                let address = std::os::unix::net::SocketAddr::from_abstract_name(name).context("Invalid NOTIFY_SOCKET")?;
+                socket.send_to_addr(message.as_bytes(), &address)
+            }
+            _ => socket.send_to(message.as_bytes(), &path)
+        }.with_context(|| format!("Failed to write to NOTIFY_SOCKET {}", path.to_string_lossy()))?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::fs::File;
+        use std::net::{Ipv4Addr, TcpStream, UdpSocket};
+        use std::os::fd::{AsRawFd, IntoRawFd};
+        use std::os::unix::net::UnixListener;
+
+        #[test]
+        fn sockets_are_found_by_name() {
+            assert_eq!(position(CONTROL_SOCKET, 2, Some("metrics:control")).unwrap(), 1);
+            assert_eq!(position(CONTROL_SOCKET, 1, None).unwrap(), 0, "a single socket is taken whatever its name");
+            assert_eq!(position(CONTROL_SOCKET, 1, Some("unknown")).unwrap(), 0);
+            assert!(position(CONTROL_SOCKET, 2, None).is_err());
+            assert!(position(CONTROL_SOCKET, 1, Some("metrics")).is_err());
+            assert!(position(CONTROL_SOCKET, 1, Some("metrics:control")).is_err(), "more names than sockets");
+        }
+
+        #[test]
+        fn listeners_are_adopted() {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let address = listener.local_addr().unwrap();
+            let fd = listener.into_raw_fd();
+            // As inherited from systemd, which doesn't set it
+            // SAFETY: no pointers are passed, `fd` is still open, owned by nothing until adopted
+            assert_eq!(unsafe { libc::fcntl(fd, libc::F_SETFD, 0) }, 0);
+            let listener = adopt(fd).unwrap();
+            // SAFETY: no pointers are passed, `fd` is open, owned by `listener`
+            assert_ne!(unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC, 0, "FD_CLOEXEC isn't set");
+            let _client = TcpStream::connect(address).unwrap();
+            assert!(listener.accept().is_ok());
+        }
+
+        #[test]
+        fn other_descriptors_are_refused() {
+            let error = |fd: RawFd| format!("{:#}", adopt(fd).unwrap_err());
+            let file = File::open("/dev/null").unwrap();
+            assert_eq!(error(file.as_raw_fd()), "it is not a socket");
+            let udp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            assert_eq!(error(udp.as_raw_fd()), "it is not a stream socket");
+            let path = env::temp_dir().join(format!("smugglrs-{}-activation.sock", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let unix = UnixListener::bind(&path).unwrap();
+            assert_eq!(error(unix.as_raw_fd()), "it is not an IPv4 or IPv6 socket");
+            let _ = std::fs::remove_file(&path);
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let connected = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            assert_eq!(error(connected.as_raw_fd()), "it is not listening");
+            // Still ours, the refused ones were left open
+            assert!(connected.peer_addr().is_ok());
+            assert!(udp.local_addr().is_ok());
+        }
+    }
+}
+
+#[cfg(unix)]
+pub use unix::listener;
+#[cfg(unix)]
+use unix::notify;
+
+#[cfg(not(unix))]
+pub fn listener(_name: &str) -> Result<Option<TcpListener>> {
+    Ok(None)
+}
+
+#[cfg(not(unix))]
+fn notify(_message: &str) -> Result<()> {
+    Ok(())
+}
+
+static READY : AtomicBool = AtomicBool::new(false);
+
+/// Tells systemd we are ready the first time, only updates the status the times after
+pub fn ready(status: &str) {
+    let message = match READY.swap(true, Ordering::Relaxed) {
+        false => format!("READY=1\nSTATUS={status}"),
+        true => format!("STATUS={status}")
+    };
+    if let Err(err) = notify(&message) {
+        warn!("Failed to notify systemd, reason: {err:#}");
+    }
+}
@@ -0,0 +1,109 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+/* Local admin socket: every connection sends a single command line and receives a single
+   response line, starting with "ok:" or "error:" */
+
+use crate::common::PipeInfo;
+use crate::{info, warn};
+use anyhow::{anyhow, Result, Context};
+use std::path::Path;
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::thread;
+
+    fn handle<F: Fn(&str) -> Result<String>>(stream: UnixStream, handler: &F) -> Result<()> {
+        let mut line = String::new();
+        BufReader::new(stream.try_clone()?).read_line(&mut line)?;
+        let response = match handler(line.trim()) {
+            Ok(response) => format!("ok: {response}\n"),
+            Err(err) => format!("error: {err:#}\n")
+        };
+        (&stream).write_all(response.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn serve<F>(path: &Path, handler: F) -> Result<()>
+    where F: Fn(&str) -> Result<String> + Send + 'static {
+        if path.exists() {
+            // Left over by a previous instance, unless one is still running
+            if UnixStream::connect(path).is_ok() {
+                return Err(anyhow!("Another instance is already listening on {}", path.display()));
+            }
+            fs::remove_file(path).with_context(|| format!("Failed to remove stale admin socket {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path).with_context(|| format!("Failed to bind admin socket {}", path.display()))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600)).context("Failed to restrict admin socket permissions")?;
+        info!("Admin socket listening on {}", path.display());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => if let Err(err) = handle(stream, &handler) {
+                        warn!("Admin command failed: {err:#}");
+                    },
+                    Err(err) => warn!("Admin connection failed: {err:#}")
+                }
+            }
+        });
+        Ok(())
+    }
+
+    pub fn request(path: &Path, command: &str) -> Result<String> {
+        let mut stream = UnixStream::connect(path)
+            .with_context(|| format!("Failed to connect to the admin socket {}, is smugglrs running?", path.display()))?;
+        stream.write_all(format!("{command}\n").as_bytes()).context("Failed to send admin command")?;
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response).context("Failed to read admin response")?;
+        Ok(response.trim_end().to_string())
+    }
+}
+
+#[cfg(unix)]
+pub use unix::{request, serve};
+
+#[cfg(not(unix))]
+pub fn serve<F>(_path: &Path, _handler: F) -> Result<()>
+where F: Fn(&str) -> Result<String> + Send + 'static {
+    warn!("The admin socket is not supported on this platform");
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn request(_path: &Path, _command: &str) -> Result<String> {
+    Err(anyhow!("The admin socket is not supported on this platform"))
+}
+
+/// Send a command from the command line to the running instance
+pub fn command(path: &Path, args: &[String]) -> Result<()> {
+    let response = request(path, &args.join(" "))?;
+    println!("{response}");
+    if !response.starts_with("ok") {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// A pipe, in `status` and `connections`
+pub(crate) fn pipe_segment(pipe: &PipeInfo) -> String {
+    let port = pipe.port.map_or("-".to_string(), |port| port.to_string());
+    let peer = pipe.peer.map_or("-".to_string(), |peer| peer.to_string());
+    let mut segment = format!("pipe={} port={port} peer={peer} bytes={} age={}", pipe.id, pipe.bytes, pipe.age.as_secs());
+    if let Some(name) = &pipe.name {
+        segment.push_str(&format!(" name={name}"));
+    }
+    segment
+}
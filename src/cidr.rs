@@ -0,0 +1,120 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! Client allow/deny lists, of addresses or CIDR ranges (`10.0.0.0/8`, `2001:db8::/32`).
+
+use anyhow::{anyhow, Result, Context};
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+
+/* IPv6, with IPv4 mapped, followed by the prefix length in IPv6 terms */
+pub const CIDR_LENGTH : usize = 17;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Cidr {
+    addr: Ipv6Addr,
+    prefix: u8
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip
+    }
+}
+
+impl Cidr {
+    pub fn parse(x: &str) -> Result<Cidr> {
+        let (ip, prefix) = match x.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix.parse::<u8>().with_context(|| format!("{x}: invalid prefix length"))?)),
+            None => (x, None)
+        };
+        let ip : IpAddr = ip.parse().with_context(|| format!("{x}: invalid address"))?;
+        let (max, offset) = match ip {
+            IpAddr::V4(_) => (32, 96),
+            IpAddr::V6(_) => (128, 0)
+        };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return Err(anyhow!("{x}: the prefix length should be at most {max}"));
+        }
+        Ok(Cidr { addr: to_ipv6(ip), prefix: prefix + offset })
+    }
+
+    fn mask(&self) -> u128 {
+        u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let mask = self.mask();
+        u128::from(to_ipv6(ip)) & mask == u128::from(self.addr) & mask
+    }
+
+    pub fn to_bytes(self) -> [u8; CIDR_LENGTH] {
+        let mut ret = [0u8; CIDR_LENGTH];
+        ret[..16].copy_from_slice(&self.addr.octets());
+        ret[16] = self.prefix;
+        ret
+    }
+
+    pub fn from_bytes(buf: &[u8; CIDR_LENGTH]) -> Result<Cidr> {
+        if buf[16] > 128 {
+            return Err(anyhow!("Invalid prefix length {}", buf[16]));
+        }
+        Ok(Cidr { addr: Ipv6Addr::from(<[u8; 16]>::try_from(&buf[..16]).unwrap()), prefix: buf[16] })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.addr.to_ipv4_mapped() {
+            Some(ip) if self.prefix >= 96 => write!(f, "{ip}/{}", self.prefix - 96),
+            _ => write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+/// A client is refused if it matches `deny`, or if `allow` isn't empty and it doesn't match it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessRules {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>
+}
+
+impl AccessRules {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|cidr| cidr.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+    }
+
+    pub fn parse_list(name: &str, list: &[toml::Value]) -> Result<Vec<Cidr>> {
+        list.iter().map(|x| match x {
+            toml::Value::String(x) => Cidr::parse(x),
+            _ => Err(anyhow!("{name} should be a list of strings"))
+        }).collect()
+    }
+}
+
+impl fmt::Display for AccessRules {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let join = |list: &[Cidr]| list.iter().map(Cidr::to_string).collect::<Vec<String>>().join(",");
+        match (self.allow.is_empty(), self.deny.is_empty()) {
+            (true, true) => write!(f, "everyone"),
+            (false, true) => write!(f, "allow={}", join(&self.allow)),
+            (true, false) => write!(f, "deny={}", join(&self.deny)),
+            (false, false) => write!(f, "allow={} deny={}", join(&self.allow), join(&self.deny))
+        }
+    }
+}
@@ -8,39 +8,1252 @@ See the GNU General Public License for more details.
 You should have received a copy of the GNU General Public License along with this program. 
 If not, see <https://www.gnu.org/licenses/>. 
 */
-use std::net::TcpStream;
-use std::thread;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use std::io::{Read, Write};
-use anyhow::Result;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use crate::accounting::{self, PortTraffic};
+use crate::config::{Keepalive, Port};
+use crate::connector::Stream;
+use crate::crypto::{Cipher, SealedReader, SealedWriter};
+use crate::integrity::{Collector, Digest, DigestReport};
+use crate::mirror::{Direction, MirrorTap};
+use crate::sockopt::{self, BufferKind};
+use crate::throttle::{self, RateLimiter};
+use crate::log;
+use crate::{debug, info, warn};
 
+/// Of the magic the server sends first, derived from the key (see `crypto::Magics`)
 pub const MAGIC1_LENGTH : usize = 17;
-pub const MAGIC1: &[u8; MAGIC1_LENGTH] = &[231, 3, 23, 145, 7, 2, 46, 41, 78, 222, 175, 4, 8, 15, 16, 23, 42];
+/// Sent by the servers older than the derived magics, with and without a nonce. Only recognized to tell that they
+/// should be updated
+pub const FIXED_MAGICS : [&[u8; MAGIC1_LENGTH]; 2] = [
+    &[231, 3, 23, 145, 7, 2, 46, 41, 78, 222, 175, 4, 8, 15, 16, 23, 43],
+    &[231, 3, 23, 145, 7, 2, 46, 41, 78, 222, 175, 4, 8, 15, 16, 23, 42]
+];
  
-const PIPE_BUFFER : usize = 65536;
-fn pipe_streams(mut src: TcpStream, mut dst: TcpStream) -> Result<()> {
-    let mut buf = [0u8; PIPE_BUFFER];
+const PIPE_BUFFER : usize = 65536; // When the socket buffer sizes are unknown
+const CANCEL_GRACE : Duration = Duration::from_secs(1);
+
+/// Bounds of the user-space pipe buffers, which are otherwise sized after the socket buffers
+#[derive(Copy, Clone, Debug)]
+pub struct BufferConfig {
+    pub min: usize,
+    pub max: usize,
+    /// SO_RCVBUF/SO_SNDBUF to set on both sockets of every pipe, for high bandwidth-delay links
+    pub socket_buffer: Option<usize>,
+    /// Bytes all the user-space buffers of the process may hold together, see `MemoryBudget`
+    pub budget: Option<usize>
+}
+
+impl BufferConfig {
+    /// With the `pipe_buffer` of a redirect, its connections get buffers of that size whatever the sockets'
+    pub fn sized(self, size: Option<usize>) -> BufferConfig {
+        match size {
+            Some(size) => BufferConfig { min: size, max: size, ..self },
+            None => self
+        }
+    }
+}
+
+impl Default for BufferConfig {
+    fn default() -> BufferConfig {
+        BufferConfig {
+            min: 8192,
+            max: 1048576,
+            socket_buffer: None,
+            budget: None
+        }
+    }
+}
+
+/// Bytes held in user-space buffers (pipes and mirror queues) by the whole process.
+/// Past the budget, new pipes get the smallest buffers, then are refused
+pub struct MemoryBudget {
+    limit: AtomicUsize, // 0 when unlimited
+    used: AtomicUsize,
+    /// Pipes that got the smallest buffers instead of the ones they should have had
+    downsized: AtomicU64,
+    /// Connections refused for lack of memory
+    rejected: AtomicU64
+}
+
+pub static MEMORY : MemoryBudget = MemoryBudget {
+    limit: AtomicUsize::new(0),
+    used: AtomicUsize::new(0),
+    downsized: AtomicU64::new(0),
+    rejected: AtomicU64::new(0)
+};
+
+/// Why `spawn_pipes` closed a connection instead of piping it: the budget had no room even for the smallest buffers
+#[derive(Debug)]
+pub struct MemoryExhausted;
+
+impl std::fmt::Display for MemoryExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Memory budget exhausted, connection refused")
+    }
+}
+
+impl std::error::Error for MemoryExhausted {}
+
+/// What `smugglrs status` shows about the budget
+pub struct MemoryStats {
+    pub used: usize,
+    pub limit: Option<usize>,
+    pub downsized: u64,
+    pub rejected: u64
+}
+
+impl MemoryBudget {
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Whether `size` more bytes fit, without reserving them
+    pub fn has_room(&self, size: usize) -> bool {
+        match self.limit.load(Ordering::Relaxed) {
+            0 => true,
+            limit => self.used.load(Ordering::Relaxed).saturating_add(size) <= limit
+        }
+    }
+
+    pub fn reserve(&'static self, size: usize) -> Option<Reservation> {
+        let used = self.used.fetch_add(size, Ordering::Relaxed);
+        match self.limit.load(Ordering::Relaxed) {
+            limit if limit != 0 && used.saturating_add(size) > limit => {
+                self.used.fetch_sub(size, Ordering::Relaxed);
+                None
+            }
+            _ => Some(Reservation { budget: self, size })
+        }
+    }
+
+    /// Count a connection refused for lack of memory
+    pub fn reject(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            used: self.used.load(Ordering::Relaxed),
+            limit: match self.limit.load(Ordering::Relaxed) {
+                0 => None,
+                limit => Some(limit)
+            },
+            downsized: self.downsized.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed)
+        }
+    }
+}
+
+/// Bytes taken from the budget, given back when dropped
+pub struct Reservation {
+    budget: &'static MemoryBudget,
+    size: usize
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.size, Ordering::Relaxed);
+    }
+}
+
+const LIMIT_WARNING_INTERVAL : Duration = Duration::from_secs(10);
+
+/// Connections forwarded at once, by the whole gateway and on each of its ports
+pub struct ConnectionLimits {
+    max: AtomicUsize,
+    max_per_port: AtomicUsize,
+    /// Live connections of each port, which add up to the total
+    ports: Mutex<HashMap<Port, usize>>,
+    /// Connections refused at the limits
+    refused: AtomicU64,
+    last_warning: Mutex<Option<Instant>>
+}
+
+impl ConnectionLimits {
+    pub fn new(max: usize, max_per_port: usize) -> Arc<ConnectionLimits> {
+        Arc::new(ConnectionLimits { max: AtomicUsize::new(max), max_per_port: AtomicUsize::new(max_per_port), ports: Mutex::default(), refused: AtomicU64::new(0), last_warning: Mutex::default() })
+    }
+
+    /// For the connections to come, the ones over them aren't closed
+    pub fn set_limits(&self, max: usize, max_per_port: usize) {
+        self.max.store(max, Ordering::Relaxed);
+        self.max_per_port.store(max_per_port, Ordering::Relaxed);
+    }
+
+    /// None when either limit is reached, in which case the connection should be refused
+    pub fn acquire(self: &Arc<Self>, port: Port) -> Option<ConnectionSlot> {
+        let mut ports = self.ports.lock().unwrap();
+        let total : usize = ports.values().sum();
+        if total >= self.max.load(Ordering::Relaxed) || ports.get(&port).is_some_and(|&count| count >= self.max_per_port.load(Ordering::Relaxed)) {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        *ports.entry(port).or_default() += 1;
+        Some(ConnectionSlot { limits: self.clone(), port })
+    }
+
+    /// The connections refused so far, when it's time to warn about them again
+    pub fn warning(&self) -> Option<u64> {
+        let mut last = self.last_warning.lock().unwrap();
+        if last.is_some_and(|last| last.elapsed() < LIMIT_WARNING_INTERVAL) {
+            return None;
+        }
+        *last = Some(Instant::now());
+        Some(self.refused.load(Ordering::Relaxed))
+    }
+}
+
+/// A connection counted against the limits until it's dropped, once both threads of its pipe are done
+pub struct ConnectionSlot {
+    limits: Arc<ConnectionLimits>,
+    port: Port
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut ports = self.limits.ports.lock().unwrap();
+        if let Some(count) = ports.get_mut(&self.port) {
+            *count -= 1;
+            if *count == 0 {
+                ports.remove(&self.port);
+            }
+        }
+    }
+}
+
+fn pipe_buffer_size(src: Option<&TcpStream>, dst: Option<&TcpStream>, config: &BufferConfig) -> usize {
+    let receive = src.and_then(|src| sockopt::buffer_size(src, BufferKind::Receive).ok()).unwrap_or(PIPE_BUFFER);
+    let send = dst.and_then(|dst| sockopt::buffer_size(dst, BufferKind::Send).ok()).unwrap_or(PIPE_BUFFER);
+    receive.max(send).clamp(config.min, config.max)
+}
+
+/// How often pipes are checked for leaks, and how long they may stay half-open
+#[derive(Copy, Clone, Debug)]
+pub struct ReaperConfig {
+    pub interval: Duration,
+    pub half_open_timeout: Duration,
+    /// Connections nothing went through for this long are closed, if set
+    pub idle_timeout: Option<Duration>
+}
+
+impl Default for ReaperConfig {
+    fn default() -> ReaperConfig {
+        ReaperConfig {
+            interval: Duration::from_secs(30),
+            half_open_timeout: Duration::from_secs(300),
+            idle_timeout: None
+        }
+    }
+}
+
+struct Pipe {
+    port: Option<u16>,
+    name: Option<String>,
+    peer: Option<SocketAddr>,
+    started: Instant,
+    tunnel: Box<dyn Stream>,
+    local: Box<dyn Stream>,
+    threads: Vec<JoinHandle<Result<()>>>,
+    state: Arc<PipeState>
+}
+
+fn reset_stream(stream: &dyn Stream) {
+    match stream.as_tcp() {
+        Some(tcp) => {
+            let _ = sockopt::set_linger_zero(tcp);
+            let _ = tcp.shutdown(Shutdown::Read);
+        }
+        None => {
+            let _ = stream.shutdown_stream();
+        }
+    }
+}
+
+/* Shared with the threads of the pipe */
+struct PipeState {
+    started: Instant,
+    /// Milliseconds after `started` that something was last read, in either direction
+    active: AtomicU64,
+    /// Read so far from the tunnel side, and from the client or local service side
+    to_local: AtomicU64,
+    from_local: AtomicU64,
+    /// When the first direction of the pipe finished
+    half_closed: Mutex<Option<Instant>>,
+    /// The threads then drop the streams without closing them cleanly
+    reset: AtomicBool,
+    /// Reset on purpose, by `cancel`
+    cancelled: AtomicBool,
+    /// See `PipeOptions::counter`
+    counter: Option<Arc<AtomicU64>>,
+    /// Of the port, with `accounting_file`
+    traffic: Option<Arc<PortTraffic>>
+}
+
+impl PipeState {
+    fn new(counter: Option<Arc<AtomicU64>>, traffic: Option<Arc<PortTraffic>>) -> PipeState {
+        PipeState {
+            started: Instant::now(),
+            active: AtomicU64::new(0),
+            to_local: AtomicU64::new(0),
+            from_local: AtomicU64::new(0),
+            half_closed: Mutex::new(None),
+            reset: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+            counter,
+            traffic
+        }
+    }
+
+    fn touch(&self, direction: Direction, len: usize) {
+        self.active.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+        match direction {
+            Direction::ToLocal => &self.to_local,
+            Direction::FromLocal => &self.from_local
+        }.fetch_add(len as u64, Ordering::Relaxed);
+        if let Some(traffic) = &self.traffic {
+            match direction {
+                Direction::ToLocal => &traffic.received,
+                Direction::FromLocal => &traffic.sent
+            }.fetch_add(len as u64, Ordering::Relaxed);
+        }
+    }
+
+    /* Once written to the destination */
+    fn count(&self, len: usize) {
+        if let Some(counter) = &self.counter {
+            counter.fetch_add(len as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn bytes(&self) -> u64 {
+        self.to_local.load(Ordering::Relaxed) + self.from_local.load(Ordering::Relaxed)
+    }
+
+    fn idle(&self) -> Duration {
+        self.started.elapsed().saturating_sub(Duration::from_millis(self.active.load(Ordering::Relaxed)))
+    }
+}
+
+impl Pipe {
+    fn shutdown(&self) {
+        let _ = self.tunnel.shutdown_stream();
+        let _ = self.local.shutdown_stream();
+    }
+
+    /* Reset both connections instead of closing them, the peers must not take a truncated stream as complete.
+       Shutting down the write side would send a FIN before the reset, so only the readers are woken up:
+       the streams are reset when the threads drop them */
+    fn reset(&self) {
+        self.state.reset.store(true, Ordering::Relaxed);
+        reset_stream(self.tunnel.as_ref());
+        reset_stream(self.local.as_ref());
+    }
+}
+
+/// What the admin socket shows about a pipe
+pub struct PipeInfo {
+    pub id: u64,
+    pub port: Option<u16>,
+    /// Of the redirect of the port
+    pub name: Option<String>,
+    /// The client, unless it's hidden
+    pub peer: Option<SocketAddr>,
+    /// Read from either side
+    pub bytes: u64,
+    pub age: Duration
+}
+
+/* Unique across the registries, so that a pipe of any session of the gateway can be killed by its ID */
+static NEXT_PIPE_ID : AtomicU64 = AtomicU64::new(0);
+
+/// Live pipes of a session, so that they can be shut down all at once
+#[derive(Default)]
+pub struct PipeRegistry {
+    pipes: Mutex<HashMap<u64, Pipe>>,
+    // Once the session is over, set while holding `pipes` so that none is registered after the others were cancelled
+    closed: AtomicBool,
+    // Dropped with the registry, which wakes its reaper up to end with it
+    reaper_stop: Mutex<Option<Sender<()>>>
+}
+
+impl PipeRegistry {
+    fn register(self: &Arc<Self>, options: &PipeOptions, a: &dyn Stream, b: &dyn Stream, state: Arc<PipeState>) -> Result<PipeGuard> {
+        let id = NEXT_PIPE_ID.fetch_add(1, Ordering::Relaxed);
+        let pipe = Pipe {
+            port: options.port,
+            name: options.name.clone(),
+            peer: options.peer,
+            started: Instant::now(),
+            tunnel: a.try_clone_stream()?,
+            local: b.try_clone_stream()?,
+            threads: Vec::new(),
+            state
+        };
+        let mut pipes = self.pipes.lock().unwrap();
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(anyhow!("The session is over"));
+        }
+        pipes.insert(id, pipe);
+        Ok(PipeGuard { id, registry: self.clone() })
+    }
+
+    /* The threads may already be done, in which case there's nothing to attach to */
+    fn attach(&self, id: u64, threads: Vec<JoinHandle<Result<()>>>) {
+        if let Some(pipe) = self.pipes.lock().unwrap().get_mut(&id) {
+            pipe.threads = threads;
+        }
+    }
+
+    /// Reset the pipes, then shut them down entirely after a while for the threads that were blocked writing
+    fn cancel(self: &Arc<Self>, ids: Vec<u64>) {
+        {
+            let pipes = self.pipes.lock().unwrap();
+            for pipe in ids.iter().filter_map(|id| pipes.get(id)) {
+                pipe.state.cancelled.store(true, Ordering::Relaxed);
+                pipe.reset();
+            }
+        }
+        let registry = Arc::downgrade(self);
+        log::spawn(move || {
+            thread::sleep(CANCEL_GRACE);
+            if let Some(registry) = registry.upgrade() {
+                let pipes = registry.pipes.lock().unwrap();
+                for pipe in ids.iter().filter_map(|id| pipes.get(id)) {
+                    pipe.shutdown();
+                }
+            }
+        });
+    }
+
+    /// Stop every pipe, the ones spawned afterwards run as usual
+    pub fn cancel_all(self: &Arc<Self>) {
+        let ids : Vec<u64> = self.pipes.lock().unwrap().keys().copied().collect();
+        if !ids.is_empty() {
+            self.cancel(ids);
+        }
+    }
+
+    /// Used when the session ends: its pipes are stopped rather than left running on their own, and the ones of the
+    /// connections still being set up are refused. Returns how many were stopped
+    pub fn close(self: &Arc<Self>) -> usize {
+        let ids : Vec<u64> = {
+            let pipes = self.pipes.lock().unwrap();
+            self.closed.store(true, Ordering::Relaxed);
+            pipes.keys().copied().collect()
+        };
+        let count = ids.len();
+        if count > 0 {
+            self.cancel(ids);
+        }
+        count
+    }
+
+    /// For the next session of the server, which keeps its registry
+    pub fn reopen(&self) {
+        let _pipes = self.pipes.lock().unwrap();
+        self.closed.store(false, Ordering::Relaxed);
+    }
+
+    pub fn len(&self) -> usize {
+        self.pipes.lock().unwrap().len()
+    }
+
+    pub fn list(&self) -> Vec<PipeInfo> {
+        let mut pipes : Vec<PipeInfo> = self.pipes.lock().unwrap().iter()
+            .map(|(id, pipe)| PipeInfo { id: *id, port: pipe.port, name: pipe.name.clone(), peer: pipe.peer, bytes: pipe.state.bytes(), age: pipe.started.elapsed() })
+            .collect();
+        pipes.sort_by_key(|pipe| pipe.id);
+        pipes
+    }
+
+    /// Returns false if there is no such pipe
+    pub fn kill(self: &Arc<Self>, id: u64) -> bool {
+        if !self.pipes.lock().unwrap().contains_key(&id) {
+            return false;
+        }
+        self.cancel(vec![id]);
+        true
+    }
+
+    /// Force-close pipes whose threads are gone but which are still registered,
+    /// and pipes that stayed half-open for too long
+    pub fn reap(&self, config: &ReaperConfig) {
+        self.pipes.lock().unwrap().retain(|id, pipe| {
+            if !pipe.threads.is_empty() && pipe.threads.iter().all(JoinHandle::is_finished) {
+                warn!("Pipe {id} exited without deregistering, removing it");
+                pipe.shutdown();
+                return false;
+            }
+            if config.idle_timeout.is_some_and(|timeout| pipe.state.idle() >= timeout) {
+                debug!("Pipe {id} has been idle for more than {}s, closing it", pipe.state.idle().as_secs());
+                pipe.shutdown();
+                return false;
+            }
+            let half_closed = *pipe.state.half_closed.lock().unwrap();
+            if half_closed.is_some_and(|since| since.elapsed() >= config.half_open_timeout) {
+                warn!("Pipe {id} has been half-open for more than {}s, closing it", config.half_open_timeout.as_secs());
+                pipe.shutdown();
+                return false;
+            }
+            true
+        });
+    }
+
+    /// The reaper stops by itself once the registry is dropped
+    pub fn spawn_reaper(self: &Arc<Self>, config: ReaperConfig) {
+        let registry = Arc::downgrade(self);
+        let (stop, stopped) = channel::<()>();
+        *self.reaper_stop.lock().unwrap() = Some(stop);
+        log::spawn(move || while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(config.interval) {
+            match registry.upgrade() {
+                Some(registry) => registry.reap(&config),
+                None => return
+            }
+        });
+    }
+}
+
+/* Shared by both threads of a pipe, removes it from the registry once both are done */
+struct PipeGuard {
+    id: u64,
+    registry: Arc<PipeRegistry>
+}
+
+impl Drop for PipeGuard {
+    fn drop(&mut self) {
+        self.registry.pipes.lock().unwrap().remove(&self.id);
+    }
+}
+
+/* Shared by both directions of a pipe like its guard, logs what went through once both are done */
+struct PipeSummary {
+    id: Option<u64>,
+    port: Option<u16>,
+    name: Option<String>,
+    peer: Option<SocketAddr>,
+    /// Of the thread that spawned the pipe, it may be dropped on a worker thread, which isn't tagged
+    tag: log::Tag,
+    state: Arc<PipeState>
+}
+
+impl Drop for PipeSummary {
+    fn drop(&mut self) {
+        let _tag = log::tagged(self.tag);
+        let mut connection = String::from("Connection");
+        if let Some(id) = self.id {
+            connection += &format!(" {id}");
+        }
+        if let Some(peer) = self.peer {
+            connection += &format!(" from {peer}");
+        }
+        if let Some(port) = self.port {
+            connection += &format!(" on port {}", port_label(port, self.name.as_deref()));
+        }
+        info!("{connection} closed after {:.1}s, {} bytes sent through the tunnel, {} bytes received from it",
+            self.state.started.elapsed().as_secs_f64(), self.state.from_local.load(Ordering::Relaxed), self.state.to_local.load(Ordering::Relaxed));
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct PipeOptions {
+    pub mirror: Option<MirrorTap>,
+    /// Incremented with the bytes transferred in both directions
+    pub counter: Option<Arc<AtomicU64>>,
+    pub registry: Option<Arc<PipeRegistry>>,
+    /// Gateway port of the redirect, its name and the client, for the admin socket
+    pub port: Option<u16>,
+    pub name: Option<String>,
+    pub peer: Option<SocketAddr>,
+    pub buffers: BufferConfig,
+    /// Hash both directions, and report their digests once the connection is over
+    pub integrity: Option<DigestReport>,
+    /// Encrypt the tunnel side: the cipher of what is written to it, and of what is read from it
+    pub encryption: Option<(Cipher, Cipher)>,
+    /// Held until both directions are done
+    pub slot: Option<Arc<ConnectionSlot>>,
+    /// Shared by every connection of the port, for both directions
+    pub rate: Option<Arc<RateLimiter>>,
+    /// Disable Nagle's algorithm on both sockets, so that the small writes of interactive protocols go out at once
+    pub nodelay: bool
+}
+
+/* Once the source is done, everything it sent is passed on before the destination is half-closed: the
+   connection is only closed once the other direction is done too (or the reaper gave up on it).
+   After an error, the whole pipe is reset (see Pipe::reset), so that the other direction stops as well
+   and the peer doesn't take a truncated stream as complete */
+fn pipe_streams<R: Read>(mut src: R, mut dst: Box<dyn Stream>, buffer_size: usize, direction: Direction, mirror: Option<MirrorTap>,
+                         integrity: Option<(Arc<Collector>, bool)>, state: &PipeState) -> Result<()> {
+    let mut buf = vec![0u8; buffer_size];
+    let mut digest = integrity.as_ref().map(|_| Digest::default());
+    let result = loop {
+        let len = match src.read(&mut buf) {
+            Ok(len) => len,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => break Err(err)
+        };
+        if let Some(tap) = &mirror {
+            tap.record(direction, &buf[0..len]);
+        }
+        if len == 0 {
+            break dst.flush(); // Connection ended successfully
+        }
+        state.touch(direction, len);
+        if let Err(err) = dst.write_all(&buf[0..len]) {
+            break Err(err);
+        }
+        if let Some(digest) = &mut digest {
+            digest.update(&buf[0..len]);
+        }
+        state.count(len);
+    };
+    if let (Some((collector, from_tunnel)), Some(digest)) = (integrity, digest) {
+        let complete = result.is_ok() && !state.reset.load(Ordering::Relaxed);
+        collector.done(from_tunnel, digest.finish(complete));
+    }
+    finish_pipe(result, dst.as_ref(), state)
+}
+
+/* Once a direction is done, the destination is half-closed, or reset after an error */
+fn finish_pipe(result: std::io::Result<()>, dst: &dyn Stream, state: &PipeState) -> Result<()> {
+    state.half_closed.lock().unwrap().get_or_insert_with(Instant::now);
+    match result {
+        _ if state.cancelled.load(Ordering::Relaxed) => Err(anyhow!("Pipe cancelled")),
+        Ok(()) if state.reset.load(Ordering::Relaxed) => Ok(()),
+        Ok(()) => {
+            let _ = dst.shutdown_write();
+            Ok(())
+        }
+        Err(err) => {
+            state.reset.store(true, Ordering::Relaxed);
+            reset_stream(dst);
+            Err(err.into())
+        }
+    }
+}
+
+/* On Linux, what goes between two TCP sockets is moved through a pipe with splice(2), without copying it to user
+   space, when nothing needs to see the bytes. Returns None when the sockets can't be spliced, before anything moved */
+fn splice_streams(src: &TcpStream, dst: &TcpStream, buffer_size: usize, direction: Direction, state: &PipeState) -> Option<std::io::Result<()>> {
+    let pipe = sockopt::SplicePipe::new(buffer_size).ok()?;
+    let mut moved = false;
     loop {
-        let len = src.read(&mut buf)?;
+        let len = match pipe.fill(src, buffer_size) {
+            Ok(len) => len,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) if !moved && matches!(err.kind(), std::io::ErrorKind::InvalidInput | std::io::ErrorKind::Unsupported) => return None,
+            Err(err) => return Some(Err(err))
+        };
         if len == 0 {
-            return Ok(()); // Connection ended successfully
+            return Some(Ok(()));
         }
-        dst.write_all(&buf[0..len])?;
+        moved = true;
+        state.touch(direction, len);
+        if let Err(err) = pipe.drain(dst, len) {
+            return Some(Err(err));
+        }
+        state.count(len);
+    }
+}
+
+const WORKER_WAIT : Duration = Duration::from_secs(1);
+const WORKER_READS : usize = 16; // Per direction, before the other pipes of the worker get their turn
+
+/// Set with `pipe_workers`: threads that each serve many pipes whose bytes are only passed on, see `spawn_pipes`
+pub static WORKERS : OnceLock<PipeWorkers> = OnceLock::new();
+
+pub struct PipeWorkers {
+    workers: Vec<Arc<Worker>>,
+    next: AtomicUsize
+}
+
+struct Worker {
+    poller: sockopt::Poller,
+    pipes: Mutex<HashMap<u64, PooledPipe>>,
+    next_token: AtomicU64
+}
+
+/* What a pipe holds until both directions are done */
+type Held = (Option<Arc<PipeGuard>>, Arc<Reservation>, Option<Arc<ConnectionSlot>>, Arc<PipeSummary>);
+
+/// Both sockets are non-blocking, `a` is the tunnel side
+struct PooledPipe {
+    a: TcpStream,
+    b: TcpStream,
+    to_a: Flow,
+    to_b: Flow,
+    state: Arc<PipeState>,
+    _held: Held
+}
+
+/* A direction of a pooled pipe, with what was read and not written yet */
+struct Flow {
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    eof: bool,
+    done: bool
+}
+
+enum Progress {
+    /// Until either socket is ready again
+    Blocked,
+    /// There's more to do right away, once the other pipes had their turn
+    More,
+    Done(std::io::Result<()>)
+}
+
+impl Flow {
+    fn new(size: usize) -> Flow {
+        Flow { buf: vec![0u8; size], start: 0, end: 0, eof: false, done: false }
+    }
+
+    fn pass(&mut self, mut src: &TcpStream, mut dst: &TcpStream, direction: Direction, state: &PipeState) -> Progress {
+        let mut reads = 0;
+        loop {
+            if self.start == self.end {
+                if self.eof {
+                    return Progress::Done(dst.flush());
+                }
+                if reads == WORKER_READS {
+                    return Progress::More;
+                }
+                match src.read(&mut self.buf) {
+                    Ok(0) => self.eof = true,
+                    Ok(len) => {
+                        (self.start, self.end) = (0, len);
+                        reads += 1;
+                        state.touch(direction, len);
+                    }
+                    Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Progress::Blocked,
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {},
+                    Err(err) => return Progress::Done(Err(err))
+                }
+                continue;
+            }
+            match dst.write(&self.buf[self.start..self.end]) {
+                Ok(0) => return Progress::Done(Err(std::io::ErrorKind::WriteZero.into())),
+                Ok(len) => {
+                    self.start += len;
+                    state.count(len);
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Progress::Blocked,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {},
+                Err(err) => return Progress::Done(Err(err))
+            }
+        }
+    }
+}
+
+impl PooledPipe {
+    /* Both directions, as far as they can go. Whether there's more to do right away */
+    fn step(&mut self) -> bool {
+        let mut more = false;
+        for (flow, src, dst, direction) in [(&mut self.to_b, &self.a, &self.b, Direction::ToLocal), (&mut self.to_a, &self.b, &self.a, Direction::FromLocal)] {
+            if flow.done {
+                continue;
+            }
+            match flow.pass(src, dst, direction, &self.state) {
+                Progress::Blocked => {},
+                Progress::More => more = true,
+                Progress::Done(result) => {
+                    flow.done = true;
+                    let _ = finish_pipe(result, dst, &self.state);
+                }
+            }
+        }
+        more
+    }
+
+    fn done(&self) -> bool {
+        self.to_a.done && self.to_b.done
+    }
+}
+
+impl Worker {
+    fn run(&self) {
+        let mut ready = Vec::new();
+        let mut again = Vec::new();
+        loop {
+            let timeout = if again.is_empty() { WORKER_WAIT } else { Duration::ZERO };
+            if let Err(err) = self.poller.wait(&mut ready, timeout) {
+                warn!("Pipe worker failed to wait for its sockets, reason: {err:#}");
+                thread::sleep(WORKER_WAIT);
+            }
+            ready.append(&mut again);
+            let mut pipes = self.pipes.lock().unwrap();
+            for token in ready.drain(..) {
+                let Some(pipe) = pipes.get_mut(&token) else {
+                    continue; // Reported twice, and over already
+                };
+                if pipe.step() {
+                    again.push(token);
+                }
+                if pipe.done() {
+                    let pipe = pipes.remove(&token).unwrap();
+                    // The registry may still have other descriptors of the sockets open
+                    let _ = self.poller.remove(&pipe.a);
+                    let _ = self.poller.remove(&pipe.b);
+                }
+            }
+            again.dedup();
+        }
+    }
+}
+
+impl PipeWorkers {
+    /// Without workers, every pipe has its own two threads
+    pub fn start(count: usize) -> Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        let mut workers = Vec::with_capacity(count);
+        for _ in 0..count {
+            let poller = match sockopt::Poller::new() {
+                Ok(poller) => poller,
+                Err(err) if err.kind() == std::io::ErrorKind::Unsupported => {
+                    warn!("pipe_workers is ignored, {err}");
+                    return Ok(());
+                }
+                Err(err) => return Err(err.into())
+            };
+            workers.push(Arc::new(Worker { poller, pipes: Mutex::default(), next_token: AtomicU64::new(0) }));
+        }
+        for worker in &workers {
+            let worker = worker.clone();
+            thread::spawn(move || worker.run());
+        }
+        let _ = WORKERS.set(PipeWorkers { workers, next: AtomicUsize::new(0) });
+        Ok(())
+    }
+
+    fn add(&self, a: TcpStream, b: TcpStream, (to_a, to_b): (usize, usize), state: Arc<PipeState>, held: Held) -> std::io::Result<()> {
+        a.set_nonblocking(true)?;
+        b.set_nonblocking(true)?;
+        let worker = &self.workers[self.next.fetch_add(1, Ordering::Relaxed) % self.workers.len()];
+        let token = worker.next_token.fetch_add(1, Ordering::Relaxed);
+        // Registered while the worker is locked out, so that it knows of the pipe by the time it sees its sockets
+        let mut pipes = worker.pipes.lock().unwrap();
+        let added = worker.poller.add(&a, token).and_then(|()| worker.poller.add(&b, token).inspect_err(|_| {
+            let _ = worker.poller.remove(&a);
+        }));
+        added?;
+        pipes.insert(token, PooledPipe { a, b, to_a: Flow::new(to_a), to_b: Flow::new(to_b), state, _held: held });
+        Ok(())
     }
 }
-pub fn spawn_pipes(a: TcpStream, b: TcpStream) -> Result<()> {
-    a.set_nonblocking(false)?;
-    b.set_nonblocking(false)?;
+
+/// A port in the logs, with the name of its redirect if it has one
+pub fn port_label(port: u16, name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("{port} ({name})"),
+        None => port.to_string()
+    }
+}
+
+/// Of a connection between the server and the gateway. Failing only means noticing later that the other side is gone
+pub fn set_keepalive(stream: &TcpStream, keepalive: Option<Keepalive>) {
+    let Some(Keepalive { idle, interval, count, .. }) = keepalive else { return };
+    if let Err(err) = sockopt::set_keepalive(stream, idle, interval, count) {
+        debug!("Failed to enable TCP keepalive, reason: {err:#}");
+    }
+}
+
+/// `a` is the tunnel side of the connection, a connection to the gateway of its own or a stream of the control
+/// connection (see `mux`), `b` the client or local service side.
+/// Past the memory budget the pipes get the smallest buffers. Past it even with those, both streams are closed and
+/// `MemoryExhausted` returned: the connection is rejected, waiting for room isn't implemented
+pub fn spawn_pipes(a: Box<dyn Stream>, b: Box<dyn Stream>, options: PipeOptions) -> Result<()> {
+    for stream in [a.as_tcp(), b.as_tcp()].into_iter().flatten() {
+        stream.set_nonblocking(false)?;
+        if options.nodelay {
+            if let Err(err) = stream.set_nodelay(true) {
+                warn!("Failed to set TCP_NODELAY: {err:#}");
+            }
+        }
+    }
+    if let Some(size) = options.buffers.socket_buffer {
+        for stream in [a.as_tcp(), b.as_tcp()].into_iter().flatten() {
+            for kind in [BufferKind::Receive, BufferKind::Send] {
+                if let Err(err) = sockopt::set_buffer_size(stream, kind, size) {
+                    warn!("Failed to set socket buffer size: {err:#}");
+                }
+            }
+        }
+    }
+    let mut to_b = pipe_buffer_size(a.as_tcp(), b.as_tcp(), &options.buffers);
+    let mut to_a = pipe_buffer_size(b.as_tcp(), a.as_tcp(), &options.buffers);
+    let reservation = match MEMORY.reserve(to_a + to_b) {
+        Some(reservation) => reservation,
+        None => {
+            let min = options.buffers.min;
+            match MEMORY.reserve(2 * min) {
+                Some(reservation) => {
+                    warn!("Memory budget exhausted, using the smallest pipe buffers");
+                    MEMORY.downsized.fetch_add(1, Ordering::Relaxed);
+                    (to_a, to_b) = (min, min);
+                    reservation
+                }
+                None => {
+                    MEMORY.reject();
+                    let _ = a.shutdown_stream();
+                    let _ = b.shutdown_stream();
+                    return Err(MemoryExhausted.into());
+                }
+            }
+        }
+    };
+    // Given back once both threads are done
+    let reservation = Arc::new(reservation);
+    debug!("Pipe buffers: {to_b} bytes towards the client/local side, {to_a} bytes towards the tunnel");
+    let state = Arc::new(PipeState::new(options.counter.clone(), options.port.and_then(accounting::port)));
+    let guard = match &options.registry {
+        Some(registry) => Some(Arc::new(registry.register(&options, a.as_ref(), b.as_ref(), state.clone())?)),
+        None => None
+    };
+    let id = guard.as_ref().map(|guard| guard.id);
+    let summary = Arc::new(PipeSummary { id, port: options.port, name: options.name.clone(), peer: options.peer, tag: log::tag(), state: state.clone() });
+    // Nothing needs to see the bytes
+    let plain = options.mirror.is_none() && options.integrity.is_none() && options.encryption.is_none() && options.rate.is_none();
+    if let (Some(workers), Some(a), Some(b), true) = (WORKERS.get(), a.as_tcp(), b.as_tcp(), plain) {
+        return Ok(workers.add(a.try_clone()?, b.try_clone()?, (to_a, to_b), state, (guard, reservation, options.slot, summary))?);
+    }
+    // Both sockets, for each direction
+    let splice = match (a.as_tcp(), b.as_tcp()) {
+        (Some(a), Some(b)) if cfg!(target_os = "linux") && plain => Some((a.try_clone()?, b.try_clone()?, a.try_clone()?, b.try_clone()?)),
+        _ => None
+    };
+    let (to_b_splice, to_a_splice) = splice.map(|(a1, b1, a2, b2)| ((a1, b1), (b2, a2))).unzip();
+    let collector = options.integrity.map(Collector::new);
+    // The streams of the control connection are encrypted with it already
+    let sealed = match (&options.encryption, a.as_tcp()) {
+        (None, _) => None,
+        (Some(_), Some(a)) => Some((a.try_clone()?, a.try_clone()?)),
+        (Some(_), None) => return Err(anyhow!("Only the connections of their own can be encrypted"))
+    };
+    let (seal, open) = options.encryption.zip(sealed).map(|((seal, open), (to, from))| ((seal, to), (open, from))).unzip();
+    let mut threads = Vec::with_capacity(2);
     {
-        let src = a.try_clone()?;
-        let dst = b.try_clone()?;
-        thread::spawn(move || pipe_streams(src, dst));
+        let src : Box<dyn Read + Send> = match open {
+            Some((cipher, from)) => Box::new(SealedReader::new(from, cipher)),
+            None => a.try_clone_stream()?
+        };
+        let src = throttle::throttle(src, options.rate.clone());
+        let dst = b.try_clone_stream()?;
+        let mirror = options.mirror.clone();
+        let guard = guard.clone();
+        let reservation = reservation.clone();
+        let slot = options.slot.clone();
+        let summary = summary.clone();
+        let state = state.clone();
+        let integrity = collector.clone().map(|collector| (collector, true));
+        threads.push(log::spawn(move || {
+            let _guard = (guard, reservation, slot, summary);
+            let spliced = to_b_splice.and_then(|(from, to)| Some((splice_streams(&from, &to, to_b, Direction::ToLocal, &state)?, to)));
+            if let Some((result, to)) = spliced {
+                return finish_pipe(result, &to, &state);
+            }
+            pipe_streams(src, dst, to_b, Direction::ToLocal, mirror, integrity, &state)
+        }));
     }
     {
-        let src = b;
-        let dst = a;
-        thread::spawn(move || pipe_streams(src, dst));
+        let src = throttle::throttle(b, options.rate);
+        let dst : Box<dyn Stream> = match seal {
+            Some((cipher, to)) => Box::new(SealedWriter::new(to, cipher)),
+            None => a
+        };
+        let mirror = options.mirror;
+        let integrity = collector.map(|collector| (collector, false));
+        let slot = options.slot;
+        threads.push(log::spawn(move || {
+            let _guard = (guard, reservation, slot, summary);
+            let spliced = to_a_splice.and_then(|(from, to)| Some((splice_streams(&from, &to, to_a, Direction::FromLocal, &state)?, to)));
+            if let Some((result, to)) = spliced {
+                return finish_pipe(result, &to, &state);
+            }
+            pipe_streams(src, dst, to_a, Direction::FromLocal, mirror, integrity, &state)
+        }));
+    }
+    if let (Some(registry), Some(id)) = (&options.registry, id) {
+        registry.attach(id, threads);
     }
     Ok(())
 }
 
-pub const TCP_CHALLENGE_LENGTH : usize = 14;
\ No newline at end of file
+pub const TCP_CHALLENGE_LENGTH : usize = 14;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+    use std::net::{Ipv4Addr, TcpListener};
+
+    /* How soon a pipe that was told to stop must be over */
+    const BOUND : Duration = Duration::from_secs(1);
+
+    /* Both ends of a loopback TCP connection */
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (client, listener.accept().unwrap().0)
+    }
+
+    /* A pipe between two connections, as the gateway and the server spawn them: returns the peers of its tunnel
+       side and of its local side */
+    fn piped(options: PipeOptions) -> Result<(TcpStream, TcpStream)> {
+        let (tunnel_peer, tunnel) = socket_pair();
+        let (local, local_peer) = socket_pair();
+        spawn_pipes(Box::new(tunnel), Box::new(local), options)?;
+        Ok((tunnel_peer, local_peer))
+    }
+
+    /* Whether the connection of `stream` ended within `bound`, closed or reset, whatever came before */
+    fn ends_within(stream: &mut TcpStream, bound: Duration) -> bool {
+        let deadline = Instant::now() + bound;
+        let mut buf = [0u8; 65536];
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return false;
+            }
+            stream.set_read_timeout(Some(left)).unwrap();
+            match stream.read(&mut buf) {
+                Ok(0) => return true,
+                Ok(_) => {}
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return false,
+                Err(_) => return true
+            }
+        }
+    }
+
+    /* Until the threads of the registry's pipes are done, which removes them */
+    fn empties_within(registry: &PipeRegistry, bound: Duration) -> bool {
+        let deadline = Instant::now() + bound;
+        while registry.len() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        registry.len() == 0
+    }
+
+    /* How long the transfers of the tests may take */
+    const TRANSFER_BOUND : Duration = Duration::from_secs(10);
+    /* More than the socket buffers hold, so that the pipe is still busy when it is stopped */
+    const PAYLOAD_LENGTH : usize = 16 << 20;
+
+    /* Recognizable at any offset */
+    fn payload(len: usize) -> Vec<u8> {
+        let mut payload = (0..251).collect::<Vec<u8>>().repeat(len / 251 + 1);
+        payload.truncate(len);
+        payload
+    }
+
+    /* Plain pipes are spliced on Linux, a rate limiter that never waits makes them copy the bytes */
+    fn variants() -> [(&'static str, PipeOptions); 2] {
+        [("spliced", PipeOptions::default()), ("copied", PipeOptions { rate: Some(RateLimiter::new(1 << 40)), ..Default::default() })]
+    }
+
+    /* Writes `data` then half-closes the connection, on a thread of its own */
+    fn send(stream: &TcpStream, data: Vec<u8>) -> JoinHandle<std::io::Result<()>> {
+        let mut stream = stream.try_clone().unwrap();
+        thread::spawn(move || {
+            stream.write_all(&data)?;
+            stream.shutdown(Shutdown::Write)
+        })
+    }
+
+    /* Everything read until the connection ended, and whether it ended with a clean close */
+    fn receive(stream: &mut TcpStream) -> (Vec<u8>, std::io::Result<()>) {
+        stream.set_read_timeout(Some(TRANSFER_BOUND)).unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0u8; 65536];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => return (received, Ok(())),
+                Ok(len) => received.extend_from_slice(&buf[..len]),
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => panic!("the connection didn't end"),
+                Err(err) => return (received, Err(err))
+            }
+        }
+    }
+
+    #[test]
+    fn closed_registries_end_their_pipes() {
+        let registry = Arc::new(PipeRegistry::default());
+        let options = PipeOptions { registry: Some(registry.clone()), ..Default::default() };
+        let mut pipes = vec![piped(options.clone()).unwrap(), piped(options.clone()).unwrap()];
+        for (tunnel_peer, local_peer) in &mut pipes {
+            local_peer.write_all(b"ping").unwrap();
+            let mut buf = [0u8; 4];
+            tunnel_peer.read_exact(&mut buf).unwrap();
+        }
+        // As when the control connection of the session dies
+        assert_eq!(registry.close(), 2);
+        for (tunnel_peer, local_peer) in &mut pipes {
+            assert!(ends_within(tunnel_peer, BOUND), "the tunnel side is still open");
+            assert!(ends_within(local_peer, BOUND), "the local side is still open");
+        }
+        assert!(empties_within(&registry, BOUND));
+        assert!(piped(options).is_err(), "a pipe spawned once the session is over is refused");
+    }
+
+    #[test]
+    fn closed_connections_are_delivered_entirely() {
+        for (variant, options) in variants() {
+            let (mut tunnel_peer, mut local_peer) = piped(options).unwrap();
+            let upload = send(&local_peer, payload(PAYLOAD_LENGTH));
+            let download = send(&tunnel_peer, payload(PAYLOAD_LENGTH / 2));
+            let (received, end) = receive(&mut tunnel_peer);
+            assert!(end.is_ok(), "{variant}: the upload ended with {end:?}");
+            assert!(received == payload(PAYLOAD_LENGTH), "{variant}: {} bytes of the upload received", received.len());
+            let (received, end) = receive(&mut local_peer);
+            assert!(end.is_ok(), "{variant}: the download ended with {end:?}");
+            assert!(received == payload(PAYLOAD_LENGTH / 2), "{variant}: {} bytes of the download received", received.len());
+            upload.join().unwrap().unwrap();
+            download.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn killed_connections_are_reset() {
+        for (variant, options) in variants() {
+            let registry = Arc::new(PipeRegistry::default());
+            let (mut tunnel_peer, local_peer) = piped(PipeOptions { registry: Some(registry.clone()), ..options }).unwrap();
+            let _upload = send(&local_peer, payload(PAYLOAD_LENGTH));
+            let mut buf = vec![0u8; 65536];
+            tunnel_peer.read_exact(&mut buf).unwrap();
+            assert!(registry.kill(registry.list()[0].id));
+            // Not reading meanwhile, the pipe is stuck writing until it gives up
+            assert!(empties_within(&registry, CANCEL_GRACE + BOUND), "{variant}: the killed pipe is still running");
+            let (received, end) = receive(&mut tunnel_peer);
+            assert!(end.is_err(), "{variant}: the truncated upload looks complete");
+            assert!(buf.len() + received.len() < PAYLOAD_LENGTH, "{variant}: the upload went through entirely");
+        }
+    }
+
+    #[test]
+    fn failures_reset_the_other_side() {
+        for (variant, options) in variants() {
+            let (mut tunnel_peer, mut local_peer) = piped(options).unwrap();
+            local_peer.write_all(&payload(65536)).unwrap();
+            let mut buf = vec![0u8; 65536];
+            tunnel_peer.read_exact(&mut buf).unwrap();
+            // The client aborts, the gateway must not see the end of a complete upload
+            sockopt::set_linger_zero(&local_peer).unwrap();
+            drop(local_peer);
+            let (received, end) = receive(&mut tunnel_peer);
+            assert!(end.is_err(), "{variant}: the aborted upload ended cleanly after {} more bytes", received.len());
+        }
+    }
+
+    #[test]
+    fn teardowns_stop_transfers_under_way() {
+        for (variant, options) in variants() {
+            let registry = Arc::new(PipeRegistry::default());
+            let (tunnel_peer, local_peer) = piped(PipeOptions { registry: Some(registry.clone()), ..options }).unwrap();
+            // Neither peer reads, so the threads of both directions end up blocked writing
+            let _upload = send(&local_peer, payload(PAYLOAD_LENGTH));
+            let _download = send(&tunnel_peer, payload(PAYLOAD_LENGTH));
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(registry.len(), 1, "{variant}: the pipe ended by itself");
+            let started = Instant::now();
+            registry.close();
+            // The pipe is deregistered once both of its threads are over
+            assert!(empties_within(&registry, CANCEL_GRACE + BOUND), "{variant}: the threads are still running");
+            debug!("{variant}: the pipe stopped after {:?}", started.elapsed());
+        }
+    }
+
+    #[test]
+    fn reapers_close_idle_pipes() {
+        let registry = Arc::new(PipeRegistry::default());
+        let idle_timeout = Duration::from_millis(200);
+        registry.spawn_reaper(ReaperConfig { interval: Duration::from_millis(20), idle_timeout: Some(idle_timeout), ..Default::default() });
+        let (mut tunnel_peer, mut local_peer) = piped(PipeOptions { registry: Some(registry.clone()), ..Default::default() }).unwrap();
+        local_peer.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        tunnel_peer.read_exact(&mut buf).unwrap();
+        assert!(!ends_within(&mut tunnel_peer, idle_timeout / 2), "closed before its idle timeout");
+        assert!(empties_within(&registry, idle_timeout + BOUND), "the idle pipe is still running");
+        assert!(ends_within(&mut tunnel_peer, BOUND));
+        assert!(ends_within(&mut local_peer, BOUND));
+    }
+
+    #[test]
+    fn reapers_close_half_open_pipes() {
+        let registry = Arc::new(PipeRegistry::default());
+        let config = ReaperConfig { interval: Duration::from_millis(20), half_open_timeout: Duration::from_millis(200), idle_timeout: None };
+        let (mut tunnel_peer, local_peer) = piped(PipeOptions { registry: Some(registry.clone()), ..Default::default() }).unwrap();
+        // The client is done sending, the gateway never answers nor closes
+        local_peer.shutdown(Shutdown::Write).unwrap();
+        assert!(ends_within(&mut tunnel_peer, BOUND), "the half-close didn't go through");
+        registry.reap(&config);
+        assert_eq!(registry.len(), 1, "reaped before its half-open timeout");
+        thread::sleep(config.half_open_timeout);
+        registry.reap(&config);
+        assert_eq!(registry.len(), 0, "the half-open pipe is still registered");
+        assert!(empties_within(&registry, BOUND));
+    }
+
+    #[test]
+    fn reapers_collect_leaked_pipes() {
+        let registry = Arc::new(PipeRegistry::default());
+        let (mut tunnel_peer, tunnel) = socket_pair();
+        let (local, mut local_peer) = socket_pair();
+        // A pipe whose threads ended without deregistering it
+        let guard = registry.register(&PipeOptions::default(), &tunnel, &local, Arc::new(PipeState::new(None, None))).unwrap();
+        let id = guard.id;
+        std::mem::forget(guard);
+        let threads : Vec<JoinHandle<Result<()>>> = (0..2).map(|_| thread::spawn(|| Ok(()))).collect();
+        while !threads.iter().all(JoinHandle::is_finished) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        registry.attach(id, threads);
+        drop((tunnel, local));
+        registry.spawn_reaper(ReaperConfig { interval: Duration::from_millis(20), ..Default::default() });
+        assert!(empties_within(&registry, BOUND), "the leaked pipe is still registered");
+        // Its streams are closed with it
+        assert!(ends_within(&mut tunnel_peer, BOUND));
+        assert!(ends_within(&mut local_peer, BOUND));
+    }
+
+    #[test]
+    fn half_closed_clients_get_the_whole_response() {
+        for (variant, options) in variants() {
+            let (mut tunnel_peer, mut local_peer) = piped(options).unwrap();
+            // A service that only answers once the request is over, as with git or `nc -N`
+            let service = thread::spawn(move || {
+                let (request, end) = receive(&mut tunnel_peer);
+                end.unwrap();
+                tunnel_peer.write_all(&payload(PAYLOAD_LENGTH))?;
+                tunnel_peer.shutdown(Shutdown::Write)?;
+                Ok::<_, std::io::Error>(request)
+            });
+            local_peer.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+            local_peer.shutdown(Shutdown::Write).unwrap();
+            let (response, end) = receive(&mut local_peer);
+            assert!(end.is_ok(), "{variant}: the response ended with {end:?}");
+            assert!(response == payload(PAYLOAD_LENGTH), "{variant}: {} bytes of the response received", response.len());
+            assert_eq!(service.join().unwrap().unwrap(), b"GET / HTTP/1.0\r\n\r\n", "{variant}");
+        }
+    }
+
+    #[test]
+    fn nodelay_is_set_on_both_sockets() {
+        for nodelay in [true, false] {
+            let (_tunnel_peer, tunnel) = socket_pair();
+            let (local, _local_peer) = socket_pair();
+            // The same sockets as the pipe's
+            let sockets = [tunnel.try_clone().unwrap(), local.try_clone().unwrap()];
+            spawn_pipes(Box::new(tunnel), Box::new(local), PipeOptions { nodelay, ..Default::default() }).unwrap();
+            for (side, socket) in ["tunnel", "local"].iter().zip(&sockets) {
+                assert_eq!(socket.nodelay().unwrap(), nodelay, "TCP_NODELAY of the {side} side with nodelay = {nodelay}");
+            }
+        }
+    }
+}
@@ -11,20 +11,81 @@ If not, see <https://www.gnu.org/licenses/>.
 
 extern crate serde;
 
-use crate::crypto::{Key, random_key};
+use crate::cidr::{AccessRules, Cidr};
+use crate::common::{BufferConfig, ReaperConfig};
+use crate::sockopt::{ListenOptions, DEFAULT_BACKLOG};
+use crate::connector::{CUSTOM_TARGET_PREFIX, UNIX_TARGET_PREFIX};
+use crate::pool::MAX_PRECONNECT;
+use crate::crypto::{Key, Magics, KEY_LENGTH, fingerprint, random_key};
+use crate::schedule::Schedule;
+use crate::error::Failure;
+use crate::format::{self, Format};
+use crate::{info, warn};
 use serde::{Serialize, Deserialize};
-use toml::Value;
+use toml::{Table, Value};
 use anyhow::{anyhow, Result, Context};
-use std::fs::{self, File};
-use std::io::Read;
-use std::path::Path;
-use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::OnceLock;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
 
 #[derive(Serialize, Deserialize, Hash, Eq, PartialEq, Debug, Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Protocol {
     UDP, TCP
 }
 
+/// Which addresses the gateway listens on, and the server reaches the gateway on
+#[derive(Default, PartialEq, Debug, Copy, Clone)]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6
+}
+
+impl AddressFamily {
+    fn parse(x: &str) -> Result<AddressFamily> {
+        match x {
+            "any" => Ok(AddressFamily::Any),
+            "ipv4" => Ok(AddressFamily::Ipv4),
+            "ipv6" => Ok(AddressFamily::Ipv6),
+            x => Err(anyhow!("{x} is not a valid address_family, expected any, ipv4 or ipv6"))
+        }
+    }
+
+    pub fn permits(self, ip: IpAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::Ipv4 => ip.is_ipv4(),
+            AddressFamily::Ipv6 => ip.is_ipv6()
+        }
+    }
+
+    /// Every address of the family; both families listen on the IPv6 one
+    pub fn unspecified(self) -> IpAddr {
+        match self {
+            AddressFamily::Ipv4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            AddressFamily::Any | AddressFamily::Ipv6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AddressFamily::Any => "IPv4 or IPv6",
+            AddressFamily::Ipv4 => "IPv4",
+            AddressFamily::Ipv6 => "IPv6"
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize, Hash, Eq, PartialEq, Debug, Copy, Clone)]
 pub struct Port {
     pub port: u16,
@@ -32,7 +93,7 @@ pub struct Port {
 }
 
 impl Port {
-    pub fn to_bytes(&self) -> [u8; 3] {
+    pub fn to_bytes(self) -> [u8; 3] {
         let mut ret = [0u8; 3];
         let port_be_bytes = self.port.to_be_bytes();
         ret[0] = port_be_bytes[0];
@@ -44,15 +105,16 @@ impl Port {
         ret
     }
 
-    pub fn from_bytes(buf: &[u8; 3]) -> Port {
-        Port {
-            port: u16::from_be_bytes(buf[0..2].try_into().unwrap()),
+    /// For the bytes of a peer, which may be malformed or come from a newer version
+    pub fn try_from_bytes(buf: &[u8; 3]) -> Result<Port> {
+        Ok(Port {
+            port: u16::from_be_bytes([buf[0], buf[1]]),
             protocol: match buf[2] {
                 0 => Protocol::UDP,
                 1 => Protocol::TCP,
-                _ => panic!("malformed port received")
+                x => return Err(anyhow!("Malformed port, unknown protocol {x}"))
             }
-        }
+        })
     }
 
     pub fn new_tcp(port: u16) -> Port {
@@ -61,16 +123,611 @@ impl Port {
             protocol: Protocol::TCP
         }
     }
+
+    pub fn new_udp(port: u16) -> Port {
+        Port {
+            port,
+            protocol: Protocol::UDP
+        }
+    }
+}
+
+/// Same format as the compact redirects, e.g. `2222/tcp`
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.protocol {
+            Protocol::TCP => write!(f, "{}/tcp", self.port),
+            Protocol::UDP => write!(f, "{}/udp", self.port)
+        }
+    }
+}
+
+const MAX_FIRST_BYTE_TIMEOUT : f64 = 3600.0;
+pub const MAX_MAINTENANCE_BODY : usize = 1024;
+const DEFAULT_MAINTENANCE_BODY : &str = "This service is down for maintenance, please retry later.\n";
+
+/// What the gateway does with the clients of a port while the server is away or the port is paused
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Maintenance {
+    /// A 503 response
+    Http { body: String, retry_after: Option<u32> },
+    /// Close the connection with a reset
+    Reset,
+    /// Keep the connection until the server is back
+    Hold
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Redirect {
+    pub local_port: u16,
+    pub mirror: Option<String>,
+    pub schedule: Option<Schedule>,
+    /// Whether the gateway tells us who connected
+    pub forward_client_addr: bool,
+    /// Idle connections to the local service kept ready
+    pub preconnect: usize,
+    /// Name of the connector serving this redirect instead of the local port, see `connector`
+    pub target: Option<String>,
+    /// Clients the gateway accepts on this port, on top of its own rules
+    pub access: AccessRules,
+    /// How long the gateway waits for the client to send something before involving us
+    pub first_byte_timeout: Option<Duration>,
+    /// Keeps the port bound on the gateway when there's nobody to serve it
+    pub maintenance: Option<Maintenance>,
+    /// Where the local port is, when it isn't on this machine; resolved at each connection
+    pub host: Option<String>,
+    /// Unix socket the connections go to instead of the local port
+    pub unix: Option<PathBuf>,
+    /// Debugging: compare what both ends of the tunnel saw of each connection
+    pub verify_integrity: bool,
+    /// Encrypt the data connections, not only the control channel
+    pub encrypt: bool,
+    /// Bytes per second all the connections of the redirect may forward together
+    pub rate: Option<u64>,
+    /// Shown next to the port in the logs and the admin socket, on both sides
+    pub name: Option<String>,
+    /// Size of the buffers of its connections on both sides, instead of one sized after the sockets
+    pub pipe_buffer: Option<usize>,
+    /// TCP_NODELAY on both ends of its connections, on both sides
+    pub nodelay: bool,
+}
+
+impl Redirect {
+    pub fn new(local_port: u16) -> Redirect {
+        Redirect {
+            local_port,
+            mirror: None,
+            schedule: None,
+            forward_client_addr: true,
+            preconnect: 0,
+            target: None,
+            access: AccessRules::default(),
+            first_byte_timeout: None,
+            maintenance: None,
+            host: None,
+            unix: None,
+            verify_integrity: false,
+            encrypt: false,
+            rate: None,
+            name: None,
+            pipe_buffer: None,
+            nodelay: true
+        }
+    }
+
+    /// Datagrams are relayed to a local port, without any of the options that are about TCP connections
+    fn check_udp(&self) -> Result<()> {
+        let options = [
+            ("preconnect", self.preconnect > 0),
+            ("target", self.target.is_some()),
+            ("host", self.host.is_some()),
+            ("first_byte_timeout", self.first_byte_timeout.is_some()),
+            ("maintenance", self.maintenance.is_some())
+        ];
+        match options.iter().find(|(_, set)| *set) {
+            Some((name, _)) => Err(anyhow!("{name} only applies to TCP redirects")),
+            None => Ok(())
+        }
+    }
+
+    /* Per-redirect options, of a `[[redirect]]` table or of the inline table at the end of a redirect */
+    fn apply_options(&mut self, options: &RawRedirect) -> Result<()> {
+        if let Some(x) = &options.mirror {
+            self.mirror = Some(x.clone());
+        }
+        self.forward_client_addr = options.forward_client_addr.unwrap_or(self.forward_client_addr);
+        self.verify_integrity = options.verify_integrity.unwrap_or(self.verify_integrity);
+        self.encrypt = options.encrypt.unwrap_or(self.encrypt);
+        self.nodelay = options.nodelay.unwrap_or(self.nodelay);
+        if let Some(rate) = &options.rate {
+            self.rate = Some(parse_rate("rate", rate)?);
+        }
+        if let Some(name) = &options.name {
+            self.name = Some(check_name(name.clone())?);
+        }
+        if let Some(size) = &options.pipe_buffer {
+            self.pipe_buffer = Some(check_pipe_buffer(parse_bytes("pipe_buffer", size)?)?);
+        }
+        if let Some(target) = &options.target {
+            match target.strip_prefix(CUSTOM_TARGET_PREFIX) {
+                Some(connector) if !connector.is_empty() => self.target = Some(connector.to_string()),
+                _ => return Err(anyhow!("target should be of the form \"{CUSTOM_TARGET_PREFIX}<name>\""))
+            }
+        }
+        if let Some(allow) = &options.allow {
+            self.access.allow = allow.iter().map(|x| Cidr::parse(x)).collect::<Result<_>>().context("Invalid allow")?;
+        }
+        if let Some(deny) = &options.deny {
+            self.access.deny = deny.iter().map(|x| Cidr::parse(x)).collect::<Result<_>>().context("Invalid deny")?;
+        }
+        if let Some(timeout) = options.first_byte_timeout {
+            if !(timeout > 0.0 && timeout <= MAX_FIRST_BYTE_TIMEOUT) {
+                return Err(anyhow!("first_byte_timeout should be greater than 0 and at most {MAX_FIRST_BYTE_TIMEOUT} seconds"));
+            }
+            self.first_byte_timeout = Some(Duration::from_secs_f64(timeout));
+        }
+        if let Some(host) = options.host.as_ref().filter(|x| !x.is_empty()) {
+            self.host = Some(host.clone());
+        }
+        if let Some(maintenance) = &options.maintenance {
+            self.maintenance = Some(match maintenance.as_str() {
+                "http" => Maintenance::Http { body: String::new(), retry_after: None },
+                "reset" => Maintenance::Reset,
+                "hold" => Maintenance::Hold,
+                x => return Err(anyhow!("{x} is not a valid maintenance mode, expected http, reset or hold"))
+            });
+        }
+        if options.maintenance_body.as_ref().is_some_and(|x| x.len() > MAX_MAINTENANCE_BODY) {
+            return Err(anyhow!("maintenance_body should be at most {MAX_MAINTENANCE_BODY} bytes"));
+        }
+        if let Some(preconnect) = options.preconnect {
+            if preconnect > MAX_PRECONNECT {
+                return Err(anyhow!("preconnect should be at most {MAX_PRECONNECT}"));
+            }
+            self.preconnect = preconnect;
+        }
+        let (maintenance_body, maintenance_retry_after) = (options.maintenance_body.clone(), options.maintenance_retry_after);
+        let (active_hours, active_days, timezone) = (&options.active_hours, &options.active_days, &options.timezone);
+        match &mut self.maintenance {
+            Some(Maintenance::Http { body, retry_after }) => {
+                *body = maintenance_body.unwrap_or_else(|| DEFAULT_MAINTENANCE_BODY.to_string());
+                *retry_after = maintenance_retry_after;
+            }
+            _ if maintenance_body.is_some() || maintenance_retry_after.is_some() => {
+                return Err(anyhow!("maintenance_body and maintenance_retry_after require maintenance = \"http\""));
+            }
+            _ => {}
+        }
+        if self.target.is_some() && self.preconnect > 0 {
+            return Err(anyhow!("preconnect only applies to redirects to a local port"));
+        }
+        if self.host.is_some() && (self.target.is_some() || self.preconnect > 0) {
+            return Err(anyhow!("host can't be combined with target or preconnect"));
+        }
+        match active_hours {
+            Some(hours) => self.schedule = Some(Schedule::parse(hours, active_days.as_deref(), timezone.as_deref())?),
+            None if active_days.is_some() || timezone.is_some() => {
+                return Err(anyhow!("active_days and timezone require active_hours"));
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+/// Parse the compact form of a redirect, `<port>[:<local port>][/<protocol>]` (e.g. `2222:22/tcp`)
+pub fn parse_compact_redirect(x: &str) -> Result<(Port, Redirect)> {
+    let (ports, protocol) = match x.split_once('/') {
+        Some((ports, protocol)) => (ports, match protocol.to_uppercase().as_str() {
+            "TCP" => Protocol::TCP,
+            "UDP" => Protocol::UDP,
+            _ => return Err(anyhow!("{} is not a valid protocol", protocol))
+        }),
+        None => (x, Protocol::TCP)
+    };
+    let parse_port = |p: &str| p.parse::<u16>().with_context(|| format!("{p} is not a valid port"));
+    let (port, local_port) = match ports.split_once(':') {
+        Some((port, local_port)) => (parse_port(port)?, parse_port(local_port)?),
+        None => (parse_port(ports)?, parse_port(ports)?)
+    };
+    Ok((Port { port, protocol }, Redirect::new(local_port)))
+}
+
+/* `host:port` or `host:first-last`, with IPv6 addresses between brackets */
+fn parse_host_port(x: &str) -> Result<(String, (u16, u16))> {
+    let (host, port) = x.rsplit_once(':').with_context(|| format!("{x} should be of the form <host>:<port>"))?;
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    if host.is_empty() {
+        return Err(anyhow!("{x} should be of the form <host>:<port>"));
+    }
+    Ok((host.to_string(), parse_port_range(port)?))
+}
+
+/* `first-last`, both included; a single port is a range of one */
+fn parse_port_range(x: &str) -> Result<(u16, u16)> {
+    let parse_port = |p: &str| p.trim().parse::<u16>().with_context(|| format!("{p} is not a valid port"));
+    let (first, last) = match x.split_once('-') {
+        Some((first, last)) => (parse_port(first)?, parse_port(last)?),
+        None => (parse_port(x)?, parse_port(x)?)
+    };
+    if first > last {
+        return Err(anyhow!("The port range {x} is empty"));
+    }
+    Ok((first, last))
+}
+
+/* `unix:<path>`, for a single port. A stream socket, connected to at each connection like a local port */
+fn parse_unix_target(x: &str, range: bool) -> Result<PathBuf> {
+    if !cfg!(unix) {
+        return Err(anyhow!("{x}: Unix sockets are only supported on Unix systems"));
+    }
+    let path = x.strip_prefix(UNIX_TARGET_PREFIX).unwrap_or(x);
+    if path.is_empty() {
+        return Err(anyhow!("{x} should be of the form {UNIX_TARGET_PREFIX}<path>"));
+    }
+    if range {
+        return Err(anyhow!("A range of ports can't go to a single Unix socket"));
+    }
+    Ok(PathBuf::from(path))
+}
+
+/* An entry of `redirects`, with the ports of its range */
+fn parse_redirect(portprot: &[Value]) -> Result<Vec<(Port, Redirect)>> {
+    let (options, portprot) = match portprot.split_last() {
+        Some((Value::Table(options), rest)) => (Some(options), rest),
+        _ => (None, portprot)
+    };
+    if portprot.len() < 2 || portprot.len() > 4 {
+        return Err(anyhow!("Each redirect should be an array of the form [<port>, <protocol>]"));
+    }
+
+    // A range forwards each of its ports to the port at the same offset on the other side
+    let (server, last) = match &portprot[0] {
+        Value::Integer(x) => {
+            let port = u16::try_from(*x).context("Server port should be a 16-bits unsigned integer")?;
+            (port, port)
+        }
+        Value::String(x) => parse_port_range(x)?,
+        _ => {
+            return Err(anyhow!("Failed to parse port, we expected an integer or a range"))
+        }
+    };
+    let name = match server == last {
+        true => server.to_string(),
+        false => format!("{server}-{last}")
+    };
+
+    let (protindex, gateway, host, unix) = match &portprot[1] {
+        Value::Integer(x) => (2, u16::try_from(*x).context("Gateway port should be a 16-bits unsigned integer")?, None, None),
+        Value::String(x) if x.starts_with(UNIX_TARGET_PREFIX) => {
+            let path = parse_unix_target(x, server != last).with_context(|| format!("Invalid target for redirect {name}"))?;
+            (2, server, None, Some(path))
+        }
+        Value::String(x) if x.contains(':') => {
+            let (host, ports) = parse_host_port(x).with_context(|| format!("Invalid target for redirect {name}"))?;
+            (2, check_range_length(&name, ports, last - server)?, Some(host), None)
+        }
+        Value::String(x) if x.contains('-') => (2, check_range_length(&name, parse_port_range(x)?, last - server)?, None, None),
+        _ => (1, server, None, None)
+    };
+    if gateway.checked_add(last - server).is_none() {
+        return Err(anyhow!("Redirect {name} goes past port {}", u16::MAX));
+    }
+
+    let protocol  = match portprot.get(protindex) {
+        Some(Value::String(x)) => {
+            match x.as_str() {
+                "UDP" => Protocol::UDP,
+                "TCP" => Protocol::TCP,
+                x => return Err(anyhow!("{} is not a valid protocol", x))
+            }
+        },
+        _ => {
+            return Err(anyhow!("Protocol should be a string"));
+        }
+    };
+
+    // The name, if any, comes right after the protocol
+    let label = match &portprot[protindex + 1..] {
+        [] => None,
+        [Value::String(x)] => Some(check_name(x.clone()).with_context(|| format!("Invalid name for redirect {name}"))?),
+        [_] => return Err(anyhow!("The name of redirect {name} should be a string")),
+        _ => return Err(anyhow!("Each redirect should be an array of the form [<port>, <protocol>]"))
+    };
+
+    let options : RawRedirect = match options {
+        Some(options) => {
+            // The ports of an entry of `redirects` are its first values, not options
+            if let Some(x) = options.keys().find(|x| TABLE_ONLY_FIELDS.contains(&x.as_str())) {
+                return Err(anyhow!("{x} is not a valid redirect option")).with_context(|| format!("Invalid options for redirect {name}"));
+            }
+            Value::Table(options.clone()).try_into().with_context(|| format!("Invalid options for redirect {name}"))?
+        }
+        None => RawRedirect::default()
+    };
+    if let Some(x) = options.unknown.keys().next() {
+        return Err(anyhow!("{x} is not a valid redirect option")).with_context(|| format!("Invalid options for redirect {name}"));
+    }
+    if host.is_some() && options.host.is_some() {
+        return Err(anyhow!("Redirect {name} has both a target host and a host option"));
+    }
+    if label.is_some() && options.name.is_some() {
+        return Err(anyhow!("Redirect {name} has both a name and a name option"));
+    }
+    let mut redirect = Redirect::new(gateway);
+    redirect.host = host;
+    redirect.name = label;
+    redirect.unix = unix;
+    redirect.apply_options(&options).with_context(|| format!("Invalid options for redirect {name}"))?;
+    expand_redirect(&name, (server, last), protocol, redirect)
+}
+
+/* A `[[redirect]]` table, with the ports of its range */
+fn parse_redirect_table(raw: &RawRedirect) -> Result<Vec<(Port, Redirect)>> {
+    let (first, last) = raw.gateway_port.as_ref().context("gateway_port should be set")?.range()?;
+    let name = match first == last {
+        true => first.to_string(),
+        false => format!("{first}-{last}")
+    };
+    let protocol = match raw.protocol.as_deref().map(str::to_uppercase).as_deref() {
+        None | Some("TCP") => Protocol::TCP,
+        Some("UDP") => Protocol::UDP,
+        Some(_) => return Err(anyhow!("{} is not a valid protocol", raw.protocol.as_deref().unwrap_or_default()))
+    };
+    let local_port = match &raw.local_port {
+        Some(ports) => check_range_length(&name, ports.range()?, last - first)?,
+        None => first
+    };
+    if local_port.checked_add(last - first).is_none() {
+        return Err(anyhow!("Redirect {name} goes past port {}", u16::MAX));
+    }
+
+    let mut redirect = Redirect::new(local_port);
+    match raw.local_host.as_deref() {
+        Some(x) if x.starts_with(UNIX_TARGET_PREFIX) => {
+            if raw.local_port.is_some() {
+                return Err(anyhow!("Redirect {name} goes to a Unix socket, it can't have a local_port"));
+            }
+            redirect.unix = Some(parse_unix_target(x, first != last).with_context(|| format!("Invalid local_host for redirect {name}"))?);
+        }
+        Some(x) => redirect.host = Some(x.strip_prefix('[').and_then(|x| x.strip_suffix(']')).unwrap_or(x).to_string()).filter(|x| !x.is_empty()),
+        None => {}
+    }
+    if raw.local_host.is_some() && raw.host.is_some() {
+        return Err(anyhow!("Redirect {name} has both a local_host and a host option"));
+    }
+    redirect.apply_options(raw).with_context(|| format!("Invalid options for redirect {name}"))?;
+    expand_redirect(&name, (first, last), protocol, redirect)
+}
+
+/* What both forms of a redirect come down to: the redirect of each port of the range */
+fn expand_redirect(name: &str, (first, last): (u16, u16), protocol: Protocol, redirect: Redirect) -> Result<Vec<(Port, Redirect)>> {
+    if protocol == Protocol::UDP {
+        redirect.check_udp().with_context(|| format!("Invalid options for redirect {name}"))?;
+    }
+    if let Some(path) = &redirect.unix {
+        if protocol == Protocol::UDP {
+            return Err(anyhow!("Redirect {name} goes to the Unix socket {}, which only TCP redirects can", path.display()));
+        }
+        if redirect.host.is_some() || redirect.target.is_some() || redirect.preconnect > 0 {
+            return Err(anyhow!("Redirect {name} goes to a Unix socket, it can't have a host, target or preconnect option"));
+        }
+    }
+    Ok((0..=last - first).map(|offset| (Port { port: first + offset, protocol }, Redirect { local_port: redirect.local_port + offset, ..redirect.clone() })).collect())
+}
+
+/* Each knock is [<port>, <protocol>] */
+fn parse_knock(raw: &[Vec<Value>]) -> Result<Vec<Port>> {
+    raw.iter().map(|knock| match knock.as_slice() {
+        [Value::Integer(port), Value::String(protocol)] => Ok(Port {
+            port: u16::try_from(*port).ok().filter(|x| *x != 0).with_context(|| format!("Knock port {port} should be between 1 and 65535"))?,
+            protocol: match protocol.as_str() {
+                "UDP" => Protocol::UDP,
+                "TCP" => Protocol::TCP,
+                x => return Err(anyhow!("{x} is not a valid protocol"))
+            }
+        }),
+        _ => Err(anyhow!("Each knock should be an array of the form [<port>, <protocol>]"))
+    }).collect()
+}
+
+pub const MAX_NAME_LENGTH : usize = 32;
+const MIN_PIPE_BUFFER : usize = 4 << 10;
+const MAX_PIPE_BUFFER : usize = 4 << 20;
+
+/// The buffers of a redirect are sent to the gateway, which allocates them too
+pub fn check_pipe_buffer(size: usize) -> Result<usize> {
+    match (MIN_PIPE_BUFFER..=MAX_PIPE_BUFFER).contains(&size) {
+        true => Ok(size),
+        false => Err(anyhow!("pipe_buffer should be between 4KiB and 4MiB, got {size} bytes"))
+    }
+}
+
+/// Names of redirects go to the gateway and into log lines: letters, digits, '-', '_' and '.' only
+pub fn check_name(name: String) -> Result<String> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(anyhow!("Names should be between 1 and {MAX_NAME_LENGTH} characters long, got {name:?}"));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err(anyhow!("Names should only contain letters, digits, '-', '_' and '.', got {name:?}"));
+    }
+    Ok(name)
+}
+
+/* It's sent in the request line as is */
+fn check_websocket_path(path: &str) -> Result<()> {
+    if !path.starts_with('/') || path.contains(|c: char| c.is_ascii_whitespace() || c.is_ascii_control()) {
+        return Err(anyhow!("websocket_path should start with / and contain no spaces, got {path:?}"));
+    }
+    Ok(())
+}
+
+/* The other side of a range may be a single port, its start, or a range of the same length */
+fn check_range_length(name: &str, (first, last): (u16, u16), length: u16) -> Result<u16> {
+    if first != last && last - first != length {
+        return Err(anyhow!("Redirect {name} forwards {} ports to {} ports", length as u32 + 1, (last - first) as u32 + 1));
+    }
+    Ok(first)
 }
 
 pub struct ServerConfig {
-    pub redirects: HashMap<Port, u16>,
-    pub gateway_address: String,
+    pub redirects: HashMap<Port, Redirect>,
+    /// `host:port` of each gateway, tried in order from the last one that worked
+    pub gateway_addresses: Vec<String>,
+    /// Scheme of the configured gateway addresses, `tcp` if there was none
+    pub transport: String,
     pub proxy: Option<String>,
+    /// Proxy of the `tcp://` transport, exclusive with `proxy`
+    pub socks5_proxy: Option<SocksProxy>,
+    /// Tor's SOCKS port, for `tor+socks5://`
+    pub socks_proxy: Option<String>,
+    pub admin_socket: PathBuf,
+    /// Where the redirects added or removed at runtime are persisted
+    pub config_path: PathBuf,
+    /// Retry failed sessions; disabled to leave restarts to a supervisor
+    pub retry: bool,
+    /// Addresses of the gateway that are tried
+    pub address_family: AddressFamily,
+    /// How long the gateway has to send its challenge
+    pub handshake_timeout: Duration,
+    /// The longest wait before opening a new session once one ended, the waits double up to it
+    pub retry_delay: Duration,
+    /// None if the server doesn't ping the gateway
+    pub heartbeat: Option<Heartbeat>,
+    /// How long the resolutions of the redirects' hosts are kept, and the failed ones
+    pub resolve_ttl: Duration,
+    pub resolve_negative_ttl: Duration,
+    /// Give up when the gateway couldn't bind some of the ports of the registration
+    pub require_all_ports: bool,
+    /// Carry the connections over the control connection rather than connecting back for each of them
+    pub multiplex: bool,
+    /// Path and `Host` of the upgrades of the `ws://` transport, the gateway's address if no host is set
+    pub websocket_path: String,
+    pub websocket_host: Option<String>,
+    /// Knocked on the gateway before each connection, directly
+    pub knock: Vec<Port>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SocksProxy {
+    /// `host:port`
+    pub address: String,
+    /// Username and password, when the proxy requires them
+    pub credentials: Option<(String, String)>
+}
+
+impl SocksProxy {
+    /* `[username:password@]host:port` */
+    fn parse(value: &str) -> Result<SocksProxy> {
+        let (credentials, address) = match value.rsplit_once('@') {
+            Some((credentials, address)) => {
+                let (username, password) = credentials.split_once(':').context("The credentials should be username:password")?;
+                for (name, x) in [("username", username), ("password", password)] {
+                    if x.is_empty() || x.len() > 255 {
+                        return Err(anyhow!("The {name} should be between 1 and 255 bytes long"));
+                    }
+                }
+                (Some((username.to_string(), password.to_string())), address)
+            }
+            None => (None, value)
+        };
+        if !address.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) {
+            return Err(anyhow!("{address} is not host:port"));
+        }
+        Ok(SocksProxy { address: address.to_string(), credentials })
+    }
+}
+
+/// The server pings the gateway every `interval`, and both give up on the other after `misses` intervals of silence
+#[derive(Debug, Copy, Clone)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub misses: u8
+}
+
+impl Heartbeat {
+    pub fn timeout(&self) -> Duration {
+        self.interval * self.misses as u32
+    }
+}
+
+/// TCP keepalive of the control connections, so that the state NATs and firewalls keep of an idle one isn't dropped,
+/// and of the data connections with `data`
+#[derive(Debug, Copy, Clone)]
+pub struct Keepalive {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub count: u32,
+    pub data: bool
+}
+
+/// Sources that fail the magic or the challenge `failures` times within `window` are dropped for `duration`
+#[derive(Debug, Copy, Clone)]
+pub struct Ban {
+    pub failures: u32,
+    pub window: Duration,
+    pub duration: Duration
 }
 
 pub struct GatewayConfig {
-    pub port: u16
+    pub port: u16,
+    pub address_family: AddressFamily,
+    /// Address the server pairs and dials back on
+    pub bind_address: IpAddr,
+    /// Address the forwarded ports are bound on
+    pub forward_bind_address: IpAddr,
+    /// Of every listener, the gateway's port and the forwarded ones
+    pub socket: ListenOptions,
+    /// Bytes a session may transfer before new connections are refused
+    pub session_quota: Option<u64>,
+    /// Also shut down the active connections once the quota is exhausted
+    pub session_quota_terminate: bool,
+    /// How long ports stay bound after a session ended, holding new connections until the server is back
+    pub reconnect_grace: Option<Duration>,
+    pub reconnect_grace_max_clients: usize,
+    /// Whether the ports stay bound between the sessions, refusing their clients, rather than unbound
+    pub keep_ports_bound: bool,
+    /// How long a server stays paired before it has to pair again
+    pub max_session_duration: Option<Duration>,
+    /// Exit once the first server session ended
+    pub one_session: bool,
+    /// Servers paired at the same time, each with its own ports
+    pub max_sessions: usize,
+    /// Clients forwarded at once, by the whole gateway and on each port
+    pub max_connections: usize,
+    pub max_connections_per_port: usize,
+    /// Clients accepted on every port
+    pub access: AccessRules,
+    /// Whether the rules sent by the server for its redirects are applied (they can only restrict ours)
+    pub server_rules: bool,
+    /// Only these may connect to the gateway's port, anyone if empty
+    pub allowed_servers: Vec<Cidr>,
+    pub ban: Option<Ban>,
+    /// The gateway's port only answers the sources that knocked these ports in order, see `knock`
+    pub knock: Vec<Port>,
+    /// How long a source has for each knock, then for connecting once it knocked them all
+    pub knock_window: Duration,
+    /// How often the probes of the ports no server is serving are summarized, if at all
+    pub scan_summary_interval: Option<Duration>,
+    /// UDP peers that sent nothing for this long are forgotten
+    pub udp_idle_timeout: Duration,
+    /// How long the server has to dial back for a new connection
+    pub connect_timeout: Duration,
+    /// How long a connection dialed back has to present its token
+    pub challenge_timeout: Duration,
+    /// Also accepted from the servers, until they all have the new key
+    pub previous_key: Option<Key>,
+    /// Answer the WebSocket upgrades to this path, for the `ws://` servers
+    pub websocket_path: Option<String>,
+    /// Read again on SIGHUP
+    pub config_path: PathBuf,
+    pub admin_socket: PathBuf,
+}
+
+impl GatewayConfig {
+    pub fn allows_server(&self, ip: IpAddr) -> bool {
+        self.allowed_servers.is_empty() || self.allowed_servers.iter().any(|cidr| cidr.contains(ip))
+    }
 }
 
 pub enum SpecificConfig {
@@ -78,75 +735,1188 @@ pub enum SpecificConfig {
     Server(ServerConfig)
 }
 
+/* The summaries of `--dry-run`, one setting per line. The key itself is never shown, only its fingerprint */
+
+fn seconds(x: Duration) -> String {
+    match x.subsec_millis() {
+        0 => format!("{}s", x.as_secs()),
+        _ => format!("{}s", x.as_secs_f64())
+    }
+}
+
+/* `[:<port>]/<protocol> -> <where the server connects>`, then the options that are set */
+fn redirect_summary(port: &Port, redirect: &Redirect) -> String {
+    let protocol = match port.protocol {
+        Protocol::TCP => "TCP",
+        Protocol::UDP => "UDP"
+    };
+    let local = match (&redirect.unix, &redirect.target, &redirect.host) {
+        (Some(path), _, _) => format!("{UNIX_TARGET_PREFIX}{}", path.display()),
+        (_, Some(target), _) => format!("{CUSTOM_TARGET_PREFIX}{target}"),
+        (_, _, Some(host)) if host.contains(':') => format!("[{host}]:{}", redirect.local_port),
+        (_, _, Some(host)) => format!("{host}:{}", redirect.local_port),
+        _ => format!("127.0.0.1:{}", redirect.local_port)
+    };
+    let mut options = Vec::new();
+    if let Some(name) = &redirect.name {
+        options.push(format!("name {name}"));
+    }
+    if let Some(schedule) = &redirect.schedule {
+        options.push(format!("active {schedule}"));
+    }
+    if !redirect.forward_client_addr {
+        options.push("client address not forwarded".to_string());
+    }
+    if redirect.preconnect > 0 {
+        options.push(format!("{} preconnected", redirect.preconnect));
+    }
+    if !redirect.access.is_empty() {
+        options.push(format!("clients {}", redirect.access));
+    }
+    if let Some(timeout) = redirect.first_byte_timeout {
+        options.push(format!("first byte within {}", seconds(timeout)));
+    }
+    match &redirect.maintenance {
+        Some(Maintenance::Http { .. }) => options.push("maintenance http".to_string()),
+        Some(Maintenance::Reset) => options.push("maintenance reset".to_string()),
+        Some(Maintenance::Hold) => options.push("maintenance hold".to_string()),
+        None => {}
+    }
+    if let Some(mirror) = &redirect.mirror {
+        options.push(format!("mirrored to {mirror}"));
+    }
+    if redirect.verify_integrity {
+        options.push("integrity verified".to_string());
+    }
+    if redirect.encrypt {
+        options.push("encrypted".to_string());
+    }
+    if let Some(rate) = redirect.rate {
+        options.push(format!("at most {rate}B/s"));
+    }
+    if let Some(size) = redirect.pipe_buffer {
+        options.push(format!("{size}B buffers"));
+    }
+    if !redirect.nodelay {
+        options.push("Nagle's algorithm kept".to_string());
+    }
+    let options = match options.is_empty() {
+        true => String::new(),
+        false => format!(" ({})", options.join(", "))
+    };
+    format!("gateway :{}/{protocol} -> local {local}{options}", port.port)
+}
+
+fn knock_summary(knock: &[Port]) -> String {
+    knock.iter().map(Port::to_string).collect::<Vec<String>>().join(", ")
+}
+
+impl fmt::Display for CommonConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.key_source {
+            KeySource::File(path) => writeln!(f, "key: {} (fingerprint {})", path.display(), fingerprint(&self.key))?,
+            KeySource::Environment => writeln!(f, "key: {ENV_KEY} (fingerprint {})", fingerprint(&self.key))?,
+            KeySource::Missing(path) => writeln!(f, "key: {} (missing, generated once the gateway starts)", path.display())?
+        }
+        writeln!(f, "pipe buffers: {}B to {}B{}{}", self.buffers.min, self.buffers.max,
+            self.buffers.socket_buffer.map_or(String::new(), |x| format!(", socket buffers {x}B")),
+            self.buffers.budget.map_or(String::new(), |x| format!(", memory budget {x}B")))?;
+        writeln!(f, "pipe workers: {}", match self.pipe_workers {
+            0 => "none, each pipe has its own threads".to_string(),
+            x => x.to_string()
+        })?;
+        writeln!(f, "connection cleanup: every {}, half-open connections after {}, idle connections {}", seconds(self.reaper.interval),
+            seconds(self.reaper.half_open_timeout), self.reaper.idle_timeout.map_or("kept".to_string(), |x| format!("after {}", seconds(x))))?;
+        writeln!(f, "rekeying: after {} operations", self.rekey_after)?;
+        match self.keepalive {
+            Some(keepalive) => writeln!(f, "keepalive: after {} idle, every {}, {} probes, {}", seconds(keepalive.idle), seconds(keepalive.interval),
+                keepalive.count, if keepalive.data { "data connections included" } else { "control connection only" })?,
+            None => writeln!(f, "keepalive: off")?
+        }
+        match &self.accounting {
+            Some(accounting) => writeln!(f, "accounting: in {}, written every {}", accounting.file.display(), seconds(accounting.interval))?,
+            None => writeln!(f, "accounting: off")?
+        }
+        match self.daemon.enabled {
+            true => write!(f, "daemon: pid in {}, logs in {}", self.daemon.pid_file.display(), self.daemon.log_file.display()),
+            false => write!(f, "daemon: no")
+        }
+    }
+}
+
+impl fmt::Display for ServerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "mode: server")?;
+        writeln!(f, "gateway: {} (over {}, {})", self.gateway_addresses.join(", then "), self.transport, self.address_family)?;
+        if let Some(proxy) = &self.proxy {
+            writeln!(f, "http proxy: {proxy}")?;
+        }
+        if let Some(proxy) = &self.socks5_proxy {
+            writeln!(f, "socks5 proxy: {}{}", proxy.address, if proxy.credentials.is_some() { " (with credentials)" } else { "" })?;
+        }
+        if let Some(proxy) = &self.socks_proxy {
+            writeln!(f, "tor socks port: {proxy}")?;
+        }
+        if self.transport == "ws" {
+            writeln!(f, "websocket: path {}, host {}", self.websocket_path, self.websocket_host.as_deref().unwrap_or("of the gateway's address"))?;
+        }
+        if !self.knock.is_empty() {
+            writeln!(f, "knock: {}", knock_summary(&self.knock))?;
+        }
+        let mut ports : Vec<&Port> = self.redirects.keys().collect();
+        ports.sort_by_key(|port| (port.port, port.protocol == Protocol::UDP));
+        writeln!(f, "redirects: {}", ports.len())?;
+        for port in ports {
+            writeln!(f, "  {}", redirect_summary(port, &self.redirects[port]))?;
+        }
+        writeln!(f, "handshake timeout: {}", seconds(self.handshake_timeout))?;
+        match self.retry {
+            true => writeln!(f, "retries: waiting up to {} between attempts", seconds(self.retry_delay))?,
+            false => writeln!(f, "retries: none")?
+        }
+        match self.heartbeat {
+            Some(heartbeat) => writeln!(f, "heartbeat: every {}, the gateway is gone after {}", seconds(heartbeat.interval), seconds(heartbeat.timeout()))?,
+            None => writeln!(f, "heartbeat: off")?
+        }
+        writeln!(f, "host resolutions: kept {}, failures {}", seconds(self.resolve_ttl), seconds(self.resolve_negative_ttl))?;
+        writeln!(f, "multiplex: {}", if self.multiplex { "yes" } else { "no" })?;
+        writeln!(f, "require all ports: {}", if self.require_all_ports { "yes" } else { "no" })?;
+        write!(f, "admin socket: {}", self.admin_socket.display())
+    }
+}
+
+impl fmt::Display for GatewayConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "mode: gateway")?;
+        writeln!(f, "listening: {} ({})", SocketAddr::new(self.bind_address, self.port), self.address_family)?;
+        writeln!(f, "forwarded ports bound on: {}", self.forward_bind_address)?;
+        writeln!(f, "listeners: backlog {}{}{}{}", self.socket.backlog, if self.socket.reuse_address { ", SO_REUSEADDR" } else { "" },
+            self.socket.device.as_ref().map_or(String::new(), |x| format!(", bound to device {x}")), if self.socket.freebind { ", freebind" } else { "" })?;
+        if let Some(key) = &self.previous_key {
+            writeln!(f, "previous key: fingerprint {}", fingerprint(key))?;
+        }
+        if !self.knock.is_empty() {
+            writeln!(f, "knock: {}, {} for each", knock_summary(&self.knock), seconds(self.knock_window))?;
+        }
+        match self.allowed_servers.is_empty() {
+            true => writeln!(f, "allowed servers: any")?,
+            false => writeln!(f, "allowed servers: {}", self.allowed_servers.iter().map(Cidr::to_string).collect::<Vec<String>>().join(", "))?
+        }
+        match self.ban {
+            Some(ban) => writeln!(f, "bans: after {} failures within {}, for {}", ban.failures, seconds(ban.window), seconds(ban.duration))?,
+            None => writeln!(f, "bans: off")?
+        }
+        writeln!(f, "clients: {}, {}", match self.access.is_empty() {
+            true => "any".to_string(),
+            false => self.access.to_string()
+        }, if self.server_rules { "with the rules of the server" } else { "ignoring the rules of the server" })?;
+        writeln!(f, "limits: {} sessions, {} connections, {} per port", self.max_sessions, self.max_connections, self.max_connections_per_port)?;
+        match self.session_quota {
+            Some(quota) => writeln!(f, "session quota: {}MB{}", quota / 1_000_000, if self.session_quota_terminate { ", then the connections are closed" } else { "" })?,
+            None => writeln!(f, "session quota: none")?
+        }
+        match self.reconnect_grace {
+            Some(grace) => writeln!(f, "reconnect grace: {}, holding at most {} clients", seconds(grace), self.reconnect_grace_max_clients)?,
+            None => writeln!(f, "reconnect grace: none")?
+        }
+        writeln!(f, "between sessions: {}", if self.keep_ports_bound { "ports kept bound, refusing their clients" } else { "ports unbound" })?;
+        match self.max_session_duration {
+            Some(duration) => writeln!(f, "sessions: paired again after {}", seconds(duration))?,
+            None => writeln!(f, "sessions: unlimited duration")?
+        }
+        writeln!(f, "dial back: within {}ms, token within {}ms", self.connect_timeout.as_millis(), self.challenge_timeout.as_millis())?;
+        writeln!(f, "udp peers: forgotten after {} idle", seconds(self.udp_idle_timeout))?;
+        match self.scan_summary_interval {
+            Some(interval) => writeln!(f, "scan summaries: every {}", seconds(interval))?,
+            None => writeln!(f, "scan summaries: off")?
+        }
+        if let Some(path) = &self.websocket_path {
+            writeln!(f, "websocket path: {path}")?;
+        }
+        write!(f, "admin socket: {}", self.admin_socket.display())
+    }
+}
+
+impl fmt::Display for SpecificConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpecificConfig::Gateway(gcfg) => gcfg.fmt(f),
+            SpecificConfig::Server(scfg) => scfg.fmt(f)
+        }
+    }
+}
+
 pub struct CommonConfig {
-    pub key : Key
+    pub key : Key,
+    /// Of the key
+    pub magics : Magics,
+    pub buffers : BufferConfig,
+    pub reaper : ReaperConfig,
+    /// Encryptions and decryptions of a control channel before its sender sends a new key
+    pub rekey_after : u64,
+    /// Threads serving the pipes that only pass bytes on, none to give each pipe its own threads
+    pub pipe_workers : usize,
+    pub keepalive : Option<Keepalive>,
+    /// Run in the background, see `daemon`
+    pub daemon : Daemon,
+    /// The traffic of each port, kept across restarts
+    pub accounting : Option<Accounting>,
+    pub key_source : KeySource
+}
+
+/// Where the key came from, for `--dry-run`
+#[derive(Debug, Clone)]
+pub enum KeySource {
+    File(PathBuf),
+    /// `SMUGGLRS_KEY`
+    Environment,
+    /// The gateway generates it once started
+    Missing(PathBuf)
+}
+
+/// `daemon = true` or `--daemon`, with where its pid and output go
+#[derive(Clone)]
+pub struct Daemon {
+    pub enabled : bool,
+    pub pid_file : PathBuf,
+    pub log_file : PathBuf
+}
+
+/// `accounting_file`, written every `interval` and when smugglrs stops
+#[derive(Clone)]
+pub struct Accounting {
+    pub file : PathBuf,
+    pub interval : Duration
+}
+
+/// `[keepalive]`, in seconds
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawKeepalive {
+    pub idle: Option<u64>,
+    pub interval: Option<u64>,
+    pub count: Option<u32>,
+    pub data_connections: Option<bool>
+}
+
+/// `[socket]`, the options of the gateway's listeners
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawSocket {
+    pub reuse_address: Option<bool>,
+    pub backlog: Option<u32>,
+    pub bind_device: Option<String>,
+    pub freebind: Option<bool>
+}
+
+/// `gateway_address`, a single address or the addresses to fail over to
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged, expecting = "expected an address, or a list of addresses")]
+pub enum RawAddresses {
+    One(String),
+    Many(Vec<String>)
+}
+
+/// A port, or a range of ports: "8000-8010"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged, expecting = "expected a port, or a range of ports like \"8000-8010\"")]
+pub enum RawPorts {
+    Port(u16),
+    Range(String)
+}
+
+impl RawPorts {
+    fn range(&self) -> Result<(u16, u16)> {
+        match self {
+            RawPorts::Port(x) => Ok((*x, *x)),
+            RawPorts::Range(x) => parse_port_range(x)
+        }
+    }
+}
+
+/* Only in `[[redirect]]` tables, the entries of `redirects` give them by position */
+const TABLE_ONLY_FIELDS : [&str; 4] = ["gateway_port", "local_port", "local_host", "protocol"];
+
+/// A `[[redirect]]` table. The options also make the inline table at the end of an entry of `redirects`
+#[derive(Debug, Default, Deserialize)]
+pub struct RawRedirect {
+    /// Bound on the gateway
+    pub gateway_port: Option<RawPorts>,
+    /// Where the connections go, the same as `gateway_port` by default
+    pub local_port: Option<RawPorts>,
+    /// 127.0.0.1 by default, or `unix:<path>`
+    pub local_host: Option<String>,
+    pub protocol: Option<String>,
+    pub name: Option<String>,
+    pub mirror: Option<String>,
+    pub active_hours: Option<String>,
+    pub active_days: Option<String>,
+    pub timezone: Option<String>,
+    pub forward_client_addr: Option<bool>,
+    pub verify_integrity: Option<bool>,
+    pub encrypt: Option<bool>,
+    pub nodelay: Option<bool>,
+    pub rate: Option<Value>,
+    pub pipe_buffer: Option<Value>,
+    pub target: Option<String>,
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+    pub first_byte_timeout: Option<f64>,
+    pub host: Option<String>,
+    pub maintenance: Option<String>,
+    pub maintenance_body: Option<String>,
+    pub maintenance_retry_after: Option<u32>,
+    pub preconnect: Option<usize>,
+    /// Warned about in the tables, rejected in the inline tables
+    #[serde(flatten)]
+    pub unknown: Table
 }
 
 #[derive(Debug, Deserialize)]
 pub struct RawConfig {
     pub mode: String,
     pub port: u16,
-    pub gateway_address: Option<String>,
+    pub gateway_address: Option<RawAddresses>,
+    pub bind_address: Option<String>,
+    pub address_family: Option<String>,
+    pub forward_bind_address: Option<String>,
     pub http_proxy: Option<String>,
+    pub socks5_proxy: Option<String>,
+    pub socks_proxy: Option<String>,
     pub redirects: Option<Vec<Vec<Value>>>,
+    /// `[[redirect]]`, the same redirects as `redirects` with named fields
+    pub redirect: Option<Vec<RawRedirect>>,
+    pub session_quota_mb: Option<u64>,
+    pub session_quota_terminate: Option<bool>,
+    pub admin_socket: Option<String>,
+    pub key_file: Option<String>,
+    pub previous_key_file: Option<String>,
+    /// Directory of the configuration file, relative paths in it are relative to it
+    #[serde(skip)]
+    pub dir: PathBuf,
+    pub reconnect_grace: Option<u64>,
+    pub reconnect_grace_max_clients: Option<usize>,
+    pub keep_ports_bound: Option<bool>,
+    pub max_session_duration: Option<u64>,
+    pub max_sessions: Option<usize>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_port: Option<usize>,
+    pub pipe_buffer_min: Option<Value>,
+    pub pipe_buffer_max: Option<Value>,
+    pub socket_buffer: Option<Value>,
+    pub memory_budget: Option<Value>,
+    pub reaper_interval: Option<u64>,
+    pub half_open_timeout: Option<u64>,
+    pub idle_timeout: Option<u64>,
+    pub rekey_after: Option<u64>,
+    pub pipe_workers: Option<usize>,
+    pub keepalive: Option<RawKeepalive>,
+    pub socket: Option<RawSocket>,
+    pub retry: Option<bool>,
+    pub handshake_timeout: Option<u64>,
+    pub connect_timeout_ms: Option<u64>,
+    pub challenge_timeout_ms: Option<u64>,
+    pub retry_delay_s: Option<u64>,
+    pub heartbeat_interval: Option<u64>,
+    pub heartbeat_misses: Option<u8>,
+    pub resolve_ttl: Option<u64>,
+    pub resolve_negative_ttl: Option<u64>,
+    pub require_all_ports: Option<bool>,
+    pub multiplex: Option<bool>,
+    pub websocket_path: Option<String>,
+    pub websocket_host: Option<String>,
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+    pub server_rules: Option<bool>,
+    pub allowed_servers: Option<Vec<String>>,
+    pub ban_failures: Option<u32>,
+    pub ban_window: Option<u64>,
+    pub ban_duration: Option<u64>,
+    pub knock: Option<Vec<Vec<Value>>>,
+    pub knock_window: Option<u64>,
+    pub scan_summary_interval: Option<u64>,
+    pub udp_idle_timeout: Option<u64>,
+    pub daemon: Option<bool>,
+    pub pid_file: Option<String>,
+    pub log_file: Option<String>,
+    pub accounting_file: Option<String>,
+    pub accounting_interval: Option<u64>,
+    /// Warned about, the configuration may be meant for another version
+    #[serde(flatten)]
+    pub unknown: Table
+}
+
+const MIN_BUFFER_SIZE : usize = 512;
+const MAX_BUFFER_SIZE : usize = 1 << 30;
+
+/// Sizes are either a number of bytes, or a string with a unit: "64KiB", "4MB"...
+pub fn parse_size(name: &str, value: &Value) -> Result<usize> {
+    let size = parse_bytes(name, value)?;
+    if !(MIN_BUFFER_SIZE..=MAX_BUFFER_SIZE).contains(&size) {
+        return Err(anyhow!("{name} should be between {MIN_BUFFER_SIZE} bytes and 1GiB"));
+    }
+    Ok(size)
+}
+
+/// Rates are sizes per second, the "/s" is optional: "5MB/s", "512KiB"...
+fn parse_rate(name: &str, value: &Value) -> Result<u64> {
+    let rate = match value {
+        Value::String(x) => parse_bytes(name, &Value::String(x.trim().trim_end_matches("/s").to_string()))?,
+        x => parse_bytes(name, x)?
+    };
+    match rate {
+        0 => Err(anyhow!("{name} should be greater than 0")),
+        x => Ok(x as u64)
+    }
+}
+
+fn parse_bytes(name: &str, value: &Value) -> Result<usize> {
+    Ok(match value {
+        Value::Integer(x) => usize::try_from(*x).with_context(|| format!("{name} should be positive"))?,
+        Value::String(x) => {
+            let x = x.trim();
+            let split = x.find(|c: char| !c.is_ascii_digit()).unwrap_or(x.len());
+            let (number, unit) = x.split_at(split);
+            let number : usize = number.parse().with_context(|| format!("{name}: {x} is not a valid size"))?;
+            let unit = match unit.trim() {
+                "" | "B" => 1,
+                "K" | "KB" => 1000,
+                "KiB" => 1 << 10,
+                "M" | "MB" => 1_000_000,
+                "MiB" => 1 << 20,
+                "G" | "GB" => 1_000_000_000,
+                "GiB" => 1 << 30,
+                u => return Err(anyhow!("{name}: {u} is not a valid unit"))
+            };
+            number.checked_mul(unit).with_context(|| format!("{name} is too large"))?
+        }
+        _ => return Err(anyhow!("{name} should be a number of bytes or a string like \"64KiB\""))
+    })
+}
+
+impl RawConfig {
+    fn buffers(&self) -> Result<BufferConfig> {
+        let mut buffers = BufferConfig::default();
+        if let Some(min) = &self.pipe_buffer_min {
+            buffers.min = parse_size("pipe_buffer_min", min)?;
+        }
+        if let Some(max) = &self.pipe_buffer_max {
+            buffers.max = parse_size("pipe_buffer_max", max)?;
+        }
+        if buffers.min > buffers.max {
+            return Err(anyhow!("pipe_buffer_min should not be greater than pipe_buffer_max"));
+        }
+        if let Some(size) = &self.socket_buffer {
+            buffers.socket_buffer = Some(parse_size("socket_buffer", size)?);
+        }
+        if let Some(budget) = &self.memory_budget {
+            let budget = parse_bytes("memory_budget", budget)?;
+            // Room for at least one pipe with the smallest buffers
+            if budget < 2 * buffers.min {
+                return Err(anyhow!("memory_budget should be at least twice pipe_buffer_min"));
+            }
+            buffers.budget = Some(budget);
+        }
+        Ok(buffers)
+    }
+
+    fn reaper(&self) -> Result<ReaperConfig> {
+        let mut reaper = ReaperConfig::default();
+        match self.reaper_interval {
+            Some(0) => return Err(anyhow!("reaper_interval should be greater than 0")),
+            Some(x) => reaper.interval = Duration::from_secs(x),
+            None => {}
+        }
+        match self.half_open_timeout {
+            Some(0) => return Err(anyhow!("half_open_timeout should be greater than 0")),
+            Some(x) => reaper.half_open_timeout = Duration::from_secs(x),
+            None => {}
+        }
+        reaper.idle_timeout = self.idle_timeout.filter(|&x| x > 0).map(Duration::from_secs);
+        Ok(reaper)
+    }
+
+    fn socket(&self) -> Result<ListenOptions> {
+        let raw = self.socket.as_ref();
+        let options = ListenOptions {
+            reuse_address: raw.and_then(|x| x.reuse_address).unwrap_or(true),
+            backlog: match raw.and_then(|x| x.backlog).unwrap_or(DEFAULT_BACKLOG) {
+                x if x == 0 || x > MAX_BACKLOG => return Err(anyhow!("socket.backlog should be between 1 and {MAX_BACKLOG}")),
+                x => x
+            },
+            device: match raw.and_then(|x| x.bind_device.clone()) {
+                // IFNAMSIZ, with the terminating NUL
+                Some(x) if x.is_empty() || x.len() > 15 || x.contains('\0') => return Err(anyhow!("socket.bind_device should be the name of an interface, got {x:?}")),
+                x => x
+            },
+            freebind: raw.and_then(|x| x.freebind).unwrap_or(false)
+        };
+        if !cfg!(target_os = "linux") && (options.device.is_some() || options.freebind) {
+            return Err(anyhow!("socket.bind_device and socket.freebind are only supported on Linux"));
+        }
+        Ok(options)
+    }
+
+    fn keepalive(&self) -> Result<Option<Keepalive>> {
+        let raw = self.keepalive.as_ref();
+        let (idle, interval, count) = (raw.and_then(|x| x.idle).unwrap_or(DEFAULT_KEEPALIVE_IDLE),
+            raw.and_then(|x| x.interval).unwrap_or(DEFAULT_KEEPALIVE_INTERVAL), raw.and_then(|x| x.count).unwrap_or(DEFAULT_KEEPALIVE_COUNT));
+        // What Linux accepts
+        match (idle, interval, count) {
+            (0, _, _) => Ok(None),
+            (x, _, _) if x > MAX_KEEPALIVE_TIME => Err(anyhow!("keepalive.idle should be at most {MAX_KEEPALIVE_TIME} seconds")),
+            (_, 0, _) => Err(anyhow!("keepalive.interval should be greater than 0")),
+            (_, x, _) if x > MAX_KEEPALIVE_TIME => Err(anyhow!("keepalive.interval should be at most {MAX_KEEPALIVE_TIME} seconds")),
+            (_, _, x) if x == 0 || x > MAX_KEEPALIVE_COUNT => Err(anyhow!("keepalive.count should be between 1 and {MAX_KEEPALIVE_COUNT}")),
+            (idle, interval, count) => Ok(Some(Keepalive {
+                idle: Duration::from_secs(idle),
+                interval: Duration::from_secs(interval),
+                count,
+                data: raw.and_then(|x| x.data_connections).unwrap_or(false)
+            }))
+        }
+    }
+
+    fn address_family(&self) -> Result<AddressFamily> {
+        self.address_family.as_deref().map_or(Ok(AddressFamily::Any), AddressFamily::parse)
+    }
+
+    /* An option of the gateway's bind addresses, by default every address of the family */
+    fn bind_address(name: &str, value: &Option<String>, family: AddressFamily) -> Result<IpAddr> {
+        let Some(x) = value else {
+            return Ok(family.unspecified());
+        };
+        let ip : IpAddr = x.parse().with_context(|| format!("{name} {x} is not a valid IP address"))?;
+        if !family.permits(ip) {
+            return Err(anyhow!("{name} {x} is not an {family} address, but address_family only allows those"));
+        }
+        Ok(ip)
+    }
+}
+
+pub const CONFIG_PATH : &str = "config.toml";
+const DEFAULT_ADMIN_SOCKET : &str = "smugglrs.sock";
+const DEFAULT_PID_FILE : &str = "smugglrs.pid";
+const DEFAULT_LOG_FILE : &str = "smugglrs.log";
+const DEFAULT_ACCOUNTING_INTERVAL : u64 = 60;
+const DEFAULT_KEY_FILE : &str = "aeskey.bin";
+const MAX_RECONNECT_GRACE : u64 = 3600;
+const DEFAULT_SCAN_SUMMARY_INTERVAL : u64 = 300;
+const DEFAULT_UDP_IDLE_TIMEOUT : u64 = 60;
+const DEFAULT_BAN_FAILURES : u32 = 10;
+const DEFAULT_BAN_WINDOW : u64 = 60;
+const DEFAULT_BAN_DURATION : u64 = 600;
+const DEFAULT_KNOCK_WINDOW : u64 = 10;
+const DEFAULT_RECONNECT_GRACE_MAX_CLIENTS : usize = 64;
+const DEFAULT_MAX_SESSIONS : usize = 8;
+const DEFAULT_MAX_CONNECTIONS : usize = 4096;
+const DEFAULT_MAX_CONNECTIONS_PER_PORT : usize = 1024;
+const DEFAULT_HANDSHAKE_TIMEOUT : u64 = 10;
+const DEFAULT_CONNECT_TIMEOUT : u64 = 2000;
+const DEFAULT_CHALLENGE_TIMEOUT : u64 = 150;
+const MAX_DIAL_BACK_TIMEOUT : u64 = 60_000; // For both of the above, in milliseconds
+const DEFAULT_RETRY_DELAY : u64 = 60;
+const MAX_RETRY_DELAY : u64 = 86400;
+const DEFAULT_HEARTBEAT_INTERVAL : u64 = 15;
+const DEFAULT_HEARTBEAT_MISSES : u8 = 3;
+const MAX_HEARTBEAT_INTERVAL : u64 = 3600;
+const DEFAULT_RESOLVE_TTL : u64 = 30;
+const DEFAULT_RESOLVE_NEGATIVE_TTL : u64 = 5;
+const DEFAULT_REKEY_AFTER : u64 = 1 << 22;
+const MAX_PIPE_WORKERS : usize = 256;
+const DEFAULT_KEEPALIVE_IDLE : u64 = 60;
+const DEFAULT_KEEPALIVE_INTERVAL : u64 = 10;
+const DEFAULT_KEEPALIVE_COUNT : u32 = 6;
+const MAX_KEEPALIVE_TIME : u64 = 32767;
+const MAX_KEEPALIVE_COUNT : u32 = 127;
+const MAX_BACKLOG : u32 = 65535;
+
+const ENV_PREFIX : &str = "SMUGGLRS_";
+const ENV_KEY : &str = "SMUGGLRS_KEY";
+const ENV_REDIRECTS : &str = "SMUGGLRS_REDIRECTS";
+
+/* Every option can be set by the environment, as SMUGGLRS_<OPTION> (e.g. SMUGGLRS_GATEWAY_ADDRESS). The values are
+   read as TOML, or taken as strings when they aren't valid TOML, except for the redirects which are given in
+   their compact form, separated by commas. Returns the variables with the option and value they set */
+fn env_overrides() -> Result<Vec<(String, String, Value)>> {
+    let mut overrides = Vec::new();
+    for (var, value) in env::vars_os().filter_map(|(var, value)| Some((var.into_string().ok()?, value.into_string().ok()?))) {
+        let Some(option) = var.strip_prefix(ENV_PREFIX) else { continue };
+        if var == ENV_KEY {
+            continue;
+        }
+        let value = if var == ENV_REDIRECTS {
+            compact_redirects(value.split(',').map(str::trim).filter(|x| !x.is_empty()))
+                .with_context(|| format!("Invalid {var}, it should be a list like 8080:80/tcp,2222:22/tcp"))?
+        } else {
+            match format!("x = {value}").parse::<Table>() {
+                Ok(mut table) => table.remove("x").unwrap(),
+                Err(_) => Value::String(value)
+            }
+        };
+        overrides.push((var.clone(), option.to_lowercase(), value));
+    }
+    Ok(overrides)
+}
+
+/* Redirects in the compact form, as entries of `redirects` */
+fn compact_redirects<'a>(list: impl Iterator<Item = &'a str>) -> Result<Value> {
+    list.map(|x| {
+        let (port, redirect) = parse_compact_redirect(x)?;
+        Ok(Value::Array(vec![Value::Integer(port.port as i64), Value::Integer(redirect.local_port as i64), Value::String(match port.protocol {
+            Protocol::TCP => "TCP",
+            Protocol::UDP => "UDP"
+        }.to_string())]))
+    }).collect::<Result<Vec<Value>>>().map(Value::Array)
+}
+
+/// Options given on the command line, that take precedence over the file and the environment
+#[derive(Debug, Default)]
+pub struct CliOverrides {
+    /// `smugglrs gateway` or `smugglrs server`
+    pub mode: Option<String>,
+    pub port: Option<u16>,
+    pub gateway_address: Option<String>,
+    pub key_file: Option<PathBuf>,
+    /// In the compact form, they replace the redirects of the file
+    pub redirects: Vec<String>
+}
+
+/* The last layer of the configuration, in the form of `env_overrides`: kept for the reloads */
+static CLI_OVERRIDES : OnceLock<Vec<(String, String, Value)>> = OnceLock::new();
+
+/// For every configuration read by this process; only the first call counts
+pub fn set_cli_overrides(cli: CliOverrides) -> Result<()> {
+    let mut overrides = Vec::new();
+    if let Some(mode) = cli.mode {
+        overrides.push((format!("smugglrs {mode}"), "mode".to_string(), Value::String(mode)));
+    }
+    if let Some(port) = cli.port {
+        overrides.push(("--port".to_string(), "port".to_string(), Value::Integer(port as i64)));
+    }
+    if let Some(address) = cli.gateway_address {
+        overrides.push(("--gateway-address".to_string(), "gateway_address".to_string(), Value::String(address)));
+    }
+    // Relative to where we're run from, not to the configuration file
+    if let Some(path) = cli.key_file {
+        let path = env::current_dir().context("Failed to get the current directory")?.join(path);
+        overrides.push(("--key-file".to_string(), "key_file".to_string(), Value::String(path.to_string_lossy().into_owned())));
+    }
+    if !cli.redirects.is_empty() {
+        let redirects = compact_redirects(cli.redirects.iter().map(String::as_str)).context("Invalid --redirect, it should be like 8080:80/tcp")?;
+        overrides.push(("--redirect".to_string(), "redirects".to_string(), redirects));
+    }
+    let _ = CLI_OVERRIDES.set(overrides);
+    Ok(())
+}
+
+/// The key can be given as `SMUGGLRS_KEY`, in hexadecimal, instead of a file
+fn env_key() -> Result<Option<Key>> {
+    let Ok(value) = env::var(ENV_KEY) else {
+        return Ok(None);
+    };
+    let value = value.trim();
+    let invalid = || anyhow!("Invalid {ENV_KEY}, it should be the {KEY_LENGTH} bytes of the key in hexadecimal (e.g. xxd -p -c {KEY_LENGTH} aeskey.bin)");
+    if value.len() != 2 * KEY_LENGTH || !value.is_ascii() {
+        return Err(invalid());
+    }
+    let mut key = [0u8; KEY_LENGTH];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(Some(key))
+}
+
+/// `--config-format`: the configuration files are read as `toml`, `json` or `yaml` whatever their extension
+pub fn set_format(name: &str) -> Result<()> {
+    format::set(Format::parse(name)?);
+    Ok(())
+}
+
+/* `[server]` or `[gateway]` sets the mode, and holds the options that are otherwise at the top of the file */
+fn flatten_section(mut table: Table) -> Result<Table> {
+    let mode = match (table.contains_key("server"), table.contains_key("gateway")) {
+        (true, true) => return Err(anyhow!("There should be a [server] or a [gateway] section, not both")),
+        (true, false) => "server",
+        (false, true) => "gateway",
+        (false, false) => return Ok(table)
+    };
+    let Some(Value::Table(section)) = table.remove(mode) else {
+        return Err(anyhow!("{mode} should be a section, [{mode}]"));
+    };
+    for (key, value) in section {
+        if table.contains_key(&key) {
+            return Err(anyhow!("{key} is set both in [{mode}] and outside of it"));
+        }
+        table.insert(key, value);
+    }
+    match table.get("mode") {
+        Some(Value::String(x)) if x != mode => return Err(anyhow!("mode = \"{x}\" contradicts the [{mode}] section")),
+        Some(_) => {}
+        None => {
+            table.insert("mode".to_string(), Value::String(mode.to_string()));
+        }
+    }
+    Ok(table)
+}
+
+impl RawConfig {
+    /// `config.toml` in the current directory by default, overridden by the environment (see `env_overrides`),
+    /// then by the command line. Without that default file, they can provide the whole configuration
+    pub fn load(path: Option<&Path>) -> Result<RawConfig> {
+        let mut overrides = env_overrides()?;
+        overrides.extend(CLI_OVERRIDES.get().into_iter().flatten().cloned());
+        let file = path.unwrap_or(Path::new(CONFIG_PATH));
+        let (table, from_file) = match fs::read_to_string(file) {
+            Ok(config) => (Format::of(file).read(&config).with_context(|| format!("Failed to parse config {}", file.display()))?, true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound && path.is_none() && !overrides.is_empty() => (Table::new(), false),
+            Err(err) => return Err(err).with_context(|| format!("Failed to read config {}", file.display()))
+        };
+        let mut table = flatten_section(table).with_context(|| format!("Failed to parse config {}", file.display()))?;
+        for (_, option, value) in &overrides {
+            // The redirects replace those of the file, whatever their form
+            if option == "redirects" {
+                table.remove("redirect");
+            }
+            table.insert(option.clone(), value.clone());
+        }
+        let mut config : RawConfig = match Value::Table(table).try_into() {
+            Ok(config) => config,
+            Err(err) => {
+                // Each variable (or flag) is tried on its own, to tell which one is wrong
+                for (var, option, value) in &overrides {
+                    let mut probe = Table::new();
+                    probe.insert("mode".to_string(), Value::String("gateway".to_string()));
+                    probe.insert("port".to_string(), Value::Integer(1));
+                    probe.insert(option.clone(), value.clone());
+                    if let Err(err) = Value::Table(probe).try_into::<RawConfig>() {
+                        return Err(err).with_context(|| format!("Invalid {var}"));
+                    }
+                }
+                return Err(err).with_context(|| match from_file {
+                    true => format!("Failed to parse config {}", file.display()),
+                    false => format!("There is no {}, and the environment and command line don't set every option they should", file.display())
+                });
+            }
+        };
+        config.dir = file.parent().map(Path::to_path_buf).unwrap_or_default();
+        for key in config.unknown.keys() {
+            warn!("Unknown option {key} in {}, ignoring it", file.display());
+        }
+        for (i, redirect) in config.redirect.iter().flatten().enumerate() {
+            for key in redirect.unknown.keys() {
+                warn!("Unknown option {key} in [[redirect]] #{} of {}, ignoring it", i + 1, file.display());
+            }
+        }
+        Ok(config)
+    }
+
+    /// Relative to the directory of the configuration file
+    pub fn resolve(&self, path: &str) -> PathBuf {
+        self.dir.join(path)
+    }
+
+    pub fn admin_socket(&self) -> PathBuf {
+        self.resolve(self.admin_socket.as_deref().unwrap_or(DEFAULT_ADMIN_SOCKET))
+    }
+
+    pub fn key_file(&self) -> PathBuf {
+        self.resolve(self.key_file.as_deref().unwrap_or(DEFAULT_KEY_FILE))
+    }
+}
+
+/* The first and last ports of a redirect of the configuration file, and their protocol, wherever it is */
+fn redirect_entry_ports(entry: &toml_edit::Value) -> Option<(u16, u16, Protocol)> {
+    let entry = entry.as_array()?;
+    let (first, last) = match entry.get(0)? {
+        toml_edit::Value::String(x) => parse_port_range(x.value()).ok()?,
+        x => {
+            let port = u16::try_from(x.as_integer()?).ok()?;
+            (port, port)
+        }
+    };
+    // The target, when there's one, is a string too
+    let protocol = entry.iter().filter_map(|v| v.as_str()).find_map(|x| match x {
+        "TCP" => Some(Protocol::TCP),
+        "UDP" => Some(Protocol::UDP),
+        _ => None
+    })?;
+    Some((first, last, protocol))
+}
+
+/* The same for a `[[redirect]]` table */
+fn redirect_table_ports(table: &toml_edit::Table) -> Option<(u16, u16, Protocol)> {
+    let (first, last) = match table.get("gateway_port")?.as_value()? {
+        toml_edit::Value::String(x) => parse_port_range(x.value()).ok()?,
+        x => {
+            let port = u16::try_from(x.as_integer()?).ok()?;
+            (port, port)
+        }
+    };
+    let protocol = match table.get("protocol").and_then(|x| x.as_str()).map(str::to_uppercase).as_deref() {
+        None | Some("TCP") => Protocol::TCP,
+        Some("UDP") => Protocol::UDP,
+        _ => return None
+    };
+    Some((first, last, protocol))
+}
+
+/* Removes the redirects of a table of the configuration file that are for the port, or refuses to edit a range */
+fn remove_redirect_entries(root: &mut toml_edit::Table, port: Port) -> Result<()> {
+    let covers = |(first, last, protocol): (u16, u16, Protocol)| protocol == port.protocol && (first..=last).contains(&port.port);
+    let ranges = root.get("redirects").and_then(|x| x.as_array()).into_iter().flatten().filter_map(redirect_entry_ports)
+        .chain(root.get("redirect").and_then(|x| x.as_array_of_tables()).into_iter().flatten().filter_map(redirect_table_ports));
+    for (first, last, protocol) in ranges {
+        if first != last && covers((first, last, protocol)) {
+            return Err(anyhow!("Port {} is part of the range {first}-{last} of the configuration, edit it by hand", port.port));
+        }
+    }
+    if let Some(redirects) = root.get_mut("redirects").and_then(|x| x.as_array_mut()) {
+        redirects.retain(|entry| !redirect_entry_ports(entry).is_some_and(covers));
+    }
+    if let Some(tables) = root.get_mut("redirect").and_then(|x| x.as_array_of_tables_mut()) {
+        tables.retain(|table| !redirect_table_ports(table).is_some_and(covers));
+    }
+    Ok(())
+}
+
+/// Rewrite the redirects of the configuration file, keeping everything else (comments included) as is. A new redirect
+/// is written in the form the file already uses, an entry of `redirects` by default
+pub fn persist_redirect(path: &Path, port: Port, redirect: Option<&Redirect>) -> Result<()> {
+    if Format::of(path) != Format::Toml {
+        return Err(anyhow!("Only a TOML configuration can be edited, change {} by hand", path.display()));
+    }
+    let config = fs::read_to_string(path).with_context(|| format!("Failed to read config {}", path.display()))?;
+    let mut document : toml_edit::DocumentMut = config.parse().context("Failed to parse config")?;
+    remove_redirect_entries(document.as_table_mut(), port)?;
+    if let Some(section) = document.get_mut("server").and_then(|x| x.as_table_mut()) {
+        remove_redirect_entries(section, port)?;
+    }
+    let Some(redirect) = redirect else {
+        return fs::write(path, document.to_string()).with_context(|| format!("Failed to write config {}", path.display()));
+    };
+    let protocol = match port.protocol {
+        Protocol::TCP => "TCP",
+        Protocol::UDP => "UDP"
+    };
+    let has_tables = |root: &toml_edit::Table| root.get("redirect").is_some_and(|x| x.is_array_of_tables());
+    // Next to the redirects that are already there, in `[server]` when there's one and they aren't
+    let top = document.as_table();
+    let in_section = match document.get("server").and_then(|x| x.as_table()) {
+        Some(section) => !has_tables(top) && (has_tables(section) || !top.contains_key("redirects")),
+        None => false
+    };
+    let root = match in_section {
+        true => document["server"].as_table_mut().unwrap(),
+        false => document.as_table_mut()
+    };
+    if has_tables(root) {
+        let mut table = toml_edit::Table::new();
+        table["gateway_port"] = toml_edit::value(port.port as i64);
+        table["local_port"] = toml_edit::value(redirect.local_port as i64);
+        table["protocol"] = toml_edit::value(protocol);
+        if let Some(name) = &redirect.name {
+            table["name"] = toml_edit::value(name.as_str());
+        }
+        root["redirect"].as_array_of_tables_mut().unwrap().push(table);
+    } else {
+        let mut entry = toml_edit::Array::new();
+        entry.push(port.port as i64);
+        entry.push(redirect.local_port as i64);
+        entry.push(protocol);
+        if let Some(name) = &redirect.name {
+            entry.push(name.as_str());
+        }
+        root.entry("redirects").or_insert(toml_edit::value(toml_edit::Array::new()))
+            .as_array_mut().context("redirects should be an array")?.push(entry);
+    }
+    fs::write(path, document.to_string()).with_context(|| format!("Failed to write config {}", path.display()))
+}
+
+/* Only readable by us, the key is all it takes to impersonate either side */
+fn write_key(path: &Path, key: &Key, overwrite: bool) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|x| !x.as_os_str().is_empty() && !x.is_dir()) {
+        return Err(anyhow!("The directory of the key file {} doesn't exist", parent.display()));
+    }
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).with_context(|| format!("Failed to create the key file {}", path.display()))?;
+    file.write_all(key).with_context(|| format!("Failed to write the key file {}", path.display()))
+}
+
+/* A key file is exactly the key, anything else means it was truncated or is not a key file at all */
+fn read_key(path: &Path) -> Result<Key> {
+    let content = fs::read(path).with_context(|| format!("Failed to read the key file {}", path.display()))?;
+    let key : Key = content.as_slice().try_into()
+        .map_err(|_| anyhow!("The key file {} is {} bytes long, a key is {KEY_LENGTH} bytes", path.display(), content.len()))?;
+    if key.iter().all(|&x| x == 0) {
+        return Err(anyhow!("The key file {} is only zeroes, it was probably not provisioned", path.display()));
+    }
+    Ok(key)
+}
+
+/// Write a new key to `path` for `smugglrs genkey`, and return its fingerprint
+pub fn generate_key(path: &Path, force: bool) -> Result<String> {
+    if !force && path.exists() {
+        return Err(anyhow!("{} already exists, use --force to replace it", path.display())).context(Failure::Usage);
+    }
+    let key = random_key();
+    write_key(path, &key, force).context(Failure::Config)?;
+    Ok(fingerprint(&key))
+}
+
+/// Configurations of their own, for the tests of the other modules
+#[cfg(test)]
+pub mod fixtures {
+    use super::*;
+
+    /// The directory of a configuration, removed once the test is over
+    pub struct TempDir(pub PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// `toml` as the configuration of a directory of its own, where its key is generated
+    pub fn temp_config(name: &str, toml: &str) -> (TempDir, CommonConfig, SpecificConfig) {
+        let dir = TempDir(env::temp_dir().join(format!("smugglrs-{}-{name}", std::process::id())));
+        let _ = fs::remove_dir_all(&dir.0);
+        fs::create_dir_all(&dir.0).unwrap();
+        generate_key(&dir.0.join(DEFAULT_KEY_FILE), false).unwrap();
+        let path = dir.0.join("config.toml");
+        fs::write(&path, toml).unwrap();
+        let (ccfg, specific) = CommonConfig::new(Some(&path)).unwrap();
+        (dir, ccfg, specific)
+    }
+}
+
+/// Where `smugglrs genkey` writes the key when no path is given
+pub fn default_key_file(config: Option<&Path>) -> Result<PathBuf> {
+    match config {
+        Some(config) => Ok(RawConfig::load(Some(config))?.key_file()),
+        None => Ok(PathBuf::from(DEFAULT_KEY_FILE))
+    }
 }
 
 impl CommonConfig {
-    pub fn new() -> Result<(CommonConfig, SpecificConfig)> {
-        let config = fs::read_to_string("config.toml").context("Failed to read config")?;
-        let config: RawConfig = toml::from_str(&config).context("Failed to parse config")?;
+    pub fn new(path: Option<&Path>) -> Result<(CommonConfig, SpecificConfig)> {
+        CommonConfig::from_raw(RawConfig::load(path)?, path, false)
+    }
+
+    /// For `--dry-run`: nothing is written or resolved, a missing key file of the gateway is only reported
+    pub fn dry_run(path: Option<&Path>) -> Result<(CommonConfig, SpecificConfig)> {
+        CommonConfig::from_raw(RawConfig::load(path)?, path, true)
+    }
+
+    /* With `check`, nothing is written: the gateway generates its missing key file once started */
+    fn from_raw(config: RawConfig, path: Option<&Path>, check: bool) -> Result<(CommonConfig, SpecificConfig)> {
+        let admin_socket = config.admin_socket();
+        let key_path = config.key_file();
+        let daemon = Daemon {
+            enabled: config.daemon.unwrap_or(false),
+            pid_file: config.resolve(config.pid_file.as_deref().unwrap_or(DEFAULT_PID_FILE)),
+            log_file: config.resolve(config.log_file.as_deref().unwrap_or(DEFAULT_LOG_FILE))
+        };
+        let accounting = match (&config.accounting_file, config.accounting_interval.unwrap_or(DEFAULT_ACCOUNTING_INTERVAL)) {
+            (_, 0) => return Err(anyhow!("accounting_interval should be greater than 0")),
+            (Some(file), interval) => Some(Accounting { file: config.resolve(file), interval: Duration::from_secs(interval) }),
+            (None, _) => None
+        };
+        let buffers = config.buffers()?;
+        let reaper = config.reaper()?;
+        let keepalive = config.keepalive()?;
+        let address_family = config.address_family()?;
+        let rekey_after = match config.rekey_after.unwrap_or(DEFAULT_REKEY_AFTER) {
+            0 => return Err(anyhow!("rekey_after should be greater than 0")),
+            x => x
+        };
+        let pipe_workers = match config.pipe_workers.unwrap_or(0) {
+            x if x > MAX_PIPE_WORKERS => return Err(anyhow!("pipe_workers should be at most {MAX_PIPE_WORKERS}")),
+            x => x
+        };
         
         let specific_config = match config.mode.as_str() {
             "gateway" => SpecificConfig::Gateway(GatewayConfig {
-                port: config.port
+                port: config.port,
+                address_family,
+                bind_address: RawConfig::bind_address("bind_address", &config.bind_address, address_family)?,
+                forward_bind_address: RawConfig::bind_address("forward_bind_address", &config.forward_bind_address, address_family)?,
+                socket: config.socket()?,
+                session_quota: match config.session_quota_mb {
+                    Some(0) => return Err(anyhow!("session_quota_mb should be greater than 0")),
+                    Some(mb) => Some(mb.checked_mul(1_000_000).context("session_quota_mb is too large")?),
+                    None => None
+                },
+                session_quota_terminate: config.session_quota_terminate.unwrap_or(false),
+                reconnect_grace: match config.reconnect_grace {
+                    Some(0) | None => None,
+                    Some(x) if x > MAX_RECONNECT_GRACE => return Err(anyhow!("reconnect_grace should be at most {MAX_RECONNECT_GRACE} seconds")),
+                    Some(x) => Some(Duration::from_secs(x))
+                },
+                reconnect_grace_max_clients: config.reconnect_grace_max_clients.unwrap_or(DEFAULT_RECONNECT_GRACE_MAX_CLIENTS),
+                keep_ports_bound: config.keep_ports_bound.unwrap_or(true),
+                max_session_duration: match config.max_session_duration {
+                    Some(0) => return Err(anyhow!("max_session_duration should be greater than 0")),
+                    x => x.map(Duration::from_secs)
+                },
+                one_session: false,
+                max_sessions: match config.max_sessions.unwrap_or(DEFAULT_MAX_SESSIONS) {
+                    0 => return Err(anyhow!("max_sessions should be greater than 0")),
+                    x => x
+                },
+                max_connections: match config.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS) {
+                    0 => return Err(anyhow!("max_connections should be greater than 0")),
+                    x => x
+                },
+                max_connections_per_port: match config.max_connections_per_port.unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_PORT) {
+                    0 => return Err(anyhow!("max_connections_per_port should be greater than 0")),
+                    x => x
+                },
+                access: AccessRules {
+                    allow: config.allow.iter().flatten().map(|x| Cidr::parse(x)).collect::<Result<_>>().context("Invalid allow")?,
+                    deny: config.deny.iter().flatten().map(|x| Cidr::parse(x)).collect::<Result<_>>().context("Invalid deny")?
+                },
+                server_rules: config.server_rules.unwrap_or(true),
+                allowed_servers: config.allowed_servers.iter().flatten().map(|x| Cidr::parse(x)).collect::<Result<_>>().context("Invalid allowed_servers")?,
+                ban: match (config.ban_failures.unwrap_or(DEFAULT_BAN_FAILURES), config.ban_window.unwrap_or(DEFAULT_BAN_WINDOW), config.ban_duration.unwrap_or(DEFAULT_BAN_DURATION)) {
+                    (0, _, _) => None,
+                    (_, 0, _) => return Err(anyhow!("ban_window should be greater than 0")),
+                    (_, _, 0) => return Err(anyhow!("ban_duration should be greater than 0")),
+                    (failures, window, duration) => Some(Ban { failures, window: Duration::from_secs(window), duration: Duration::from_secs(duration) })
+                },
+                knock: match parse_knock(config.knock.as_deref().unwrap_or_default()).context("Invalid knock")? {
+                    knock if knock.contains(&Port { port: config.port, protocol: Protocol::TCP }) => return Err(anyhow!("The gateway's port can't be knocked on")),
+                    knock => knock
+                },
+                knock_window: match config.knock_window.unwrap_or(DEFAULT_KNOCK_WINDOW) {
+                    0 => return Err(anyhow!("knock_window should be greater than 0")),
+                    x => Duration::from_secs(x)
+                },
+                scan_summary_interval: match config.scan_summary_interval.unwrap_or(DEFAULT_SCAN_SUMMARY_INTERVAL) {
+                    0 => None,
+                    x => Some(Duration::from_secs(x))
+                },
+                udp_idle_timeout: match config.udp_idle_timeout.unwrap_or(DEFAULT_UDP_IDLE_TIMEOUT) {
+                    0 => return Err(anyhow!("udp_idle_timeout should be greater than 0")),
+                    x => Duration::from_secs(x)
+                },
+                connect_timeout: match config.connect_timeout_ms.unwrap_or(DEFAULT_CONNECT_TIMEOUT) {
+                    0 => return Err(anyhow!("connect_timeout_ms should be greater than 0")),
+                    x if x > MAX_DIAL_BACK_TIMEOUT => return Err(anyhow!("connect_timeout_ms should be at most {MAX_DIAL_BACK_TIMEOUT}")),
+                    x => Duration::from_millis(x)
+                },
+                challenge_timeout: match config.challenge_timeout_ms.unwrap_or(DEFAULT_CHALLENGE_TIMEOUT) {
+                    0 => return Err(anyhow!("challenge_timeout_ms should be greater than 0")),
+                    x if x > MAX_DIAL_BACK_TIMEOUT => return Err(anyhow!("challenge_timeout_ms should be at most {MAX_DIAL_BACK_TIMEOUT}")),
+                    x => Duration::from_millis(x)
+                },
+                previous_key: match &config.previous_key_file {
+                    Some(path) => Some(read_key(&config.resolve(path)).context("Invalid previous_key_file")?),
+                    None => None
+                },
+                websocket_path: config.websocket_path.map(|x| check_websocket_path(&x).map(|_| x)).transpose()?,
+                config_path: path.unwrap_or(Path::new(CONFIG_PATH)).to_path_buf(),
+                admin_socket
             }),
             "server" => {
-                let raw_redirects = config.redirects.context("redirects should be defined when running as a server")?;
-                let mut redirects = HashMap::with_capacity(raw_redirects.len());
-                
-                for portprot in raw_redirects {
-                    if portprot.len() > 3 {
-                        return Err(anyhow!("Each redirect should be an array of the form [<port>, <protocol>]"));
-                    }
+                if config.redirects.is_none() && config.redirect.is_none() {
+                    return Err(anyhow!("redirects or [[redirect]] should be defined when running as a server"));
+                }
+                let raw_redirects = config.redirects.unwrap_or_default();
+                let tables = config.redirect.unwrap_or_default();
+                let mut redirects = HashMap::with_capacity(raw_redirects.len() + tables.len());
 
-                    let server = match portprot[0] {
-                        Value::Integer(x) => u16::try_from(x).context("Server port should be a 16-bits unsigned integer")?,
-                        _ => {
-                            return Err(anyhow!("Failed to parse port, we expected an integer"))
+                let parsed = raw_redirects.iter().map(|x| parse_redirect(x)).chain(tables.iter().map(parse_redirect_table));
+                for entry in parsed {
+                    for (port, redirect) in entry? {
+                        if redirects.insert(port, redirect).is_some() {
+                            return Err(anyhow!("Duplicate port detected, {} is bound at least twice", port.port));
                         }
-                    };
+                    }
+                }
 
-                    let (protindex, gateway) = if let Value::Integer(x) = portprot[1] {
-                        (2, u16::try_from(x).context("Gateway port should be a 16-bits unsigned integer")?)
-                    } else {
-                        (1, server)
+                let addresses = match config.gateway_address.context("Server should indicate gateway address")? {
+                    RawAddresses::One(address) => vec![address],
+                    RawAddresses::Many(addresses) if addresses.is_empty() => return Err(anyhow!("gateway_address should have at least one address")),
+                    RawAddresses::Many(addresses) => addresses
+                };
+                let mut transport = None;
+                let mut gateway_addresses = Vec::with_capacity(addresses.len());
+                for address in &addresses {
+                    let (scheme, host) = match address.split_once("://") {
+                        Some((scheme, host)) => (scheme, host),
+                        None => ("tcp", address.as_str())
                     };
-
-                    let protocol  = match &portprot[protindex] {
-                        Value::String(x) => {
-                            match x.as_str() {
-                                "UDP" => Protocol::UDP,
-                                "TCP" => Protocol::TCP,
-                                x => return Err(anyhow!("{} is not a valid protocol", x))
-                            }
-                        },
-                        _ => {
-                            return Err(anyhow!("Protocol should be a string"));
+                    // A single transport for all of them, with its proxies
+                    match &transport {
+                        Some(transport) if transport != scheme => {
+                            return Err(anyhow!("The gateway addresses should all use the same transport, {address} doesn't use {transport}"));
                         }
-                    };
-                    
-                    if redirects.insert(Port { port: server, protocol}, gateway).is_some() {
-                        return Err(anyhow!("Duplicate port detected, {} is bound at least twice", gateway));
+                        _ => transport = Some(scheme.to_string())
                     }
+                    // An IPv6 address needs brackets once the port is appended
+                    gateway_addresses.push(match host.parse::<Ipv6Addr>() {
+                        Ok(ip) => format!("[{ip}]:{}", config.port),
+                        Err(_) => format!("{host}:{}", config.port)
+                    });
+                }
+                let transport = transport.unwrap_or_default();
+                let knock = parse_knock(config.knock.as_deref().unwrap_or_default()).context("Invalid knock")?;
+                // The knocks can't go through a proxy
+                if !knock.is_empty() && (config.http_proxy.is_some() || config.socks5_proxy.is_some() || transport == "tor+socks5") {
+                    return Err(anyhow!("knock can't be used through a proxy or Tor"));
                 }
-
-                let gateway_address = format!("{}:{}", config.gateway_address.context("Server should indicate gateway address")?, config.port);
-                
 
                 SpecificConfig::Server(ServerConfig {
                     redirects,
-                    gateway_address,
-                    proxy: config.http_proxy
+                    gateway_addresses,
+                    transport,
+                    socks5_proxy: match (&config.http_proxy, config.socks5_proxy) {
+                        (Some(_), Some(_)) => return Err(anyhow!("http_proxy and socks5_proxy can't be both set")),
+                        (_, Some(proxy)) => Some(SocksProxy::parse(&proxy).context("Invalid socks5_proxy")?),
+                        (_, None) => None
+                    },
+                    proxy: config.http_proxy,
+                    socks_proxy: config.socks_proxy,
+                    admin_socket,
+                    config_path: path.unwrap_or(Path::new(CONFIG_PATH)).to_path_buf(),
+                    retry: config.retry.unwrap_or(true),
+                    address_family,
+                    handshake_timeout: match config.handshake_timeout {
+                        Some(0) => return Err(anyhow!("handshake_timeout should be greater than 0")),
+                        Some(x) => Duration::from_secs(x),
+                        None => Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT)
+                    },
+                    retry_delay: match config.retry_delay_s.unwrap_or(DEFAULT_RETRY_DELAY) {
+                        0 => return Err(anyhow!("retry_delay_s should be greater than 0")),
+                        x if x > MAX_RETRY_DELAY => return Err(anyhow!("retry_delay_s should be at most {MAX_RETRY_DELAY} seconds")),
+                        x => Duration::from_secs(x)
+                    },
+                    heartbeat: match (config.heartbeat_interval.unwrap_or(DEFAULT_HEARTBEAT_INTERVAL), config.heartbeat_misses.unwrap_or(DEFAULT_HEARTBEAT_MISSES)) {
+                        (_, 0) => return Err(anyhow!("heartbeat_misses should be greater than 0")),
+                        (0, _) => None,
+                        (x, _) if x > MAX_HEARTBEAT_INTERVAL => return Err(anyhow!("heartbeat_interval should be at most {MAX_HEARTBEAT_INTERVAL} seconds")),
+                        (x, misses) => Some(Heartbeat { interval: Duration::from_secs(x), misses })
+                    },
+                    resolve_ttl: Duration::from_secs(config.resolve_ttl.unwrap_or(DEFAULT_RESOLVE_TTL)),
+                    resolve_negative_ttl: Duration::from_secs(config.resolve_negative_ttl.unwrap_or(DEFAULT_RESOLVE_NEGATIVE_TTL)),
+                    require_all_ports: config.require_all_ports.unwrap_or(false),
+                    multiplex: config.multiplex.unwrap_or(false),
+                    websocket_path: match config.websocket_path {
+                        Some(x) => check_websocket_path(&x).map(|_| x)?,
+                        None => "/".to_string()
+                    },
+                    websocket_host: config.websocket_host,
+                    knock
                 })
             }
             x => {
@@ -154,21 +1924,150 @@ impl CommonConfig {
             }
         };
 
-        let path = Path::new("aeskey.bin");
+        let path = key_path.as_path();
 
         let mut key = random_key();
-        if !path.exists() {
+        let mut key_source = KeySource::File(key_path.clone());
+        if let Some(env_key) = env_key()? {
+            key = env_key;
+            key_source = KeySource::Environment;
+        } else if !path.exists() {
             if let SpecificConfig::Gateway(_) = specific_config {
-                fs::write(path, key)?;
+                if !check {
+                    write_key(path, &key, false)?;
+                    info!("Generated a new key in {} (fingerprint {})", path.display(), fingerprint(&key));
+                } else {
+                    key_source = KeySource::Missing(key_path.clone());
+                }
             } else {
-                return Err(anyhow!("No key file found, please copy the aeskey.bin file generated by the gateway to the server"));
+                return Err(anyhow!("No key file found at {}, please copy the key file generated by the gateway there", path.display()));
             }
         } else {
-            let mut file = File::open(path)?;
-            file.read(&mut key)?;
+            key = read_key(path)?;
         };
         
-        Ok((CommonConfig { key }, specific_config))
+        Ok((CommonConfig { key, magics: Magics::derive(&key), buffers, reaper, rekey_after, pipe_workers, keepalive, daemon, accounting, key_source }, specific_config))
+    }
+}
+
+/// What `CommonConfig::new` checks, for `--check`, with as many of the errors as possible: each redirect is checked on
+/// its own. Nothing is written, and nothing is bound; the gateway's address is resolved, unless a proxy does it
+pub fn check(path: Option<&Path>) -> Vec<anyhow::Error> {
+    let mut config = match RawConfig::load(path) {
+        Ok(config) => config,
+        Err(err) => return vec![err]
+    };
+    let mut errors = Vec::new();
+    let mut ports = HashSet::new();
+    let mut parsed = |entry: Result<Vec<(Port, Redirect)>>, what: String| match entry {
+        Ok(redirects) => match redirects.iter().find(|(port, _)| !ports.insert(*port)) {
+            Some((port, _)) => {
+                errors.push(anyhow!("Duplicate port detected, {} is bound at least twice", port.port));
+                false
+            }
+            None => true
+        },
+        Err(err) => {
+            errors.push(err.context(format!("Invalid {what}")));
+            false
+        }
+    };
+    if let Some(raw_redirects) = config.redirects.take() {
+        let valid = raw_redirects.into_iter().enumerate()
+            .filter(|(i, entry)| parsed(parse_redirect(entry), format!("redirect #{}", i + 1)))
+            .map(|(_, entry)| entry).collect();
+        config.redirects = Some(valid);
+    }
+    if let Some(tables) = config.redirect.take() {
+        let valid = tables.into_iter().enumerate()
+            .filter(|(i, table)| parsed(parse_redirect_table(table), format!("[[redirect]] #{}", i + 1)))
+            .map(|(_, table)| table).collect();
+        config.redirect = Some(valid);
+    }
+    match CommonConfig::from_raw(config, path, true) {
+        Ok((_, SpecificConfig::Server(scfg))) if matches!(scfg.transport.as_str(), "tcp" | "ws") && scfg.proxy.is_none() && scfg.socks5_proxy.is_none() => {
+            for address in &scfg.gateway_addresses {
+                match address.to_socket_addrs().map(|mut addrs| addrs.any(|addr| scfg.address_family.permits(addr.ip()))) {
+                    Ok(true) => {}
+                    Ok(false) => errors.push(anyhow!("{address} has no {} address", scfg.address_family)),
+                    Err(err) => errors.push(anyhow::Error::new(err).context(format!("Failed to resolve {address}")))
+                }
+            }
+        }
+        Ok(_) => {}
+        Err(err) => errors.push(err)
     }
+    errors
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* A file of its own in the temporary directory, removed once the test is over */
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, content: Option<&[u8]>) -> TempFile {
+            let path = env::temp_dir().join(format!("smugglrs-{}-{name}", std::process::id()));
+            let _ = fs::remove_file(&path);
+            if let Some(content) = content {
+                fs::write(&path, content).unwrap();
+            }
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn key_error(name: &str, content: &[u8]) -> String {
+        let file = TempFile::new(name, Some(content));
+        format!("{:#}", read_key(&file.0).unwrap_err())
+    }
+
+    #[test]
+    fn key_files_of_the_key_length_are_read() {
+        let key : Key = std::array::from_fn(|i| i as u8 + 1);
+        let file = TempFile::new("key.bin", Some(&key));
+        assert_eq!(read_key(&file.0).unwrap(), key);
+    }
+
+    #[test]
+    fn truncated_key_files_are_refused() {
+        assert!(key_error("short.bin", &[7u8; KEY_LENGTH - 1]).ends_with("is 31 bytes long, a key is 32 bytes"));
+        assert!(key_error("half.bin", &[7u8; 16]).ends_with("is 16 bytes long, a key is 32 bytes"));
+        assert!(key_error("empty.bin", &[]).ends_with("is 0 bytes long, a key is 32 bytes"));
+    }
+
+    #[test]
+    fn oversized_key_files_are_refused() {
+        assert!(key_error("long.bin", &[7u8; KEY_LENGTH + 1]).ends_with("is 33 bytes long, a key is 32 bytes"));
+        let text = "not a key, but some text that is longer than one";
+        assert!(key_error("text.bin", text.as_bytes()).ends_with(&format!("is {} bytes long, a key is 32 bytes", text.len())));
+    }
+
+    #[test]
+    fn zeroed_key_files_are_refused() {
+        assert!(key_error("zeroes.bin", &[0u8; KEY_LENGTH]).ends_with("is only zeroes, it was probably not provisioned"));
+    }
+
+    #[test]
+    fn missing_key_files_are_refused() {
+        let file = TempFile::new("missing.bin", None);
+        let err = format!("{:#}", read_key(&file.0).unwrap_err());
+        assert!(err.starts_with(&format!("Failed to read the key file {}", file.0.display())), "{err}");
+    }
+
+    #[test]
+    fn generated_keys_are_read_back() {
+        let file = TempFile::new("generated.bin", None);
+        let fingerprint = generate_key(&file.0, false).unwrap();
+        assert_eq!(crate::crypto::fingerprint(&read_key(&file.0).unwrap()), fingerprint);
+        assert!(generate_key(&file.0, false).is_err(), "an existing key is only replaced with --force");
+        assert_ne!(generate_key(&file.0, true).unwrap(), fingerprint);
+    }
+}
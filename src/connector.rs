@@ -0,0 +1,193 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! Where the server sends tunnelled connections. By default, to `127.0.0.1:<local port>` (or the `host`
+//! of the redirect, or its `unix:` socket); a redirect with `target = "custom:<name>"` is served by the connector
+//! registered under that name.
+
+use anyhow::{anyhow, Result, Context};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::config::Redirect;
+use crate::info;
+
+/// The local side of a pipe. Both directions are copied by different threads, hence `try_clone`.
+pub trait Stream: Read + Write + Send {
+    fn try_clone_stream(&self) -> io::Result<Box<dyn Stream>>;
+    /// Unblocks the threads reading or writing to this stream
+    fn shutdown_stream(&self) -> io::Result<()>;
+    /// Tells the other end that nothing more will be written, once a direction of the pipe is done.
+    /// Streams that can't be half-closed are shut down entirely
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown_stream()
+    }
+    /// Socket options are only tuned for TCP streams
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl Stream for TcpStream {
+    fn try_clone_stream(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn shutdown_stream(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+/// For the redirects to a Unix socket, and `UnixStream::pair()` is the simplest way to serve a connection in-process
+#[cfg(unix)]
+impl Stream for std::os::unix::net::UnixStream {
+    fn try_clone_stream(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn shutdown_stream(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+pub trait Connector: Send + Sync {
+    fn connect(&self, redirect: &Redirect) -> Result<Box<dyn Stream>>;
+}
+
+/// What the server does without a custom target
+pub struct TcpConnector {
+    resolver: Arc<Resolver>
+}
+
+impl Connector for TcpConnector {
+    fn connect(&self, redirect: &Redirect) -> Result<Box<dyn Stream>> {
+        #[cfg(unix)]
+        if let Some(path) = &redirect.unix {
+            let stream = std::os::unix::net::UnixStream::connect(path).with_context(|| format!("Failed to connect to the Unix socket {}", path.display()))?;
+            return Ok(Box::new(stream));
+        }
+        let stream = match &redirect.host {
+            Some(host) => self.resolver.connect(host, redirect.local_port)?,
+            None => TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], redirect.local_port))).context("Failed to connect to the local server")?
+        };
+        Ok(Box::new(stream))
+    }
+}
+
+const HOST_CONNECT_TIMEOUT : Duration = Duration::from_secs(3);
+
+/* What a host resolved to, or why it didn't, and until when it holds */
+type Resolution = (Instant, std::result::Result<Vec<SocketAddr>, String>);
+
+/// Resolves the hosts of the redirects at each connection, as their addresses may change while we run,
+/// with a small cache so that bursts of connections don't each wait for the resolver
+pub struct Resolver {
+    ttl: Duration,
+    negative_ttl: Duration,
+    cache: Mutex<HashMap<(String, u16), Resolution>>,
+    // Where we last managed to connect, to tell when it changes
+    last: Mutex<HashMap<String, SocketAddr>>
+}
+
+impl Default for Resolver {
+    fn default() -> Resolver {
+        Resolver::new(Duration::from_secs(30), Duration::from_secs(5))
+    }
+}
+
+impl Resolver {
+    pub fn new(ttl: Duration, negative_ttl: Duration) -> Resolver {
+        Resolver { ttl, negative_ttl, cache: Mutex::new(HashMap::new()), last: Mutex::new(HashMap::new()) }
+    }
+
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let key = (host.to_string(), port);
+        if let Some((until, resolution)) = self.cache.lock().unwrap().get(&key) {
+            if Instant::now() < *until {
+                return resolution.clone().map_err(|err| anyhow!("Failed to resolve {host}: {err} (cached)"));
+            }
+        }
+        // Not holding the lock, the resolution may take a while
+        let resolution = match (host, port).to_socket_addrs() {
+            Ok(addrs) => {
+                let addrs : Vec<SocketAddr> = addrs.collect();
+                if addrs.is_empty() { Err("no address found".to_string()) } else { Ok(addrs) }
+            }
+            Err(err) => Err(err.to_string())
+        };
+        let ttl = if resolution.is_ok() { self.ttl } else { self.negative_ttl };
+        self.cache.lock().unwrap().insert(key, (Instant::now() + ttl, resolution.clone()));
+        resolution.map_err(|err| anyhow!("Failed to resolve {host}: {err}"))
+    }
+
+    /// Tries every address of the host in order
+    pub fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let addrs = self.resolve(host, port)?;
+        let mut errors = Vec::new();
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, HOST_CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    if let Some(previous) = self.last.lock().unwrap().insert(host.to_string(), addr).filter(|x| *x != addr) {
+                        info!("{host} is now reached at {addr} instead of {previous}");
+                    }
+                    return Ok(stream);
+                }
+                Err(err) => errors.push(format!("{addr}: {err}"))
+            }
+        }
+        Err(anyhow!("Failed to connect to {host}:{port} ({})", errors.join(", ")))
+    }
+}
+
+pub const CUSTOM_TARGET_PREFIX : &str = "custom:";
+pub const UNIX_TARGET_PREFIX : &str = "unix:";
+
+/// Custom connectors, by name
+#[derive(Default, Clone)]
+pub struct Connectors {
+    custom: HashMap<String, Arc<dyn Connector>>,
+    resolver: Arc<Resolver>
+}
+
+impl Connectors {
+    pub fn register<C: Connector + 'static>(&mut self, name: &str, connector: C) -> &mut Connectors {
+        self.custom.insert(name.to_string(), Arc::new(connector));
+        self
+    }
+
+    /// Used by the default connector for the redirects with a `host`
+    pub fn set_resolver(&mut self, resolver: Resolver) -> &mut Connectors {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    /// The connector serving a redirect
+    pub fn get(&self, redirect: &Redirect) -> Result<Arc<dyn Connector>> {
+        match &redirect.target {
+            None => Ok(Arc::new(TcpConnector { resolver: self.resolver.clone() })),
+            Some(name) => self.custom.get(name).cloned().ok_or_else(|| anyhow!("No connector is registered for target {CUSTOM_TARGET_PREFIX}{name}"))
+        }
+    }
+}
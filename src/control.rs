@@ -0,0 +1,874 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+use crate::cidr::{AccessRules, Cidr, CIDR_LENGTH};
+use crate::common::TCP_CHALLENGE_LENGTH;
+use crate::config::{check_name, check_pipe_buffer, Maintenance, Port, Protocol, MAX_MAINTENANCE_BODY};
+use crate::crypto::{Cipher, AEAD_LENGTH};
+use crate::integrity::{PipeDigests, PIPE_DIGESTS_LENGTH};
+use crate::schedule::Schedule;
+use crate::{debug, warn};
+use anyhow::{anyhow, Result, Context};
+use std::io::{ErrorKind, Read, Write};
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+
+/* Control messages are sent as an encrypted 2 bytes length, followed by the encrypted message.
+   The first byte of the message is its type. */
+const LENGTH_SIZE : usize = 2;
+/// The largest message that fits in the length framing
+pub const MAX_MESSAGE_LENGTH : usize = u16::MAX as usize - AEAD_LENGTH;
+const NEW_CONNECTION : u8 = 0;
+const ERROR : u8 = 1;
+const REGISTER : u8 = 2;
+const ADD_PORT : u8 = 3;
+const REMOVE_PORT : u8 = 4;
+const PROBE : u8 = 5;
+const UPDATE_PORT : u8 = 6;
+const INTEGRITY : u8 = 7;
+const PING : u8 = 8;
+const PONG : u8 = 9;
+const BIND_STATUS : u8 = 10;
+/* The key material the sender continues with, see write_message. Handled by the framing, it's no ControlMessage */
+const REKEY : u8 = 11;
+/* A frame of a multiplexed stream: the stream, the kind of frame, then what it carries */
+const STREAM : u8 = 12;
+/* From the server, for a connection it can't make: the token of the client, then why */
+const REFUSED : u8 = 13;
+
+/* Kinds of stream frames */
+const STREAM_DATA : u8 = 0; // The bytes
+const STREAM_OPEN : u8 = 1; // Port, token and client, like NEW_CONNECTION
+const STREAM_END : u8 = 2; // No value
+const STREAM_RESET : u8 = 3; // No value
+const STREAM_WINDOW : u8 = 4; // Bytes, u32
+
+/* Flags that follow the registrations, older gateways ignore them */
+const REGISTER_BIND_STATUS : u8 = 1; // The server wants a BindStatus
+const REGISTER_REKEY : u8 = 2; // The server understands REKEY
+const REGISTER_MULTIPLEX : u8 = 4; // The server wants the connections multiplexed over the control connection
+/* Flags that follow the statuses, older servers ignore them */
+const BIND_STATUS_REKEY : u8 = 1; // The gateway understands REKEY
+const BIND_STATUS_MULTIPLEX : u8 = 2; // The gateway multiplexes the connections
+const BIND_STATUS_REFUSED : u8 = 4; // The gateway understands REFUSED
+
+/* Tags of the per-redirect options sent with the registration */
+const OPTION_SCHEDULE : u8 = 0;
+const OPTION_HIDE_CLIENT : u8 = 1; // No value
+const OPTION_ALLOW : u8 = 2; // A list of CIDRs
+const OPTION_DENY : u8 = 3;
+const OPTION_FIRST_BYTE_TIMEOUT : u8 = 4; // Milliseconds, u32
+const OPTION_MAINTENANCE : u8 = 5; // Mode, then for http the Retry-After (u32, 0 if none) and body
+const OPTION_PAUSED : u8 = 6; // No value
+const OPTION_VERIFY_INTEGRITY : u8 = 7; // No value
+const OPTION_ENCRYPT : u8 = 8; // No value
+const OPTION_NAME : u8 = 9; // UTF-8, see config::check_name
+const OPTION_PIPE_BUFFER : u8 = 10; // Bytes, u32, see config::check_pipe_buffer
+const OPTION_NAGLE : u8 = 11; // No value, TCP_NODELAY is set unless sent
+
+/* IPv6 (IPv4 addresses are mapped) + port + timestamp */
+const CLIENT_INFO_LENGTH : usize = 16 + 2 + 8;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorCode {
+    QuotaExhausted,
+    /// Its ports are served by another session, or the gateway serves as many as it can; the gateway closes the connection
+    SessionRefused,
+    /// The session lasted `max_session_duration`, the gateway closes the connection and the server pairs again
+    SessionExpired,
+    Unknown(u8)
+}
+
+impl ErrorCode {
+    fn to_byte(self) -> u8 {
+        match self {
+            ErrorCode::QuotaExhausted => 0,
+            ErrorCode::SessionRefused => 1,
+            ErrorCode::SessionExpired => 2,
+            ErrorCode::Unknown(x) => x
+        }
+    }
+
+    fn from_byte(x: u8) -> ErrorCode {
+        match x {
+            0 => ErrorCode::QuotaExhausted,
+            1 => ErrorCode::SessionRefused,
+            2 => ErrorCode::SessionExpired,
+            x => ErrorCode::Unknown(x)
+        }
+    }
+}
+
+/// Why the gateway couldn't bind a port
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BindError {
+    InUse,
+    PermissionDenied,
+    /// The address the gateway binds the ports on isn't one of its own
+    AddressUnavailable,
+    /// Another session of the gateway serves it
+    Taken,
+    Other,
+    Unknown(u8)
+}
+
+impl BindError {
+    pub fn of(err: &std::io::Error) -> BindError {
+        match err.kind() {
+            ErrorKind::AddrInUse => BindError::InUse,
+            ErrorKind::PermissionDenied => BindError::PermissionDenied,
+            ErrorKind::AddrNotAvailable => BindError::AddressUnavailable,
+            _ => BindError::Other
+        }
+    }
+
+    /* 0 is a port that was bound */
+    fn to_byte(self) -> u8 {
+        match self {
+            BindError::InUse => 1,
+            BindError::PermissionDenied => 2,
+            BindError::AddressUnavailable => 3,
+            BindError::Taken => 4,
+            BindError::Other => 5,
+            BindError::Unknown(x) => x
+        }
+    }
+
+    fn from_byte(x: u8) -> Option<BindError> {
+        match x {
+            0 => None,
+            1 => Some(BindError::InUse),
+            2 => Some(BindError::PermissionDenied),
+            3 => Some(BindError::AddressUnavailable),
+            4 => Some(BindError::Taken),
+            5 => Some(BindError::Other),
+            x => Some(BindError::Unknown(x))
+        }
+    }
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindError::InUse => write!(f, "something else listens on it"),
+            BindError::PermissionDenied => write!(f, "permission denied"),
+            BindError::AddressUnavailable => write!(f, "the address it is bound on isn't one of the gateway's"),
+            BindError::Taken => write!(f, "another server forwards it"),
+            BindError::Other => write!(f, "failed to bind it"),
+            BindError::Unknown(x) => write!(f, "unknown reason {x}")
+        }
+    }
+}
+
+/// What the gateway needs to know about a redirect
+#[derive(Clone)]
+pub struct Registration {
+    pub port: Port,
+    pub schedule: Option<Schedule>,
+    /// Don't tell the server who connected
+    pub hide_client: bool,
+    /// Only ever restricts the rules of the gateway
+    pub access: AccessRules,
+    /// Clients that don't send anything for this long are closed before the server is asked to connect
+    pub first_byte_timeout: Option<Duration>,
+    pub maintenance: Option<Maintenance>,
+    /// Only sent for ports with maintenance, which stay bound while paused
+    pub paused: bool,
+    /// The gateway sends the digests of the port's connections, see `integrity`
+    pub verify_integrity: bool,
+    /// The data connections of the port are encrypted, see `crypto::SealedWriter`
+    pub encrypt: bool,
+    /// For the logs and the admin socket
+    pub name: Option<String>,
+    /// The size of the buffers of the port's connections
+    pub pipe_buffer: Option<usize>,
+    /// TCP_NODELAY on the sockets of the port's connections
+    pub nodelay: bool
+}
+
+fn cidrs_to_bytes(list: &[Cidr]) -> Vec<u8> {
+    list.iter().flat_map(|cidr| cidr.to_bytes()).collect()
+}
+
+fn cidrs_from_bytes(buf: &[u8]) -> Result<Vec<Cidr>> {
+    if !buf.len().is_multiple_of(CIDR_LENGTH) {
+        return Err(anyhow!("Malformed CIDR list"));
+    }
+    buf.chunks_exact(CIDR_LENGTH).map(|x| Cidr::from_bytes(x.try_into().unwrap())).collect()
+}
+
+impl Registration {
+    fn write(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.extend_from_slice(&self.port.to_bytes());
+        let mut options = Vec::new();
+        if let Some(schedule) = self.schedule {
+            options.push((OPTION_SCHEDULE, schedule.to_bytes().to_vec()));
+        }
+        if self.hide_client {
+            options.push((OPTION_HIDE_CLIENT, Vec::new()));
+        }
+        if !self.access.allow.is_empty() {
+            options.push((OPTION_ALLOW, cidrs_to_bytes(&self.access.allow)));
+        }
+        if !self.access.deny.is_empty() {
+            options.push((OPTION_DENY, cidrs_to_bytes(&self.access.deny)));
+        }
+        if let Some(timeout) = self.first_byte_timeout {
+            let timeout = u32::try_from(timeout.as_millis()).context("first_byte_timeout is too long")?;
+            options.push((OPTION_FIRST_BYTE_TIMEOUT, timeout.to_be_bytes().to_vec()));
+        }
+        if let Some(maintenance) = &self.maintenance {
+            options.push((OPTION_MAINTENANCE, match maintenance {
+                Maintenance::Http { body, retry_after } => {
+                    let mut value = vec![0];
+                    value.extend_from_slice(&retry_after.unwrap_or(0).to_be_bytes());
+                    value.extend_from_slice(body.as_bytes());
+                    value
+                }
+                Maintenance::Reset => vec![1],
+                Maintenance::Hold => vec![2]
+            }));
+        }
+        if self.paused {
+            options.push((OPTION_PAUSED, Vec::new()));
+        }
+        if self.verify_integrity {
+            options.push((OPTION_VERIFY_INTEGRITY, Vec::new()));
+        }
+        if self.encrypt {
+            options.push((OPTION_ENCRYPT, Vec::new()));
+        }
+        if let Some(name) = &self.name {
+            options.push((OPTION_NAME, name.as_bytes().to_vec()));
+        }
+        if let Some(size) = self.pipe_buffer {
+            options.push((OPTION_PIPE_BUFFER, u32::try_from(size).context("pipe_buffer is too large")?.to_be_bytes().to_vec()));
+        }
+        if !self.nodelay {
+            options.push((OPTION_NAGLE, Vec::new()));
+        }
+        buf.push(options.len() as u8);
+        for (tag, value) in options {
+            buf.push(tag);
+            buf.extend_from_slice(&u16::try_from(value.len()).context("Redirect option too long")?.to_be_bytes());
+            buf.extend_from_slice(&value);
+        }
+        Ok(())
+    }
+
+    fn read(buf: &mut &[u8]) -> Result<Registration> {
+        let port = Port::try_from_bytes(take(buf, 3)?.try_into().unwrap())?;
+        let mut registration = Registration { port, schedule: None, hide_client: false, access: AccessRules::default(),
+            first_byte_timeout: None, maintenance: None, paused: false, verify_integrity: false, encrypt: false, name: None, pipe_buffer: None, nodelay: true };
+        let option_count = take(buf, 1)?[0];
+        for _ in 0..option_count {
+            let tag = take(buf, 1)?[0];
+            let length = u16::from_be_bytes(take(buf, 2)?.try_into().unwrap());
+            let value = take(buf, length as usize)?;
+            match tag {
+                OPTION_SCHEDULE => registration.schedule = Some(Schedule::from_bytes(value)?),
+                OPTION_HIDE_CLIENT => registration.hide_client = true,
+                OPTION_ALLOW => registration.access.allow = cidrs_from_bytes(value)?,
+                OPTION_DENY => registration.access.deny = cidrs_from_bytes(value)?,
+                OPTION_FIRST_BYTE_TIMEOUT => {
+                    let timeout = u32::from_be_bytes(value.try_into().context("Malformed first byte timeout")?);
+                    registration.first_byte_timeout = Some(Duration::from_millis(timeout as u64));
+                }
+                OPTION_MAINTENANCE => registration.maintenance = Some(match value.split_first() {
+                    Some((0, rest)) if rest.len() >= 4 && rest.len() - 4 <= MAX_MAINTENANCE_BODY => Maintenance::Http {
+                        retry_after: Some(u32::from_be_bytes(rest[..4].try_into().unwrap())).filter(|x| *x != 0),
+                        body: String::from_utf8_lossy(&rest[4..]).into_owned()
+                    },
+                    Some((1, [])) => Maintenance::Reset,
+                    Some((2, [])) => Maintenance::Hold,
+                    _ => return Err(anyhow!("Malformed maintenance option"))
+                }),
+                OPTION_PAUSED => registration.paused = true,
+                OPTION_VERIFY_INTEGRITY => registration.verify_integrity = true,
+                OPTION_ENCRYPT => registration.encrypt = true,
+                OPTION_NAME => registration.name = Some(String::from_utf8(value.to_vec()).map_err(anyhow::Error::from).and_then(check_name).context("Malformed name option")?),
+                OPTION_PIPE_BUFFER => {
+                    let size = u32::from_be_bytes(value.try_into().context("Malformed pipe buffer option")?);
+                    registration.pipe_buffer = Some(check_pipe_buffer(size as usize).context("Malformed pipe buffer option")?);
+                }
+                OPTION_NAGLE => registration.nodelay = false,
+                x => warn!("Unknown option {x} for port {}, ignoring", port.port)
+            }
+        }
+        Ok(registration)
+    }
+}
+
+/// Who connected to the gateway, forwarded to the server for logging
+#[derive(Debug, Copy, Clone)]
+pub struct ClientInfo {
+    /// None when the redirect hides it, or when the gateway is too old to send it
+    pub addr: Option<SocketAddr>,
+    /// When the gateway asked the server for this connection, in milliseconds since the epoch (0 if unknown)
+    pub requested_at: u64,
+    /// Number of the connection in the session, for the logs. Only sent to the servers that gave a session
+    pub conn: Option<u32>
+}
+
+impl ClientInfo {
+    fn write(&self, buf: &mut Vec<u8>) {
+        // A hidden client is sent as zeros
+        let (ip, port) = match self.addr {
+            Some(addr) => (match addr.ip() {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip
+            }, addr.port()),
+            None => (Ipv6Addr::UNSPECIFIED, 0)
+        };
+        buf.extend_from_slice(&ip.octets());
+        buf.extend_from_slice(&port.to_be_bytes());
+        buf.extend_from_slice(&self.requested_at.to_be_bytes());
+    }
+
+    fn read(buf: &[u8; CLIENT_INFO_LENGTH]) -> ClientInfo {
+        let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&buf[0..16]).unwrap());
+        let port = u16::from_be_bytes(buf[16..18].try_into().unwrap());
+        let addr = if ip.is_unspecified() && port == 0 {
+            None
+        } else {
+            let ip = ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4);
+            Some(SocketAddr::new(ip, port))
+        };
+        ClientInfo {
+            addr,
+            requested_at: u64::from_be_bytes(buf[18..].try_into().unwrap()),
+            conn: None
+        }
+    }
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.addr {
+            Some(addr) => write!(f, "{addr}"),
+            None => write!(f, "a hidden client")
+        }
+    }
+}
+
+fn take<'a>(buf: &mut &'a [u8], length: usize) -> Result<&'a [u8]> {
+    if buf.len() < length {
+        return Err(anyhow!("Truncated control message"));
+    }
+    let (ret, rest) = buf.split_at(length);
+    *buf = rest;
+    Ok(ret)
+}
+
+pub enum ControlMessage {
+    /// For UDP, a new client peer
+    NewConnection { port: Port, challenge: [u8; TCP_CHALLENGE_LENGTH], client: ClientInfo },
+    Error { code: ErrorCode, message: String },
+    /// Sent once by the server, right after the handshake. `rekey` tells that the gateway may rekey its messages,
+    /// `multiplex` that the server wants the connections carried over the control connection. `session` tags the
+    /// logs of both sides, and tells that the server understands the connection numbers of `ClientInfo`
+    Register { registrations: Vec<Registration>, bind_status: bool, rekey: bool, multiplex: bool, session: Option<u16> },
+    AddPort(Registration),
+    RemovePort(Port),
+    /// New options for a port that is already registered
+    UpdatePort(Registration),
+    /// Sent by `smugglrs test-connection` instead of registering, and echoed back by the gateway
+    Probe,
+    /// Sent by the gateway once a connection of a port with `verify_integrity` is over
+    Integrity { id: u64, digests: PipeDigests },
+    /// Sent by the server every `interval`: both sides end the session once nothing came from
+    /// the other for `misses` intervals
+    Ping { interval: Duration, misses: u8 },
+    /// The gateway's answer to a ping
+    Pong,
+    /// Sent by the gateway after the registration and each added port, when the server asked for it:
+    /// the ports it bound, and why it couldn't bind the others. `rekey` tells that the server may rekey its messages,
+    /// `multiplex` that the gateway agreed to multiplex the connections, `refused` that it understands `Refused`
+    BindStatus { statuses: Vec<(Port, Option<BindError>)>, rekey: bool, multiplex: bool, refused: bool },
+    /// Sent by the server when it couldn't connect back for the client of a `NewConnection`, with its token,
+    /// so that the gateway closes it now rather than once `connect_timeout` is over
+    Refused { token: [u8; TCP_CHALLENGE_LENGTH], reason: String },
+    /// Of a multiplexed connection, see `mux`
+    Stream { id: u32, frame: StreamFrame }
+}
+
+/// What goes through a multiplexed connection, in either direction unless told otherwise
+pub enum StreamFrame {
+    /// From the gateway, for a new client, instead of a `NewConnection`
+    Open { port: Port, token: [u8; TCP_CHALLENGE_LENGTH], client: ClientInfo },
+    Data(Vec<u8>),
+    /// Nothing more will be sent
+    End,
+    /// The connection is over, whatever was sent or not
+    Reset,
+    /// The bytes read since the last window, that the other side may send again
+    Window(u32)
+}
+
+impl ControlMessage {
+    /// Length of the message once serialized, before encryption
+    pub fn encoded_length(&self) -> Result<usize> {
+        Ok(self.to_bytes()?.len())
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut ret = Vec::new();
+        match self {
+            ControlMessage::NewConnection { port, challenge, client } => {
+                ret.push(NEW_CONNECTION);
+                ret.extend_from_slice(&port.port.to_be_bytes());
+                ret.extend_from_slice(challenge);
+                client.write(&mut ret);
+                // TCP goes without, as servers that don't know of UDP or of the connection numbers expect
+                if port.protocol == Protocol::UDP || client.conn.is_some() {
+                    ret.push(port.to_bytes()[2]);
+                }
+                if let Some(conn) = client.conn {
+                    ret.extend_from_slice(&conn.to_be_bytes());
+                }
+            }
+            ControlMessage::Error { code, message } => {
+                ret.push(ERROR);
+                ret.push(code.to_byte());
+                ret.extend_from_slice(message.as_bytes());
+            }
+            ControlMessage::Register { registrations, bind_status, rekey, multiplex, session } => {
+                ret.push(REGISTER);
+                ret.extend_from_slice(&u16::try_from(registrations.len()).context("Too many redirects")?.to_be_bytes());
+                for registration in registrations {
+                    registration.write(&mut ret)?;
+                }
+                ret.push(if *bind_status { REGISTER_BIND_STATUS } else { 0 } | if *rekey { REGISTER_REKEY } else { 0 } | if *multiplex { REGISTER_MULTIPLEX } else { 0 });
+                if let Some(session) = session {
+                    ret.extend_from_slice(&session.to_be_bytes());
+                }
+            }
+            ControlMessage::AddPort(registration) => {
+                ret.push(ADD_PORT);
+                registration.write(&mut ret)?;
+            }
+            ControlMessage::RemovePort(port) => {
+                ret.push(REMOVE_PORT);
+                ret.extend_from_slice(&port.to_bytes());
+            }
+            ControlMessage::UpdatePort(registration) => {
+                ret.push(UPDATE_PORT);
+                registration.write(&mut ret)?;
+            }
+            ControlMessage::Probe => ret.push(PROBE),
+            ControlMessage::Integrity { id, digests } => {
+                ret.push(INTEGRITY);
+                ret.extend_from_slice(&id.to_be_bytes());
+                ret.extend_from_slice(&digests.to_bytes());
+            }
+            ControlMessage::Ping { interval, misses } => {
+                ret.push(PING);
+                ret.extend_from_slice(&u32::try_from(interval.as_millis()).context("Heartbeat interval too long")?.to_be_bytes());
+                ret.push(*misses);
+            }
+            ControlMessage::Pong => ret.push(PONG),
+            ControlMessage::BindStatus { statuses, rekey, multiplex, refused } => {
+                ret.push(BIND_STATUS);
+                ret.extend_from_slice(&u16::try_from(statuses.len()).context("Too many ports")?.to_be_bytes());
+                for (port, error) in statuses {
+                    ret.extend_from_slice(&port.to_bytes());
+                    ret.push(error.map_or(0, BindError::to_byte));
+                }
+                ret.push(if *rekey { BIND_STATUS_REKEY } else { 0 } | if *multiplex { BIND_STATUS_MULTIPLEX } else { 0 }
+                    | if *refused { BIND_STATUS_REFUSED } else { 0 });
+            }
+            ControlMessage::Refused { token, reason } => {
+                ret.push(REFUSED);
+                ret.extend_from_slice(token);
+                ret.extend_from_slice(reason.as_bytes());
+            }
+            ControlMessage::Stream { id, frame } => {
+                ret.push(STREAM);
+                ret.extend_from_slice(&id.to_be_bytes());
+                match frame {
+                    StreamFrame::Open { port, token, client } => {
+                        ret.push(STREAM_OPEN);
+                        ret.extend_from_slice(&port.to_bytes());
+                        ret.extend_from_slice(token);
+                        client.write(&mut ret);
+                        if let Some(conn) = client.conn {
+                            ret.extend_from_slice(&conn.to_be_bytes());
+                        }
+                    }
+                    StreamFrame::Data(data) => {
+                        ret.push(STREAM_DATA);
+                        ret.extend_from_slice(data);
+                    }
+                    StreamFrame::End => ret.push(STREAM_END),
+                    StreamFrame::Reset => ret.push(STREAM_RESET),
+                    StreamFrame::Window(credit) => {
+                        ret.push(STREAM_WINDOW);
+                        ret.extend_from_slice(&credit.to_be_bytes());
+                    }
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<ControlMessage> {
+        match buf.split_first() {
+            // Older gateways don't send the client information
+            Some((&NEW_CONNECTION, payload)) if payload.len() == 2 + TCP_CHALLENGE_LENGTH || payload.len() == 2 + TCP_CHALLENGE_LENGTH + CLIENT_INFO_LENGTH => {
+                let (payload, client) = payload.split_at(2 + TCP_CHALLENGE_LENGTH);
+                Ok(ControlMessage::NewConnection {
+                    port: Port::new_tcp(u16::from_be_bytes(payload[0..2].try_into().unwrap())),
+                    challenge: payload[2..].try_into().unwrap(),
+                    client: match client.try_into() {
+                        Ok(client) => ClientInfo::read(client),
+                        Err(_) => ClientInfo { addr: None, requested_at: 0, conn: None }
+                    }
+                })
+            },
+            // With the connection number, the protocol comes whatever it is
+            Some((&NEW_CONNECTION, payload)) if payload.len() == 2 + TCP_CHALLENGE_LENGTH + CLIENT_INFO_LENGTH + 1 + 4 => {
+                let (payload, client) = payload.split_at(2 + TCP_CHALLENGE_LENGTH);
+                let (client, rest) = client.split_at(CLIENT_INFO_LENGTH);
+                let mut client = ClientInfo::read(client.try_into().unwrap());
+                client.conn = Some(u32::from_be_bytes(rest[1..].try_into().unwrap()));
+                Ok(ControlMessage::NewConnection {
+                    port: Port::try_from_bytes(&[payload[0], payload[1], rest[0]]).context("Malformed new connection protocol")?,
+                    challenge: payload[2..].try_into().unwrap(),
+                    client
+                })
+            },
+            // Only UDP comes with its protocol
+            Some((&NEW_CONNECTION, payload)) if payload.len() == 2 + TCP_CHALLENGE_LENGTH + CLIENT_INFO_LENGTH + 1 => {
+                let (payload, client) = payload.split_at(2 + TCP_CHALLENGE_LENGTH);
+                if client[CLIENT_INFO_LENGTH] != Port::new_udp(0).to_bytes()[2] {
+                    return Err(anyhow!("Malformed new connection protocol"));
+                }
+                Ok(ControlMessage::NewConnection {
+                    port: Port::new_udp(u16::from_be_bytes(payload[0..2].try_into().unwrap())),
+                    challenge: payload[2..].try_into().unwrap(),
+                    client: ClientInfo::read(client[..CLIENT_INFO_LENGTH].try_into().unwrap())
+                })
+            },
+            Some((&ERROR, payload)) if !payload.is_empty() => Ok(ControlMessage::Error {
+                code: ErrorCode::from_byte(payload[0]),
+                message: String::from_utf8_lossy(&payload[1..]).into_owned()
+            }),
+            Some((&REGISTER, mut payload)) => {
+                let count = u16::from_be_bytes(take(&mut payload, 2)?.try_into().unwrap());
+                let mut registrations = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    registrations.push(Registration::read(&mut payload)?);
+                }
+                // Older servers send no flags, nor session
+                let flags = take(&mut payload, 1).map_or(0, |x| x[0]);
+                Ok(ControlMessage::Register {
+                    registrations,
+                    bind_status: flags & REGISTER_BIND_STATUS != 0,
+                    rekey: flags & REGISTER_REKEY != 0,
+                    multiplex: flags & REGISTER_MULTIPLEX != 0,
+                    session: take(&mut payload, 2).ok().map(|x| u16::from_be_bytes(x.try_into().unwrap()))
+                })
+            }
+            Some((&ADD_PORT, mut payload)) => Ok(ControlMessage::AddPort(Registration::read(&mut payload)?)),
+            Some((&REMOVE_PORT, payload)) if payload.len() == 3 => Ok(ControlMessage::RemovePort(Port::try_from_bytes(payload.try_into().unwrap())?)),
+            Some((&UPDATE_PORT, mut payload)) => Ok(ControlMessage::UpdatePort(Registration::read(&mut payload)?)),
+            Some((&PROBE, [])) => Ok(ControlMessage::Probe),
+            Some((&INTEGRITY, payload)) if payload.len() == 8 + PIPE_DIGESTS_LENGTH => Ok(ControlMessage::Integrity {
+                id: u64::from_be_bytes(payload[..8].try_into().unwrap()),
+                digests: PipeDigests::from_bytes(payload[8..].try_into().unwrap())
+            }),
+            Some((&PING, payload)) if payload.len() == 5 && payload[4] > 0 => Ok(ControlMessage::Ping {
+                interval: Duration::from_millis(u32::from_be_bytes(payload[..4].try_into().unwrap()) as u64).max(Duration::from_millis(1)),
+                misses: payload[4]
+            }),
+            Some((&PONG, [])) => Ok(ControlMessage::Pong),
+            Some((&BIND_STATUS, mut payload)) => {
+                let count = u16::from_be_bytes(take(&mut payload, 2)?.try_into().unwrap());
+                let mut statuses = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let entry = take(&mut payload, 4)?;
+                    statuses.push((Port::try_from_bytes(entry[..3].try_into().unwrap())?, BindError::from_byte(entry[3])));
+                }
+                // Older gateways send no flags
+                let flags = take(&mut payload, 1).map_or(0, |x| x[0]);
+                Ok(ControlMessage::BindStatus {
+                    statuses,
+                    rekey: flags & BIND_STATUS_REKEY != 0,
+                    multiplex: flags & BIND_STATUS_MULTIPLEX != 0,
+                    refused: flags & BIND_STATUS_REFUSED != 0
+                })
+            }
+            Some((&REFUSED, payload)) if payload.len() >= TCP_CHALLENGE_LENGTH => Ok(ControlMessage::Refused {
+                token: payload[..TCP_CHALLENGE_LENGTH].try_into().unwrap(),
+                reason: String::from_utf8_lossy(&payload[TCP_CHALLENGE_LENGTH..]).into_owned()
+            }),
+            Some((&STREAM, mut payload)) => {
+                let id = u32::from_be_bytes(take(&mut payload, 4)?.try_into().unwrap());
+                let frame = match take(&mut payload, 1)?[0] {
+                    // The connection number is optional, as with NEW_CONNECTION
+                    STREAM_OPEN if payload.len() == 3 + TCP_CHALLENGE_LENGTH + CLIENT_INFO_LENGTH || payload.len() == 3 + TCP_CHALLENGE_LENGTH + CLIENT_INFO_LENGTH + 4 => {
+                        let (client, conn) = payload[3 + TCP_CHALLENGE_LENGTH..].split_at(CLIENT_INFO_LENGTH);
+                        StreamFrame::Open {
+                            port: Port::try_from_bytes(payload[..3].try_into().unwrap()).context("Malformed stream protocol")?,
+                            token: payload[3..3 + TCP_CHALLENGE_LENGTH].try_into().unwrap(),
+                            client: ClientInfo { conn: conn.try_into().ok().map(u32::from_be_bytes), ..ClientInfo::read(client.try_into().unwrap()) }
+                        }
+                    },
+                    STREAM_DATA if !payload.is_empty() => StreamFrame::Data(payload.to_vec()),
+                    STREAM_END if payload.is_empty() => StreamFrame::End,
+                    STREAM_RESET if payload.is_empty() => StreamFrame::Reset,
+                    STREAM_WINDOW if payload.len() == 4 => StreamFrame::Window(u32::from_be_bytes(payload.try_into().unwrap())),
+                    x => return Err(anyhow!("Malformed stream frame of type {x}"))
+                };
+                Ok(ControlMessage::Stream { id, frame })
+            }
+            Some((x, _)) => Err(anyhow!("Malformed control message of type {x}")),
+            None => Err(anyhow!("Empty control message"))
+        }
+    }
+}
+
+/// Once the cipher needs it, the message is followed by a new key, sent under the current one, which the other side
+/// swaps to as well when reading it
+pub fn write_message<W: Write>(stream: &mut W, cipher: &mut Cipher, msg: &ControlMessage) -> Result<()> {
+    write_frame(stream, cipher, &msg.to_bytes()?)?;
+    if cipher.needs_rekey() {
+        let material = Cipher::new_key_material();
+        let mut rekey = vec![REKEY];
+        rekey.extend_from_slice(&material);
+        write_frame(stream, cipher, &rekey).context("Failed to send the new key of the control channel")?;
+        cipher.rekey(&material)?;
+        debug!("Sent a new key for the control channel");
+    }
+    Ok(())
+}
+
+fn write_frame<W: Write>(stream: &mut W, cipher: &mut Cipher, msg: &[u8]) -> Result<()> {
+    let length : u16 = (msg.len()+AEAD_LENGTH).try_into().context("Control message too long")?;
+    stream.write_all(&cipher.encrypt(&length.to_be_bytes()).context("Failed to encrypt control message length")?).context("Failed to write control message length")?;
+    stream.write_all(&cipher.encrypt(msg).context("Failed to encrypt control message")?).context("Failed to write control message")?;
+    stream.flush().context("Failed to flush control message")?;
+    Ok(())
+}
+
+/// Sending half of a control channel, for when several threads need to send messages
+pub struct ControlSender {
+    stream: TcpStream,
+    cipher: Cipher
+}
+
+impl ControlSender {
+    pub fn new(stream: TcpStream, cipher: Cipher) -> ControlSender {
+        ControlSender { stream, cipher }
+    }
+
+    pub fn send(&mut self, msg: &ControlMessage) -> Result<()> {
+        write_message(&mut self.stream, &mut self.cipher, msg)
+    }
+
+    /// Ends the connection, which unblocks its reader too
+    pub fn close(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    pub fn set_rekey_after(&mut self, operations: Option<u64>) {
+        self.cipher.set_rekey_after(operations);
+    }
+}
+
+pub fn read_message<R: Read>(stream: &mut R, cipher: &mut Cipher) -> Result<ControlMessage> {
+    try_read_message(stream, cipher)?.context("Control connection closed")
+}
+
+/// Whether reading a message failed because the read timeout of the stream expired
+pub fn is_timeout(err: &anyhow::Error) -> bool {
+    err.chain().any(|x| x.downcast_ref::<std::io::Error>().is_some_and(|x| matches!(x.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)))
+}
+
+/// Like `read_message`, but a connection closed between two messages gives `None`. New keys are swapped to on the way
+pub fn try_read_message<R: Read>(stream: &mut R, cipher: &mut Cipher) -> Result<Option<ControlMessage>> {
+    loop {
+        let Some(msg) = read_frame(stream, cipher)? else { return Ok(None) };
+        match msg.split_first() {
+            Some((&REKEY, material)) => {
+                cipher.rekey(material).context("Malformed control channel key")?;
+                debug!("Received a new key for the control channel");
+            }
+            _ => return ControlMessage::from_bytes(&msg).map(Some)
+        }
+    }
+}
+
+fn read_frame<R: Read>(stream: &mut R, cipher: &mut Cipher) -> Result<Option<Vec<u8>>> {
+    let mut length = [0u8; LENGTH_SIZE + AEAD_LENGTH];
+    let first = loop {
+        match stream.read(&mut length) {
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            x => break x.context("Failed to read control message length")?
+        }
+    };
+    if first == 0 {
+        return Ok(None);
+    }
+    stream.read_exact(&mut length[first..]).context("Failed to read control message length")?;
+    let length = cipher.decrypt(&length).context("Failed to decrypt control message length")?;
+    let length = u16::from_be_bytes(length[..].try_into().context("Malformed control message length")?);
+    let mut msg = vec![0u8; length as usize];
+    stream.read_exact(&mut msg).context("Failed to read control message")?;
+    cipher.decrypt(&msg).context("Failed to decrypt control message").map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cidr::Cidr;
+
+    const CLIENT : ClientInfo = ClientInfo { addr: None, requested_at: 1_700_000_000_000, conn: Some(7) };
+
+    /* With every option, so that each of them gets truncated too */
+    fn registration(port: Port) -> Registration {
+        Registration {
+            port,
+            schedule: Some(Schedule::parse("08:00-18:00", Some("mon-fri"), Some("+02:00")).unwrap()),
+            hide_client: true,
+            access: AccessRules { allow: vec![Cidr::parse("10.0.0.0/8").unwrap()], deny: vec![Cidr::parse("10.1.0.0/16").unwrap()] },
+            first_byte_timeout: Some(Duration::from_secs(5)),
+            maintenance: Some(Maintenance::Http { body: "Back soon".to_string(), retry_after: Some(60) }),
+            paused: true,
+            verify_integrity: true,
+            encrypt: true,
+            name: Some("web".to_string()),
+            pipe_buffer: Some(65536),
+            nodelay: false
+        }
+    }
+
+    /* A message of every type and frame, and how many of its first bytes it can't go without: the fields after them
+       are the optional ones older versions don't send */
+    fn samples() -> Vec<(&'static str, ControlMessage, usize)> {
+        let stream = |frame| ControlMessage::Stream { id: 3, frame };
+        let token = [9u8; TCP_CHALLENGE_LENGTH];
+        let messages = vec![
+            ("new connection", ControlMessage::NewConnection { port: Port::new_tcp(443), challenge: token, client: ClientInfo { conn: None, ..CLIENT } }, CLIENT_INFO_LENGTH),
+            ("new connection with its number", ControlMessage::NewConnection { port: Port::new_tcp(443), challenge: token, client: CLIENT }, CLIENT_INFO_LENGTH + 1 + 4),
+            ("new UDP connection", ControlMessage::NewConnection { port: Port::new_udp(53), challenge: token, client: ClientInfo { conn: None, ..CLIENT } }, CLIENT_INFO_LENGTH + 1),
+            ("error", ControlMessage::Error { code: ErrorCode::QuotaExhausted, message: "quota".to_string() }, "quota".len()),
+            ("register", ControlMessage::Register { registrations: vec![registration(Port::new_tcp(80)), registration(Port::new_udp(53))],
+                bind_status: true, rekey: true, multiplex: true, session: Some(0xbeef) }, 3),
+            ("add port", ControlMessage::AddPort(registration(Port::new_tcp(8080))), 0),
+            ("remove port", ControlMessage::RemovePort(Port::new_udp(53)), 0),
+            ("update port", ControlMessage::UpdatePort(registration(Port::new_tcp(8080))), 0),
+            ("probe", ControlMessage::Probe, 0),
+            ("integrity", ControlMessage::Integrity { id: 42, digests: PipeDigests::from_bytes(&[5u8; PIPE_DIGESTS_LENGTH]) }, 0),
+            ("ping", ControlMessage::Ping { interval: Duration::from_secs(10), misses: 3 }, 0),
+            ("pong", ControlMessage::Pong, 0),
+            ("bind status", ControlMessage::BindStatus { statuses: vec![(Port::new_tcp(80), None), (Port::new_udp(53), Some(BindError::InUse))],
+                rekey: true, multiplex: true, refused: true }, 1),
+            ("refused", ControlMessage::Refused { token, reason: "connection refused".to_string() }, "connection refused".len()),
+            ("stream open", stream(StreamFrame::Open { port: Port::new_tcp(443), token, client: CLIENT }), 4),
+            ("stream data", stream(StreamFrame::Data(vec![1, 2, 3])), 2),
+            ("stream end", stream(StreamFrame::End), 0),
+            ("stream reset", stream(StreamFrame::Reset), 0),
+            ("stream window", stream(StreamFrame::Window(65536)), 0)
+        ];
+        messages.into_iter().map(|(name, msg, optional)| {
+            let length = msg.to_bytes().unwrap().len();
+            (name, msg, length - optional)
+        }).collect()
+    }
+
+    /* The bytes of a message with its port's protocol set to `protocol` */
+    fn with_protocol(msg: &ControlMessage, offset: usize, protocol: u8) -> Vec<u8> {
+        let mut bytes = msg.to_bytes().unwrap();
+        bytes[offset] = protocol;
+        bytes
+    }
+
+    #[test]
+    fn samples_round_trip() {
+        for (name, msg, _) in samples() {
+            let bytes = msg.to_bytes().unwrap();
+            let decoded = ControlMessage::from_bytes(&bytes).unwrap_or_else(|err| panic!("{name}: {err:#}"));
+            assert_eq!(decoded.to_bytes().unwrap(), bytes, "{name}");
+        }
+    }
+
+    #[test]
+    fn truncated_messages_are_refused() {
+        for (name, msg, mandatory) in samples() {
+            let bytes = msg.to_bytes().unwrap();
+            for length in 0..mandatory {
+                assert!(ControlMessage::from_bytes(&bytes[..length]).is_err(), "{name} truncated to {length} bytes");
+            }
+            // Without some of the optional fields, it's still a message, if not the same one
+            for length in mandatory..bytes.len() {
+                let _ = ControlMessage::from_bytes(&bytes[..length]);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_protocols_are_refused() {
+        let port_offsets = [
+            ("new connection with its number", 1 + 2 + TCP_CHALLENGE_LENGTH + CLIENT_INFO_LENGTH),
+            ("register", 1 + 2 + 2),
+            ("add port", 1 + 2),
+            ("remove port", 1 + 2),
+            ("bind status", 1 + 2 + 2),
+            ("update port", 1 + 2),
+            ("stream open", 1 + 4 + 1 + 2)
+        ];
+        let samples = samples();
+        for (name, offset) in port_offsets {
+            let (_, msg, _) = samples.iter().find(|(x, _, _)| *x == name).unwrap();
+            assert!(ControlMessage::from_bytes(&with_protocol(msg, offset, 1)).is_ok(), "{name} at {offset} is its port's protocol");
+            for protocol in [2, 0x80, 0xff] {
+                assert!(ControlMessage::from_bytes(&with_protocol(msg, offset, protocol)).is_err(), "{name} with the protocol {protocol}");
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_types_are_refused() {
+        for kind in [REKEY, 14, 0xff] {
+            assert!(ControlMessage::from_bytes(&[kind, 0, 0, 0]).is_err(), "type {kind}");
+        }
+        assert!(ControlMessage::from_bytes(&[STREAM, 0, 0, 0, 3, 0xff]).is_err());
+    }
+
+    #[test]
+    fn hundreds_of_ports_are_registered_at_once() {
+        let registrations : Vec<Registration> = (0..300u16).map(|i| {
+            let port = match i % 2 {
+                0 => Port::new_tcp(10000 + i),
+                _ => Port::new_udp(10000 + i)
+            };
+            Registration { name: Some(format!("port-{i}")), ..registration(port) }
+        }).collect();
+        let msg = ControlMessage::Register { registrations: registrations.clone(), bind_status: true, rekey: true, multiplex: true, session: Some(1) };
+        let bytes = msg.to_bytes().unwrap();
+        assert!(msg.encoded_length().unwrap() <= MAX_MESSAGE_LENGTH, "{} bytes", bytes.len());
+        let decoded = ControlMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes().unwrap(), bytes);
+        match decoded {
+            ControlMessage::Register { registrations: decoded, session, .. } => {
+                assert_eq!(session, Some(1));
+                assert_eq!(decoded.len(), registrations.len());
+                for (decoded, registration) in decoded.iter().zip(&registrations) {
+                    assert!(decoded.port == registration.port, "port {} came back as {}", registration.port.port, decoded.port.port);
+                    assert_eq!(decoded.name, registration.name);
+                }
+            }
+            _ => panic!("not a registration")
+        }
+    }
+}
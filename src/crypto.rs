@@ -10,10 +10,15 @@ If not, see <https://www.gnu.org/licenses/>.
 */
 
 use anyhow::{anyhow, Result, Context};
-use aes_gcm::{aead::Aead, KeyInit, Aes256Gcm};
+use crate::error::Failure;
+use crate::debug;
+use crate::common::MAGIC1_LENGTH;
+use crate::connector::Stream;
+use aes_gcm::{aead::{Aead, Payload}, KeyInit, Aes256Gcm};
 use rand::{RngCore, rngs::OsRng};
-use std::net::TcpStream;
-use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
 
 
 pub const AEAD_LENGTH : usize = 16;
@@ -21,6 +26,8 @@ pub const NONCE_LENGTH : usize = 12;
 
 pub const KEY_LENGTH : usize = 32;
 pub const ENCRYPTED_CHALLENGE_LENGTH : usize = KEY_LENGTH + NONCE_LENGTH + AEAD_LENGTH; 
+/// A new key and the nonce it starts from, see `Cipher::rekey`
+pub const KEY_MATERIAL_LENGTH : usize = KEY_LENGTH + NONCE_LENGTH;
 
 pub type Key = [u8; KEY_LENGTH];
 type Nonce = [u8; NONCE_LENGTH];
@@ -31,22 +38,74 @@ pub fn random_key() -> Key {
     key
 }
 
+/// Short identifier of a key, to check that both sides have the same one without showing it.
+/// It's the tag of an empty message under an all zero nonce, which sessions never use
+/// (their nonces come from the challenge)
+pub fn fingerprint(key: &Key) -> String {
+    let tag = Aes256Gcm::new(key.into()).encrypt(&[0u8; NONCE_LENGTH].into(), &[][..]).expect("an empty message always fits");
+    tag[..8].chunks(2).map(|x| format!("{:02x}{:02x}", x[0], x[1])).collect::<Vec<_>>().join(":")
+}
+
+
+/// The streams of messages of a session are encrypted independently, each in its own part
+/// of the nonce space (the last byte of the nonce), so that they never reuse a nonce
+#[derive(Copy, Clone)]
+pub enum Channel {
+    ToServer = 0,
+    ToGateway = 0x80,
+    /// Challenges sent by the server on new data connections
+    DataChallenge = 0x40
+}
 
+#[derive(Clone)]
 pub struct Cipher {
     cipher: Aes256Gcm,
-    nonce: Nonce
+    nonce: Nonce,
+    /// Encryptions and decryptions since the key was set
+    operations: u64,
+    /// How many of them the key is used for before this side sends a new one, if it does
+    rekey_after: Option<u64>
 }
 
 impl Cipher {
 
     fn new(cipher: Aes256Gcm, nonce: Nonce) -> Cipher {
         let mut ret = Cipher {
-            cipher, nonce
+            cipher, nonce, operations: 0, rekey_after: None
         };
         ret.increase_nonce();
         ret
     }
 
+    pub fn set_rekey_after(&mut self, operations: Option<u64>) {
+        self.rekey_after = operations;
+    }
+
+    /// Whether the key was used for `rekey_after` operations, and should be replaced
+    pub fn needs_rekey(&self) -> bool {
+        self.rekey_after.is_some_and(|x| self.operations >= x)
+    }
+
+    /// A key and nonce for `rekey`, for the other side to swap to as well
+    pub fn new_key_material() -> [u8; KEY_MATERIAL_LENGTH] {
+        let mut material = [0u8; KEY_MATERIAL_LENGTH];
+        OsRng.fill_bytes(&mut material);
+        material
+    }
+
+    /// Continue with the key and nonce of `material`, the one that was sent or received under the current key
+    pub fn rekey(&mut self, material: &[u8]) -> Result<()> {
+        let (key, nonce) = key_and_nonce(material)?;
+        *self = Cipher { rekey_after: self.rekey_after, ..Cipher::new(Aes256Gcm::new(&key.into()), nonce) };
+        Ok(())
+    }
+
+    pub fn channel(&self, channel: Channel) -> Cipher {
+        let mut ret = self.clone();
+        ret.nonce[NONCE_LENGTH-1] ^= channel as u8;
+        ret
+    }
+
     fn increase_nonce(&mut self) {
         for i in 0..NONCE_LENGTH {
             if self.nonce[i] < u8::MAX {
@@ -58,29 +117,235 @@ impl Cipher {
         }
     }
 
-    pub fn encrypt(&mut self, buf: &[u8]) -> Vec<u8> {
-        let ret = self.cipher.encrypt(&self.nonce.into(), buf).unwrap();
+    pub fn encrypt(&mut self, buf: &[u8]) -> Result<Vec<u8>> {
+        let ret = self.cipher.encrypt(&self.nonce.into(), buf).map_err(|e| anyhow!("Unencryptable packet of {} bytes: {e:?}", buf.len()))?;
         self.increase_nonce();
-        ret
+        self.operations += 1;
+        Ok(ret)
     }
 
     pub fn decrypt(&mut self, buf: &[u8]) -> Result<Vec<u8>> {
         match self.cipher.decrypt(&self.nonce.into(), buf) {
             Ok(buf) => {
                 self.increase_nonce(); //We only increase the nonce when the decryption suceeds
+                self.operations += 1;
                 Ok(buf)
             }
             Err(e) => Err(anyhow!("Undecryptable packet: {e:?}"))
         }
     }
+
+    /// Seal a random single use token under a nonce taken from the token itself: unlike `encrypt`,
+    /// it doesn't depend on the order of the messages, so that a lost one doesn't break the next ones
+    pub fn seal_token(&self, token: &[u8]) -> Result<Vec<u8>> {
+        let nonce = token_nonce(token)?;
+        self.cipher.encrypt(&nonce.into(), token).map_err(|e| anyhow!("Failed to seal the token: {e:?}"))
+    }
+
+    /// A cipher under a key of its own, derived from the session key under `label` and `counter`: both sides
+    /// derive the same one, and it shares no nonce with the session or the other subkeys. Whichever channel of the
+    /// session it is derived from
+    pub fn derive_subkey(&self, label: &SubkeyLabel, counter: u64) -> Result<Cipher> {
+        let mut nonce = [0u8; NONCE_LENGTH];
+        nonce[..SUBKEY_LABEL_LENGTH].copy_from_slice(label);
+        nonce[SUBKEY_LABEL_LENGTH..].copy_from_slice(&counter.to_be_bytes());
+        Ok(Cipher::new(derive_key(&self.cipher, &nonce)?, [0; NONCE_LENGTH]))
+    }
+
+    /// The ciphers of both directions of a data connection with `encrypt`: towards the server, and towards
+    /// the gateway. They're a subkey of the session, whose counter is taken from the connection's token: the
+    /// gateway already sends it in NewConnection, and it's random, so that no two connections share one
+    pub fn connection_ciphers(&self, token: &[u8]) -> Result<(Cipher, Cipher)> {
+        let counter = token.get(..8).with_context(|| format!("Token of {} bytes, too short for a counter", token.len()))?;
+        let cipher = self.derive_subkey(CONNECTION_LABEL, u64::from_be_bytes(counter.try_into()?))?;
+        Ok((cipher.channel(Channel::ToServer), cipher.channel(Channel::ToGateway)))
+    }
+}
+
+/* Tokens start with their nonce */
+fn token_nonce(token: &[u8]) -> Result<Nonce> {
+    let nonce = token.get(..NONCE_LENGTH).with_context(|| format!("Token of {} bytes, too short for a nonce", token.len()))?;
+    Ok(nonce.try_into()?)
+}
+
+/* A key followed by the nonce it starts from */
+fn key_and_nonce(material: &[u8]) -> Result<(Key, Nonce)> {
+    if material.len() != KEY_MATERIAL_LENGTH {
+        return Err(anyhow!("Key material of {} bytes, expected {KEY_MATERIAL_LENGTH}", material.len()));
+    }
+    let (key, nonce) = material.split_at(KEY_LENGTH);
+    Ok((key.try_into()?, nonce.try_into()?))
+}
+
+/* A new key, from the keystream of `cipher` under `nonce` */
+fn derive_key(cipher: &Aes256Gcm, nonce: &Nonce) -> Result<Aes256Gcm> {
+    let key = cipher.encrypt(nonce.into(), &[0u8; KEY_LENGTH][..]).map_err(|e| anyhow!("Failed to derive a key: {e:?}"))?;
+    Aes256Gcm::new_from_slice(&key[..KEY_LENGTH]).map_err(|e| anyhow!("Failed to derive a key: {e:?}"))
+}
+
+/* Data connections with `encrypt` are sent as records of at most RECORD_SIZE bytes:
+   `[length of the encrypted record: u16 BE][encrypted record]`. An empty record marks the end of the
+   stream, so that cutting the connection between two records can't pass for its end */
+const RECORD_SIZE : usize = 16 * 1024;
+
+/// Encrypts what is written to the tunnel side of a pipe
+pub struct SealedWriter {
+    stream: TcpStream,
+    cipher: Arc<Mutex<Cipher>>
+}
+
+impl SealedWriter {
+    pub fn new(stream: TcpStream, cipher: Cipher) -> SealedWriter {
+        SealedWriter { stream, cipher: Arc::new(Mutex::new(cipher)) }
+    }
+
+    fn write_record(&self, buf: &[u8]) -> io::Result<()> {
+        let sealed = self.cipher.lock().unwrap().encrypt(buf).map_err(|err| io::Error::other(format!("{err:#}")))?;
+        let mut record = Vec::with_capacity(2 + sealed.len());
+        record.extend_from_slice(&(sealed.len() as u16).to_be_bytes());
+        record.extend_from_slice(&sealed);
+        (&self.stream).write_all(&record)
+    }
+}
+
+impl Write for SealedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(RECORD_SIZE);
+        if len > 0 {
+            self.write_record(&buf[..len])?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Read for SealedWriter {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "SealedWriter is only written to"))
+    }
+}
+
+impl Stream for SealedWriter {
+    fn try_clone_stream(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(SealedWriter { stream: self.stream.try_clone()?, cipher: self.cipher.clone() }))
+    }
+
+    fn shutdown_stream(&self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Both)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.write_record(&[])?;
+        self.stream.shutdown(Shutdown::Write)
+    }
+
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        Some(&self.stream)
+    }
+}
+
+/// Decrypts what is read from the tunnel side of a pipe
+pub struct SealedReader {
+    stream: TcpStream,
+    cipher: Cipher,
+    record: Vec<u8>,
+    plain: Vec<u8>,
+    pos: usize,
+    finished: bool
+}
+
+impl SealedReader {
+    pub fn new(stream: TcpStream, cipher: Cipher) -> SealedReader {
+        SealedReader { stream, cipher, record: Vec::new(), plain: Vec::new(), pos: 0, finished: false }
+    }
+
+    /* false if the stream ended before the first byte */
+    fn read_header(&mut self, header: &mut [u8; 2]) -> io::Result<bool> {
+        let mut read = 0;
+        while read < header.len() {
+            match self.stream.read(&mut header[read..]) {
+                Ok(0) if read == 0 => return Ok(false),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(len) => read += len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Read for SealedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            let mut header = [0u8; 2];
+            if !self.read_header(&mut header)? {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The encrypted stream was cut before its end"));
+            }
+            let len = u16::from_be_bytes(header) as usize;
+            if !(AEAD_LENGTH..=RECORD_SIZE + AEAD_LENGTH).contains(&len) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid encrypted record length {len}")));
+            }
+            self.record.resize(len, 0);
+            self.stream.read_exact(&mut self.record)?;
+            self.plain = self.cipher.decrypt(&self.record).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{err:#}")))?;
+            self.pos = 0;
+            self.finished = self.plain.is_empty();
+        }
+        let len = buf.len().min(self.plain.len() - self.pos);
+        buf[..len].copy_from_slice(&self.plain[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
 }
     
 
 pub const MAGIC2_LENGTH : usize = 32;
-pub const MAGIC2: &[u8; MAGIC2_LENGTH] = &[
-    198, 158, 252, 226, 190, 135, 45, 91, 254, 58, 121, 222, 55, 121, 188,
-    144, 42, 174, 18, 202, 227, 208, 46, 22, 31, 81, 62, 77, 45, 14, 42, 98
-];
+pub const MAGIC3_LENGTH : usize = 32;
+
+/* Nonces of the keystreams the magics are taken from: the handshakes only use random nonces, the fingerprint zeros */
+const MAGIC1_LABEL : &Nonce = b"smugglrs:mg1";
+const MAGIC2_LABEL : &Nonce = b"smugglrs:mg2";
+const MAGIC3_LABEL : &Nonce = b"smugglrs:mg3";
+/// Nonce the key of the gateway's confirmation is derived from the session key with, which the session's own random
+/// nonces don't come near
+const CONFIRMATION_LABEL : &Nonce = b"smugglrs:cfm";
+
+/// Start of the nonces the subkeys of `Cipher::derive_subkey` are derived under, followed by their counter. They
+/// don't start like the labels above, so that no counter brings a subkey to them
+pub type SubkeyLabel = [u8; SUBKEY_LABEL_LENGTH];
+const SUBKEY_LABEL_LENGTH : usize = NONCE_LENGTH - 8;
+/// Subkeys of the data connections with `encrypt`, see `Cipher::connection_ciphers`
+const CONNECTION_LABEL : &SubkeyLabel = b"cnx:";
+
+/// The magics of a key, so that only its holders can even elicit a challenge from the gateway
+#[derive(Copy, Clone)]
+pub struct Magics {
+    /// Sent by the server first, followed by its nonce
+    pub magic1: [u8; MAGIC1_LENGTH],
+    /// Sent by the server under the session key, once it solved the challenge
+    pub magic2: [u8; MAGIC2_LENGTH],
+    /// Sent back by the gateway under the confirmation key, once it checked MAGIC2
+    pub magic3: [u8; MAGIC3_LENGTH]
+}
+
+impl Magics {
+    pub fn derive(key: &Key) -> Magics {
+        let cipher = Aes256Gcm::new(key.into());
+        let keystream = |label: &Nonce| cipher.encrypt(label.into(), &[0u8; MAGIC2_LENGTH][..]).expect("the magics always fit");
+        Magics {
+            magic1: keystream(MAGIC1_LABEL)[..MAGIC1_LENGTH].try_into().unwrap(),
+            magic2: keystream(MAGIC2_LABEL)[..MAGIC2_LENGTH].try_into().unwrap(),
+            magic3: keystream(MAGIC3_LABEL)[..MAGIC3_LENGTH].try_into().unwrap()
+        }
+    }
+}
 
 // Not critical; the attacker shouldn't be able
 // To control MAGIC2, but it will make MAGIC1 way stronger 
@@ -98,58 +363,230 @@ pub fn constant_eq(x: &[u8], y: &[u8]) -> bool {
     test_bit == 0u8
 }
 
-pub fn challenge(key: &Key, stream: &mut TcpStream) -> Result<Cipher> {
+/* The handshake, once the server sent the MAGIC1 of its key and a random nonce of its own:
+   - gateway -> server: init_nonce, the number of keys the gateway accepts, then for each of them a control key and
+     nonce, under that key and init_nonce
+   - server -> gateway: the MAGIC2 of its key, under the session key and the control nonce of the one its key opened
+   - gateway -> server: the MAGIC3 of the key, under a key derived from the session key and the control nonce, so
+     that the server knows the gateway has the key too and not only relays a challenge it can't check
+   Every message authenticates everything exchanged before it, and the session key is derived from the control key
+   and the server's nonce, so that neither side can be replayed a recorded handshake.
+   The gateway sets CHALLENGE_CONFIRMED in the number of keys when it sends the last message, older gateways don't */
+#[derive(Clone)]
+struct Transcript(Vec<u8>);
+
+impl Transcript {
+    fn push(&mut self, buf: &[u8]) {
+        self.0.extend_from_slice(buf);
+    }
+
+    fn aad(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Keys a gateway may offer in a challenge
+pub const MAX_CHALLENGE_KEYS : usize = 4;
+/// In the number of keys of a challenge: the gateway confirms that it has the key once it checked MAGIC2
+const CHALLENGE_CONFIRMED : u8 = 0x80;
+
+fn session_key(control_key: &Key, server_nonce: &Nonce) -> Result<Aes256Gcm> {
+    derive_key(&Aes256Gcm::new(control_key.into()), server_nonce)
+}
+
+/// What the server sends first
+pub struct Hello {
+    nonce: Nonce
+}
+
+pub fn send_hello(magics: &Magics, stream: &mut impl Write) -> Result<Hello> {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+    let mut hello = magics.magic1.to_vec();
+    hello.extend_from_slice(&nonce);
+    stream.write_all(&hello).context("Failed to write MAGIC1")?;
+    stream.flush().context("Failed to flush MAGIC1")?;
+    Ok(Hello { nonce })
+}
+
+/// Once the server sent `magic1`, that of one of the keys. Returns the session cipher, and which of the keys the
+/// server has
+pub fn challenge(keys: &[(Key, Magics)], magic1: &[u8; MAGIC1_LENGTH], stream: &mut (impl Read + Write)) -> Result<(Cipher, usize)> {
+    let keys = &keys[..keys.len().min(MAX_CHALLENGE_KEYS)];
+    let mut transcript = Transcript(magic1.to_vec());
+    let mut server_nonce = [0u8; NONCE_LENGTH];
+    stream.read_exact(&mut server_nonce).context("Failed to read the server's nonce")?;
+    transcript.push(&server_nonce);
     let mut init_nonce = [0; NONCE_LENGTH];
     OsRng.fill_bytes(&mut init_nonce);
+    let mut challenge = init_nonce.to_vec();
+    challenge.push(keys.len() as u8 | CHALLENGE_CONFIRMED);
+    transcript.push(&challenge);
 
-    let mut control_key_and_nonce = [0; KEY_LENGTH+NONCE_LENGTH];
-    OsRng.fill_bytes(&mut control_key_and_nonce);
-    let init_cipher = Aes256Gcm::new(key.try_into().context("Key format is invalid")?);
-    stream.write_all(&init_nonce).context("Failed to write init nonce")?;
-    
-    let encrypted_key_and_nonce = init_cipher.encrypt(&init_nonce.into(), control_key_and_nonce.as_ref()).unwrap();
-    stream.write_all(&encrypted_key_and_nonce).context("Failed to write encrypted key+nonce")?;
+    // Each key opens its own control key, which tells which one the server has
+    let mut controls = Vec::with_capacity(keys.len());
+    let mut sealed = Vec::new();
+    for (key, _) in keys {
+        let mut control_key_and_nonce = [0; KEY_LENGTH+NONCE_LENGTH];
+        OsRng.fill_bytes(&mut control_key_and_nonce);
+        let init_cipher = Aes256Gcm::new(key.into());
+        sealed.extend(init_cipher.encrypt(&init_nonce.into(), Payload { msg: &control_key_and_nonce, aad: transcript.aad() })
+            .map_err(|e| anyhow!("Failed to encrypt the challenge: {e:?}"))?);
+        let (control_key, control_nonce) = key_and_nonce(&control_key_and_nonce)?;
+        controls.push((session_key(&control_key, &server_nonce)?, control_nonce));
+    }
+    challenge.extend_from_slice(&sealed);
+    stream.write_all(&challenge).context("Failed to write the challenge")?;
     stream.flush().context("Failed to flush init nonce+encrypted(key+nonce)")?;
-    println!("Sent challenge, waiting for response...");
-
-    let control_key : Key = control_key_and_nonce[..KEY_LENGTH].try_into().unwrap();
-    let control_nonce : Nonce = control_key_and_nonce[KEY_LENGTH..].try_into().unwrap();
-    let control_cipher = Aes256Gcm::new(&control_key.into());
-    
+    transcript.push(&sealed);
+    debug!("Sent challenge, waiting for response...");
     
     let mut magic2_test = [0u8; MAGIC2_LENGTH+AEAD_LENGTH];
     stream.read_exact(&mut magic2_test).context("Failed to read encrypted MAGIC2")?;
     
-    if let Ok(magic2_test) = control_cipher.decrypt(&control_nonce.into(), magic2_test.as_ref()) {
-        if constant_eq(&magic2_test, MAGIC2) {
-            return Ok(Cipher::new(control_cipher, control_nonce));
+    for (i, ((control_cipher, control_nonce), (_, magics))) in controls.into_iter().zip(keys).enumerate() {
+        if let Ok(magic2) = control_cipher.decrypt(&control_nonce.into(), Payload { msg: &magic2_test, aad: transcript.aad() }) {
+            if constant_eq(&magic2, &magics.magic2) {
+                transcript.push(&magic2_test);
+                let confirmation = derive_key(&control_cipher, CONFIRMATION_LABEL)?
+                    .encrypt(&control_nonce.into(), Payload { msg: &magics.magic3[..], aad: transcript.aad() })
+                    .map_err(|e| anyhow!("Failed to encrypt magic3: {e:?}"))?;
+                stream.write_all(&confirmation).context("Failed to write encrypted MAGIC3")?;
+                stream.flush().context("Failed to flush encrypted MAGIC3")?;
+                return Ok((Cipher::new(control_cipher, control_nonce), i));
+            }
         }
     }
     Err(anyhow!("Challenge failed, decryption didn't complete properly"))
 }
 
-pub fn answer_challenge(key: &Key, stream: &mut TcpStream) -> Result<Cipher> {
-    let init_cipher = Aes256Gcm::new(key.try_into().context("Key format is invalid")?);
-    
+/// What the gateway sends first, see `challenge`
+pub struct ReceivedChallenge {
+    init_nonce: [u8; NONCE_LENGTH],
+    /// Whether the gateway confirms that it has the key, see `confirm_gateway`
+    confirmed: bool,
+    /// The encrypted control key and nonce, for each key of the gateway
+    sealed: Vec<[u8; ENCRYPTED_CHALLENGE_LENGTH]>
+}
+
+pub fn receive_challenge(stream: &mut impl Read) -> Result<ReceivedChallenge> {
     let mut init_nonce = [0u8; NONCE_LENGTH];
-    stream.read_exact(&mut init_nonce).context("Failed to read init nonce")?;
-    let mut encrypted_key_and_nonce = [0u8; ENCRYPTED_CHALLENGE_LENGTH];
-    stream.read_exact(&mut encrypted_key_and_nonce).context("Failed to read encrypted key + nonce")?;
-
-    println!("Received challenge; solving...");
-
-    match init_cipher.decrypt(&init_nonce.into(), encrypted_key_and_nonce.as_ref()) {
-        Ok(control_key_and_nonce) => {
-            let control_key : Key = control_key_and_nonce[..KEY_LENGTH].try_into().unwrap();
-            let control_nonce : Nonce = control_key_and_nonce[KEY_LENGTH..].try_into().unwrap();
-            let control_cipher = Aes256Gcm::new(&control_key.into());
-            let encrypted_magic2 = &control_cipher.encrypt(&control_nonce.into(), MAGIC2.as_ref()).unwrap();
+    match stream.read_exact(&mut init_nonce) {
+        // It didn't know the magic
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Err(err).context(
+            "The gateway closed the connection without a challenge: it has another key, expects another knock sequence, or is older than the server (the magics are derived from the key since then)"),
+        x => x.context("Failed to read init nonce")?
+    }
+    let mut count = [0u8; 1];
+    stream.read_exact(&mut count).context("Failed to read the number of keys of the challenge")?;
+    let confirmed = count[0] & CHALLENGE_CONFIRMED != 0;
+    let count = (count[0] & !CHALLENGE_CONFIRMED) as usize;
+    if !(1..=MAX_CHALLENGE_KEYS).contains(&count) {
+        return Err(anyhow!("The challenge has {count} keys"));
+    }
+    let mut sealed = vec![[0u8; ENCRYPTED_CHALLENGE_LENGTH]; count];
+    for x in &mut sealed {
+        stream.read_exact(x).context("Failed to read encrypted key + nonce")?;
+    }
+    Ok(ReceivedChallenge { init_nonce, confirmed, sealed })
+}
+
+/// The server once it solved the challenge, until the gateway confirmed that it has the key
+pub struct Solved {
+    cipher: Cipher,
+    /// The confirmation key, control nonce and transcript the gateway's MAGIC3 is checked with, None if the gateway
+    /// is too old to send it
+    confirmation: Option<(Aes256Gcm, Nonce, Transcript)>
+}
+
+pub fn solve_challenge(key: &Key, magics: &Magics, hello: &Hello, challenge: &ReceivedChallenge, stream: &mut impl Write) -> Result<Solved> {
+    let mut transcript = Transcript(magics.magic1.to_vec());
+    transcript.push(&hello.nonce);
+    transcript.push(&challenge.init_nonce);
+    transcript.push(&[challenge.sealed.len() as u8 | if challenge.confirmed { CHALLENGE_CONFIRMED } else { 0 }]);
+    let init_cipher = Aes256Gcm::new(key.into());
+    let opened = challenge.sealed.iter()
+        .find_map(|sealed| init_cipher.decrypt(&challenge.init_nonce.into(), Payload { msg: sealed, aad: transcript.aad() }).ok());
+    match opened {
+        Some(control_key_and_nonce) => {
+            for sealed in &challenge.sealed {
+                transcript.push(sealed);
+            }
+            let (control_key, control_nonce) = key_and_nonce(&control_key_and_nonce).context("Malformed challenge")?;
+            let control_cipher = session_key(&control_key, &hello.nonce)?;
+            let encrypted_magic2 = &control_cipher.encrypt(&control_nonce.into(), Payload { msg: &magics.magic2[..], aad: transcript.aad() })
+                .map_err(|e| anyhow!("Failed to encrypt magic2: {e:?}"))?;
             stream.write_all(encrypted_magic2).context("Failed to write encrypted magic2")?;
             stream.flush().context("Failed to flush encrypted magic2")?;
-            Ok(Cipher::new(control_cipher, control_nonce))
+            transcript.push(encrypted_magic2);
+            let confirmation = match challenge.confirmed {
+                true => Some((derive_key(&control_cipher, CONFIRMATION_LABEL)?, control_nonce, transcript)),
+                false => None
+            };
+            Ok(Solved { cipher: Cipher::new(control_cipher, control_nonce), confirmation })
         },
-        Err(err) => {
-            Err(anyhow!("Could not decrypt the server challenge : {err:?}"))
+        None => {
+            // A challenge recorded from another handshake fails the same way
+            Err(anyhow::Error::new(Failure::Authentication).context(format!("Could not decrypt the server challenge : none of its {} keys is ours", challenge.sealed.len())))
         }
     }
 }
+
+/// Checks the gateway's MAGIC3. Returns the session cipher, and whether the gateway proved that it has the key: older
+/// gateways don't
+pub fn confirm_gateway(magics: &Magics, solved: Solved, stream: &mut impl Read) -> Result<(Cipher, bool)> {
+    let Some((confirmation_cipher, control_nonce, transcript)) = solved.confirmation else {
+        return Ok((solved.cipher, false));
+    };
+    let mut magic3_test = [0u8; MAGIC3_LENGTH+AEAD_LENGTH];
+    stream.read_exact(&mut magic3_test).context("Failed to read encrypted MAGIC3")?;
+    match confirmation_cipher.decrypt(&control_nonce.into(), Payload { msg: &magic3_test, aad: transcript.aad() }) {
+        Ok(magic3) if constant_eq(&magic3, &magics.magic3) => Ok((solved.cipher, true)),
+        _ => Err(anyhow::Error::new(Failure::Authentication).context("The gateway sent the challenge, but failed to prove that it has the key"))
+    }
+}
+
+pub fn answer_challenge(key: &Key, magics: &Magics, hello: &Hello, stream: &mut (impl Read + Write)) -> Result<(Cipher, bool)> {
+    let challenge = receive_challenge(stream)?;
+    debug!("Received challenge; solving...");
+    let solved = solve_challenge(key, magics, hello, &challenge, stream)?;
+    debug!("Sent MAGIC2; waiting for the gateway's confirmation...");
+    confirm_gateway(magics, solved, stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Cipher {
+        Cipher::new(Aes256Gcm::new(&random_key().into()), [0; NONCE_LENGTH])
+    }
+
+    #[test]
+    fn subkeys_depend_on_their_label_and_counter() {
+        let session = session();
+        let sealed = session.derive_subkey(CONNECTION_LABEL, 7).unwrap().encrypt(b"data").unwrap();
+        assert_eq!(session.channel(Channel::ToGateway).derive_subkey(CONNECTION_LABEL, 7).unwrap().decrypt(&sealed).unwrap(), b"data",
+            "the same label and counter should give the same subkey, on any channel of the session");
+        assert!(session.derive_subkey(CONNECTION_LABEL, 8).unwrap().decrypt(&sealed).is_err(), "another counter should give another subkey");
+        assert!(session.derive_subkey(b"tst:", 7).unwrap().decrypt(&sealed).is_err(), "another label should give another subkey");
+        assert!(session.clone().decrypt(&sealed).is_err(), "the subkey shouldn't be the session key");
+        assert!(Cipher::new(derive_key(&session.cipher, CONFIRMATION_LABEL).unwrap(), [0; NONCE_LENGTH]).decrypt(&sealed).is_err(),
+            "the subkey shouldn't be the confirmation key");
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_connection_ciphers() {
+        let session = session();
+        let token = [0x5au8; 32];
+        let (mut to_server, mut to_gateway) = session.connection_ciphers(&token).unwrap();
+        let (mut server_side, mut gateway_side) = session.clone().connection_ciphers(&token).unwrap();
+        assert_eq!(server_side.decrypt(&to_server.encrypt(b"request").unwrap()).unwrap(), b"request", "the server should read what is sent to it");
+        assert_eq!(gateway_side.decrypt(&to_gateway.encrypt(b"response").unwrap()).unwrap(), b"response", "the gateway should read what is sent to it");
+        let (mut to_server, mut to_gateway) = session.connection_ciphers(&token).unwrap();
+        assert!(to_gateway.decrypt(&to_server.encrypt(b"request").unwrap()).is_err(), "the directions shouldn't share nonces");
+        let (mut other, _) = session.connection_ciphers(&[0xa5u8; 32]).unwrap();
+        assert!(other.decrypt(&to_server.encrypt(b"request").unwrap()).is_err(), "another token should give other keys");
+        assert!(session.connection_ciphers(&[0u8; 4]).is_err(), "a token too short for a counter should be refused");
+    }
+}
@@ -0,0 +1,105 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! `daemon = true` or `--daemon`: forks into the background, detached from the terminal, with stdout and stderr
+//! appended to `log_file` and the pid written to `pid_file`. It has to happen before any thread is spawned, as only
+//! the thread that forks survives. The working directory is kept, the relative paths of the configuration depend on it.
+
+use crate::config::Daemon;
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+
+/// Removes the pid file once dropped, and on SIGTERM or SIGINT
+pub struct PidFile(PathBuf);
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use crate::{info, signal};
+    use anyhow::Context;
+    use std::fs::{self, File, OpenOptions};
+    use std::os::fd::AsRawFd;
+    use std::path::Path;
+    use std::{io, process};
+
+    /* The pid in the file, if that process still runs */
+    fn running(path: &Path) -> Option<libc::pid_t> {
+        let pid = fs::read_to_string(path).ok()?.trim().parse::<libc::pid_t>().ok().filter(|pid| *pid > 0)?;
+        // SAFETY: signal 0 only checks that the process exists. EPERM means it does, as someone else's
+        let alive = unsafe { libc::kill(pid, 0) } == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+        alive.then_some(pid)
+    }
+
+    fn fork() -> Result<libc::pid_t> {
+        // SAFETY: we are the only thread, the child can do anything the parent could
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()).context("Failed to fork"),
+            pid => Ok(pid)
+        }
+    }
+
+    fn redirect(file: &File, fd: libc::c_int) -> Result<()> {
+        // SAFETY: both descriptors are open
+        match unsafe { libc::dup2(file.as_raw_fd(), fd) } {
+            -1 => Err(io::Error::last_os_error()).context("Failed to redirect the standard streams"),
+            _ => Ok(())
+        }
+    }
+
+    /// Returns in the background process, the one it was called from exits
+    pub fn start(daemon: &Daemon) -> Result<PidFile> {
+        if let Some(pid) = running(&daemon.pid_file) {
+            return Err(anyhow!("smugglrs is already running with pid {pid} (from {}), stop it first", daemon.pid_file.display()));
+        }
+        // Opened before forking, so that errors are still shown on the terminal
+        let log = OpenOptions::new().create(true).append(true).open(&daemon.log_file)
+            .with_context(|| format!("Failed to open the log file {}", daemon.log_file.display()))?;
+        let null = File::open("/dev/null").context("Failed to open /dev/null")?;
+        fs::write(&daemon.pid_file, b"").with_context(|| format!("Failed to write the pid file {}", daemon.pid_file.display()))?;
+        println!("Running in the background, logging to {}", daemon.log_file.display());
+        if fork()? != 0 {
+            process::exit(0);
+        }
+        // SAFETY: the child of a fork isn't a process group leader, setsid can't fail
+        unsafe { libc::setsid() };
+        // Not a session leader anymore, so no terminal can be acquired again
+        if fork()? != 0 {
+            // SAFETY: nothing of this process needs cleaning up
+            unsafe { libc::_exit(0) };
+        }
+        redirect(&null, libc::STDIN_FILENO)?;
+        redirect(&log, libc::STDOUT_FILENO)?;
+        redirect(&log, libc::STDERR_FILENO)?;
+        fs::write(&daemon.pid_file, format!("{}\n", process::id()))
+            .with_context(|| format!("Failed to write the pid file {}", daemon.pid_file.display()))?;
+        let path = daemon.pid_file.clone();
+        signal::on_termination(move || {
+            info!("Terminated, removing {}", path.display());
+            let _ = fs::remove_file(&path);
+        })?;
+        info!("Running in the background with pid {}", process::id());
+        Ok(PidFile(daemon.pid_file.clone()))
+    }
+}
+
+#[cfg(unix)]
+pub use unix::start;
+
+#[cfg(not(unix))]
+pub fn start(_daemon: &Daemon) -> Result<PidFile> {
+    Err(anyhow!("daemon is only supported on Unix, run smugglrs as a service instead (--install-service)"))
+}
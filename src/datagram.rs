@@ -0,0 +1,167 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! UDP through the tunnel: each client peer gets its own connection to the server, like a TCP client,
+//! on which its datagrams are framed as `[length: u16 BE][payload]`. `DatagramStream` does the framing,
+//! so that the datagrams are piped like any other stream.
+
+use crate::connector::Stream;
+use crate::debug;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const MAX_DATAGRAM : usize = 65535;
+const FRAME_HEADER_LENGTH : usize = 2;
+/* How often a blocked reader checks whether the stream was shut down */
+const POLL_INTERVAL : Duration = Duration::from_millis(500);
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+enum Source {
+    /// The socket is connected to the local service, on the server
+    Socket,
+    /// The gateway's socket is shared by all the peers of a port, its listener dispatches their datagrams
+    Channel(Mutex<Receiver<Vec<u8>>>)
+}
+
+struct Inner {
+    socket: UdpSocket,
+    /// Where datagrams are sent, when the socket isn't connected
+    peer: Option<SocketAddr>,
+    source: Source,
+    closed: AtomicBool,
+    /// Last datagram in either direction, in milliseconds since the epoch
+    last_active: Arc<AtomicU64>
+}
+
+/// Datagrams can't be half-closed: once a direction is done, the stream is
+pub struct DatagramStream {
+    inner: Arc<Inner>,
+    // What's left of the frame being read, and what we have of the one being written
+    read_buf: Vec<u8>,
+    read_offset: usize,
+    write_buf: Vec<u8>
+}
+
+impl DatagramStream {
+    fn new(inner: Inner) -> DatagramStream {
+        DatagramStream { inner: Arc::new(inner), read_buf: Vec::new(), read_offset: 0, write_buf: Vec::new() }
+    }
+
+    /// A socket connected to the local service
+    pub fn connected(socket: UdpSocket) -> io::Result<DatagramStream> {
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(DatagramStream::new(Inner {
+            socket, peer: None, source: Source::Socket, closed: AtomicBool::new(false), last_active: Arc::new(AtomicU64::new(now_ms()))
+        }))
+    }
+
+    /// The datagrams of `peer` arrive through `rx`, the answers are sent to it from `socket`
+    pub fn peer(socket: UdpSocket, peer: SocketAddr, rx: Receiver<Vec<u8>>, last_active: Arc<AtomicU64>) -> DatagramStream {
+        DatagramStream::new(Inner {
+            socket, peer: Some(peer), source: Source::Channel(Mutex::new(rx)), closed: AtomicBool::new(false), last_active
+        })
+    }
+
+    /* None once the stream is over */
+    fn next_datagram(&self) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            if self.inner.closed.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+            match &self.inner.source {
+                Source::Socket => match self.inner.socket.recv(&mut buf) {
+                    Ok(len) => {
+                        buf.truncate(len);
+                        return Ok(Some(buf));
+                    }
+                    Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+                    // Nobody listens on the local port (yet), as far as ICMP tells
+                    Err(err) if err.kind() == ErrorKind::ConnectionRefused => {}
+                    Err(err) => return Err(err)
+                },
+                Source::Channel(rx) => match rx.lock().unwrap().recv_timeout(POLL_INTERVAL) {
+                    Ok(datagram) => return Ok(Some(datagram)),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    // The listener evicted the peer, or was stopped
+                    Err(RecvTimeoutError::Disconnected) => return Ok(None)
+                }
+            }
+        }
+    }
+}
+
+impl Read for DatagramStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read_offset >= self.read_buf.len() {
+            let Some(datagram) = self.next_datagram()? else {
+                return Ok(0);
+            };
+            self.inner.last_active.store(now_ms(), Ordering::Relaxed);
+            self.read_buf.clear();
+            self.read_buf.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+            self.read_buf.extend_from_slice(&datagram);
+            self.read_offset = 0;
+        }
+        let len = buf.len().min(self.read_buf.len() - self.read_offset);
+        buf[..len].copy_from_slice(&self.read_buf[self.read_offset..self.read_offset + len]);
+        self.read_offset += len;
+        Ok(len)
+    }
+}
+
+impl Write for DatagramStream {
+    /// Frames can be split across writes, each datagram is sent once complete
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_buf.extend_from_slice(buf);
+        while self.write_buf.len() >= FRAME_HEADER_LENGTH {
+            let len = u16::from_be_bytes([self.write_buf[0], self.write_buf[1]]) as usize;
+            if self.write_buf.len() < FRAME_HEADER_LENGTH + len {
+                break;
+            }
+            let datagram = &self.write_buf[FRAME_HEADER_LENGTH..FRAME_HEADER_LENGTH + len];
+            let sent = match self.inner.peer {
+                Some(peer) => self.inner.socket.send_to(datagram, peer),
+                None => self.inner.socket.send(datagram)
+            };
+            match sent {
+                Ok(_) => self.inner.last_active.store(now_ms(), Ordering::Relaxed),
+                // Lost like any datagram would be, the stream goes on
+                Err(err) => debug!("Failed to send a datagram of {len} bytes: {err}")
+            }
+            self.write_buf.drain(..FRAME_HEADER_LENGTH + len);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for DatagramStream {
+    fn try_clone_stream(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(DatagramStream { inner: self.inner.clone(), read_buf: Vec::new(), read_offset: 0, write_buf: Vec::new() }))
+    }
+
+    /// The reader notices within `POLL_INTERVAL`
+    fn shutdown_stream(&self) -> io::Result<()> {
+        self.inner.closed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
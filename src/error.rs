@@ -0,0 +1,57 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! Classification of session failures, to tell the ones worth retrying from the others.
+
+use std::fmt;
+
+/// Attached to an error (`anyhow::Error::new(Failure::...)` or `.context(Failure::...)`) when it is known
+/// that retrying won't help. Anything else is considered transient: refused or reset connections, timeouts...
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Failure {
+    Transient,
+    /// Our key doesn't match the gateway's
+    Authentication,
+    /// The configuration contradicts itself or the peer's
+    Config,
+    /// Invalid command line
+    Usage,
+}
+
+impl Failure {
+    pub fn of(err: &anyhow::Error) -> Failure {
+        // Goes through contexts, the outermost classification wins
+        err.downcast_ref::<Failure>().copied().unwrap_or(Failure::Transient)
+    }
+
+    /// Follows sysexits.h, so that supervisors can tell them apart
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Failure::Usage => 64, // EX_USAGE
+            Failure::Transient => 75, // EX_TEMPFAIL
+            Failure::Authentication => 77, // EX_NOPERM
+            Failure::Config => 78 // EX_CONFIG
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Transient => write!(f, "transient failure"),
+            Failure::Authentication => write!(f, "authentication failed, is the key the same as the gateway's?"),
+            Failure::Config => write!(f, "configuration error"),
+            Failure::Usage => write!(f, "usage: smugglrs [gateway | server] [options] [<config>], or smugglrs <command> [--config <path>]; see smugglrs --help")
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
@@ -0,0 +1,689 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! The formats of the configuration file. JSON and YAML are read into the same `toml::Table` as TOML, everything
+//! after that is shared. A key set to `null` is the same as a key that isn't there, TOML having no null.
+//!
+//! YAML is read as far as a configuration needs: block mappings and sequences, flow collections on one line, quoted
+//! and plain scalars. Anchors, tags, block scalars and multiple documents are refused.
+
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use std::sync::OnceLock;
+use toml::{Table, Value};
+
+/* Deeper than any configuration, and far from the end of the stack */
+const MAX_DEPTH : usize = 64;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Toml,
+    Json,
+    Yaml
+}
+
+/* `--config-format`, for every file read by this process */
+static FORMAT : OnceLock<Format> = OnceLock::new();
+
+impl Format {
+    pub fn parse(x: &str) -> Result<Format> {
+        match x.to_lowercase().as_str() {
+            "toml" => Ok(Format::Toml),
+            "json" => Ok(Format::Json),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            _ => Err(anyhow!("{x} is not a valid configuration format, expected toml, json or yaml"))
+        }
+    }
+
+    /// The one given with `--config-format`, or the one of the extension; TOML by default
+    pub fn of(path: &Path) -> Format {
+        if let Some(format) = FORMAT.get() {
+            return *format;
+        }
+        match path.extension().and_then(|x| x.to_str()).map(str::to_lowercase).as_deref() {
+            Some("json") => Format::Json,
+            Some("yaml" | "yml") => Format::Yaml,
+            _ => Format::Toml
+        }
+    }
+
+    pub fn read(self, text: &str) -> Result<Table> {
+        match self {
+            Format::Toml => Ok(text.parse::<Table>()?),
+            Format::Json => json(text),
+            Format::Yaml => yaml(text)
+        }
+    }
+}
+
+/// Only the first call counts
+pub fn set(format: Format) {
+    let _ = FORMAT.set(format);
+}
+
+fn top_level(value: Option<Value>) -> Result<Table> {
+    match value {
+        Some(Value::Table(table)) => Ok(table),
+        _ => Err(anyhow!("The configuration should be a mapping of options"))
+    }
+}
+
+/* Keys set to null are left out: they take their default */
+fn insert(table: &mut Table, key: String, value: Option<Value>) -> Result<()> {
+    if table.contains_key(&key) {
+        return Err(anyhow!("{key} is set twice"));
+    }
+    if let Some(value) = value {
+        table.insert(key, value);
+    }
+    Ok(())
+}
+
+fn element(value: Option<Value>) -> Result<Value> {
+    value.ok_or_else(|| anyhow!("null can't be an element of a list"))
+}
+
+/* Where an error is, numbered from 1 like the editors do */
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rfind('\n').map_or(before, |x| &before[x + 1..]).chars().count() + 1;
+    (line, column)
+}
+
+fn json(text: &str) -> Result<Table> {
+    let mut reader = Json { text, pos: 0 };
+    let value = reader.value(0);
+    let value = value.and_then(|value| {
+        reader.skip_whitespace();
+        match reader.pos < text.len() {
+            true => Err(anyhow!("Unexpected characters after the configuration")),
+            false => top_level(value)
+        }
+    });
+    value.map_err(|err| {
+        let (line, column) = position(text, reader.pos);
+        anyhow!("{err:#} at line {line} column {column}")
+    })
+}
+
+struct Json<'a> {
+    text: &'a str,
+    /// In bytes, where the error is when there's one
+    pos: usize
+}
+
+impl Json<'_> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| matches!(c, ' ' | '\t' | '\n' | '\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(c) => Err(anyhow!("Expected {expected}, found {c}")),
+            None => Err(anyhow!("Expected {expected}, found the end of the file"))
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Option<Value>> {
+        if depth > MAX_DEPTH {
+            return Err(anyhow!("The configuration is nested too deep"));
+        }
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => {
+                self.pos += 1;
+                let mut table = Table::new();
+                self.skip_whitespace();
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                    return Ok(Some(Value::Table(table)));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let start = self.pos;
+                    if self.peek() != Some('"') {
+                        return Err(anyhow!("Expected a key between double quotes"));
+                    }
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    let value = self.value(depth + 1)?;
+                    if table.contains_key(&key) {
+                        self.pos = start;
+                    }
+                    insert(&mut table, key, value)?;
+                    self.skip_whitespace();
+                    match self.next() {
+                        Some(',') => continue,
+                        Some('}') => return Ok(Some(Value::Table(table))),
+                        _ => return Err(anyhow!("Expected , or }} after a value"))
+                    }
+                }
+            }
+            Some('[') => {
+                self.pos += 1;
+                let mut array = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(']') {
+                    self.pos += 1;
+                    return Ok(Some(Value::Array(array)));
+                }
+                loop {
+                    array.push(element(self.value(depth + 1)?)?);
+                    self.skip_whitespace();
+                    match self.next() {
+                        Some(',') => continue,
+                        Some(']') => return Ok(Some(Value::Array(array))),
+                        _ => return Err(anyhow!("Expected , or ] after a value"))
+                    }
+                }
+            }
+            Some('"') => Ok(Some(Value::String(self.string()?))),
+            Some('-' | '0'..='9') => self.number().map(Some),
+            Some(_) => {
+                let word : String = self.text[self.pos..].chars().take_while(|c| c.is_ascii_alphabetic()).collect();
+                let value = match word.as_str() {
+                    "true" => Some(Value::Boolean(true)),
+                    "false" => Some(Value::Boolean(false)),
+                    "null" => None,
+                    _ => return Err(anyhow!("Expected a value"))
+                };
+                self.pos += word.len();
+                Ok(value)
+            }
+            None => Err(anyhow!("Expected a value, found the end of the file"))
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.pos += 1;
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => match self.next() {
+                    Some('"') => string.push('"'),
+                    Some('\\') => string.push('\\'),
+                    Some('/') => string.push('/'),
+                    Some('b') => string.push('\u{8}'),
+                    Some('f') => string.push('\u{c}'),
+                    Some('n') => string.push('\n'),
+                    Some('r') => string.push('\r'),
+                    Some('t') => string.push('\t'),
+                    Some('u') => {
+                        let high = self.hex()?;
+                        // Outside of the basic plane, a character is a pair of surrogates
+                        let c = match high {
+                            0xd800..=0xdbff if self.text[self.pos..].starts_with("\\u") => {
+                                self.pos += 2;
+                                let low = self.hex()?;
+                                char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff))
+                                    .filter(|_| (0xdc00..=0xdfff).contains(&low))
+                            }
+                            x => char::from_u32(x)
+                        };
+                        string.push(c.ok_or_else(|| anyhow!("Invalid \\u escape"))?);
+                    }
+                    _ => return Err(anyhow!("Invalid escape in a string"))
+                },
+                Some(c) if (c as u32) < 0x20 => return Err(anyhow!("Control characters should be escaped in strings")),
+                Some(c) => string.push(c),
+                None => return Err(anyhow!("Unterminated string"))
+            }
+        }
+    }
+
+    fn hex(&mut self) -> Result<u32> {
+        let digits = self.text.get(self.pos..self.pos + 4).ok_or_else(|| anyhow!("Invalid \\u escape"))?;
+        let x = u32::from_str_radix(digits, 16).map_err(|_| anyhow!("Invalid \\u escape"))?;
+        self.pos += 4;
+        Ok(x)
+    }
+
+    fn number(&mut self) -> Result<Value> {
+        let len = self.text[self.pos..].find(|c: char| !matches!(c, '-' | '+' | '.' | 'e' | 'E' | '0'..='9'))
+            .unwrap_or(self.text.len() - self.pos);
+        let number = &self.text[self.pos..self.pos + len];
+        let value = match number.parse::<i64>() {
+            Ok(x) => Value::Integer(x),
+            Err(_) => Value::Float(number.parse::<f64>().ok().filter(|x| x.is_finite()).ok_or_else(|| anyhow!("{number} is not a valid number"))?)
+        };
+        self.pos += len;
+        Ok(value)
+    }
+}
+
+/* A line of a YAML file, without its indentation and comment */
+struct Line<'a> {
+    number: usize,
+    indent: usize,
+    content: &'a str
+}
+
+fn yaml(text: &str) -> Result<Table> {
+    let mut lines = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let content = strip_comment(line);
+        let trimmed = content.trim_start_matches(' ');
+        if trimmed.starts_with('\t') {
+            return Err(anyhow!("Tabs can't indent YAML, at line {}", i + 1));
+        }
+        let trimmed = trimmed.trim_end();
+        match trimmed {
+            "" => {}
+            "---" if lines.is_empty() => {}
+            "---" | "..." => return Err(anyhow!("Only one YAML document is supported, at line {}", i + 1)),
+            _ => lines.push(Line { number: i + 1, indent: content.len() - content.trim_start_matches(' ').len(), content: trimmed })
+        }
+    }
+    let mut reader = Yaml { lines, next: 0 };
+    let indent = reader.lines.first().map_or(0, |x| x.indent);
+    let value = match reader.lines.is_empty() {
+        true => None,
+        false => reader.block(indent, 0)?
+    };
+    if let Some(line) = reader.lines.get(reader.next) {
+        return Err(anyhow!("Unexpected indentation at line {}", line.number));
+    }
+    top_level(value)
+}
+
+/* `#` starts a comment at the start of a line or after a space, outside of quotes */
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '#') if previous == ' ' || previous == '\t' => return &line[..i],
+            (None, '"' | '\'') => quote = Some(c),
+            (Some('"'), '\\') if previous == '\\' => {
+                previous = ' ';
+                continue;
+            }
+            (Some('"'), '"') if previous != '\\' => quote = None,
+            (Some('\''), '\'') => quote = None,
+            _ => {}
+        }
+        previous = c;
+    }
+    line
+}
+
+struct Yaml<'a> {
+    lines: Vec<Line<'a>>,
+    next: usize
+}
+
+impl Yaml<'_> {
+    /* The mapping or sequence whose first line is the next one, at `indent` */
+    fn block(&mut self, indent: usize, depth: usize) -> Result<Option<Value>> {
+        let line = &self.lines[self.next];
+        if depth > MAX_DEPTH {
+            return Err(anyhow!("The configuration is nested too deep, at line {}", line.number));
+        }
+        match line.content == "-" || line.content.starts_with("- ") {
+            true => self.sequence(indent, depth).map(Some),
+            false if split_key(line.content).is_some() => self.mapping(indent, depth).map(Some),
+            // A value on the lines after its key
+            false if depth > 0 => {
+                self.next += 1;
+                scalar(line.content).map_err(|err| anyhow!("{err:#} at line {}", line.number))
+            }
+            false => Err(anyhow!("Expected an option at line {}", line.number))
+        }
+    }
+
+    fn mapping(&mut self, indent: usize, depth: usize) -> Result<Value> {
+        let mut table = Table::new();
+        while let Some(line) = self.lines.get(self.next).filter(|x| x.indent == indent) {
+            let number = line.number;
+            let (key, rest) = split_key(line.content).ok_or_else(|| anyhow!("Expected key: value at line {number}"))?;
+            let key = match key.starts_with(['"', '\'']) {
+                true => match scalar(key) {
+                    Ok(Some(Value::String(key))) => key,
+                    _ => return Err(anyhow!("Invalid key at line {number}"))
+                },
+                false => key.to_string()
+            };
+            self.next += 1;
+            let value = match rest {
+                "" => self.nested(indent, depth, true)?,
+                x => scalar(x).map_err(|err| anyhow!("{err:#} at line {number}"))?
+            };
+            insert(&mut table, key, value).map_err(|err| anyhow!("{err:#} at line {number}"))?;
+        }
+        Ok(Value::Table(table))
+    }
+
+    fn sequence(&mut self, indent: usize, depth: usize) -> Result<Value> {
+        let mut array = Vec::new();
+        while let Some(line) = self.lines.get(self.next).filter(|x| x.indent == indent && (x.content == "-" || x.content.starts_with("- "))) {
+            let number = line.number;
+            let rest = line.content[1..].trim_start_matches(' ');
+            let value = match rest {
+                "" => {
+                    self.next += 1;
+                    self.nested(indent, depth, false)?
+                }
+                // `- key: value` starts a mapping, indented as far as its first key
+                x if split_key(x).is_some() => {
+                    let line = &mut self.lines[self.next];
+                    line.indent += line.content.len() - x.len();
+                    line.content = x;
+                    let indent = line.indent;
+                    self.block(indent, depth + 1)?
+                }
+                x => {
+                    self.next += 1;
+                    scalar(x).map_err(|err| anyhow!("{err:#} at line {number}"))?
+                }
+            };
+            array.push(element(value).map_err(|err| anyhow!("{err:#} at line {number}"))?);
+        }
+        Ok(Value::Array(array))
+    }
+
+    /* What's under a `key:` or a `-`. A sequence may be at the indentation of its key */
+    fn nested(&mut self, indent: usize, depth: usize, key: bool) -> Result<Option<Value>> {
+        match self.lines.get(self.next) {
+            Some(line) if line.indent > indent => {
+                let indent = line.indent;
+                self.block(indent, depth + 1)
+            }
+            Some(line) if key && line.indent == indent && (line.content == "-" || line.content.starts_with("- ")) => self.sequence(indent, depth + 1).map(Some),
+            _ => Ok(None)
+        }
+    }
+}
+
+/* `key: value` or `key:`, the key may be quoted */
+fn split_key(content: &str) -> Option<(&str, &str)> {
+    let end = match content.chars().next()? {
+        quote @ ('"' | '\'') => content[1..].find(quote)? + 2,
+        '[' | '{' => return None,
+        _ => 0
+    };
+    let colon = end + content[end..].find(": ").or_else(|| content[end..].strip_suffix(':').map(str::len))?;
+    let key = content[..colon].trim_end();
+    (!key.is_empty()).then_some((key, content[colon + 1..].trim_start()))
+}
+
+fn scalar(x: &str) -> Result<Option<Value>> {
+    let mut flow = Flow { text: x, pos: 0 };
+    let value = flow.value(0)?;
+    if !flow.text[flow.pos..].trim().is_empty() {
+        return Err(anyhow!("Unexpected characters after the value {}", &x[..flow.pos]));
+    }
+    Ok(value)
+}
+
+/* A scalar, or a flow collection (`[1, "TCP"]`, `{ name: grafana }`) */
+struct Flow<'a> {
+    text: &'a str,
+    pos: usize
+}
+
+impl Flow<'_> {
+    fn rest(&self) -> &str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_spaces(&mut self) {
+        self.pos = self.text.len() - self.rest().trim_start().len();
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Option<Value>> {
+        if depth > MAX_DEPTH {
+            return Err(anyhow!("The configuration is nested too deep"));
+        }
+        self.skip_spaces();
+        let text = self.text;
+        let rest = &text[self.pos..];
+        match rest.chars().next() {
+            Some('[') => {
+                self.pos += 1;
+                let mut array = Vec::new();
+                loop {
+                    self.skip_spaces();
+                    if self.rest().starts_with(']') {
+                        self.pos += 1;
+                        return Ok(Some(Value::Array(array)));
+                    }
+                    array.push(element(self.value(depth + 1)?)?);
+                    self.separator(']')?;
+                }
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut table = Table::new();
+                loop {
+                    self.skip_spaces();
+                    if self.rest().starts_with('}') {
+                        self.pos += 1;
+                        return Ok(Some(Value::Table(table)));
+                    }
+                    let key = match self.value(depth + 1)? {
+                        Some(Value::String(key)) => key,
+                        Some(Value::Integer(x)) => x.to_string(),
+                        _ => return Err(anyhow!("Expected a key"))
+                    };
+                    self.skip_spaces();
+                    if !self.rest().starts_with(':') {
+                        return Err(anyhow!("Expected : after the key {key}"));
+                    }
+                    self.pos += 1;
+                    let value = self.value(depth + 1)?;
+                    insert(&mut table, key, value)?;
+                    self.separator('}')?;
+                }
+            }
+            Some('"') => {
+                let mut string = String::new();
+                let mut chars = rest.char_indices().skip(1);
+                loop {
+                    match chars.next() {
+                        Some((i, '"')) => {
+                            self.pos += i + 1;
+                            return Ok(Some(Value::String(string)));
+                        }
+                        Some((_, '\\')) => match chars.next().map(|(_, c)| c) {
+                            Some('"') => string.push('"'),
+                            Some('\\') => string.push('\\'),
+                            Some('/') => string.push('/'),
+                            Some('n') => string.push('\n'),
+                            Some('r') => string.push('\r'),
+                            Some('t') => string.push('\t'),
+                            Some('0') => string.push('\0'),
+                            _ => return Err(anyhow!("Invalid escape in a string"))
+                        },
+                        Some((_, c)) => string.push(c),
+                        None => return Err(anyhow!("Unterminated string"))
+                    }
+                }
+            }
+            Some('\'') => {
+                let mut string = String::new();
+                let mut chars = rest.char_indices().skip(1).peekable();
+                loop {
+                    match chars.next() {
+                        Some((_, '\'')) if chars.peek().is_some_and(|(_, c)| *c == '\'') => {
+                            chars.next();
+                            string.push('\'');
+                        }
+                        Some((i, '\'')) => {
+                            self.pos += i + 1;
+                            return Ok(Some(Value::String(string)));
+                        }
+                        Some((_, c)) => string.push(c),
+                        None => return Err(anyhow!("Unterminated string"))
+                    }
+                }
+            }
+            Some('&' | '*' | '!') => Err(anyhow!("Anchors, aliases and tags are not supported")),
+            Some('|' | '>') => Err(anyhow!("Block scalars are not supported, use a quoted string")),
+            _ => {
+                // Up to what ends it in a flow collection, or the end of the line
+                let in_flow = depth > 0;
+                let len = match in_flow {
+                    true => rest.find([',', ']', '}']).map(|x| rest[..x].find(": ").unwrap_or(x)).unwrap_or(rest.len()),
+                    false => rest.len()
+                };
+                let len = match in_flow {
+                    true => rest[..len].strip_suffix(':').map_or(len, str::len),
+                    false => len
+                };
+                self.pos += len;
+                Ok(plain(rest[..len].trim_end()))
+            }
+        }
+    }
+
+    fn separator(&mut self, end: char) -> Result<()> {
+        self.skip_spaces();
+        match self.rest().chars().next() {
+            Some(',') => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(c) if c == end => Ok(()),
+            _ => Err(anyhow!("Expected , or {end}"))
+        }
+    }
+}
+
+/* What an unquoted scalar stands for */
+fn plain(x: &str) -> Option<Value> {
+    match x {
+        "" | "~" | "null" | "Null" | "NULL" => return None,
+        "true" | "True" | "TRUE" => return Some(Value::Boolean(true)),
+        "false" | "False" | "FALSE" => return Some(Value::Boolean(false)),
+        _ => {}
+    }
+    let number = x.strip_prefix('+').unwrap_or(x);
+    let integer = match number.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => number.parse::<i64>().ok()
+    };
+    if let Some(x) = integer {
+        return Some(Value::Integer(x));
+    }
+    // `inf` or `nan` would be strings to anyone reading the file
+    if number.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '.') {
+        if let Ok(x) = number.parse::<f64>().map(Value::Float) {
+            return Some(x);
+        }
+    }
+    Some(Value::String(x.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(format: Format, text: &str) -> String {
+        format!("{:#}", format.read(text).unwrap_err())
+    }
+
+    /* Nested `depth` levels deep under the key a, in JSON */
+    fn nested_json(depth: usize) -> String {
+        format!("{{\"a\": {}{}}}", "[".repeat(depth), "]".repeat(depth))
+    }
+
+    const TOML : &str = "mode = \"gateway\"\nport = 14531\nredirects = [[5333, 8000, \"TCP\"]]\nratio = 0.5\n\
+        [[redirect]]\nport = 80\ntarget = \"web:80\"\n[[redirect]]\nport = 443\nname = \"it's\"\n";
+
+    #[test]
+    fn every_format_reads_the_same_configuration() {
+        let toml = Format::Toml.read(TOML).unwrap();
+        let json = r#"{"mode": "gateway", "port": 14531, "redirects": [[5333, 8000, "TCP"]], "ratio": 0.5, "unset": null,
+            "redirect": [{"port": 80, "target": "web:80"}, {"port": 443, "name": "it's"}]}"#;
+        assert_eq!(Format::Json.read(json).unwrap(), toml);
+        let yaml = "---\nmode: gateway # the end of the line is a comment\nport: 14531\nredirects:\n- [5333, 8000, \"TCP\"]\nratio: 0.5\nunset: ~\n\
+            redirect:\n  - port: 80\n    target: \"web:80\"\n  - {port: 443, name: 'it''s'}\n";
+        assert_eq!(Format::Yaml.read(yaml).unwrap(), toml);
+    }
+
+    #[test]
+    fn escapes_are_read() {
+        let json = Format::Json.read(r#"{"a": "q\"b\\s\/n\nt\tu\u00e9p\ud83d\ude00"}"#).unwrap();
+        assert_eq!(json["a"].as_str(), Some("q\"b\\s/n\nt\tuép😀"));
+        for invalid in [r#"{"a": "\x"}"#, r#"{"a": "\u12"}"#, r#"{"a": "\ud83d\u0041"}"#, "{\"a\": \"tab\there\"}", r#"{"a": "open"#] {
+            assert!(Format::Json.read(invalid).is_err(), "{invalid}");
+        }
+        let yaml = Format::Yaml.read("a: \"q\\\"b\\\\s\\tt # not a comment\"\nb: 'single ''quoted'' \\n'\n").unwrap();
+        assert_eq!(yaml["a"].as_str(), Some("q\"b\\s\tt # not a comment"));
+        assert_eq!(yaml["b"].as_str(), Some("single 'quoted' \\n"), "single quotes have no escapes");
+        assert!(Format::Yaml.read("a: \"\\x\"\n").is_err());
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        assert!(Format::Json.read(&nested_json(MAX_DEPTH)).is_ok());
+        assert!(error(Format::Json, &nested_json(MAX_DEPTH + 1)).starts_with("The configuration is nested too deep"));
+        // Refused before it gets anywhere near the end of the stack
+        assert!(Format::Json.read(&nested_json(100_000)).is_err());
+        assert!(Format::Yaml.read(&format!("a: {}{}\n", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH))).is_ok());
+        assert!(Format::Yaml.read(&format!("a: {}{}\n", "[".repeat(100_000), "]".repeat(100_000))).is_err());
+        let block = |depth: usize| (0..=depth).map(|i| format!("{}k:{}\n", " ".repeat(i), if i == depth { " 1" } else { "" })).collect::<String>();
+        assert!(Format::Yaml.read(&block(MAX_DEPTH)).is_ok());
+        assert_eq!(error(Format::Yaml, &block(MAX_DEPTH + 1)), format!("The configuration is nested too deep, at line {}", MAX_DEPTH + 2));
+    }
+
+    #[test]
+    fn unsupported_yaml_is_refused() {
+        for (yaml, expected) in [
+            ("a: &anchor 1\n", "Anchors, aliases and tags are not supported at line 1"),
+            ("a: 1\nb: *anchor\n", "Anchors, aliases and tags are not supported at line 2"),
+            ("a: !!str 1\n", "Anchors, aliases and tags are not supported at line 1"),
+            ("a: |\n  text\n", "Block scalars are not supported, use a quoted string at line 1"),
+            ("a:\n\tb: 1\n", "Tabs can't indent YAML, at line 2"),
+            ("a: 1\n---\nb: 2\n", "Only one YAML document is supported, at line 2"),
+            ("a: 1\n...\n", "Only one YAML document is supported, at line 2")
+        ] {
+            assert_eq!(error(Format::Yaml, yaml), expected, "{yaml}");
+        }
+    }
+
+    #[test]
+    fn errors_tell_where_they_are() {
+        for (format, text, expected) in [
+            (Format::Json, "{\"a\": tru}", "Expected a value at line 1 column 7"),
+            (Format::Json, "{\n  \"port\": 1,\n  \"port\": 2\n}", "port is set twice at line 3 column 3"),
+            (Format::Json, "{\n  \"é\": [1 2]\n}", "Expected , or ] after a value at line 2 column 12"),
+            (Format::Json, "{} x", "Unexpected characters after the configuration at line 1 column 4"),
+            (Format::Json, "[1]", "The configuration should be a mapping of options at line 1 column 4"),
+            (Format::Yaml, "a: 1\n  b: 2\n", "Unexpected indentation at line 2"),
+            (Format::Yaml, "a: 1\n\n# comment\na: 2\n", "a is set twice at line 4"),
+            (Format::Yaml, "a: [1, 2\n", "Expected , or ] at line 1"),
+            (Format::Yaml, "- 1\n", "The configuration should be a mapping of options"),
+            (Format::Yaml, "a:\n  - 1\n  - null\n", "null can't be an element of a list at line 3")
+        ] {
+            assert_eq!(error(format, text), expected, "{text}");
+        }
+    }
+}
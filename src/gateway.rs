@@ -9,249 +9,2059 @@ You should have received a copy of the GNU General Public License along with thi
 If not, see <https://www.gnu.org/licenses/>. 
 */
 
-use crate::config::{CommonConfig, Port, Protocol, GatewayConfig};
-use crate::common::{spawn_pipes, MAGIC1, MAGIC1_LENGTH, TCP_CHALLENGE_LENGTH};
-use crate::crypto::{self, AEAD_LENGTH};
+use crate::accounting;
+use crate::activation;
+use crate::admin;
+use crate::error::Failure;
+use crate::cidr::AccessRules;
+use crate::config::{AddressFamily, Ban, CommonConfig, Port, Protocol, GatewayConfig, Maintenance, SpecificConfig};
+use crate::common::{self, port_label, spawn_pipes, BufferConfig, ConnectionLimits, ConnectionSlot, MemoryExhausted, PipeOptions, PipeRegistry, PipeWorkers, ReaperConfig, MEMORY, FIXED_MAGICS, MAGIC1_LENGTH, TCP_CHALLENGE_LENGTH};
+use crate::control::{self, BindError, ControlMessage, ControlSender, ClientInfo, ErrorCode, Registration, StreamFrame};
+use crate::crypto::{self, Channel, Cipher, Key, Magics, AEAD_LENGTH};
+use crate::connector::Stream;
+use crate::datagram::{DatagramStream, MAX_DATAGRAM};
+use crate::integrity::{self, DigestReport, PipeDigests};
+use crate::knock::Knocks;
+use crate::log;
+use crate::mux::{Mux, MuxStream};
+use crate::signal::Hangups;
+use crate::sockopt::{self, ListenOptions};
+use crate::websocket;
+use crate::scan::{self, SCANS};
+use crate::schedule::Schedule;
+use crate::{debug, error, info, warn};
 use anyhow::{anyhow, Result, Context};
-use std::net::{Shutdown, UdpSocket, SocketAddr, TcpListener, TcpStream};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, UdpSocket, SocketAddr, TcpListener, TcpStream};
 use std::io::{self, Read, Write};
-use std::time::Duration;
-use std::sync::mpsc::{channel, Sender};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::sync::mpsc::{self, channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use rand::{RngCore, rngs::OsRng};
+use std::{fmt, iter};
 use std::thread;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-const BUSY_LOOP_DELAY : u64 = 15;
-const CONNECT_TIMEOUT : u64 = 2000;
-const CONNECT_CHALLENGE_TIMEOUT : u32 = 150 * 1_000_000; // exponent 6 because I like miliseconds
+const TICK_DELAY : u64 = 1000;
+const MAX_UDP_PEERS : usize = 1024;
+const UDP_PEER_QUEUE : usize = 256; // Datagrams
+const UDP_RETRY_DELAY : Duration = Duration::from_secs(5);
+const MAX_PENDING_CONNECTIONS : usize = 256; // Clients waiting for the server to connect back
+const MAX_CANDIDATES : usize = 64; // Connections to the gateway's port being sorted
+const ACCEPT_POLL_INTERVAL : Duration = Duration::from_millis(100);
+const LISTENER_POLL_INTERVAL : Duration = Duration::from_millis(100); // How soon a forwarded port is unbound
+const THREAD_JOIN_TIMEOUT : Duration = Duration::from_secs(2); // Past which a thread told to stop is left behind
+const JOIN_POLL_INTERVAL : Duration = Duration::from_millis(10);
+const UNALLOWED_WARNING_INTERVAL : Duration = Duration::from_secs(60);
+const ACCEPT_WARNING_INTERVAL : Duration = Duration::from_secs(10);
+const MAX_BAN_SOURCES : usize = 65536;
+const EXPIRY_INTERVAL : Duration = Duration::from_secs(60); // Of the bans and the knocks
+const SEALED_TOKEN_LENGTH : usize = TCP_CHALLENGE_LENGTH + AEAD_LENGTH;
 
+/* Each session has its own events. Its listeners outlive the connections of its server, control events are tagged
+   with the connection they come from */
 enum EventType {
-    ControlClosed,
+    /// Whether the server closed it cleanly, between two messages
+    ControlClosed(u64, bool),
     NewTCPConnection(u16, TcpStream),
+    /// The first datagram of a client peer, which is in the stream
+    NewUDPPeer(u16, SocketAddr, DatagramStream),
+    Control(u64, ControlMessage),
+    /// Of a connection of a port with `verify_integrity`, to be sent to the server
+    Digests(u64, u64, PipeDigests),
+    /// A connection to the gateway's port, with the token of one of the session's clients it presented
+    DataConnection(SocketAddr, [u8; SEALED_TOKEN_LENGTH], TcpStream),
+    /// The server of a waiting session paired again
+    Resume(Box<Pairing>),
+    /// Another server registered the ports of a waiting session, which ends
+    Superseded,
+    Tick,
 }
 
-/// Monitor the socket: if the connection is closed, we notify the main thread to transition 
-/// back into "pairing" mode
-fn socket_monitor(mut socket: TcpStream, tx: Sender<EventType>) -> Result<()> {
-    socket.set_read_timeout(None).context("Set readtime out on socket monitor failed")?;
-    let mut buf = [0u8; 1];
-    match socket.read_exact(&mut buf) {
-        Err(err) => {
-            eprintln!("Connection with server ended, reason :\n{err:?}\nNotifying main thread...");
+/// Wake the session up regularly, for everything that has to be checked periodically.
+fn ticker(tx: Sender<EventType>) -> Result<()> {
+    let delay = Duration::from_millis(TICK_DELAY);
+    loop {
+        thread::sleep(delay);
+        tx.send(EventType::Tick)?;
+    }
+}
+
+/// Read the messages of the server: if the connection is closed, we notify the session to wait for it.
+/// The frames of the multiplexed connections go to them directly
+fn control_reader(mut socket: TcpStream, mut cipher: Cipher, connection_id: u64, mux: Option<Arc<Mux>>, tx: Sender<EventType>) -> Result<()> {
+    // Whichever way the connection ends, its streams do too
+    let _closer = mux.clone().map(MuxCloser);
+    socket.set_read_timeout(None).context("Set readtime out on control reader failed")?;
+    // Servers that don't ping aren't watched
+    let mut heartbeat_timeout = None;
+    loop {
+        match control::try_read_message(&mut socket, &mut cipher) {
+            Ok(Some(ControlMessage::Ping { interval, misses })) => {
+                let timeout = interval * misses as u32;
+                if heartbeat_timeout != Some(timeout) {
+                    socket.set_read_timeout(Some(timeout)).context("Set heartbeat timeout on control reader failed")?;
+                    heartbeat_timeout = Some(timeout);
+                }
+                tx.send(EventType::Control(connection_id, ControlMessage::Ping { interval, misses }))?;
+            }
+            Ok(Some(ControlMessage::Stream { id, frame })) if mux.is_some() => {
+                mux.as_ref().unwrap().dispatch(id, frame);
+            }
+            Ok(Some(msg)) => tx.send(EventType::Control(connection_id, msg))?,
+            Ok(None) => {
+                info!("Server closed the session, notifying the session...");
+                tx.send(EventType::ControlClosed(connection_id, true))?;
+                return Ok(());
+            }
+            Err(err) if control::is_timeout(&err) => {
+                warn!("Nothing came from the server for {}s, notifying the session...", heartbeat_timeout.unwrap_or_default().as_secs());
+                tx.send(EventType::ControlClosed(connection_id, false))?;
+                return Ok(());
+            }
+            Err(err) => {
+                warn!("Connection with server ended, notifying the session, reason: {err:#}");
+                tx.send(EventType::ControlClosed(connection_id, false))?;
+                return Ok(());
+            }
         }
-        Ok(_) => {
-            eprintln!("Something weird is going on, the server should never send anything");
+    }
+}
+
+struct MuxCloser(Arc<Mux>);
+
+impl Drop for MuxCloser {
+    fn drop(&mut self) {
+        self.0.close_all();
+    }
+}
+
+/// Failures to accept a client, such as running out of descriptors: warned about now and then rather than each time,
+/// the listener waits a little after each of them rather than spinning until it has descriptors again
+#[derive(Default)]
+struct AcceptErrors {
+    count: u64,
+    last_warning: Option<Instant>
+}
+
+impl AcceptErrors {
+    /// The failures so far, when it's time to warn about them again
+    fn fail(&mut self) -> Option<u64> {
+        self.count += 1;
+        if self.last_warning.is_some_and(|last| last.elapsed() < ACCEPT_WARNING_INTERVAL) {
+            return None;
+        }
+        self.last_warning = Some(Instant::now());
+        Some(self.count)
+    }
+}
+
+/// The clients of a port with a first byte timeout, until they sent something. They're polled along with the
+/// listener rather than each on a thread of its own, and count against the connection limits meanwhile: past them
+/// they're reset right away
+struct SilentClients {
+    port: u16,
+    connections: Arc<ConnectionLimits>,
+    /// Closed for not sending anything in time
+    silent: Arc<AtomicU64>,
+    clients: Vec<(TcpStream, Instant, ConnectionSlot)>
+}
+
+impl SilentClients {
+    fn admit(&mut self, socket: TcpStream, timeout: Duration) {
+        match self.connections.acquire(Port::new_tcp(self.port)) {
+            Some(slot) => self.clients.push((socket, Instant::now() + timeout, slot)),
+            None => {
+                match self.connections.warning() {
+                    Some(refused) => warn!("Too many connections are forwarded or waiting for their first byte, resetting a client of port {} ({refused} refused so far)", self.port),
+                    None => debug!("Too many connections are forwarded or waiting for their first byte, resetting a client of port {}", self.port)
+                }
+                reset(self.port, socket);
+            }
+        }
+    }
+
+    /* Until there's a client to accept, one of the waiting ones sent something, or the first deadline. Those that sent
+       something are forwarded without their slot, the session counts them again as it pairs them */
+    fn wait(&mut self, listener: &TcpListener, tx: &Sender<EventType>) -> Result<()> {
+        let now = Instant::now();
+        let timeout = self.clients.iter().map(|(_, deadline, _)| deadline.saturating_duration_since(now)).fold(LISTENER_POLL_INTERVAL, Duration::min);
+        let readable = sockopt::wait_clients(listener, &self.clients.iter().map(|(socket, _, _)| socket).collect::<Vec<_>>(), timeout)
+            .with_context(|| format!("Failed to wait for the clients of TCP port {}", self.port))?;
+        let now = Instant::now();
+        for ((socket, deadline, slot), readable) in std::mem::take(&mut self.clients).into_iter().zip(readable) {
+            if readable {
+                drop(slot);
+                tx.send(EventType::NewTCPConnection(self.port, socket))?;
+            } else if deadline <= now {
+                drop(slot);
+                let count = self.silent.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!("Client {} on port {} sent nothing in time, closing it ({count} closed so far)",
+                    socket.peer_addr().context("Failed to get peer address")?, self.port);
+                let _ = socket.shutdown(Shutdown::Both);
+            } else {
+                self.clients.push((socket, deadline, slot));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a port answers in place of the server
+enum MaintenanceResponse {
+    Http(Arc<[u8]>),
+    Reset,
+    Hold,
+    /// Of the ports without maintenance kept bound between the sessions, see `keep_ports_bound`: their clients are
+    /// reset as soon as they're accepted
+    Refuse
+}
+
+impl MaintenanceResponse {
+    fn new(maintenance: &Maintenance) -> MaintenanceResponse {
+        match maintenance {
+            Maintenance::Http { body, retry_after } => {
+                let retry_after = retry_after.map_or(String::new(), |x| format!("Retry-After: {x}\r\n"));
+                let response = format!("HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n{retry_after}Connection: close\r\n\r\n{body}", body.len());
+                MaintenanceResponse::Http(response.into_bytes().into())
+            }
+            Maintenance::Reset => MaintenanceResponse::Reset,
+            Maintenance::Hold => MaintenanceResponse::Hold
+        }
+    }
+}
+
+/// Shared by the session and the listener thread of a port
+#[derive(Default)]
+struct ListenerState {
+    stop: AtomicBool,
+    /// In milliseconds, 0 if none
+    first_byte_timeout: AtomicU64,
+    maintenance: Mutex<Option<Arc<MaintenanceResponse>>>,
+    /// Whether there's a server to serve the port: false between the connections of the server and while paused
+    unavailable: AtomicBool
+}
+
+const MAX_RESPONDERS : usize = 32;
+const RESPONDER_TIMEOUT : u64 = 1;
+const SCAN_READ_TIMEOUT : u64 = 500;
+static RESPONDERS : AtomicUsize = AtomicUsize::new(0);
+
+/* Whatever the client sends first, within the timeout; also counted for the summary of scans */
+fn read_probe(port: u16, socket: &mut TcpStream, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+    socket.set_read_timeout(Some(timeout))?;
+    let read = match socket.read(buf) {
+        Ok(n) => n,
+        Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => 0,
+        Err(err) => return Err(err)
+    };
+    SCANS.record(port, scan::classify(&buf[..read]));
+    Ok(read)
+}
+
+/* Read (some of) the request before answering, closing a socket with unread data would reset it. The whole exchange
+   takes at most RESPONDER_TIMEOUT, however slowly the client sends */
+fn respond(port: u16, mut socket: TcpStream, response: &[u8]) -> io::Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(RESPONDER_TIMEOUT);
+    let left = || deadline.checked_duration_since(Instant::now()).filter(|left| !left.is_zero());
+    socket.set_write_timeout(Some(Duration::from_secs(RESPONDER_TIMEOUT)))?;
+    let mut request = [0u8; 1024];
+    read_probe(port, &mut socket, &mut request, left().unwrap_or(Duration::from_millis(1)))?;
+    socket.write_all(response)?;
+    socket.shutdown(Shutdown::Write)?;
+    while let Some(left) = left() {
+        socket.set_read_timeout(Some(left))?;
+        if socket.read(&mut request)? == 0 {
+            break;
         }
     }
-    tx.send(EventType::ControlClosed)?;
     Ok(())
 }
 
-fn tcp_listener(port: u16, tx: Sender<EventType>) -> Result<()> {
-    println!("Binding port {port}");
-    match TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))) {
-        Ok(listener) => loop {
-            match listener.accept() {
-                Err(err) => {
-                    eprintln!("Client connection on TCP port {port} failed. Reason:\n{err:?}\n");
-                    eprintln!("Ignoring...");
+fn probe_and_reset(port: u16, mut socket: TcpStream) {
+    let mut first = [0u8; 256];
+    let _ = read_probe(port, &mut socket, &mut first, Duration::from_millis(SCAN_READ_TIMEOUT));
+    reset(port, socket);
+}
+
+fn reset(port: u16, socket: TcpStream) {
+    if let Err(err) = sockopt::set_linger_zero(&socket) {
+        debug!("Failed to reset the connection on port {port}: {err:#}");
+    }
+}
+
+/* Never blocks the listener: responses are sent from a bounded number of threads, past which clients are reset
+   without even being classified */
+fn answer_maintenance(port: u16, socket: TcpStream, response: &MaintenanceResponse, tx: &Sender<EventType>) -> Result<()> {
+    if let MaintenanceResponse::Hold = response {
+        tx.send(EventType::NewTCPConnection(port, socket))?;
+    } else if let MaintenanceResponse::Refuse = response {
+        reset(port, socket);
+    } else if RESPONDERS.fetch_add(1, Ordering::Relaxed) < MAX_RESPONDERS {
+        let response = match response {
+            MaintenanceResponse::Http(response) => Some(response.clone()),
+            _ => None
+        };
+        log::spawn(move || {
+            match response {
+                Some(response) => { let _ = respond(port, socket, &response); }
+                None => probe_and_reset(port, socket)
+            }
+            RESPONDERS.fetch_sub(1, Ordering::Relaxed);
+        });
+    } else {
+        RESPONDERS.fetch_sub(1, Ordering::Relaxed);
+        reset(port, socket);
+    }
+    Ok(())
+}
+
+/* The unspecified IPv6 address listens on IPv4 too, unless only IPv6 is wanted, and falls back to IPv4 on hosts without IPv6 */
+fn bind<T>(address: SocketAddr, family: AddressFamily, bind: impl Fn(SocketAddr, bool) -> io::Result<T>) -> io::Result<T> {
+    match bind(address, family == AddressFamily::Ipv6) {
+        Err(err) if family == AddressFamily::Any && address.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) && err.kind() != io::ErrorKind::AddrInUse => {
+            debug!("Failed to bind {address}, falling back to IPv4, reason: {err:#}");
+            bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), address.port()), false)
+        }
+        result => result
+    }
+}
+
+/// Ports with a first byte timeout only forward a client once it sent something. Non-blocking, to notice within
+/// `LISTENER_POLL_INTERVAL` that the port is unbound: the clients it didn't accept yet are reset with it
+fn tcp_listener(listener: TcpListener, address: SocketAddr, state: Arc<ListenerState>, connections: Arc<ConnectionLimits>, silent: Arc<AtomicU64>, tx: Sender<EventType>) -> Result<()> {
+    let port = address.port();
+    listener.set_nonblocking(true).context("Failed to set the listener non-blocking")?;
+    let mut waiting = SilentClients { port, connections, silent, clients: Vec::new() };
+    let mut errors = AcceptErrors::default();
+    loop {
+        if state.stop.load(Ordering::Relaxed) {
+            info!("Unbinding port {address}");
+            return Ok(());
+        }
+        match listener.accept().and_then(|(socket, addr)| socket.set_nonblocking(false).map(|()| (socket, addr))) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if let Err(err) = waiting.wait(&listener, &tx) {
+                    // The session is over
+                    if err.is::<mpsc::SendError<EventType>>() {
+                        return Err(err);
+                    }
+                    warn!("{err:#}");
+                    thread::sleep(LISTENER_POLL_INTERVAL);
+                }
+            }
+            Err(err) => {
+                match errors.fail() {
+                    Some(count) => warn!("Client connection on TCP port {port} failed, ignoring, reason: {err:#} ({count} failed so far)"),
+                    None => debug!("Client connection on TCP port {port} failed, ignoring, reason: {err:#}")
                 }
-                Ok((socket,_addr)) => {
-                    tx.send(EventType::NewTCPConnection(port, socket))?;
+                thread::sleep(LISTENER_POLL_INTERVAL);
+            }
+            Ok((socket,_addr)) if state.unavailable.load(Ordering::Relaxed) => {
+                let response = state.maintenance.lock().unwrap().clone();
+                match response {
+                    Some(response) => answer_maintenance(port, socket, &response, &tx)?,
+                    None => tx.send(EventType::NewTCPConnection(port, socket))?
+                }
+            }
+            Ok((socket,_addr)) => match state.first_byte_timeout.load(Ordering::Relaxed) {
+                0 => tx.send(EventType::NewTCPConnection(port, socket))?,
+                timeout => waiting.admit(socket, Duration::from_millis(timeout))
+            }
+        }
+    }
+}
+
+/// A client peer of a UDP port, whose datagrams go to its own connection to the server
+struct UdpPeer {
+    tx: SyncSender<Vec<u8>>,
+    last_active: Arc<AtomicU64>,
+    /// Its connection was refused or is over: its datagrams are dropped until then, rather than asking again for each
+    closed_until: Option<Instant>
+}
+
+fn udp_listener(socket: UdpSocket, address: SocketAddr, state: Arc<ListenerState>, idle_timeout: Duration, tx: Sender<EventType>) -> Result<()> {
+    let port = address.port();
+    // Wakes us up to notice that the port is unbound, and to evict the idle peers
+    socket.set_read_timeout(Some(LISTENER_POLL_INTERVAL)).context("Failed to set the read timeout of the UDP socket")?;
+    let mut last_eviction = Instant::now();
+    let mut peers : HashMap<SocketAddr, UdpPeer> = HashMap::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let idle_ms = idle_timeout.as_millis() as u64;
+    let mut dropped : u64 = 0;
+    loop {
+        let received = socket.recv_from(&mut buf);
+        // Checked after receiving, the datagram may be the one waking us up
+        if state.stop.load(Ordering::Relaxed) {
+            // The relays end once their peer is dropped
+            info!("Unbinding UDP port {address} ({} peers)", peers.len());
+            return Ok(());
+        }
+        match received {
+            Ok((len, addr)) => {
+                let datagram = buf[..len].to_vec();
+                let full = peers.len() >= MAX_UDP_PEERS;
+                match peers.get_mut(&addr) {
+                    Some(peer) if peer.closed_until.is_some_and(|until| Instant::now() < until) => {}
+                    Some(peer) if peer.closed_until.is_none() => {
+                        peer.last_active.store(unix_time_ms(), Ordering::Relaxed);
+                        match peer.tx.try_send(datagram) {
+                            // Like any datagram, it may be lost when the relay can't keep up
+                            Ok(()) | Err(TrySendError::Full(_)) => {}
+                            Err(TrySendError::Disconnected(_)) => peer.closed_until = Some(Instant::now() + UDP_RETRY_DELAY)
+                        }
+                    }
+                    None if full => {
+                        dropped += 1;
+                        if dropped.is_power_of_two() {
+                            warn!("UDP port {port} has too many peers, dropping the datagrams of new ones ({dropped} so far)");
+                        }
+                    }
+                    _ => {
+                        let (peer_tx, peer_rx) = sync_channel(UDP_PEER_QUEUE);
+                        let _ = peer_tx.try_send(datagram);
+                        let last_active = Arc::new(AtomicU64::new(unix_time_ms()));
+                        let stream = DatagramStream::peer(socket.try_clone().context("Failed to clone the UDP socket")?, addr, peer_rx, last_active.clone());
+                        peers.insert(addr, UdpPeer { tx: peer_tx, last_active, closed_until: None });
+                        tx.send(EventType::NewUDPPeer(port, addr, stream))?;
+                    }
                 }
             }
-        },
-        Err(err) => {
-            eprintln!("Failed to bind port {port}, reason:\n{err:?}\n");
-            eprintln!("A service may be running on this port already.");
-            eprintln!("The gateway will continue working without this port");
-            Err(anyhow!("Failed to bind port {port}, reason:\n{err:?}\n"))
+            Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) => {}
+            // ICMP errors about previous datagrams, among others
+            Err(err) => warn!("UDP port {port}: failed to receive a datagram, ignoring, reason: {err:#}")
         }
+        if last_eviction.elapsed() < Duration::from_millis(TICK_DELAY) {
+            continue;
+        }
+        last_eviction = Instant::now();
+        let now = unix_time_ms();
+        peers.retain(|addr, peer| {
+            if peer.closed_until.is_some_and(|until| Instant::now() >= until) {
+                return false;
+            }
+            let idle = now.saturating_sub(peer.last_active.load(Ordering::Relaxed)) >= idle_ms;
+            if idle && peer.closed_until.is_none() {
+                debug!("UDP peer {addr} on port {port} has been idle for {}s, closing its relay", idle_timeout.as_secs());
+            }
+            !idle
+        });
     }
 }
 
+/* Ends the control reader of a connection of the server with it, and waits for it */
 struct ThreadKiller {
     control_stream: TcpStream,
-    //@TODO add udp socket
-    ports: Vec<Port>
+    reader: Option<thread::JoinHandle<Result<()>>>
+}
+
+/// A client waiting for the server to connect back with its token
+struct PendingClient {
+    token: [u8; TCP_CHALLENGE_LENGTH],
+    port: Port,
+    addr: SocketAddr,
+    stream: Box<dyn Stream>,
+    deadline: Instant,
+    slot: ConnectionSlot,
+    /// Its number in the session, in the logs
+    conn: u32
+}
+
+/* The clients of a connection of the server waiting for it to connect back, by the token it will present once sealed.
+   The acceptor knows which session a token belongs to until the client is matched, or the connection is over */
+struct PendingClients<'a> {
+    shared: &'a Shared,
+    session: u64,
+    clients: HashMap<[u8; SEALED_TOKEN_LENGTH], PendingClient>
+}
+
+impl PendingClients<'_> {
+    fn insert(&mut self, sealed: [u8; SEALED_TOKEN_LENGTH], client: PendingClient) {
+        self.shared.sessions.lock().unwrap().tokens.insert(sealed, self.session);
+        self.clients.insert(sealed, client);
+    }
+
+    fn remove(&mut self, sealed: &[u8; SEALED_TOKEN_LENGTH]) -> Option<PendingClient> {
+        self.shared.sessions.lock().unwrap().tokens.remove(sealed);
+        self.clients.remove(sealed)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let mut sessions = self.shared.sessions.lock().unwrap();
+        self.clients.retain(|sealed, client| client.deadline > now || {
+            let _tag = log::connection(Some(client.conn));
+            warn!("Server took too long to connect for {} on port {}, closing it", client.addr, client.port.port);
+            let _ = client.stream.shutdown_stream();
+            sessions.tokens.remove(sealed);
+            false
+        });
+    }
+}
+
+impl Drop for PendingClients<'_> {
+    fn drop(&mut self) {
+        let mut sessions = self.shared.sessions.lock().unwrap();
+        for sealed in self.clients.keys() {
+            sessions.tokens.remove(sealed);
+        }
+    }
+}
+
+/* Of a source that failed the magic or the challenge */
+#[derive(Default)]
+struct Failures {
+    /// Within the window of the ban
+    times: Vec<Instant>,
+    banned_until: Option<Instant>
+}
+
+/// The sources to drop without reading anything, for failing too often (see `Ban`)
+#[derive(Default)]
+struct Bans {
+    sources: Mutex<HashMap<IpAddr, Failures>>
+}
+
+impl Bans {
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        self.sources.lock().unwrap().get(&ip.to_canonical()).and_then(|x| x.banned_until).is_some_and(|until| until > now)
+    }
+
+    fn fail(&self, ip: IpAddr, ban: Option<Ban>) {
+        let Some(ban) = ban else { return };
+        let (ip, now) = (ip.to_canonical(), Instant::now());
+        let mut sources = self.sources.lock().unwrap();
+        // The sources are only forgotten once expired, past that many the new ones go unnoticed until then
+        if sources.len() >= MAX_BAN_SOURCES && !sources.contains_key(&ip) {
+            return;
+        }
+        let source = sources.entry(ip).or_default();
+        source.times.retain(|x| now.duration_since(*x) < ban.window);
+        source.times.push(now);
+        if source.times.len() >= ban.failures as usize {
+            source.times.clear();
+            source.banned_until = Some(now + ban.duration);
+            warn!("Banning {ip} for {}s, it failed the magic or the challenge {} times within {}s", ban.duration.as_secs(), ban.failures, ban.window.as_secs());
+        }
+    }
+
+    /* Forget the sources that are neither banned nor failed recently */
+    fn expire(&self, ban: Option<Ban>) {
+        let now = Instant::now();
+        let window = ban.map_or(Duration::ZERO, |ban| ban.window);
+        self.sources.lock().unwrap().retain(|_, source| source.banned_until.is_some_and(|until| until > now)
+            || source.times.last().is_some_and(|last| now.duration_since(*last) < window));
+    }
+
+    fn banned(&self) -> usize {
+        let now = Instant::now();
+        self.sources.lock().unwrap().values().filter(|x| x.banned_until.is_some_and(|until| until > now)).count()
+    }
+}
+
+/// What the threads of the gateway share
+struct Shared {
+    ccfg: CommonConfig,
+    /// Replaced when the configuration is reloaded
+    gcfg: Mutex<Arc<GatewayConfig>>,
+    sessions: Mutex<Sessions>,
+    connections: Arc<ConnectionLimits>,
+    bans: Bans,
+    started: Instant,
+    /// How each session ended, for `one_session`
+    ended: Sender<Result<()>>
+}
+
+impl Shared {
+    fn gcfg(&self) -> Arc<GatewayConfig> {
+        self.gcfg.lock().unwrap().clone()
+    }
+
+    /* On SIGHUP. The timeouts, limits and quotas apply to the connections and sessions to come, the ports and rules
+       of a session are kept until it ends; where the gateway listens only changes once it restarts */
+    fn reload(&self) -> Result<()> {
+        let gcfg = match CommonConfig::new(Some(&self.gcfg().config_path))? {
+            (_, SpecificConfig::Gateway(gcfg)) => gcfg,
+            (_, SpecificConfig::Server(_)) => return Err(anyhow!("The configuration is now a server's, restart smugglrs to change its mode"))
+        };
+        let current = self.gcfg();
+        if (gcfg.port, gcfg.bind_address, gcfg.address_family) != (current.port, current.bind_address, current.address_family) {
+            warn!("The gateway listens on {} once smugglrs restarts", SocketAddr::new(gcfg.bind_address, gcfg.port));
+        }
+        if (&gcfg.knock, gcfg.knock_window) != (&current.knock, current.knock_window) {
+            warn!("The knock sequence changes once smugglrs restarts");
+        }
+        if gcfg.socket != current.socket {
+            warn!("The [socket] options apply to the sessions to come, and to the gateway's port once smugglrs restarts");
+        }
+        self.connections.set_limits(gcfg.max_connections, gcfg.max_connections_per_port);
+        *self.gcfg.lock().unwrap() = Arc::new(gcfg);
+        Ok(())
+    }
+}
+
+/// A session outlives the connections of its server for as long as some of its ports stay bound
+#[derive(Default)]
+struct Sessions {
+    /// Of both the sessions and the connections of their servers, a session is numbered after its first connection
+    next_id: u64,
+    entries: HashMap<u64, SessionEntry>,
+    /// The sealed tokens of the clients waiting in the sessions
+    tokens: HashMap<[u8; SEALED_TOKEN_LENGTH], u64>
+}
+
+struct SessionEntry {
+    /// Its latest server, and when it paired
+    server: SocketAddr,
+    paired_at: Instant,
+    /// No other session can register them
+    ports: HashSet<Port>,
+    /// Of its current server, for the admin socket
+    pipes: Option<Arc<PipeRegistry>>,
+    /// Bytes its servers transferred, against session_quota. Kept when a server resumes it
+    transferred: Arc<AtomicU64>,
+    tx: Sender<EventType>,
+    thread: Option<thread::JoinHandle<()>>,
+    /// Its server is gone, it can be handed the next one that registers some of its ports
+    waiting: bool
+}
+
+impl Sessions {
+    /// Whether `ip` is the server of a session that is established
+    fn serves(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.entries.values().any(|entry| !entry.waiting && entry.server.ip() == ip)
+    }
+
+    fn owner(&self, port: Port) -> Option<u64> {
+        self.entries.iter().find(|(_, entry)| entry.ports.contains(&port)).map(|(id, _)| *id)
+    }
+}
+
+/// A connection of a server that paired, for the session it belongs to
+struct Pairing {
+    socket: TcpStream,
+    addr: SocketAddr,
+    paired: Paired,
+    /// Tells its control events apart from the ones left over by the previous connections of the session
+    connection_id: u64
+}
+
+/* Why `gateway` returns once the connection of the server lasted max_session_duration: its ports aren't held for it */
+#[derive(Debug)]
+struct Expired(Duration);
+
+impl fmt::Display for Expired {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The session reached its max_session_duration of {}s", self.0.as_secs())
+    }
+}
+
+impl std::error::Error for Expired {}
+
+/* Stops the pipes of a session once it is over */
+struct PipeCanceller(Arc<PipeRegistry>);
+
+impl Drop for PipeCanceller {
+    fn drop(&mut self) {
+        let count = self.0.close();
+        if count > 0 {
+            info!("Cancelled the {count} active connections of the session");
+        }
+    }
 }
 
 impl Drop for ThreadKiller {
-    // Attempt a connection on every port, waking them up in the process
-    // They should stop because the receiving part of the mpsc channel has been closed
+    // Wake the control reader up, it stops once the stream is closed
     fn drop(&mut self) {
-        if let Err(_) = self.control_stream.shutdown(Shutdown::Both) {
-            eprintln!("Failed to shutdown tcp monitor thread");
-        }
-        let udp = UdpSocket::bind("0.0.0.0:0").unwrap(); //@TODO, we should reuse the udp socket from the main thread
-        for p in &self.ports {
-            let addr = SocketAddr::from(([127, 0, 0, 1], p.port));
-            match p.protocol {
-                Protocol::TCP => {
-                    if let Err(_) = TcpStream::connect(addr) {
-                        eprintln!("Failed to connect to our own thread, it probably died on its own");
-                    }
+        if self.control_stream.shutdown(Shutdown::Both).is_err() {
+            error!("Failed to shutdown tcp monitor thread");
+        }
+        if let Some(reader) = self.reader.take() {
+            join_until(reader, Instant::now() + THREAD_JOIN_TIMEOUT, "control reader");
+        }
+    }
+}
+
+/* Waits for a thread that was told to stop, up to a deadline shared with the others stopped along with it. One that
+   is still running past it is left behind rather than holding the session, it ends once its socket does */
+fn join_until(thread: thread::JoinHandle<Result<()>>, deadline: Instant, name: &str) {
+    while !thread.is_finished() && Instant::now() < deadline {
+        thread::sleep(JOIN_POLL_INTERVAL);
+    }
+    if !thread.is_finished() {
+        warn!("The {name} thread didn't stop within {}s, leaving it behind", THREAD_JOIN_TIMEOUT.as_secs());
+        return;
+    }
+    match thread.join() {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => warn!("The {name} thread failed, reason: {err:#}"),
+        Err(_) => error!("The {name} thread panicked")
+    }
+}
+
+/// Bound ports; they outlive the connection of the server, unless `keep_ports_bound` is disabled and they have no maintenance.
+/// Used both for the initial registration and for the ports added or removed afterwards,
+/// so that they are validated the same way
+struct Listeners {
+    shared: Arc<Shared>,
+    session: u64,
+    tx: Sender<EventType>,
+    bound: HashMap<Port, Arc<ListenerState>>,
+    // Scheduled ports, and whether they are currently active
+    schedules: HashMap<u16, (Schedule, bool)>,
+    // Ports for which the client address isn't forwarded
+    hidden_clients: HashSet<u16>,
+    // Ports with verify_integrity
+    verified: HashSet<u16>,
+    // Ports whose data connections are encrypted
+    encrypted: HashSet<u16>,
+    // The size of the buffers of the ports that have one
+    pipe_buffers: HashMap<u16, usize>,
+    // Ports whose connections keep Nagle's algorithm
+    nagle: HashSet<u16>,
+    // Names the server gave to its redirects, for the logs
+    names: HashMap<u16, String>,
+    // Address every port is bound on
+    bind_address: IpAddr,
+    family: AddressFamily,
+    socket: ListenOptions,
+    // Our own rules, for every port
+    access: AccessRules,
+    server_rules: bool,
+    // The rules sent by the server, applied on top of ours
+    server_access: HashMap<u16, AccessRules>,
+    // Clients closed for not sending anything in time
+    silent: Arc<AtomicU64>,
+    udp_idle_timeout: Duration,
+    // Whether the ports without maintenance stay bound between the connections of the server too
+    keep_bound: bool,
+    // Joined when their port is unbound, so that another session can bind it right away
+    threads: HashMap<Port, thread::JoinHandle<Result<()>>>,
+}
+
+impl Listeners {
+    fn new(shared: &Arc<Shared>, session: u64, tx: Sender<EventType>) -> Listeners {
+        let gcfg = shared.gcfg();
+        Listeners {
+            shared: shared.clone(),
+            session,
+            tx,
+            bound: HashMap::new(),
+            schedules: HashMap::new(),
+            hidden_clients: HashSet::new(),
+            verified: HashSet::new(),
+            encrypted: HashSet::new(),
+            pipe_buffers: HashMap::new(),
+            nagle: HashSet::new(),
+            names: HashMap::new(),
+            bind_address: gcfg.forward_bind_address,
+            family: gcfg.address_family,
+            socket: gcfg.socket.clone(),
+            access: gcfg.access.clone(),
+            server_rules: gcfg.server_rules,
+            server_access: HashMap::new(),
+            silent: Arc::new(AtomicU64::new(0)),
+            udp_idle_timeout: gcfg.udp_idle_timeout,
+            keep_bound: gcfg.keep_ports_bound,
+            threads: HashMap::new()
+        }
+    }
+
+    /// Bound right away, so that the server can be told which ports failed
+    fn register(&mut self, registration: &Registration) -> Option<BindError> {
+        let port = registration.port;
+        if self.bound.contains_key(&port) {
+            warn!("Port {} is registered twice, ignoring", port.port);
+            return None;
+        }
+        {
+            let mut sessions = self.shared.sessions.lock().unwrap();
+            if let Some(owner) = sessions.owner(port).filter(|x| *x != self.session) {
+                warn!("Port {} is served by session {owner}, ignoring", port.port);
+                return Some(BindError::Taken);
+            }
+            if let Some(entry) = sessions.entries.get_mut(&self.session) {
+                entry.ports.insert(port);
+            }
+        }
+        let address = SocketAddr::new(self.bind_address, port.port);
+        let state = Arc::new(ListenerState::default());
+        let name = registration.name.as_ref().map(|name| format!(" ({name})")).unwrap_or_default();
+        let thread = match port.protocol {
+            Protocol::TCP => {
+                info!("Binding port {address}{name}");
+                bind(address, self.family, |address, v6only| sockopt::bind_tcp(address, v6only, &self.socket)).map(|listener| {
+                    let (state, connections, silent, tx) = (state.clone(), self.shared.connections.clone(), self.silent.clone(), self.tx.clone());
+                    log::spawn(move || tcp_listener(listener, address, state, connections, silent, tx))
+                })
+            }
+            Protocol::UDP => {
+                info!("Binding UDP port {address}{name}");
+                bind(address, self.family, |address, v6only| sockopt::bind_udp(address, v6only, &self.socket)).map(|socket| {
+                    let (state, idle_timeout, tx) = (state.clone(), self.udp_idle_timeout, self.tx.clone());
+                    log::spawn(move || udp_listener(socket, address, state, idle_timeout, tx))
+                })
+            }
+        };
+        match thread {
+            Ok(thread) => {
+                self.bound.insert(port, state);
+                self.threads.insert(port, thread);
+                self.set_options(registration);
+                None
+            }
+            Err(err) => {
+                let kind = if port.protocol == Protocol::UDP { "UDP port" } else { "port" };
+                warn!("Failed to bind {kind} {address}, a service may be running on this port already, the gateway will continue working without it, reason: {err:#}");
+                if let Some(entry) = self.shared.sessions.lock().unwrap().entries.get_mut(&self.session) {
+                    entry.ports.remove(&port);
                 }
-                Protocol::UDP => {
-                    if let Err(_) = udp.send_to(&[], addr) {
-                        eprintln!("Failed to send a UDP message to our own thread, it probably died on its own");
+                Some(BindError::of(&err))
+            }
+        }
+    }
+
+    fn set_options(&mut self, registration: &Registration) {
+        let port = registration.port;
+        match &registration.name {
+            Some(name) => self.names.insert(port.port, name.clone()),
+            None => self.names.remove(&port.port)
+        };
+        if registration.hide_client {
+            self.hidden_clients.insert(port.port);
+        } else {
+            self.hidden_clients.remove(&port.port);
+        }
+        if registration.verify_integrity {
+            info!("Verifying the integrity of the connections of port {}", port.port);
+            self.verified.insert(port.port);
+        } else {
+            self.verified.remove(&port.port);
+        }
+        if registration.encrypt {
+            info!("Encrypting the data connections of port {}", port.port);
+            self.encrypted.insert(port.port);
+        } else {
+            self.encrypted.remove(&port.port);
+        }
+        if registration.nodelay {
+            self.nagle.remove(&port.port);
+        } else {
+            self.nagle.insert(port.port);
+        }
+        match registration.pipe_buffer {
+            Some(size) => self.pipe_buffers.insert(port.port, size),
+            None => self.pipe_buffers.remove(&port.port)
+        };
+        if registration.access.is_empty() {
+            self.server_access.remove(&port.port);
+        } else if !self.server_rules {
+            info!("Ignoring the client rules of the server for port {} ({}), server_rules is disabled", port.port, registration.access);
+            self.server_access.remove(&port.port);
+        } else {
+            info!("Client rules of the server for port {}: {}, on top of the gateway's ({})", port.port, registration.access, self.access);
+            self.server_access.insert(port.port, registration.access.clone());
+        }
+        if let Some(state) = self.bound.get(&port) {
+            let millis = registration.first_byte_timeout.map_or(0, |timeout| timeout.as_millis().max(1) as u64);
+            if millis != 0 {
+                info!("Port {} waits {millis}ms for the first byte of its clients", port.port);
+            }
+            state.first_byte_timeout.store(millis, Ordering::Relaxed);
+            *state.maintenance.lock().unwrap() = registration.maintenance.as_ref().map(|x| Arc::new(MaintenanceResponse::new(x)));
+            if registration.paused {
+                info!("Port {} is paused, answering its clients in maintenance mode", port.port);
+            }
+            state.unavailable.store(registration.paused, Ordering::Relaxed);
+        }
+        match registration.schedule {
+            Some(schedule) => {
+                let active = schedule.is_active(unix_time());
+                info!("Port {} is only active {schedule}, currently {}", port.port, if active { "active" } else { "inactive" });
+                self.schedules.insert(port.port, (schedule, active));
+            }
+            None => {
+                self.schedules.remove(&port.port);
+            }
+        }
+    }
+
+    /// The port, with the name of its redirect
+    fn label(&self, port: u16) -> String {
+        port_label(port, self.names.get(&port).map(String::as_str))
+    }
+
+    fn unregister(&mut self, port: Port) {
+        self.unbind(&[port]);
+    }
+
+    /* Every listener is told to stop before any is joined, so that unbinding many ports doesn't take longer */
+    fn unbind(&mut self, ports: &[Port]) {
+        let mut threads = Vec::new();
+        for port in ports {
+            match self.bound.remove(port) {
+                Some(state) => {
+                    state.stop.store(true, Ordering::Relaxed);
+                    self.schedules.remove(&port.port);
+                    self.hidden_clients.remove(&port.port);
+                    self.verified.remove(&port.port);
+                    self.encrypted.remove(&port.port);
+                    self.pipe_buffers.remove(&port.port);
+                    self.nagle.remove(&port.port);
+                    self.names.remove(&port.port);
+                    self.server_access.remove(&port.port);
+                    if let Some(entry) = self.shared.sessions.lock().unwrap().entries.get_mut(&self.session) {
+                        entry.ports.remove(port);
                     }
+                    threads.extend(self.threads.remove(port).map(|thread| (*port, thread)));
                 }
+                None => warn!("Server removed port {} which was not registered, ignoring", port.port)
             }
         }
+        let deadline = Instant::now() + THREAD_JOIN_TIMEOUT;
+        for (port, thread) in threads {
+            join_until(thread, deadline, &format!("listener of port {port}"));
+        }
+    }
+
+    /// Keep the ports that are still registered, bind the new ones and unbind the others. Returns which ones
+    /// couldn't be bound
+    fn sync(&mut self, registrations: &[Registration]) -> Vec<(Port, Option<BindError>)> {
+        let kept : Vec<Port> = self.bound.keys().copied().filter(|p| registrations.iter().any(|r| r.port == *p)).collect();
+        let removed : Vec<Port> = self.bound.keys().copied().filter(|p| !kept.contains(p)).collect();
+        self.unbind(&removed);
+        registrations.iter().map(|r| {
+            if kept.contains(&r.port) {
+                self.set_options(r);
+                (r.port, None)
+            } else {
+                (r.port, self.register(r))
+            }
+        }).collect()
+    }
+
+    fn client_info(&self, port: u16, addr: SocketAddr) -> ClientInfo {
+        ClientInfo {
+            addr: if self.hidden_clients.contains(&port) { None } else { Some(addr) },
+            requested_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64),
+            conn: None
+        }
+    }
+
+    /* For the ports with verify_integrity, the digests are sent to the server from the session */
+    fn integrity(&self, connection_id: u64, port: u16, challenge: &[u8]) -> Option<DigestReport> {
+        self.verified.contains(&port).then(|| {
+            let (tx, id) = (self.tx.clone(), integrity::connection_id(challenge));
+            Arc::new(move |digests| { let _ = tx.send(EventType::Digests(connection_id, id, digests)); }) as DigestReport
+        })
+    }
+
+    /// A client has to pass both our rules and the ones of the server
+    fn permits(&self, port: u16, ip: IpAddr) -> bool {
+        self.access.permits(ip) && self.server_access.get(&port).is_none_or(|access| access.permits(ip))
+    }
+
+    /// Ports with maintenance stay bound, answering for the server until it's back. So do the others, refusing their
+    /// clients, unless `keep_ports_bound` is disabled. The server that comes back sets them again as it registers them
+    fn clear(&mut self) {
+        let mut unbound = Vec::new();
+        for (port, state) in &self.bound {
+            let mut maintenance = state.maintenance.lock().unwrap();
+            if maintenance.is_none() && self.keep_bound && port.protocol == Protocol::TCP {
+                *maintenance = Some(Arc::new(MaintenanceResponse::Refuse));
+            }
+            if maintenance.is_some() {
+                state.unavailable.store(true, Ordering::Relaxed);
+            } else {
+                unbound.push(*port);
+            }
+        }
+        self.unbind(&unbound);
+    }
+
+    /// Once the session is over
+    fn close(&mut self) {
+        let ports : Vec<Port> = self.bound.keys().copied().collect();
+        self.unbind(&ports);
     }
 }
 
-fn gateway(ccfg: &CommonConfig, _gcfg: &GatewayConfig, listener: &TcpListener, mut socket: TcpStream, addr: SocketAddr) -> Result<()> {
-    println!("Server candidate connected from {addr}");
-    socket.set_read_timeout(Some(Duration::new(1,0))).context("Candidate server; set read time out failed")?;
-    let mut magic_test = [0 as u8; MAGIC1_LENGTH];
-    socket.read_exact(&mut magic_test).context("Candidate server; read magic1 failed")?;
+/// Client connections accepted after a session ended, waiting for the server to come back
+struct Held {
+    server_ip: IpAddr,
+    deadline: Instant,
+    clients: Vec<(u16, TcpStream)>
+}
 
-    if !crypto::constant_eq(&magic_test,MAGIC1) {
-        return Err(anyhow!("{addr} did not send the correct magic; it's probably some kind of bot"));
+impl Held {
+    fn hold(&mut self, max_clients: usize, port: u16, tcp: TcpStream) {
+        if self.clients.len() >= max_clients {
+            debug!("Too many connections are held, refusing connection on port {port}");
+            let _ = tcp.shutdown(Shutdown::Both);
+        } else {
+            debug!("Holding connection on port {port} until the server comes back");
+            self.clients.push((port, tcp));
+        }
+    }
+
+    fn release(self) {
+        if !self.clients.is_empty() {
+            info!("Closing {} held connections", self.clients.len());
+        }
+        for (_, tcp) in self.clients {
+            let _ = tcp.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// A server that completed the handshake and registered its ports
+struct Paired {
+    to_server: Cipher,
+    to_gateway: Cipher,
+    data_cipher: Cipher,
+    registrations: Vec<Registration>,
+    /// Whether it wants to know which ports were bound
+    bind_status: bool,
+    /// Whether it wants its connections over the control connection, see `mux`
+    multiplex: bool,
+    /// Given by the server for the logs, older servers don't
+    session: Option<u16>,
+    buffers: BufferConfig,
+    reaper: ReaperConfig
+}
+
+/* The keys servers may have, the current one first */
+fn server_keys(ccfg: &CommonConfig, gcfg: &GatewayConfig) -> Vec<(Key, Magics)> {
+    iter::once((ccfg.key, ccfg.magics)).chain(gcfg.previous_key.map(|key| (key, Magics::derive(&key)))).collect()
+}
+
+/// Once the server sent the magic of one of `keys`. Returns None for a connection test, which is answered right away
+fn pair(ccfg: &CommonConfig, gcfg: &GatewayConfig, bans: &Bans, socket: &mut TcpStream, addr: SocketAddr, keys: &[(Key, Magics)], magic1: &[u8; MAGIC1_LENGTH]) -> Result<Option<Paired>> {
+    let (cipher, key) = crypto::challenge(keys, magic1, socket).inspect_err(|_| bans.fail(addr.ip(), gcfg.ban)).context("Candidate server failed the challenge")?;
+    info!("Server {addr} authenticated as a peer (key fingerprint {})", crypto::fingerprint(&keys[key].0));
+    if key > 0 {
+        warn!("Server {addr} has the previous key, it should be given the current one");
     }
-    
-    let mut cipher = crypto::challenge(&ccfg.key, &mut socket).context("Candidate server failed the challenge")?;
 
     socket.set_read_timeout(None).context("Set read time out failed")?; // Client completed the challenge, no need for timeouts
     
-    println!("Connection established; Receiving ports...");
-    let (ports, _mapping) = {
-        let mut length = [0u8; 1+AEAD_LENGTH];
-        socket.read_exact(&mut length).context("Read encrypted length failed")?;
-        let length = cipher.decrypt(&length).context("Decrypt length failed")?[0];
-
-        let mut encrypted_ports = vec![0u8; length as usize];
-        socket.read_exact(&mut encrypted_ports).context("Read encrypted ports failed")?;
-        let ports_raw = cipher.decrypt(&encrypted_ports).context("Decrypt ports failed")?;
-
-        let ports_length = ports_raw.len()/3;
-        let mut ports = Vec::with_capacity(ports_length);
-        let mut mapping = HashMap::with_capacity(ports_length);
-        for i in 0..ports_length {
-            let port = Port::from_bytes(ports_raw[i*3..(i*3)+3].try_into().unwrap());
-            ports.push(port);
-            mapping.insert(port, i);
-        }
-        (ports,mapping)
+    info!("Connection established; Receiving ports...");
+    let mut to_gateway = cipher.channel(Channel::ToGateway);
+    let (registrations, bind_status, rekey, multiplex, session) = match control::read_message(socket, &mut to_gateway).context("Failed to receive ports")? {
+        ControlMessage::Register { registrations, bind_status, rekey, multiplex, session } => (registrations, bind_status, rekey, multiplex, session),
+        ControlMessage::Probe => {
+            let mut to_server = cipher.channel(Channel::ToServer);
+            control::write_message(socket, &mut to_server, &ControlMessage::Probe).context("Failed to answer connection test")?;
+            info!("Connection test from {addr} succeeded");
+            return Ok(None);
+        }
+        _ => return Err(anyhow!("Server should register its ports first"))
     };
-    
-    let (tx, rx) = channel();
-    
-    for p in &ports {
-        match p.protocol {
-            Protocol::TCP => {
-                let tx = tx.clone();
-                let port = p.port;
-                thread::spawn(move || tcp_listener(port, tx));
-            },
-            Protocol::UDP => {
-                eprintln!("UDP is not implemented yet, ignoring bind {}", p.port);
+    let mut to_server = cipher.channel(Channel::ToServer);
+    // Older servers can't follow a new key
+    to_server.set_rekey_after(rekey.then_some(ccfg.rekey_after));
+    Ok(Some(Paired {
+        to_server,
+        to_gateway,
+        data_cipher: cipher.channel(Channel::DataChallenge),
+        registrations,
+        bind_status,
+        // It's told with the status of the ports
+        multiplex: multiplex && bind_status,
+        session,
+        buffers: ccfg.buffers,
+        reaper: ccfg.reaper
+    }))
+}
+
+fn gateway(shared: &Shared, pairing: Pairing, rx: &Receiver<EventType>, listeners: &mut Listeners, held: &mut Option<Held>) -> Result<()> {
+    let Pairing { socket, addr, paired, connection_id } = pairing;
+    let paired_at = Instant::now();
+    let Paired { to_server, to_gateway, data_cipher, registrations, bind_status, multiplex, session, buffers, reaper } = paired;
+    // The server is only sent the connection numbers once it gave the session
+    let _tag = log::tagged(log::Tag { session: Some(session.unwrap_or_else(log::new_session)), conn: None });
+    let send_conn = session.is_some();
+    let mut next_conn = 0;
+    let mut thread_killer = ThreadKiller {
+        control_stream: socket.try_clone().context("Socket clone for ThreadKiller failed")?,
+        reader: None
+    };
+    let statuses = match held.take() {
+        Some(held) if held.server_ip == addr.ip() => {
+            info!("Server is back, resuming {} held connections", held.clients.len());
+            let statuses = listeners.sync(&registrations);
+            for (port, tcp) in held.clients {
+                listeners.tx.send(EventType::NewTCPConnection(port, tcp))?;
             }
+            statuses
         }
+        Some(held) => {
+            info!("A different server paired, dropping the ports of the previous one");
+            held.release();
+            listeners.clear();
+            listeners.sync(&registrations)
+        }
+        None => listeners.sync(&registrations)
+    };
+    // Shared with the pipes of the multiplexed connections
+    let sender = Arc::new(Mutex::new(ControlSender::new(socket.try_clone().context("Socket clone for the control sender failed")?, to_server)));
+    if bind_status {
+        sender.lock().unwrap().send(&ControlMessage::BindStatus { statuses, rekey: true, multiplex, refused: true }).context("Failed to send the status of the ports")?;
     }
+    let mux = multiplex.then(|| {
+        let sender = sender.clone();
+        Mux::new(Box::new(move |msg| sender.lock().unwrap().send(msg)))
+    });
     
     {
-        let socket = socket.try_clone().context("Socket clone for socket_monitor failed")?;
-        let tx = tx.clone();
-        thread::spawn(move || socket_monitor(socket, tx));
+        let socket = socket.try_clone().context("Socket clone for control_reader failed")?;
+        let (mux, tx) = (mux.clone(), listeners.tx.clone());
+        thread_killer.reader = Some(log::spawn(move || control_reader(socket, to_gateway, connection_id, mux, tx)));
     }
 
-    // The only purpose of this object is to clean everything when it's dropped (for instance if we return an error)
-    let _thread_killer = ThreadKiller {
-        control_stream: socket.try_clone().context("Socket clone for ThreadKiller failed")?,
-        ports: ports.clone()
+    let pipes = Arc::new(PipeRegistry::default());
+    pipes.spawn_reaper(reaper);
+    // The quota is the session's, a server that resumes it doesn't start over
+    let transferred = match shared.sessions.lock().unwrap().entries.get_mut(&listeners.session) {
+        Some(entry) => {
+            entry.pipes = Some(pipes.clone());
+            entry.transferred.clone()
+        }
+        None => Arc::new(AtomicU64::new(0))
     };
+    let _pipe_canceller = PipeCanceller(pipes.clone());
+    let mut quota_exhausted = false;
 
-    // Set the listener to non-blocking; this allows us to have timeouts later
-    listener.set_nonblocking(true).context("Set listener to non-blocking failed")?;
-    
-    for msg in rx { 
+    let mut pending = PendingClients { shared, session: listeners.session, clients: HashMap::new() };
+    // Through the connection the server connected back with, or a stream of the control connection
+    let pipe_client = |tunnel: Box<dyn Stream>, client: PendingClient, listeners: &Listeners| -> Result<()> {
+        let (addr, port) = (client.addr, client.port.port);
+        let options = PipeOptions {
+            counter: Some(transferred.clone()),
+            registry: Some(pipes.clone()),
+            port: Some(client.port.port),
+            name: listeners.names.get(&client.port.port).cloned(),
+            peer: Some(SocketAddr::new(client.addr.ip().to_canonical(), client.addr.port())),
+            buffers: buffers.sized(listeners.pipe_buffers.get(&client.port.port).copied()),
+            nodelay: !listeners.nagle.contains(&client.port.port),
+            integrity: listeners.integrity(connection_id, client.port.port, &client.token),
+            // What goes towards the server is sealed, what comes from it opened. The streams of the control
+            // connection are encrypted with it already
+            encryption: (tunnel.as_tcp().is_some() && listeners.encrypted.contains(&client.port.port))
+                .then(|| data_cipher.connection_ciphers(&client.token)).transpose()
+                .context("Failed to derive the keys of the connection")?,
+            slot: Some(Arc::new(client.slot)),
+            ..Default::default()
+        };
+        match spawn_pipes(tunnel, client.stream, options) {
+            // The client is refused, not the session
+            Err(err) if err.is::<MemoryExhausted>() => {
+                warn!("Memory budget exhausted, refused connection from {addr} on port {} ({} refused so far)",
+                    listeners.label(port), MEMORY.stats().rejected);
+                Ok(())
+            }
+            piped => piped.context("Spawning pipe failed")
+        }
+    };
+
+    for msg in rx.iter() { 
+        let gcfg = shared.gcfg();
+        if let Some(quota) = gcfg.session_quota {
+            if !quota_exhausted && transferred.load(Ordering::Relaxed) >= quota {
+                quota_exhausted = true;
+                warn!("Session quota of {}MB exhausted, refusing new connections", quota/1_000_000);
+                let message = format!("The session quota of {}MB is exhausted, new connections are refused", quota/1_000_000);
+                sender.lock().unwrap().send(&ControlMessage::Error { code: ErrorCode::QuotaExhausted, message })
+                    .context("Failed to notify server of exhausted quota")?;
+                if gcfg.session_quota_terminate {
+                    info!("Terminating {} active connections", pipes.len());
+                    pipes.cancel_all();
+                }
+            }
+        }
         match msg {
-            EventType::ControlClosed => {
+            EventType::ControlClosed(id, _) | EventType::Control(id, _) if id != connection_id => {
+                // Left over by a previous connection
+            },
+            EventType::ControlClosed(_, true) => return Ok(()),
+            EventType::ControlClosed(_, false) => {
                 break;
             },
+            EventType::Control(_, ControlMessage::AddPort(registration)) => {
+                info!("Server added port {}", port_label(registration.port.port, registration.name.as_deref()));
+                let status = listeners.register(&registration);
+                if bind_status {
+                    sender.lock().unwrap().send(&ControlMessage::BindStatus { statuses: vec![(registration.port, status)], rekey: true, multiplex, refused: true })
+                        .context("Failed to send the status of the port")?;
+                }
+            },
+            EventType::Control(_, ControlMessage::RemovePort(port)) => {
+                info!("Server removed port {}", listeners.label(port.port));
+                listeners.unregister(port);
+            },
+            EventType::Control(_, ControlMessage::UpdatePort(registration)) if listeners.bound.contains_key(&registration.port) => {
+                info!("Server updated port {}", registration.port.port);
+                listeners.set_options(&registration);
+            },
+            EventType::Control(_, ControlMessage::UpdatePort(registration)) => {
+                warn!("Server updated port {} which was not registered, ignoring", registration.port.port);
+            },
+            EventType::Control(_, ControlMessage::Ping { .. }) => {
+                sender.lock().unwrap().send(&ControlMessage::Pong).context("Failed to answer the server's ping")?;
+            },
+            EventType::Control(_, ControlMessage::Refused { token, reason }) => match pending.remove(&sealed_token(&data_cipher, &token)?) {
+                Some(client) => {
+                    let _tag = log::connection(Some(client.conn));
+                    warn!("Server couldn't connect back for {} on port {}, closing it, reason: {reason}", client.addr, listeners.label(client.port.port));
+                    let _ = client.stream.shutdown_stream();
+                }
+                // It took too long already
+                None => debug!("Server couldn't connect back for a client that is gone, reason: {reason}")
+            },
+            EventType::Control(_, _) => {
+                return Err(anyhow!("Server sent an unexpected control message"));
+            },
+            EventType::Digests(id, _, _) if id != connection_id => {},
+            EventType::Digests(_, id, digests) => {
+                sender.lock().unwrap().send(&ControlMessage::Integrity { id, digests })
+                    .context("Failed to send the digests of a connection")?;
+            },
+            EventType::DataConnection(addr, sealed, stream) => {
+                let Some(client) = pending.remove(&sealed) else {
+                    warn!("Candidate {addr} did not present the token of a waiting client, ignoring");
+                    continue;
+                };
+                let _tag = log::connection(Some(client.conn));
+                debug!("Server connected back for {} on port {}", client.addr, listeners.label(client.port.port));
+                pipe_client(Box::new(stream), client, listeners)?;
+            },
+            // Only sent to waiting sessions
+            EventType::Resume(_) | EventType::Superseded => {},
+            EventType::Tick if gcfg.max_session_duration.is_some_and(|max| paired_at.elapsed() >= max) => {
+                let max = gcfg.max_session_duration.unwrap();
+                // The server pairs again as soon as it's told, by then its ports must be free and the session waiting for
+                // it, or it would be refused for the ones with maintenance
+                listeners.clear();
+                if let Some(entry) = shared.sessions.lock().unwrap().entries.get_mut(&listeners.session) {
+                    entry.waiting = true;
+                }
+                let message = format!("The session lasted the {}s of max_session_duration", max.as_secs());
+                sender.lock().unwrap().send(&ControlMessage::Error { code: ErrorCode::SessionExpired, message })
+                    .context("Failed to notify the server that the session expired")?;
+                return Err(Expired(max).into());
+            },
+            EventType::Tick => {
+                pending.expire(Instant::now());
+                let now = unix_time();
+                for (port, (schedule, active)) in listeners.schedules.iter_mut() {
+                    if schedule.is_active(now) != *active {
+                        *active = !*active;
+                        info!("Port {port} is now {} ({schedule})", if *active { "active" } else { "inactive" });
+                    }
+                }
+            },
+            EventType::NewTCPConnection(_, tcp) if quota_exhausted => {
+                debug!("Session quota exhausted, refusing connection from {}", tcp.peer_addr().context("Failed to get peer address")?);
+                let _ = tcp.shutdown(Shutdown::Both);
+            },
+            // Better now than after the server connected back
+            EventType::NewTCPConnection(port, tcp) if !MEMORY.has_room(2 * buffers.min) => {
+                MEMORY.reject();
+                warn!("Memory budget exhausted, refusing connection from {} on port {port} ({} refused so far)",
+                    tcp.peer_addr().context("Failed to get peer address")?, MEMORY.stats().rejected);
+                let _ = tcp.shutdown(Shutdown::Both);
+            },
+            EventType::NewTCPConnection(port, tcp) if !listeners.bound.contains_key(&Port::new_tcp(port)) => {
+                debug!("Port {port} has been removed, refusing connection from {}", tcp.peer_addr().context("Failed to get peer address")?);
+                let _ = tcp.shutdown(Shutdown::Both);
+            },
+            // Held by maintenance while the port is paused
+            EventType::NewTCPConnection(port, tcp) if listeners.bound[&Port::new_tcp(port)].unavailable.load(Ordering::Relaxed) => {
+                debug!("Port {port} is paused, refusing connection from {}", tcp.peer_addr().context("Failed to get peer address")?);
+                let _ = tcp.shutdown(Shutdown::Both);
+            },
+            EventType::NewTCPConnection(port, tcp) if tcp.peer_addr().is_ok_and(|addr| !listeners.permits(port, addr.ip())) => {
+                debug!("Refusing connection from {} on port {port}, it is not allowed", tcp.peer_addr().context("Failed to get peer address")?);
+                let _ = tcp.shutdown(Shutdown::Both);
+            },
+            EventType::NewTCPConnection(port, tcp) if listeners.schedules.get(&port).is_some_and(|(_, active)| !active) => {
+                debug!("Port {port} is outside its active hours, refusing connection from {}", tcp.peer_addr().context("Failed to get peer address")?);
+                let _ = tcp.shutdown(Shutdown::Both);
+            },
+            EventType::NewTCPConnection(port, tcp) if pending.clients.len() >= MAX_PENDING_CONNECTIONS => {
+                warn!("Too many clients are waiting for the server, refusing connection from {} on port {port}", tcp.peer_addr().context("Failed to get peer address")?);
+                let _ = tcp.shutdown(Shutdown::Both);
+            },
             EventType::NewTCPConnection(port, tcp) => {
-                println!("New connection from {} on port {port}, notifying server...", tcp.peer_addr().context("Failed to get peer address")?);
-                
-                //We craft a response message : it contains the port,
-                //And some random byte that the server needs to send
-                //Once it has created a new connection
-                let mut msg = [0u8; 2+TCP_CHALLENGE_LENGTH];
-                msg[0..2].copy_from_slice(&port.to_be_bytes());
-                OsRng.fill_bytes(&mut msg[2..]);
-                
-                let encrypted_msg = cipher.encrypt(&msg);
-
-                socket.write_all(&encrypted_msg).context("Failed to notify server of new connection")?;
-                socket.flush().context("Failed to flush the new connection notification")?;
-                println!("Server has been notified. Now waiting for a matching connection...");
-                let new_socket;
-                let mut milis_elapsed = 0;
-                let busy = Duration::from_millis(BUSY_LOOP_DELAY);
-                loop {
-                    match listener.accept() {
-                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                            // No connection yet, let's wait a bit
-                            if milis_elapsed >= CONNECT_TIMEOUT {
-                                return Err(anyhow!("Server took too long to connect"));
-                            } else {
-                                thread::sleep(busy);
-                                milis_elapsed += BUSY_LOOP_DELAY;
-                            }
-                        }
-                        Err(e) => eprintln!("Candidate client connection failed. Reason:\n{e:?}\nIgnoring..."),
-                        Ok((mut candidate_socket,candidate_addr)) => {
-                            println!("Candidate matching connection from {candidate_addr}");
-                            if candidate_addr.ip() == addr.ip() {
-                                candidate_socket.set_read_timeout(Some(Duration::new(0,CONNECT_CHALLENGE_TIMEOUT)))
-                                .context("Candidate match; failed to set read timeout")?;
-                            
-                                let mut response = [0u8; TCP_CHALLENGE_LENGTH + AEAD_LENGTH];
-                                if let Ok(_) = candidate_socket.read_exact(&mut response) {
-                                    if let Ok(response) = cipher.decrypt(&response) {
-                                        if crypto::constant_eq(&response, &msg[2..]) {
-                                            // We don't need timeout anymore
-                                            candidate_socket.set_read_timeout(None).context("Match; failed to disable timeout")?;
-                                            println!("Candidate has been accepted.");
-                                            new_socket = candidate_socket;
-                                            break;
-                                        } else {
-                                            println!("Candidate sent a valid encrypted message with wrong content. Wtf?");
-                                        }
-                                    } else {
-                                        println!("Candidate did not solve the challenge, ignoring");
-                                    }
-                                } else {
-                                    println!("Candidate failed to send the challenge in time, ignoring");
-                                }
-                            } else {
-                                println!("Candidate IP does not match, ignoring");
-                            }
-                            
-                        }
+                let client_addr = tcp.peer_addr().context("Failed to get peer address")?;
+                let Some(slot) = connection_slot(shared, Port::new_tcp(port), client_addr) else {
+                    let _ = tcp.shutdown(Shutdown::Both);
+                    continue;
+                };
+                next_conn += 1;
+                let _tag = log::connection(Some(next_conn));
+                debug!("New connection from {client_addr} on port {}, notifying server...", listeners.label(port));
+                if let Some(quota) = gcfg.session_quota {
+                    let remaining = quota.saturating_sub(transferred.load(Ordering::Relaxed));
+                    debug!("Remaining session quota: {:.1}MB", remaining as f64 / 1_000_000.0);
+                }
+                let client = ClientInfo { conn: send_conn.then_some(next_conn), ..listeners.client_info(port, client_addr) };
+                let waiting = PendingClient {
+                    token: new_token(),
+                    port: Port::new_tcp(port),
+                    addr: client_addr,
+                    stream: Box::new(tcp),
+                    deadline: Instant::now() + gcfg.connect_timeout,
+                    slot,
+                    conn: next_conn
+                };
+                match &mux {
+                    Some(mux) => pipe_client(Box::new(open_stream(&sender, mux, &waiting, client)?), waiting, listeners)?,
+                    None => {
+                        let token = waiting.token;
+                        pending.insert(sealed_token(&data_cipher, &token)?, waiting);
+                        request_connection(&sender, Port::new_tcp(port), token, client)?;
+                    }
+                }
+            }
+            EventType::NewUDPPeer(port, peer, _) if quota_exhausted => {
+                debug!("Session quota exhausted, refusing UDP peer {peer} on port {port}");
+            },
+            EventType::NewUDPPeer(port, peer, _) if !MEMORY.has_room(2 * buffers.min) => {
+                MEMORY.reject();
+                warn!("Memory budget exhausted, refusing UDP peer {peer} on port {port} ({} refused so far)", MEMORY.stats().rejected);
+            },
+            EventType::NewUDPPeer(port, peer, _) if !listeners.bound.contains_key(&Port::new_udp(port)) => {
+                debug!("UDP port {port} has been removed, refusing peer {peer}");
+            },
+            EventType::NewUDPPeer(port, peer, _) if !listeners.permits(port, peer.ip()) => {
+                debug!("Refusing UDP peer {peer} on port {port}, it is not allowed");
+            },
+            EventType::NewUDPPeer(port, peer, _) if listeners.schedules.get(&port).is_some_and(|(_, active)| !active) => {
+                debug!("UDP port {port} is outside its active hours, refusing peer {peer}");
+            },
+            EventType::NewUDPPeer(port, peer, _) if pending.clients.len() >= MAX_PENDING_CONNECTIONS => {
+                warn!("Too many clients are waiting for the server, refusing UDP peer {peer} on port {port}");
+            },
+            EventType::NewUDPPeer(port, peer, stream) => {
+                let Some(slot) = connection_slot(shared, Port::new_udp(port), peer) else {
+                    continue;
+                };
+                next_conn += 1;
+                let _tag = log::connection(Some(next_conn));
+                debug!("New UDP peer {peer} on port {}, notifying server...", listeners.label(port));
+                let client = ClientInfo { conn: send_conn.then_some(next_conn), ..listeners.client_info(port, peer) };
+                let waiting = PendingClient {
+                    token: new_token(),
+                    port: Port::new_udp(port),
+                    addr: peer,
+                    stream: Box::new(stream),
+                    deadline: Instant::now() + gcfg.connect_timeout,
+                    slot,
+                    conn: next_conn
+                };
+                match &mux {
+                    Some(mux) => pipe_client(Box::new(open_stream(&sender, mux, &waiting, client)?), waiting, listeners)?,
+                    None => {
+                        let token = waiting.token;
+                        pending.insert(sealed_token(&data_cipher, &token)?, waiting);
+                        request_connection(&sender, Port::new_udp(port), token, client)?;
                     }
                 }
-                spawn_pipes(tcp, new_socket).context("Spawning pipe failed")?;
             }
         }
     }
     Err(anyhow!("Control socket closed"))
 }
 
+/* The token the connection of the server for a client will present (sealed), which also identifies the connection on both ends */
+fn new_token() -> [u8; TCP_CHALLENGE_LENGTH] {
+    let mut token = [0u8; TCP_CHALLENGE_LENGTH];
+    OsRng.fill_bytes(&mut token);
+    token
+}
+
+/* What the server presents when it connects back, by which the pending client is found */
+fn sealed_token(data_cipher: &Cipher, token: &[u8]) -> Result<[u8; SEALED_TOKEN_LENGTH]> {
+    data_cipher.seal_token(token).context("Failed to seal the token of a client")?
+        .try_into().map_err(|sealed: Vec<u8>| anyhow!("Sealed token of {} bytes instead of {SEALED_TOKEN_LENGTH}", sealed.len()))
+}
+
+/* Past the limits the client is refused, with a warning now and then rather than one for each of them */
+fn connection_slot(shared: &Shared, port: Port, client: SocketAddr) -> Option<ConnectionSlot> {
+    let slot = shared.connections.acquire(port);
+    if slot.is_none() {
+        let gcfg = shared.gcfg();
+        let (max, max_per_port) = (gcfg.max_connections, gcfg.max_connections_per_port);
+        match shared.connections.warning() {
+            Some(refused) => warn!("Too many connections are forwarded ({max} in total, {max_per_port} per port), refusing {client} on port {} ({refused} refused so far)", port.port),
+            None => debug!("Too many connections are forwarded, refusing {client} on port {}", port.port)
+        }
+    }
+    slot
+}
+
+/* Ask the server to connect back for a client, once the acceptor knows the token */
+fn request_connection(sender: &Mutex<ControlSender>, port: Port, token: [u8; TCP_CHALLENGE_LENGTH], client: ClientInfo) -> Result<()> {
+    sender.lock().unwrap().send(&ControlMessage::NewConnection { port, challenge: token, client })
+        .context("Failed to notify server of new connection")?;
+    debug!("Server has been notified. Now waiting for a matching connection...");
+    Ok(())
+}
+
+/* With multiplex, the client gets a stream of the control connection instead, which the server pipes as it opens */
+fn open_stream(sender: &Mutex<ControlSender>, mux: &Arc<Mux>, client: &PendingClient, info: ClientInfo) -> Result<MuxStream> {
+    let stream = mux.open();
+    sender.lock().unwrap().send(&ControlMessage::Stream { id: stream.id(), frame: StreamFrame::Open { port: client.port, token: client.token, client: info } })
+        .context("Failed to open a stream for a new connection")?;
+    Ok(stream)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn unix_time_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/* Between the connections of its server, a session holds the new clients until the server pairs again or the grace
+   period is over, after which the ports refuse their clients (or answer them with their maintenance) until a server
+   registers them.
+   Returns None once the session is over */
+fn wait_for_server(shared: &Shared, session: u64, rx: &Receiver<EventType>, listeners: &mut Listeners, held: &mut Option<Held>) -> Option<Pairing> {
+    if held.is_none() {
+        listeners.clear();
+    }
+    shared.sessions.lock().unwrap().entries.get_mut(&session)?.waiting = true;
+    let max_clients = shared.gcfg().reconnect_grace_max_clients;
+    // Clients of the ports held by their maintenance, for whichever server comes next
+    let mut queued = Vec::new();
+    loop {
+        if held.is_none() && listeners.bound.is_empty() {
+            let mut sessions = shared.sessions.lock().unwrap();
+            // Unless a server is being handed to it
+            if sessions.entries.get(&session).is_none_or(|entry| entry.waiting) {
+                sessions.entries.remove(&session);
+                return None;
+            }
+        }
+        let event = match held {
+            Some(held) => rx.recv_timeout(held.deadline.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        };
+        match event {
+            Ok(EventType::Resume(pairing)) => {
+                for (port, tcp) in queued {
+                    let _ = listeners.tx.send(EventType::NewTCPConnection(port, tcp));
+                }
+                return Some(*pairing);
+            }
+            Ok(EventType::Superseded) => {
+                info!("Another server registered the ports of the session, ending it");
+                if let Some(held) = held.take() {
+                    held.release();
+                }
+                return None;
+            }
+            Ok(EventType::NewTCPConnection(port, tcp)) => match held {
+                Some(held) => held.hold(max_clients, port, tcp),
+                None if queued.len() >= max_clients => {
+                    debug!("Too many connections are held, refusing connection on port {port}");
+                    let _ = tcp.shutdown(Shutdown::Both);
+                }
+                None => queued.push((port, tcp))
+            },
+            // Left over by the last connection
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout) => {
+                info!("Server did not come back in time, unbinding its ports");
+                if let Some(held) = held.take() {
+                    held.release();
+                }
+                listeners.clear();
+            }
+            Err(RecvTimeoutError::Disconnected) => return None
+        }
+    }
+}
+
+/* Each session runs on its own thread, from the first connection of its server until none of its ports are bound */
+fn session(shared: Arc<Shared>, id: u64, tx: Sender<EventType>, rx: Receiver<EventType>, mut pairing: Pairing) {
+    {
+        let tx = tx.clone();
+        log::spawn(move || ticker(tx));
+    }
+    let mut listeners = Listeners::new(&shared, id, tx);
+    let mut held = None;
+    let result = loop {
+        let server_ip = pairing.addr.ip();
+        let result = gateway(&shared, pairing, &rx, &mut listeners, &mut held);
+        let gcfg = shared.gcfg();
+        // The server pairs again right away, its ports refuse their clients until then
+        let expired = result.as_ref().is_err_and(|err| err.is::<Expired>());
+        let result = match result {
+            Err(err) if expired => {
+                info!("{err:#}, unbinding its ports until the server pairs again");
+                Ok(())
+            }
+            result => result
+        };
+        if gcfg.one_session {
+            break result;
+        }
+        if let Err(err) = result {
+            warn!("Gateway session finished, waiting for the server, reason: {err:#}");
+        }
+        held = gcfg.reconnect_grace.filter(|_| !expired).map(|grace| Held {
+            server_ip,
+            deadline: Instant::now() + grace,
+            clients: Vec::new()
+        });
+        match wait_for_server(&shared, id, &rx, &mut listeners, &mut held) {
+            Some(next) => pairing = next,
+            None => break Ok(())
+        }
+    };
+    listeners.close();
+    shared.sessions.lock().unwrap().entries.remove(&id);
+    info!("Session over");
+    let _ = shared.ended.send(result);
+}
+
+/* A server that paired resumes the waiting session its ports belong to, preferably the one it was the server of, which
+   ends the other waiting sessions it takes ports from. Otherwise it starts a new session. It is refused when some of
+   its ports are served by an active session, or when max_sessions are active already */
+fn start_session(shared: &Arc<Shared>, socket: TcpStream, addr: SocketAddr, paired: Paired) -> Result<()> {
+    let ports : HashSet<Port> = paired.registrations.iter().map(|x| x.port).collect();
+    let mut sessions = loop {
+        let mut sessions = shared.sessions.lock().unwrap();
+        if let Some(id) = sessions.entries.iter().find(|(_, entry)| !entry.waiting && !entry.ports.is_disjoint(&ports)).map(|(id, _)| *id) {
+            drop(sessions);
+            return refuse(socket, addr, paired, format!("some of its ports are served by session {id}"));
+        }
+        let mut waiting : Vec<(bool, u64)> = sessions.entries.iter()
+            .filter(|(_, entry)| !entry.ports.is_disjoint(&ports))
+            .map(|(id, entry)| (entry.server.ip() != addr.ip(), *id)).collect();
+        waiting.sort();
+        if waiting.len() < 2 {
+            break sessions;
+        }
+        // Their ports are unbound once their thread is over
+        let superseded : Vec<_> = waiting[1..].iter().filter_map(|(_, id)| {
+            let entry = sessions.entries.remove(id)?;
+            let _ = entry.tx.send(EventType::Superseded);
+            entry.thread
+        }).collect();
+        drop(sessions);
+        for thread in superseded {
+            let _ = thread.join();
+        }
+    };
+    sessions.next_id += 1;
+    let connection_id = sessions.next_id;
+    let mut pairing = Pairing { socket, addr, paired, connection_id };
+    let resumed = sessions.entries.iter().find(|(_, entry)| !entry.ports.is_disjoint(&ports)).map(|(id, _)| *id);
+    if let Some(id) = resumed {
+        let entry = sessions.entries.get_mut(&id).unwrap();
+        info!("Server {addr} resumes session {id}");
+        entry.waiting = false;
+        (entry.server, entry.paired_at) = (addr, Instant::now());
+        entry.ports.extend(&ports);
+        if let Err(err) = entry.tx.send(EventType::Resume(Box::new(pairing))) {
+            // Its thread died
+            let EventType::Resume(resumed) = err.0 else { unreachable!() };
+            pairing = *resumed;
+            sessions.entries.remove(&id);
+        } else {
+            return Ok(());
+        }
+    }
+    let active = sessions.entries.values().filter(|entry| !entry.waiting).count();
+    if active >= shared.gcfg().max_sessions {
+        drop(sessions);
+        let Pairing { socket, addr, paired, .. } = pairing;
+        return refuse(socket, addr, paired, format!("the gateway already serves {active} servers (max_sessions)"));
+    }
+    let (tx, rx) = channel();
+    info!("Server {addr} starts session {connection_id}");
+    let thread = {
+        let (shared, tx) = (shared.clone(), tx.clone());
+        thread::spawn(move || session(shared, connection_id, tx, rx, pairing))
+    };
+    sessions.entries.insert(connection_id, SessionEntry { server: addr, paired_at: Instant::now(), ports, pipes: None, transferred: Arc::new(AtomicU64::new(0)), tx, thread: Some(thread), waiting: false });
+    Ok(())
+}
+
+/* Closed once the server read why, closing it with unread pings would reset it before */
+fn refuse(mut socket: TcpStream, addr: SocketAddr, mut paired: Paired, reason: String) -> Result<()> {
+    warn!("Refusing the server {addr}: {reason}");
+    control::write_message(&mut socket, &mut paired.to_server, &ControlMessage::Error { code: ErrorCode::SessionRefused, message: reason })
+        .context("Failed to notify the server that it is refused")?;
+    socket.shutdown(Shutdown::Write).context("Failed to close the refused server's connection")?;
+    socket.set_read_timeout(Some(Duration::new(1,0))).context("Refused server; set read time out failed")?;
+    let mut buf = [0u8; 1024];
+    while socket.read(&mut buf).is_ok_and(|n| n > 0) {}
+    Ok(())
+}
+
+/* Whoever connects to the gateway's port is either a server pairing, or one connecting back for a client with its
+   token, which tells the session the client waits in. Short of either, what was sent tells what kind of bot it is */
+/* `upgraded` once the socket is the relay of a WebSocket, which isn't upgraded again */
+fn sort_candidate(shared: &Arc<Shared>, mut socket: TcpStream, addr: SocketAddr, upgraded: bool) -> Result<()> {
+    socket.set_nonblocking(false).context("Candidate; set blocking failed")?;
+    socket.set_read_timeout(Some(Duration::new(1,0))).context("Candidate; set read time out failed")?;
+    let mut first = [0u8; SEALED_TOKEN_LENGTH];
+    let mut read = 0;
+    while read < MAGIC1_LENGTH {
+        match socket.read(&mut first[read..MAGIC1_LENGTH]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) if read == 0 && matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+            Err(err) => return Err(err).context("Candidate; read failed")
+        }
+    }
+    let gcfg = shared.gcfg();
+    if let Some(path) = gcfg.websocket_path.as_ref().filter(|_| !upgraded && first[..read].starts_with(b"GET ")) {
+        let relayed = socket.try_clone().context("Candidate; clone failed")?;
+        socket.set_read_timeout(Some(gcfg.challenge_timeout)).context("Candidate; set read time out failed")?;
+        if let Some(relayed) = websocket::accept(relayed, &first[..read], path).with_context(|| format!("WebSocket upgrade from {addr} failed"))? {
+            debug!("WebSocket upgrade from {addr}");
+            return sort_candidate(shared, relayed, addr, true);
+        }
+    }
+    let keys = server_keys(&shared.ccfg, &gcfg);
+    let magic1 : [u8; MAGIC1_LENGTH] = first[..MAGIC1_LENGTH].try_into().unwrap();
+    if read == MAGIC1_LENGTH && keys.iter().any(|(_, magics)| crypto::constant_eq(&magic1, &magics.magic1)) {
+        info!("Server candidate connected from {addr}");
+        common::set_keepalive(&socket, shared.ccfg.keepalive);
+        return match pair(&shared.ccfg, &gcfg, &shared.bans, &mut socket, addr, &keys, &magic1)? {
+            Some(paired) => start_session(shared, socket, addr, paired),
+            None => Ok(())
+        };
+    }
+    let token = read == MAGIC1_LENGTH && socket.set_read_timeout(Some(gcfg.challenge_timeout))
+        .and_then(|()| socket.read_exact(&mut first[MAGIC1_LENGTH..]))
+        .and_then(|()| socket.set_read_timeout(None))
+        .is_ok();
+    if token {
+        let sessions = shared.sessions.lock().unwrap();
+        if let Some(entry) = sessions.tokens.get(&first).and_then(|id| sessions.entries.get(id)) {
+            common::set_keepalive(&socket, shared.ccfg.keepalive.filter(|x| x.data));
+            let _ = entry.tx.send(EventType::DataConnection(addr, first, socket));
+            return Ok(());
+        }
+    }
+    // Answered like any other wrong magic, the gateway must not tell that it knows it
+    if read == MAGIC1_LENGTH && FIXED_MAGICS.iter().any(|fixed| crypto::constant_eq(&magic1, *fixed)) {
+        shared.bans.fail(addr.ip(), gcfg.ban);
+        return Err(anyhow!("{addr} sent the fixed magic of the versions older than the gateway, which derives it from the key: update the server"));
+    }
+    match scan::classify(&first[..read]) {
+        scan::ProbeKind::Other if token => Err(anyhow!("{addr} did not present the token of a waiting client")),
+        kind => {
+            SCANS.record(socket.local_addr().map_or(0, |x| x.port()), kind.clone());
+            shared.bans.fail(addr.ip(), gcfg.ban);
+            Err(anyhow!("{addr} did not send the correct magic; it's probably some kind of bot ({kind})"))
+        }
+    }
+}
+
+/* In the format of the server's: segments separated by "; ", each made of space separated key=value fields */
+fn status_command(shared: &Shared) -> String {
+    let quota = shared.gcfg().session_quota;
+    let sessions = shared.sessions.lock().unwrap();
+    let mut segments = vec![format!("gateway uptime={} sessions={} banned={}", shared.started.elapsed().as_secs(), sessions.entries.len(), shared.bans.banned())];
+    let mut ids : Vec<&u64> = sessions.entries.keys().collect();
+    ids.sort();
+    for id in ids {
+        let entry = &sessions.entries[id];
+        let mut ports : Vec<&Port> = entry.ports.iter().collect();
+        ports.sort_by_key(|port| port.port);
+        let ports = match ports.is_empty() {
+            true => "-".to_string(),
+            false => ports.iter().map(|port| port.to_string()).collect::<Vec<String>>().join(",")
+        };
+        segments.push(format!("session={id} server={} state={} uptime={} ports={ports} pipes={}", entry.server,
+            if entry.waiting { "waiting" } else { "up" }, entry.paired_at.elapsed().as_secs(), entry.pipes.as_ref().map_or(0, |pipes| pipes.len())));
+        if let Some(quota) = quota {
+            let left = quota.saturating_sub(entry.transferred.load(Ordering::Relaxed));
+            segments.push(format!("quota session={id} left={:.1}MB of={}MB", left as f64 / 1_000_000.0, quota / 1_000_000));
+        }
+    }
+    let memory = MEMORY.stats();
+    segments.push(format!("memory used={} budget={} downsized={} rejected={}", memory.used,
+        memory.limit.map_or("none".to_string(), |limit| limit.to_string()), memory.downsized, memory.rejected));
+    segments.extend(connection_segments(&sessions));
+    segments.join("; ")
+}
+
+/* The pipes of every session, their IDs are unique across them */
+fn connection_segments(sessions: &Sessions) -> Vec<String> {
+    let mut ids : Vec<&u64> = sessions.entries.keys().collect();
+    ids.sort();
+    ids.into_iter()
+        .filter_map(|id| Some((id, sessions.entries[id].pipes.as_ref()?)))
+        .flat_map(|(id, pipes)| pipes.list().into_iter().map(move |pipe| format!("{} session={id}", admin::pipe_segment(&pipe))))
+        .collect()
+}
+
+fn kick_command(shared: &Shared, id: &str) -> Result<String> {
+    let id : u64 = id.parse().with_context(|| format!("{id} is not a valid pipe ID"))?;
+    let sessions = shared.sessions.lock().unwrap();
+    if !sessions.entries.values().filter_map(|entry| entry.pipes.as_ref()).any(|pipes| pipes.kill(id)) {
+        return Err(anyhow!("There is no pipe {id}"));
+    }
+    info!("Pipe {id} killed from the admin socket");
+    Ok(format!("pipe {id} killed"))
+}
+
+/* A port knocked on with both protocols is bound once for each */
+fn listen_knocks(gcfg: &GatewayConfig) -> Result<Arc<Knocks>> {
+    let knocks = Knocks::new(gcfg.knock.clone(), gcfg.knock_window);
+    let ports : HashSet<Port> = gcfg.knock.iter().copied().collect();
+    for port in ports {
+        let address = SocketAddr::new(gcfg.bind_address, port.port);
+        match port.protocol {
+            Protocol::TCP => knocks.serve_tcp(bind(address, gcfg.address_family, |address, v6only| sockopt::bind_tcp(address, v6only, &gcfg.socket)).with_context(|| format!("Failed to bind the knock port TCP {address}"))?, port.port),
+            Protocol::UDP => knocks.serve_udp(bind(address, gcfg.address_family, |address, v6only| sockopt::bind_udp(address, v6only, &gcfg.socket)).with_context(|| format!("Failed to bind the knock port UDP {address}"))?, port.port)
+        }
+    }
+    info!("The gateway's port only answers the sources that knock the sequence of {} ports", gcfg.knock.len());
+    Ok(knocks)
+}
+
 pub fn main(ccfg: CommonConfig, gcfg: GatewayConfig) -> Result<()> {
-    let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], gcfg.port))).context("Failed to bind gateway address. Is another process already running?")?;
-    println!("Gateway started.");
+    let hangups = Hangups::block()?;
+    let _accounting = accounting::start(&ccfg.accounting)?;
+    MEMORY.set_limit(ccfg.buffers.budget);
+    PipeWorkers::start(ccfg.pipe_workers).context("Failed to start the pipe workers")?;
+    let listener = match activation::listener(activation::CONTROL_SOCKET).context("Failed to adopt the socket passed by systemd").context(Failure::Config)? {
+        Some(listener) => {
+            info!("Using the socket passed by systemd, {}", listener.local_addr().context("Failed to get the address of the socket passed by systemd")?);
+            listener
+        }
+        None => {
+            let address = SocketAddr::new(gcfg.bind_address, gcfg.port);
+            info!("Listening for the server on {address}");
+            bind(address, gcfg.address_family, |address, v6only| sockopt::bind_tcp(address, v6only, &gcfg.socket)).with_context(|| format!("Failed to bind gateway address {address}. Is another process already running?"))?
+        }
+    };
+    if let Some(interval) = gcfg.scan_summary_interval {
+        scan::spawn_summary(interval, listener.local_addr().map_or(gcfg.port, |x| x.port()));
+    }
+    let knocks = match gcfg.knock.is_empty() {
+        true => None,
+        false => Some(listen_knocks(&gcfg)?)
+    };
+    // Non-blocking, to notice when the session ended with one_session
+    listener.set_nonblocking(true).context("Set listener to non-blocking failed")?;
+    let (ended, results) = channel();
+    let connections = ConnectionLimits::new(gcfg.max_connections, gcfg.max_connections_per_port);
+    let admin_socket = gcfg.admin_socket.clone();
+    let shared = Arc::new(Shared { ccfg, gcfg: Mutex::new(Arc::new(gcfg)), sessions: Mutex::default(), connections, bans: Bans::default(), started: Instant::now(), ended });
+    {
+        let shared = shared.clone();
+        admin::serve(&admin_socket, move |command| {
+            let args : Vec<&str> = command.split_whitespace().collect();
+            match args.as_slice() {
+                ["status"] => Ok(status_command(&shared)),
+                ["connections"] => Ok(connection_segments(&shared.sessions.lock().unwrap()).join("; ")),
+                ["traffic"] => accounting::traffic_command(),
+                ["kick", id] | ["pipe", "kill", id] => kick_command(&shared, id),
+                _ => Err(anyhow!("Unknown command {command}"))
+            }
+        })?;
+    }
+    {
+        let shared = shared.clone();
+        hangups.handle(move || match shared.reload() {
+            Ok(()) => info!("Configuration reloaded"),
+            Err(err) => error!("Failed to reload the configuration, keeping the current one, reason: {err:#}")
+        });
+    }
+    let candidates = Arc::new(AtomicUsize::new(0));
+    // Dropped for not being in allowed_servers, warned about now and then
+    let (mut unallowed, mut last_warning) = (0u64, None::<Instant>);
+    let mut errors = AcceptErrors::default();
+    let mut last_expiry = Instant::now();
+    if let Some(previous) = &shared.gcfg().previous_key {
+        info!("Servers may also use the previous key ({})", crypto::fingerprint(previous));
+    }
+    info!("Gateway started.");
+    activation::ready(&format!("Listening for the servers on {}", listener.local_addr().map_or_else(|_| shared.gcfg().port.to_string(), |x| x.to_string())));
     loop {
-        listener.set_nonblocking(false)?; // Set to blocking (because the gateway function sets it to nonblocking which isn't what we want)
+        if shared.gcfg().one_session {
+            if let Ok(result) = results.try_recv() {
+                return result;
+            }
+        }
+        if last_expiry.elapsed() >= EXPIRY_INTERVAL {
+            last_expiry = Instant::now();
+            shared.bans.expire(shared.gcfg().ban);
+            if let Some(knocks) = &knocks {
+                knocks.expire();
+            }
+        }
         match listener.accept() {
-            Err(e) => eprintln!("Client connection failed {e:?}, ignoring"),
-            Ok((socket,addr)) => if let Err(err) = gateway(&ccfg, &gcfg, &listener, socket, addr) {
-                eprintln!("Gateway session finished. Details:\n{err:?}\ntransitioning into pairing mode...");
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if let Err(err) = sockopt::wait_acceptable(&listener, ACCEPT_POLL_INTERVAL) {
+                    warn!("Failed to wait for connections, reason: {err:#}");
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                }
+            }
+            Err(e) => {
+                match errors.fail() {
+                    Some(count) => warn!("Client connection failed, ignoring, reason: {e:#} ({count} failed so far)"),
+                    None => debug!("Client connection failed, ignoring, reason: {e:#}")
+                }
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+            Ok((_, addr)) if !shared.gcfg().allows_server(addr.ip()) => {
+                unallowed += 1;
+                if last_warning.is_none_or(|last| last.elapsed() >= UNALLOWED_WARNING_INTERVAL) {
+                    last_warning = Some(Instant::now());
+                    warn!("Dropping the connection from {}, it is not in allowed_servers ({unallowed} dropped so far)", SocketAddr::new(addr.ip().to_canonical(), addr.port()));
+                }
+            }
+            // Already warned about when banned
+            Ok((_, addr)) if shared.bans.is_banned(addr.ip()) => {}
+            // The servers of the sessions dial back without knocking
+            Ok((_, addr)) if knocks.as_ref().is_some_and(|x| !x.is_open(addr.ip())) && !shared.sessions.lock().unwrap().serves(addr.ip()) => {}
+            Ok((_, addr)) if candidates.load(Ordering::Relaxed) >= MAX_CANDIDATES => {
+                debug!("Too many connections to the gateway's port are being sorted, dropping the one from {addr}");
+            }
+            Ok((socket, addr)) => {
+                // A server reaching a dual-stack listener over IPv4 shows up with an IPv4-mapped address
+                let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                debug!("Candidate connection from {addr}");
+                candidates.fetch_add(1, Ordering::Relaxed);
+                let (shared, candidates) = (shared.clone(), candidates.clone());
+                thread::spawn(move || {
+                    if let Err(err) = sort_candidate(&shared, socket, addr, false) {
+                        warn!("{err:#}");
+                    }
+                    candidates.fetch_sub(1, Ordering::Relaxed);
+                });
             }
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::fixtures::{temp_config, TempDir};
+
+    /* For the listener thread to be scheduled */
+    const MARGIN : Duration = Duration::from_millis(200);
+
+    /* What the threads of a gateway of its own directory share, with the forwarded ports on localhost */
+    fn shared(name: &str, options: &str) -> (TempDir, Arc<Shared>) {
+        let (dir, ccfg, gcfg) = match temp_config(name, &format!("mode = \"gateway\"\nport = 1\nforward_bind_address = \"127.0.0.1\"\n{options}")) {
+            (dir, ccfg, SpecificConfig::Gateway(gcfg)) => (dir, ccfg, gcfg),
+            _ => unreachable!()
+        };
+        let connections = ConnectionLimits::new(gcfg.max_connections, gcfg.max_connections_per_port);
+        let shared = Shared { ccfg, gcfg: Mutex::new(Arc::new(gcfg)), sessions: Mutex::default(), connections, bans: Bans::default(), started: Instant::now(), ended: channel().0 };
+        (dir, Arc::new(shared))
+    }
+
+    /* Bound as `register` does, returns the address it got */
+    fn listen(address: SocketAddr, connections: &Arc<ConnectionLimits>, tx: &Sender<EventType>) -> (SocketAddr, Arc<ListenerState>, thread::JoinHandle<Result<()>>) {
+        let listener = sockopt::bind_tcp(address, false, &ListenOptions::default()).unwrap();
+        let address = listener.local_addr().unwrap();
+        let state = Arc::new(ListenerState::default());
+        let (listener_state, connections, tx) = (state.clone(), connections.clone(), tx.clone());
+        let thread = thread::spawn(move || tcp_listener(listener, address, listener_state, connections, Arc::new(AtomicU64::new(0)), tx));
+        (address, state, thread)
+    }
+
+    /* A TCP redirect of a port that was free a moment ago */
+    fn registration() -> Registration {
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+        Registration { port: Port::new_tcp(port), schedule: None, hide_client: false, access: AccessRules::default(), first_byte_timeout: None,
+            maintenance: None, paused: false, verify_integrity: false, encrypt: false, name: None, pipe_buffer: None, nodelay: true }
+    }
+
+    /* Whether a new client of the port is forwarded to the session, or reset right away */
+    fn forwarded(port: Port, rx: &Receiver<EventType>) -> bool {
+        let mut client = TcpStream::connect((Ipv4Addr::LOCALHOST, port.port)).unwrap();
+        match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(EventType::NewTCPConnection(forwarded, _)) => {
+                assert_eq!(forwarded, port.port, "not a client of the port");
+                true
+            }
+            Ok(_) => panic!("not a client of port {}", port.port),
+            Err(_) => {
+                client.set_read_timeout(Some(MARGIN)).unwrap();
+                assert!(matches!(client.read(&mut [0u8; 1]), Err(err) if err.kind() == io::ErrorKind::ConnectionReset),
+                    "the client of port {} should be either forwarded or reset", port.port);
+                false
+            }
+        }
+    }
+
+    #[test]
+    fn ports_refuse_their_clients_between_sessions() {
+        let (_dir, shared) = shared("between-sessions", "");
+        let (tx, rx) = channel();
+        let mut listeners = Listeners::new(&shared, 1, tx);
+        let registrations = [registration()];
+        let port = registrations[0].port;
+        listeners.sync(&registrations);
+        assert!(forwarded(port, &rx), "the client of a session should be forwarded");
+        listeners.clear();
+        let started = Instant::now();
+        assert!(!forwarded(port, &rx), "the client of a port between two sessions should be reset");
+        assert!(started.elapsed() < Duration::from_secs(1) + MARGIN, "the client of a port between two sessions was reset after {:?}", started.elapsed());
+        listeners.sync(&registrations);
+        assert!(forwarded(port, &rx), "the client of the next session should be forwarded");
+        listeners.close();
+        assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, port.port)).is_err(), "the port should be unbound once the session is over");
+    }
+
+    #[test]
+    fn ports_can_be_unbound_between_sessions() {
+        let (_dir, shared) = shared("unbound-between-sessions", "keep_ports_bound = false\n");
+        let (tx, rx) = channel();
+        let mut listeners = Listeners::new(&shared, 1, tx);
+        let registrations = [registration()];
+        let port = registrations[0].port;
+        listeners.sync(&registrations);
+        assert!(forwarded(port, &rx), "the client of a session should be forwarded");
+        listeners.clear();
+        assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, port.port)).is_err(), "with keep_ports_bound = false, the port should be unbound");
+        listeners.sync(&registrations);
+        assert!(forwarded(port, &rx), "the client of the next session should be forwarded");
+        listeners.close();
+    }
+
+    #[test]
+    fn slow_clients_hold_a_responder_until_the_timeout_only() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        // A byte every 300ms, within the timeout of each read
+        thread::spawn(move || {
+            for _ in 0..10 {
+                if client.write_all(b"G").is_err() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(300));
+            }
+        });
+        let started = Instant::now();
+        let _ = respond(80, socket, b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n");
+        assert!(started.elapsed() < Duration::from_secs(RESPONDER_TIMEOUT) + MARGIN, "the responder was held for {:?}", started.elapsed());
+    }
 
+    #[test]
+    fn silent_clients_count_against_the_limits() {
+        let (tx, rx) = channel();
+        let connections = ConnectionLimits::new(16, 2);
+        let (address, state, thread) = listen(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0), &connections, &tx);
+        state.first_byte_timeout.store(1000, Ordering::Relaxed);
+        let mut talking = TcpStream::connect(address).unwrap();
+        let mut silent = TcpStream::connect(address).unwrap();
+        thread::sleep(MARGIN);
+        let mut refused = TcpStream::connect(address).unwrap();
+        refused.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        assert!(matches!(refused.read(&mut [0u8; 1]), Err(err) if err.kind() == io::ErrorKind::ConnectionReset),
+            "a client past max_connections_per_port should be reset while the others wait");
+        talking.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(EventType::NewTCPConnection(port, _)) => assert_eq!(port, address.port()),
+            Ok(_) => panic!("not a client of the port"),
+            Err(err) => panic!("the client that talked wasn't forwarded: {err}")
+        }
+        silent.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        assert_eq!(silent.read(&mut [0u8; 1]).unwrap(), 0, "the silent client should be closed after its timeout");
+        let slots : Vec<_> = (0..2).filter_map(|_| connections.acquire(Port::new_tcp(address.port()))).collect();
+        assert_eq!(slots.len(), 2, "the waiting clients should give their slots back");
+        state.stop.store(true, Ordering::Relaxed);
+        thread.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn ports_work_after_ten_reconnections() {
+        for options in ["", "keep_ports_bound = false\n"] {
+            let (_dir, shared) = shared("reconnections", options);
+            let (tx, rx) = channel();
+            let mut listeners = Listeners::new(&shared, 1, tx);
+            // The same ports each time, as a server that reconnects registers them again
+            let registrations = [registration(), registration(), registration()];
+            for session in 0..10 {
+                let statuses = listeners.sync(&registrations);
+                assert!(statuses.iter().all(|(_, status)| status.is_none()), "session {session} ({options:?}): some ports weren't bound");
+                for registration in &registrations {
+                    assert!(forwarded(registration.port, &rx), "session {session} ({options:?}): port {} doesn't forward its clients", registration.port.port);
+                }
+                // As the server disconnects
+                listeners.clear();
+            }
+            listeners.close();
+            assert!(listeners.threads.is_empty(), "{options:?}: the listener threads should be joined");
+            for registration in &registrations {
+                assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, registration.port.port)).is_err(), "{options:?}: port {} is still bound", registration.port.port);
+            }
+        }
+    }
+
+    #[test]
+    fn accept_errors_are_warned_about_now_and_then() {
+        let mut errors = AcceptErrors::default();
+        assert_eq!(errors.fail(), Some(1), "the first failure should be warned about");
+        assert_eq!((0..100).filter_map(|_| errors.fail()).count(), 0, "the next ones should wait for ACCEPT_WARNING_INTERVAL");
+        errors.last_warning = Some(Instant::now() - ACCEPT_WARNING_INTERVAL);
+        assert_eq!(errors.fail(), Some(102), "the warning should count the failures so far");
+    }
+
+    #[test]
+    fn data_connections_are_matched_right_away() {
+        let (_dir, shared) = shared("matching", "");
+        let (tx, rx) = channel();
+        let session = 1;
+        shared.sessions.lock().unwrap().entries.insert(session, SessionEntry {
+            server: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0), paired_at: Instant::now(), ports: HashSet::new(), pipes: None,
+            transferred: Arc::new(AtomicU64::new(0)), tx, thread: None, waiting: false
+        });
+        // Accepted as by `main`, which waits on the non-blocking listener
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        {
+            let shared = shared.clone();
+            thread::spawn(move || loop {
+                match listener.accept() {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => { sockopt::wait_acceptable(&listener, ACCEPT_POLL_INTERVAL).unwrap(); }
+                    Err(err) => panic!("{err}"),
+                    Ok((socket, addr)) => sort_candidate(&shared, socket, addr, false).unwrap()
+                }
+            });
+        }
+        let mut latencies = Vec::new();
+        for _ in 0..20 {
+            let mut token = [0u8; SEALED_TOKEN_LENGTH];
+            OsRng.fill_bytes(&mut token);
+            shared.sessions.lock().unwrap().tokens.insert(token, session);
+            // Whatever the listener was doing, as clients come at any time
+            thread::sleep(Duration::from_millis(OsRng.next_u64() % 20));
+            let started = Instant::now();
+            let mut server = TcpStream::connect(address).unwrap();
+            server.write_all(&token).unwrap();
+            match rx.recv_timeout(Duration::from_secs(1)) {
+                Ok(EventType::DataConnection(_, matched, _)) => assert_eq!(matched, token),
+                Ok(_) => panic!("not a data connection"),
+                Err(err) => panic!("the data connection wasn't matched: {err}")
+            }
+            latencies.push(started.elapsed());
+            shared.sessions.lock().unwrap().tokens.remove(&token);
+        }
+        latencies.sort();
+        let median = latencies[latencies.len() / 2];
+        assert!(median < Duration::from_millis(1), "matched in {median:?}, the fastest in {:?}", latencies[0]);
+    }
+}
@@ -0,0 +1,262 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! `verify_integrity`: both ends of the tunnel hash what goes through each direction of a connection,
+//! the gateway sends its digests to the server once the connection is over, and the server compares them.
+
+use crate::{error, info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Odd, so that multiplying by it can't make two states collide
+const MULTIPLIER : u64 = 0x9e37_79b9_7f4a_7c15;
+/// How long the digests of one end wait for those of the other
+const PENDING_TIMEOUT : Duration = Duration::from_secs(60);
+pub const PIPE_DIGESTS_LENGTH : usize = 2 * DIRECTION_DIGEST_LENGTH;
+const DIRECTION_DIGEST_LENGTH : usize = 8 + 8 + 1;
+
+/* Not cryptographic, this is about catching corruption: every step is a bijection of the state,
+   so a change confined to a single 8 bytes word always changes the digest */
+fn mix(state: u64, word: u64) -> u64 {
+    (state ^ word).wrapping_mul(MULTIPLIER).rotate_left(31)
+}
+
+/// Running hash of a stream, fed with chunks of any size
+#[derive(Default)]
+pub struct Digest {
+    state: u64,
+    pending: [u8; 8],
+    pending_length: usize,
+    bytes: u64
+}
+
+impl Digest {
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.bytes += data.len() as u64;
+        if self.pending_length > 0 {
+            let length = data.len().min(8 - self.pending_length);
+            self.pending[self.pending_length..self.pending_length + length].copy_from_slice(&data[..length]);
+            self.pending_length += length;
+            data = &data[length..];
+            if self.pending_length < 8 {
+                return;
+            }
+            self.state = mix(self.state, u64::from_le_bytes(self.pending));
+            self.pending_length = 0;
+        }
+        let words = data.chunks_exact(8);
+        let rest = words.remainder();
+        for word in words {
+            self.state = mix(self.state, u64::from_le_bytes(word.try_into().unwrap()));
+        }
+        self.pending[..rest.len()].copy_from_slice(rest);
+        self.pending_length = rest.len();
+    }
+
+    /// `complete` is whether the stream ended cleanly, rather than being cut
+    pub fn finish(&self, complete: bool) -> DirectionDigest {
+        let mut state = self.state;
+        if self.pending_length > 0 {
+            let mut last = [0u8; 8];
+            last[..self.pending_length].copy_from_slice(&self.pending[..self.pending_length]);
+            state = mix(state, u64::from_le_bytes(last));
+        }
+        state = mix(state, self.bytes);
+        DirectionDigest { bytes: self.bytes, digest: state ^ (state >> 29), complete }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DirectionDigest {
+    pub bytes: u64,
+    pub digest: u64,
+    pub complete: bool
+}
+
+/// Both directions of a connection, as seen by one end of the tunnel
+#[derive(Debug, Copy, Clone)]
+pub struct PipeDigests {
+    pub from_tunnel: DirectionDigest,
+    pub to_tunnel: DirectionDigest
+}
+
+impl PipeDigests {
+    pub fn to_bytes(self) -> [u8; PIPE_DIGESTS_LENGTH] {
+        let mut ret = [0u8; PIPE_DIGESTS_LENGTH];
+        for (i, direction) in [self.from_tunnel, self.to_tunnel].iter().enumerate() {
+            let buf = &mut ret[i * DIRECTION_DIGEST_LENGTH..(i + 1) * DIRECTION_DIGEST_LENGTH];
+            buf[0..8].copy_from_slice(&direction.bytes.to_be_bytes());
+            buf[8..16].copy_from_slice(&direction.digest.to_be_bytes());
+            buf[16] = direction.complete as u8;
+        }
+        ret
+    }
+
+    pub fn from_bytes(buf: &[u8; PIPE_DIGESTS_LENGTH]) -> PipeDigests {
+        let direction = |buf: &[u8]| DirectionDigest {
+            bytes: u64::from_be_bytes(buf[0..8].try_into().unwrap()),
+            digest: u64::from_be_bytes(buf[8..16].try_into().unwrap()),
+            complete: buf[16] != 0
+        };
+        PipeDigests {
+            from_tunnel: direction(&buf[..DIRECTION_DIGEST_LENGTH]),
+            to_tunnel: direction(&buf[DIRECTION_DIGEST_LENGTH..])
+        }
+    }
+}
+
+/// Both ends know the challenge of a connection, which is random: its first bytes identify it
+pub fn connection_id(challenge: &[u8]) -> u64 {
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&challenge[..8]);
+    u64::from_be_bytes(id)
+}
+
+/// Called once both directions of a connection are done
+pub type DigestReport = Arc<dyn Fn(PipeDigests) + Send + Sync>;
+
+/* Shared by the two threads of a pipe, the last one done reports */
+pub struct Collector {
+    directions: Mutex<(Option<DirectionDigest>, Option<DirectionDigest>)>,
+    report: DigestReport
+}
+
+impl Collector {
+    pub fn new(report: DigestReport) -> Arc<Collector> {
+        Arc::new(Collector { directions: Mutex::new((None, None)), report })
+    }
+
+    pub fn done(&self, from_tunnel: bool, digest: DirectionDigest) {
+        let digests = {
+            let mut directions = self.directions.lock().unwrap();
+            if from_tunnel {
+                directions.0 = Some(digest);
+            } else {
+                directions.1 = Some(digest);
+            }
+            match *directions {
+                (Some(from_tunnel), Some(to_tunnel)) => PipeDigests { from_tunnel, to_tunnel },
+                _ => return
+            }
+        };
+        (self.report)(digests);
+    }
+}
+
+enum Side {
+    /// The server's own, with the port of the connection
+    Local(u16, PipeDigests),
+    Gateway(PipeDigests)
+}
+
+/// The digests of the server's connections, until the gateway sent its own (or the other way around)
+#[derive(Default)]
+pub struct Pending(Mutex<HashMap<u64, (Instant, Side)>>);
+
+impl Pending {
+    pub fn local(&self, id: u64, port: u16, digests: PipeDigests) {
+        if let Some(Side::Gateway(gateway)) = self.insert(id, Side::Local(port, digests)) {
+            compare(id, port, &digests, &gateway);
+        }
+    }
+
+    pub fn gateway(&self, id: u64, digests: PipeDigests) {
+        if let Some(Side::Local(port, local)) = self.insert(id, Side::Gateway(digests)) {
+            compare(id, port, &local, &digests);
+        }
+    }
+
+    /* Gives the other side back if it was there, instead of inserting */
+    fn insert(&self, id: u64, side: Side) -> Option<Side> {
+        let mut pending = self.0.lock().unwrap();
+        // The other end may never send its digests, if the connection failed on its side
+        pending.retain(|_, (since, _)| since.elapsed() < PENDING_TIMEOUT);
+        match pending.remove(&id) {
+            Some((_, other)) => Some(other),
+            None => {
+                pending.insert(id, (Instant::now(), side));
+                None
+            }
+        }
+    }
+}
+
+/* Returns whether both directions were verified */
+fn compare(id: u64, port: u16, local: &PipeDigests, gateway: &PipeDigests) -> bool {
+    let directions = [
+        ("client to local", "the gateway sent", gateway.to_tunnel, "the server received", local.from_tunnel),
+        ("local to client", "the server sent", local.to_tunnel, "the gateway received", gateway.from_tunnel)
+    ];
+    let mut verified = true;
+    for (name, sent_by, sent, received_by, received) in directions {
+        if !sent.complete || !received.complete {
+            warn!("Connection {id:016x} on port {port}: {name} was interrupted, it can't be verified ({sent_by} {} bytes, {received_by} {})",
+                sent.bytes, received.bytes);
+            verified = false;
+        } else if sent != received {
+            error!("INTEGRITY ERROR: connection {id:016x} on port {port} was corrupted {name}: {sent_by} {} bytes (digest {:016x}), {received_by} {} bytes (digest {:016x})",
+                sent.bytes, sent.digest, received.bytes, received.digest);
+            verified = false;
+        }
+    }
+    if verified {
+        info!("Connection {id:016x} on port {port} verified: {} bytes from the client, {} bytes from the local side",
+            gateway.to_tunnel.bytes, local.to_tunnel.bytes);
+    }
+    verified
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* Fed in chunks of `chunk` bytes, as the reads of a pipe come */
+    fn digest(data: &[u8], chunk: usize) -> DirectionDigest {
+        let mut digest = Digest::default();
+        for chunk in data.chunks(chunk) {
+            digest.update(chunk);
+        }
+        digest.finish(true)
+    }
+
+    #[test]
+    fn digests_ignore_how_streams_are_cut() {
+        let data : Vec<u8> = (0..1000u32).map(|i| (i * 7 % 256) as u8).collect();
+        let whole = digest(&data, data.len());
+        for chunk in [1, 3, 8, 13, 64] {
+            assert_eq!(digest(&data, chunk), whole, "chunks of {chunk} bytes");
+        }
+        assert_eq!(whole.bytes, 1000);
+        assert_ne!(digest(&data[..999], 64), whole);
+        assert_ne!(digest(&[0u8; 8], 8), digest(&[0u8; 16], 8), "zeros count too");
+    }
+
+    #[test]
+    fn corrupted_bytes_are_caught() {
+        let request : Vec<u8> = (0..1000u32).map(|i| (i * 7 % 256) as u8).collect();
+        let response = b"a response that stays intact".to_vec();
+        let local = |request: &[u8]| PipeDigests { from_tunnel: digest(request, 64), to_tunnel: digest(&response, 64) };
+        // The gateway's digests travel in a message
+        let gateway = PipeDigests::from_bytes(&PipeDigests { from_tunnel: digest(&response, 5), to_tunnel: digest(&request, 7) }.to_bytes());
+        assert!(compare(1, 80, &local(&request), &gateway));
+        for offset in [0, 7, 8, 500, 999] {
+            for flip in [0x01, 0x80] {
+                let mut corrupted = request.clone();
+                corrupted[offset] ^= flip;
+                assert!(!compare(1, 80, &local(&corrupted), &gateway), "byte {offset} flipped with {flip:#04x}");
+            }
+        }
+        let mut interrupted = local(&request);
+        interrupted.from_tunnel.complete = false;
+        assert!(!compare(1, 80, &interrupted, &gateway), "interrupted connections can't be verified");
+    }
+}
@@ -0,0 +1,149 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! Port knocking: with a `knock` sequence, the gateway's port drops every connection before reading anything, unless
+//! its source knocked the ports of the sequence in order shortly before. The knocks are TCP connections, closed as
+//! soon as they are accepted, or UDP datagrams of any content; the server sends them before each connection.
+
+use crate::config::{AddressFamily, Port, Protocol};
+use crate::{debug, warn};
+use anyhow::{anyhow, Result, Context};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Past this many sources knocking at once, the new ones are ignored until some expire
+const MAX_SOURCES : usize = 65536;
+// Between the knocks of the server, for them to arrive in order
+const KNOCK_INTERVAL : Duration = Duration::from_millis(100);
+const TCP_KNOCK_TIMEOUT : Duration = Duration::from_secs(2);
+
+struct Progress {
+    /// Index in the sequence of the knock expected next, the length of the sequence once it's complete
+    next: usize,
+    /// The next knock has to come before it, or the connection once complete
+    deadline: Instant
+}
+
+/// The sources knocking on the gateway, and the ones that knocked the whole sequence
+pub struct Knocks {
+    sequence: Vec<Port>,
+    window: Duration,
+    sources: Mutex<HashMap<IpAddr, Progress>>
+}
+
+impl Knocks {
+    pub fn new(sequence: Vec<Port>, window: Duration) -> Arc<Knocks> {
+        Arc::new(Knocks { sequence, window, sources: Mutex::default() })
+    }
+
+    /* A knock that isn't the one expected starts the sequence over, or forgets the source. The sources the port
+       is open to keep it open, unless they start knocking again */
+    fn knock(&self, ip: IpAddr, port: Port) {
+        let (ip, now) = (ip.to_canonical(), Instant::now());
+        let mut sources = self.sources.lock().unwrap();
+        let current = sources.get(&ip).filter(|x| x.deadline > now).map_or(0, |x| x.next);
+        let next = match current {
+            x if x < self.sequence.len() && self.sequence[x] == port => x + 1,
+            _ if self.sequence[0] == port => 1,
+            x if x == self.sequence.len() => return,
+            _ => {
+                sources.remove(&ip);
+                return;
+            }
+        };
+        if sources.len() >= MAX_SOURCES && !sources.contains_key(&ip) {
+            return;
+        }
+        if next == self.sequence.len() {
+            debug!("{ip} knocked the whole sequence, the gateway's port is open to it for {}s", self.window.as_secs());
+        }
+        sources.insert(ip, Progress { next, deadline: now + self.window });
+    }
+
+    pub fn is_open(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        self.sources.lock().unwrap().get(&ip.to_canonical()).is_some_and(|x| x.next == self.sequence.len() && x.deadline > now)
+    }
+
+    pub fn expire(&self) {
+        let now = Instant::now();
+        self.sources.lock().unwrap().retain(|_, x| x.deadline > now);
+    }
+
+    /// Listen for the knocks on `port`, already bound
+    pub fn serve_tcp(self: &Arc<Self>, listener: TcpListener, port: u16) {
+        let knocks = self.clone();
+        thread::spawn(move || loop {
+            match listener.accept() {
+                Ok((_, addr)) => knocks.knock(addr.ip(), Port { port, protocol: Protocol::TCP }),
+                Err(err) => {
+                    warn!("Failed to accept a knock on TCP port {port}, reason: {err:#}");
+                    thread::sleep(KNOCK_INTERVAL);
+                }
+            }
+        });
+    }
+
+    pub fn serve_udp(self: &Arc<Self>, socket: UdpSocket, port: u16) {
+        let knocks = self.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1];
+            loop {
+                match socket.recv_from(&mut buf) {
+                    Ok((_, addr)) => knocks.knock(addr.ip(), Port { port, protocol: Protocol::UDP }),
+                    Err(err) => {
+                        debug!("Failed to receive a knock on UDP port {port}, reason: {err:#}");
+                        thread::sleep(KNOCK_INTERVAL);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Knock the sequence on every address of the gateway of the family, `address` is `host:port`
+pub fn send(address: &str, family: AddressFamily, sequence: &[Port]) -> Result<()> {
+    let mut ips : Vec<IpAddr> = address.to_socket_addrs().with_context(|| format!("Failed to resolve {address}"))?
+        .map(|addr| addr.ip())
+        .filter(|ip| family.permits(*ip))
+        .collect();
+    ips.dedup();
+    if ips.is_empty() {
+        return Err(anyhow!("{address} has no {family} address"));
+    }
+    for ip in ips {
+        let unspecified = match ip {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        };
+        for (i, knock) in sequence.iter().enumerate() {
+            if i > 0 {
+                thread::sleep(KNOCK_INTERVAL);
+            }
+            let target = SocketAddr::new(ip, knock.port);
+            match knock.protocol {
+                Protocol::UDP => {
+                    let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0)).context("Failed to bind a socket to knock")?;
+                    socket.send_to(&[], target).with_context(|| format!("Failed to knock on UDP {target}"))?;
+                }
+                // Refused when the gateway doesn't listen for knocks there, which only shows once connecting fails
+                Protocol::TCP => if let Err(err) = TcpStream::connect_timeout(&target, TCP_KNOCK_TIMEOUT) {
+                    debug!("Knock on TCP {target} failed, reason: {err:#}");
+                }
+            }
+        }
+    }
+    debug!("Knocked the sequence of {} ports on {address}", sequence.len());
+    Ok(())
+}
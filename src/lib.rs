@@ -0,0 +1,47 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! smugglrs can also be embedded: `server::run` takes `Extensions`, so that tunnelled connections
+//! can be served in-process instead of through a local TCP port (see `connector`), and the gateway
+//! reached through custom transports (see `transport`).
+
+pub mod admin;
+pub mod config;
+pub mod connector;
+pub mod daemon;
+pub mod server;
+pub mod transport;
+pub mod gateway;
+mod accounting;
+mod activation;
+pub mod cidr;
+mod common;
+mod control;
+mod crypto;
+mod datagram;
+pub mod error;
+mod format;
+mod integrity;
+mod knock;
+pub mod log;
+mod mirror;
+mod mux;
+mod pool;
+mod scan;
+mod schedule;
+mod signal;
+mod sockopt;
+mod throttle;
+mod websocket;
+#[cfg(feature = "tui")]
+pub mod top;
+#[cfg(windows)]
+pub mod service;
@@ -0,0 +1,246 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! Leveled, single line logging on stderr (or a file, see `to_file`), filtered like env_logger with `RUST_LOG`:
+//! a comma separated list of `level` or `module=level` (e.g. `smugglrs=debug`,
+//! `info,smugglrs::gateway=trace`), the longest matching module wins.
+//! Logs are at `info` when `RUST_LOG` isn't set.
+//!
+//! Levels: `error` for what can't be recovered from, `warn` for failed handshakes and
+//! anything unexpected from the other side, `info` for the lifecycle of the sessions
+//! and their ports, `debug` for the individual connections.
+//!
+//! The threads of a session tag their lines with its ID, which both sides share, and those of a
+//! forwarded connection with its number in the session: `[session=ab12 conn=7]`, so that the
+//! lines of the sessions of several servers and of concurrent clients can be told apart.
+
+use rand::{RngCore, rngs::OsRng};
+use std::cell::Cell;
+use std::env;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_LEVEL : Level = Level::Info;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off, Error, Warn, Info, Debug, Trace
+}
+
+impl Level {
+    fn parse(x: &str) -> Option<Level> {
+        match x.to_lowercase().as_str() {
+            "off" => Some(Level::Off),
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE"
+        }
+    }
+}
+
+struct Filter {
+    default: Level,
+    /// Module prefix and its level
+    modules: Vec<(String, Level)>
+}
+
+static FILTER : OnceLock<Filter> = OnceLock::new();
+static FILE : OnceLock<Mutex<File>> = OnceLock::new();
+
+/// What the lines of a thread are tagged with
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Tag {
+    pub session: Option<u16>,
+    pub conn: Option<u32>
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.session, self.conn) {
+            (Some(session), Some(conn)) => write!(f, "[session={session:04x} conn={conn}] "),
+            (Some(session), None) => write!(f, "[session={session:04x}] "),
+            (None, Some(conn)) => write!(f, "[conn={conn}] "),
+            (None, None) => Ok(())
+        }
+    }
+}
+
+thread_local! {
+    static TAG : Cell<Tag> = const { Cell::new(Tag { session: None, conn: None }) };
+}
+
+/// Puts the previous tag back once dropped
+pub struct Tagged(Tag);
+
+impl Drop for Tagged {
+    fn drop(&mut self) {
+        TAG.with(|x| x.set(self.0));
+    }
+}
+
+impl Filter {
+    fn parse(spec: &str) -> Filter {
+        let mut filter = Filter { default: Level::Error, modules: Vec::new() };
+        for directive in spec.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => match Level::parse(level) {
+                    Some(level) => filter.modules.push((module.to_string(), level)),
+                    None => eprintln!("Ignoring the invalid RUST_LOG directive {directive}")
+                },
+                // A bare module name enables everything in it, like env_logger
+                None => match Level::parse(directive) {
+                    Some(level) => filter.default = level,
+                    None => filter.modules.push((directive.to_string(), Level::Trace))
+                }
+            }
+        }
+        filter
+    }
+
+    fn level(&self, module: &str) -> Level {
+        self.modules.iter()
+            .filter(|(prefix, _)| module == prefix || module.strip_prefix(prefix.as_str()).is_some_and(|x| x.starts_with("::")))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+
+/// Read `RUST_LOG`, logging before that only uses the default level
+pub fn init() {
+    FILTER.get_or_init(|| match env::var("RUST_LOG") {
+        Ok(spec) => Filter::parse(&spec),
+        Err(_) => Filter { default: DEFAULT_LEVEL, modules: Vec::new() }
+    });
+}
+
+/// Append the lines to a file instead of stderr, when nothing reads it (e.g. as a Windows service)
+pub fn to_file(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = FILE.set(Mutex::new(file));
+    Ok(())
+}
+
+pub fn enabled(level: Level, module: &str) -> bool {
+    match FILTER.get() {
+        Some(filter) => level <= filter.level(module),
+        None => level <= DEFAULT_LEVEL
+    }
+}
+
+/// A random ID for a new session, short as it only needs to tell apart those of the same time
+pub fn new_session() -> u16 {
+    OsRng.next_u32() as u16
+}
+
+/// Tag what this thread logs with a session, and no connection
+pub fn set_session(session: Option<u16>) {
+    TAG.with(|x| x.set(Tag { session, conn: None }));
+}
+
+pub fn tag() -> Tag {
+    TAG.with(Cell::get)
+}
+
+/// Tag what this thread logs with `tag` until the guard is dropped
+pub fn tagged(tag: Tag) -> Tagged {
+    Tagged(TAG.with(|x| x.replace(tag)))
+}
+
+/// Tag what this thread logs with a connection of its session until the guard is dropped
+pub fn connection(conn: Option<u32>) -> Tagged {
+    tagged(Tag { conn, ..tag() })
+}
+
+/// Spawn a thread that logs with the tag of this one
+pub fn spawn<F, T>(f: F) -> thread::JoinHandle<T> where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    let tag = tag();
+    thread::spawn(move || {
+        TAG.with(|x| x.set(tag));
+        f()
+    })
+}
+
+/* UTC, from the days since the epoch to the civil date */
+fn timestamp() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs()) as i64;
+    let (days, time) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2) / 153;
+    let day = doy - (153*mp + 2)/5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z", time / 3600, time / 60 % 60, time % 60)
+}
+
+#[doc(hidden)]
+pub fn log(level: Level, module: &str, args: fmt::Arguments) {
+    if !enabled(level, module) {
+        return;
+    }
+    // One write per line, so that the lines of different threads don't interleave
+    let line = format!("[{} {:<5} {module}] {}{args}\n", timestamp(), level.name(), tag());
+    match FILE.get() {
+        Some(file) => {
+            let _ = file.lock().unwrap().write_all(line.as_bytes());
+        }
+        None => {
+            let _ = io::stderr().write_all(line.as_bytes());
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log::log($crate::log::Level::Error, module_path!(), format_args!($($arg)+)) }
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log::log($crate::log::Level::Warn, module_path!(), format_args!($($arg)+)) }
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log::log($crate::log::Level::Info, module_path!(), format_args!($($arg)+)) }
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log::log($crate::log::Level::Debug, module_path!(), format_args!($($arg)+)) }
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log::log($crate::log::Level::Trace, module_path!(), format_args!($($arg)+)) }
+}
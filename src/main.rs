@@ -9,17 +9,219 @@ You should have received a copy of the GNU General Public License along with thi
 If not, see <https://www.gnu.org/licenses/>. 
 */
 
-mod config;
-mod server;
-mod gateway;
-mod common;
-mod crypto;
+use smugglrs::config::{self, CliOverrides, CommonConfig, RawConfig, SpecificConfig};
+use smugglrs::error::Failure;
+use smugglrs::{admin, daemon, gateway, server};
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+use std::{env, process};
 
-use config::{CommonConfig, SpecificConfig};
-use anyhow::Result;
+const HELP : &str = "\
+smugglrs: forwards ports of a gateway to a server behind a NAT or a firewall
 
-fn main() -> Result<()> {
-    let (config,specific) = CommonConfig::new()?; // Read and parse config
+Usage: smugglrs [gateway | server] [options] [<config>]
+       smugglrs <command> [--config <path>]
+
+Commands:
+  gateway, server          Run, overriding the mode of the configuration
+  check                    Check the configuration and print every error (--check)
+  genkey [path] [--force]  Generate a key file
+  test-connection          Check step by step that the server reaches the gateway
+  status, connections, kick, port, pipe, traffic, ctl, top
+                           Talk to the running instance over its admin socket
+
+Options:
+  --config <path>           The configuration file, config.toml by default
+  --config-format <format>  toml, json or yaml, instead of the extension's
+  --port <port>             The port of the gateway for the servers
+  --gateway-address <host>  Where the server connects to
+  --key-file <path>         The key file, relative to the current directory
+  --redirect <redirect>     Like 8080:80/tcp, replaces the redirects of the file; can be repeated
+  --one-shot, --one-session Don't reconnect / serve a single session
+  --dry-run, --print-config Print the resolved configuration and exit
+  --daemon                  Run in the background
+  --service, --install-service, --uninstall-service
+                            Windows only: run as, install or remove the service
+  -h, --help                Print this help
+  -V, --version             Print the version
+
+The options override the environment (SMUGGLRS_<OPTION>), which overrides the file.
+";
+
+fn main() {
+    smugglrs::log::init();
+    if let Err(err) = run() {
+        eprintln!("Error: {err:?}");
+        process::exit(Failure::of(&err).exit_code());
+    }
+}
+
+#[cfg(feature = "tui")]
+fn top(config: Option<&Path>) -> Result<()> {
+    smugglrs::top::main(&RawConfig::load(config)?.admin_socket())
+}
+
+#[cfg(not(feature = "tui"))]
+fn top(_config: Option<&Path>) -> Result<()> {
+    Err(anyhow!("smugglrs was built without the tui feature")).context(Failure::Usage)
+}
+
+#[cfg(windows)]
+fn service(command: &str, config: Option<&Path>) -> Result<()> {
+    match command {
+        "--service" => smugglrs::service::run(config),
+        "--install-service" => smugglrs::service::install(config),
+        _ => smugglrs::service::uninstall()
+    }
+}
+
+#[cfg(not(windows))]
+fn service(command: &str, _config: Option<&Path>) -> Result<()> {
+    Err(anyhow!("{command} is only available on Windows")).context(Failure::Usage)
+}
+
+/* `<flag> <value>` can be given anywhere, before or after the command */
+fn take_value(args: &mut Vec<String>, flag: &str) -> Result<Option<String>> {
+    match args.iter().position(|x| x == flag) {
+        Some(i) if i + 1 < args.len() => {
+            let value = args.remove(i + 1);
+            args.remove(i);
+            Ok(Some(value))
+        }
+        Some(_) => Err(anyhow!("{flag} should be followed by a value")).context(Failure::Usage),
+        None => Ok(None)
+    }
+}
+
+/* The flags that override options of the configuration, `--redirect` can be repeated */
+fn cli_overrides(args: &mut Vec<String>) -> Result<CliOverrides> {
+    let mut cli = CliOverrides {
+        port: take_value(args, "--port")?.map(|x| x.parse::<u16>().with_context(|| format!("--port: {x} is not a valid port"))).transpose().context(Failure::Usage)?,
+        gateway_address: take_value(args, "--gateway-address")?,
+        key_file: take_value(args, "--key-file")?.map(PathBuf::from),
+        ..Default::default()
+    };
+    while let Some(redirect) = take_value(args, "--redirect")? {
+        cli.redirects.push(redirect);
+    }
+    Ok(cli)
+}
+
+/* `genkey [path] [--force]`, the path defaults to the key file of the configuration */
+fn genkey(args: &[String], config: Option<&Path>) -> Result<()> {
+    let mut force = false;
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            "--force" => force = true,
+            x if x.starts_with('-') => return Err(anyhow!("Unknown option {x}")).context(Failure::Usage),
+            x if path.is_none() => path = Some(PathBuf::from(x)),
+            x => return Err(anyhow!("Unexpected argument {x}")).context(Failure::Usage)
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => config::default_key_file(config).context(Failure::Config)?
+    };
+    let fingerprint = config::generate_key(&path, force)?;
+    println!("Key written to {} (fingerprint {fingerprint})", path.display());
+    Ok(())
+}
+
+/* `--check`: every error is printed, not only the first */
+fn check(config: Option<&Path>) -> Result<()> {
+    let errors = config::check(config);
+    for err in &errors {
+        eprintln!("Error: {err:?}");
+    }
+    match errors.len() {
+        0 => {
+            println!("{} is valid", config.unwrap_or(Path::new(config::CONFIG_PATH)).display());
+            Ok(())
+        }
+        1 => Err(anyhow!("The configuration has an error")).context(Failure::Config),
+        n => Err(anyhow!("The configuration has {n} errors")).context(Failure::Config)
+    }
+}
+
+fn run() -> Result<()> {
+    let mut args : Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|x| x == "--help" || x == "-h") {
+        print!("{HELP}");
+        return Ok(());
+    }
+    if args.iter().any(|x| x == "--version" || x == "-V") {
+        println!("smugglrs {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+    let mut config_path = take_value(&mut args, "--config")?.map(PathBuf::from);
+    if let Some(format) = take_value(&mut args, "--config-format")? {
+        config::set_format(&format).context(Failure::Usage)?;
+    }
+    let mut cli = cli_overrides(&mut args)?;
+    // `smugglrs gateway|server [options]` sets the mode, `smugglrs check` is `--check`
+    match args.first().map(String::as_str) {
+        Some(mode @ ("gateway" | "server")) => {
+            cli.mode = Some(mode.to_string());
+            args.remove(0);
+        }
+        Some("check") => args[0] = "--check".to_string(),
+        _ => {}
+    }
+    config::set_cli_overrides(cli).context(Failure::Usage)?;
+    match args.first().map(String::as_str) {
+        Some("port" | "pipe" | "status" | "connections" | "kick" | "traffic") => return admin::command(&RawConfig::load(config_path.as_deref())?.admin_socket(), &args),
+        Some("ctl") => return admin::command(&RawConfig::load(config_path.as_deref())?.admin_socket(), &args[1..]),
+        Some("top") => return top(config_path.as_deref()),
+        Some("genkey") => return genkey(&args[1..], config_path.as_deref()),
+        Some("test-connection") => return match CommonConfig::new(config_path.as_deref()).context(Failure::Config)? {
+            (config, SpecificConfig::Server(scfg)) => server::test_connection(config, scfg),
+            (_, SpecificConfig::Gateway(_)) => Err(anyhow!("test-connection should be run on the server")).context(Failure::Usage)
+        },
+        _ => {}
+    }
+    let mut one_shot = false;
+    let mut check_only = false;
+    let mut dry_run = false;
+    let mut background = false;
+    let mut service_command = None;
+    for arg in &args {
+        match arg.as_str() {
+            "--one-shot" | "--one-session" => one_shot = true,
+            "--check" => check_only = true,
+            "--dry-run" | "--print-config" => dry_run = true,
+            "--daemon" => background = true,
+            "--service" | "--install-service" | "--uninstall-service" => service_command = Some(arg.as_str()),
+            x if x.starts_with('-') => return Err(anyhow!("Unknown option {x}")).context(Failure::Usage),
+            // Anything else is the configuration file, which can be given only once
+            x if config_path.is_none() => config_path = Some(PathBuf::from(x)),
+            x => return Err(anyhow!("Unknown command {x}")).context(Failure::Usage)
+        }
+    }
+    if check_only {
+        return check(config_path.as_deref());
+    }
+    if dry_run {
+        let (config, specific) = CommonConfig::dry_run(config_path.as_deref()).context(Failure::Config)?;
+        println!("{specific}\n{config}");
+        return Ok(());
+    }
+    if let Some(command) = service_command {
+        return service(command, config_path.as_deref());
+    }
+    let (mut config, mut specific) = CommonConfig::new(config_path.as_deref()).context(Failure::Config)?; // Read and parse config
+    config.daemon.enabled |= background;
+    // Before anything spawns a thread
+    let _pid_file = match config.daemon.enabled {
+        true => Some(daemon::start(&config.daemon)?),
+        false => None
+    };
+    if one_shot {
+        match &mut specific {
+            SpecificConfig::Server(scfg) => scfg.retry = false,
+            SpecificConfig::Gateway(gcfg) => gcfg.one_session = true
+        }
+    }
     match specific {
         SpecificConfig::Server(scfg) => server::main(config,scfg),
         SpecificConfig::Gateway(gcfg) => gateway::main(config,gcfg)
@@ -0,0 +1,170 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! Traffic mirroring: a copy of everything flowing through a redirect is sent to a sink
+//! (a TCP address or a file) for debugging.
+//!
+//! Every chunk is written as a record:
+//! `[connection id: u32 BE][direction: u8][length: u32 BE][payload]`
+//! where direction is `0` for gateway -> local and `1` for local -> gateway.
+//! A record with a length of 0 marks the end of that direction.
+
+use crate::common::{Reservation, MEMORY};
+use crate::{info, warn};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MIRROR_QUEUE : usize = 1024; // Records, not bytes
+const MIRROR_RETRY_DELAY : u64 = 5;
+pub const MIRROR_HEADER_LENGTH : usize = 9;
+
+#[derive(Debug, Copy, Clone)]
+pub enum Direction {
+    ToLocal, FromLocal
+}
+
+impl Direction {
+    fn to_byte(self) -> u8 {
+        match self {
+            Direction::ToLocal => 0,
+            Direction::FromLocal => 1
+        }
+    }
+}
+
+enum Sink {
+    Tcp(TcpStream),
+    File(std::fs::File)
+}
+
+impl Sink {
+    /* Anything that looks like "host:port" is an address, everything else is a file path */
+    fn open(target: &str) -> std::io::Result<Sink> {
+        let is_address = match target.rsplit_once(':') {
+            Some((host, port)) => !host.is_empty() && !host.contains('/') && port.parse::<u16>().is_ok(),
+            None => false
+        };
+        if is_address {
+            let stream = TcpStream::connect(target)?;
+            // A stalled reader should make us reconnect rather than hold the writer forever
+            stream.set_write_timeout(Some(Duration::from_secs(MIRROR_RETRY_DELAY)))?;
+            Ok(Sink::Tcp(stream))
+        } else {
+            Ok(Sink::File(OpenOptions::new().create(true).append(true).open(target)?))
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        match self {
+            Sink::Tcp(stream) => stream.write_all(buf),
+            Sink::File(file) => file.write_all(buf)
+        }
+    }
+}
+
+/// Shared by every connection of a redirect; owns the writer thread.
+#[derive(Clone)]
+pub struct MirrorSink {
+    tx: SyncSender<(Vec<u8>, Reservation)>,
+    next_id: Arc<AtomicU32>,
+    dropped: Arc<AtomicU64>
+}
+
+impl MirrorSink {
+    pub fn new(target: String) -> MirrorSink {
+        let (tx, rx) = sync_channel(MIRROR_QUEUE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        {
+            let dropped = dropped.clone();
+            thread::spawn(move || mirror_writer(target, rx, dropped));
+        }
+        MirrorSink {
+            tx,
+            next_id: Arc::new(AtomicU32::new(0)),
+            dropped
+        }
+    }
+
+    /// Create a tap for a new connection
+    pub fn tap(&self) -> MirrorTap {
+        MirrorTap {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            sink: self.clone()
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MirrorTap {
+    id: u32,
+    sink: MirrorSink
+}
+
+impl MirrorTap {
+    /// Never blocks: if the writer can't keep up, or the memory budget is exhausted, the record is dropped
+    pub fn record(&self, direction: Direction, buf: &[u8]) {
+        let Some(reservation) = MEMORY.reserve(MIRROR_HEADER_LENGTH + buf.len()) else {
+            self.sink.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        let mut record = Vec::with_capacity(MIRROR_HEADER_LENGTH + buf.len());
+        record.extend_from_slice(&self.id.to_be_bytes());
+        record.push(direction.to_byte());
+        record.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+        record.extend_from_slice(buf);
+        match self.sink.tx.try_send((record, reservation)) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.sink.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn mirror_writer(target: String, rx: Receiver<(Vec<u8>, Reservation)>, dropped: Arc<AtomicU64>) {
+    let retry = Duration::from_secs(MIRROR_RETRY_DELAY);
+    let mut sink: Option<Sink> = None;
+    let mut last_attempt: Option<Instant> = None;
+    let mut reported_drops = 0;
+    // Records are given back to the memory budget once written
+    for (record, _reservation) in rx {
+        if sink.is_none() && last_attempt.is_none_or(|t| t.elapsed() >= retry) {
+            last_attempt = Some(Instant::now());
+            match Sink::open(&target) {
+                Ok(s) => {
+                    info!("Mirroring to {target}");
+                    sink = Some(s);
+                }
+                Err(err) => warn!("Failed to open mirror sink {target}, dropping mirrored data, reason: {err:#}"),
+            }
+        }
+        match &mut sink {
+            Some(s) => if let Err(err) = s.write_all(&record) {
+                warn!("Mirror sink {target} failed, dropping mirrored data, reason: {err:#}");
+                sink = None;
+            },
+            None => {
+                dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let total_drops = dropped.load(Ordering::Relaxed);
+        if total_drops >= reported_drops + MIRROR_QUEUE as u64 {
+            warn!("Mirror sink {target}: {total_drops} records dropped so far");
+            reported_drops = total_drops;
+        }
+    }
+}
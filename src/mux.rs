@@ -0,0 +1,249 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! `multiplex`: the connections of the clients are carried over the control connection, for gateways that can only
+//! be reached on a single port. The gateway opens a stream for each client (`StreamFrame::Open`) instead of asking
+//! the server to connect back, then both sides send `[stream id: u32 BE][kind: u8][payload]` frames as control messages.
+//!
+//! Each direction of a stream has a window: no more than `WINDOW` bytes are sent that the other side didn't read yet,
+//! which it gives back with `StreamFrame::Window`. The reader of the control connection never blocks on a stream, so
+//! a slow client holds back nothing but its own stream.
+
+use crate::connector::Stream;
+use crate::control::{ControlMessage, StreamFrame};
+use anyhow::Result;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Bytes of a direction of a stream that may be in flight
+pub const WINDOW : usize = 262144;
+const CHUNK : usize = 16384; // Of the data frames
+
+type Sender = Box<dyn Fn(&ControlMessage) -> Result<()> + Send + Sync>;
+
+/// The streams of a control connection
+pub struct Mux {
+    /// Writes a message to the control connection
+    send: Sender,
+    streams: Mutex<HashMap<u32, Arc<Shared>>>,
+    next_id: AtomicU32
+}
+
+#[derive(Default)]
+struct State {
+    /// Received and not read yet, `offset` bytes of the first one were
+    chunks: VecDeque<Vec<u8>>,
+    offset: usize,
+    queued: usize,
+    received_end: bool,
+    /// Bytes that may still be sent
+    credit: usize,
+    sent_end: bool,
+    /// Nothing more is read or written
+    closed: bool,
+    /// The other side knows the stream is over: it reset it, or was sent a reset
+    peer_knows: bool
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar
+}
+
+impl Shared {
+    fn new() -> Arc<Shared> {
+        Arc::new(Shared { state: Mutex::new(State { credit: WINDOW, ..Default::default() }), changed: Condvar::new() })
+    }
+}
+
+/* Shared by the clones of a stream, the last one removes it from the mux */
+struct Handle {
+    id: u32,
+    mux: Arc<Mux>,
+    shared: Arc<Shared>
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.mux.streams.lock().unwrap().remove(&self.id);
+        let state = self.shared.state.lock().unwrap();
+        // Dropped before both directions were over, like a TCP socket closed with unread data
+        let finished = state.sent_end && state.received_end && state.chunks.is_empty();
+        if !finished && !state.peer_knows {
+            drop(state);
+            let _ = self.mux.frame(self.id, StreamFrame::Reset);
+        }
+    }
+}
+
+/// A connection carried over the control connection, the tunnel side of its pipe
+pub struct MuxStream(Arc<Handle>);
+
+impl Mux {
+    pub fn new(send: Sender) -> Arc<Mux> {
+        Arc::new(Mux { send, streams: Mutex::default(), next_id: AtomicU32::new(0) })
+    }
+
+    fn frame(&self, id: u32, frame: StreamFrame) -> io::Result<()> {
+        (self.send)(&ControlMessage::Stream { id, frame }).map_err(|err| io::Error::new(io::ErrorKind::ConnectionAborted, format!("{err:#}")))
+    }
+
+    fn insert(self: &Arc<Self>, id: u32) -> MuxStream {
+        let shared = Shared::new();
+        self.streams.lock().unwrap().insert(id, shared.clone());
+        MuxStream(Arc::new(Handle { id, mux: self.clone(), shared }))
+    }
+
+    /// A new stream, to be announced with a `StreamFrame::Open`
+    pub fn open(self: &Arc<Self>) -> MuxStream {
+        self.insert(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The stream the other side opened
+    pub fn accept(self: &Arc<Self>, id: u32) -> MuxStream {
+        self.insert(id)
+    }
+
+    /// A frame that came from the other side. Never blocks: a stream that overruns its window is closed, and reset
+    /// once dropped. Frames of the streams that are over already are ignored
+    pub fn dispatch(&self, id: u32, frame: StreamFrame) {
+        let Some(shared) = self.streams.lock().unwrap().get(&id).cloned() else {
+            return;
+        };
+        let mut state = shared.state.lock().unwrap();
+        match frame {
+            // Only the gateway opens streams
+            StreamFrame::Open { .. } => state.closed = true,
+            StreamFrame::Data(data) if state.received_end || state.queued + data.len() > WINDOW => state.closed = true,
+            StreamFrame::Data(data) => {
+                state.queued += data.len();
+                state.chunks.push_back(data);
+            }
+            StreamFrame::End => state.received_end = true,
+            StreamFrame::Reset => {
+                state.closed = true;
+                state.peer_knows = true;
+            }
+            StreamFrame::Window(credit) => state.credit = state.credit.saturating_add(credit as usize)
+        }
+        shared.changed.notify_all();
+    }
+
+    /// The control connection is gone, so are its streams
+    pub fn close_all(&self) {
+        for shared in self.streams.lock().unwrap().values() {
+            let mut state = shared.state.lock().unwrap();
+            state.closed = true;
+            state.peer_knows = true;
+            shared.changed.notify_all();
+        }
+    }
+}
+
+impl MuxStream {
+    pub fn id(&self) -> u32 {
+        self.0.id
+    }
+}
+
+impl Read for MuxStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let shared = &self.0.shared;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            let State { chunks, offset, queued, .. } = &mut *state;
+            if let Some(chunk) = chunks.front() {
+                let len = buf.len().min(chunk.len() - *offset);
+                buf[..len].copy_from_slice(&chunk[*offset..*offset + len]);
+                *offset += len;
+                if *offset == chunk.len() {
+                    chunks.pop_front();
+                    *offset = 0;
+                }
+                *queued -= len;
+                drop(state);
+                self.0.mux.frame(self.0.id, StreamFrame::Window(len as u32))?;
+                return Ok(len);
+            }
+            if state.received_end {
+                return Ok(0);
+            }
+            state = shared.changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl Write for MuxStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let shared = &self.0.shared;
+        let mut state = shared.state.lock().unwrap();
+        let len = loop {
+            if state.closed {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            if state.sent_end {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            if state.credit > 0 {
+                break buf.len().min(state.credit).min(CHUNK);
+            }
+            state = shared.changed.wait(state).unwrap();
+        };
+        state.credit -= len;
+        drop(state);
+        self.0.mux.frame(self.0.id, StreamFrame::Data(buf[..len].to_vec()))?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for MuxStream {
+    fn try_clone_stream(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(MuxStream(self.0.clone())))
+    }
+
+    fn shutdown_stream(&self) -> io::Result<()> {
+        let mut state = self.0.shared.state.lock().unwrap();
+        state.closed = true;
+        self.0.shared.changed.notify_all();
+        if state.peer_knows {
+            return Ok(());
+        }
+        state.peer_knows = true;
+        drop(state);
+        self.0.mux.frame(self.0.id, StreamFrame::Reset)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        let mut state = self.0.shared.state.lock().unwrap();
+        if state.sent_end || state.closed {
+            return Ok(());
+        }
+        state.sent_end = true;
+        self.0.shared.changed.notify_all();
+        drop(state);
+        self.0.mux.frame(self.0.id, StreamFrame::End)
+    }
+}
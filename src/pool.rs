@@ -0,0 +1,110 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! Idle connections to a local service, established in advance for the redirects with `preconnect`.
+
+use crate::warn;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const POOL_CHECK_DELAY : u64 = 1;
+pub const MAX_PRECONNECT : usize = 64;
+
+pub struct LocalPool {
+    addr: SocketAddr,
+    size: usize,
+    idle: Mutex<VecDeque<TcpStream>>,
+    /// Connections handed out from the pool, and the ones that had to be established on demand
+    hits: AtomicU64,
+    misses: AtomicU64
+}
+
+/* A closed idle connection reads as EOF; one the service already spoke on (a banner...) is still usable */
+fn is_alive(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let alive = match stream.peek(&mut [0u8; 1]) {
+        Ok(0) => false,
+        Ok(_) => true,
+        Err(err) => err.kind() == ErrorKind::WouldBlock
+    };
+    alive && stream.set_nonblocking(false).is_ok()
+}
+
+impl LocalPool {
+    /// The pool is refilled in the background until it is dropped
+    pub fn new(addr: SocketAddr, size: usize) -> Arc<LocalPool> {
+        let pool = Arc::new(LocalPool {
+            addr,
+            size,
+            idle: Mutex::new(VecDeque::with_capacity(size)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0)
+        });
+        let weak = Arc::downgrade(&pool);
+        thread::spawn(move || {
+            let delay = Duration::from_secs(POOL_CHECK_DELAY);
+            let mut failing = false;
+            while let Some(pool) = weak.upgrade() {
+                match pool.refill() {
+                    Ok(()) => failing = false,
+                    Err(err) if !failing => {
+                        failing = true;
+                        warn!("Failed to preconnect to {addr}, retrying, reason: {err:#}");
+                    }
+                    Err(_) => {}
+                }
+                drop(pool);
+                thread::sleep(delay);
+            }
+        });
+        pool
+    }
+
+    fn refill(&self) -> io::Result<()> {
+        self.idle.lock().unwrap().retain(is_alive);
+        // Don't hold the lock while connecting
+        while self.idle.lock().unwrap().len() < self.size {
+            let stream = TcpStream::connect(self.addr)?;
+            self.idle.lock().unwrap().push_back(stream);
+        }
+        Ok(())
+    }
+
+    /// An idle connection if one is still alive, a new one otherwise
+    pub fn take(&self) -> io::Result<TcpStream> {
+        loop {
+            let stream = self.idle.lock().unwrap().pop_front();
+            match stream {
+                Some(stream) if is_alive(&stream) => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(stream);
+                }
+                Some(_) => continue,
+                None => {
+                    self.misses.fetch_add(1, Ordering::Relaxed);
+                    return TcpStream::connect(self.addr);
+                }
+            }
+        }
+    }
+
+    /// For the status output: `idle/size hits misses`
+    pub fn stats(&self) -> (usize, usize, u64, u64) {
+        (self.idle.lock().unwrap().len(), self.size, self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+}
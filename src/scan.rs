@@ -0,0 +1,130 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! What scanners send to the gateway: connections to ports no server is serving, and to the control port,
+//! are told apart by their first bytes and counted, for a periodic summary rather than a line per probe.
+
+use crate::info;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const HTTP_METHODS : [&str; 9] = ["GET", "POST", "HEAD", "PUT", "DELETE", "OPTIONS", "PATCH", "CONNECT", "TRACE"];
+const MAX_PATH_LENGTH : usize = 64;
+// Past this many distinct kinds in an interval, HTTP probes aren't told apart by their path anymore
+const MAX_KINDS : usize = 256;
+
+/// What a probe looked like
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProbeKind {
+    Tls,
+    Http { method: String, path: String },
+    Ssh,
+    /// Connected and sent nothing, like a connect scan
+    Empty,
+    Other
+}
+
+impl fmt::Display for ProbeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProbeKind::Tls => write!(f, "tls"),
+            ProbeKind::Http { method, path } if path.is_empty() => write!(f, "http {method}"),
+            ProbeKind::Http { method, path } => write!(f, "http {method} {path}"),
+            ProbeKind::Ssh => write!(f, "ssh"),
+            ProbeKind::Empty => write!(f, "empty"),
+            ProbeKind::Other => write!(f, "other")
+        }
+    }
+}
+
+/// Only needs the first few bytes of the connection
+pub fn classify(first: &[u8]) -> ProbeKind {
+    if first.is_empty() {
+        return ProbeKind::Empty;
+    }
+    // A handshake record, of any SSL 3 / TLS version
+    if first[0] == 0x16 && first.get(1).is_none_or(|x| *x == 0x03) {
+        return ProbeKind::Tls;
+    }
+    if first.starts_with(b"SSH-") {
+        return ProbeKind::Ssh;
+    }
+    let line = first.split(|x| *x == b'\r' || *x == b'\n').next().unwrap_or_default();
+    let mut words = line.split(|x| *x == b' ');
+    let method = words.next().unwrap_or_default();
+    match HTTP_METHODS.iter().find(|x| x.as_bytes() == method) {
+        Some(method) => {
+            let path = String::from_utf8_lossy(words.next().unwrap_or_default());
+            ProbeKind::Http {
+                method: method.to_string(),
+                path: path.chars().filter(|x| !x.is_control()).take(MAX_PATH_LENGTH).collect()
+            }
+        }
+        None => ProbeKind::Other
+    }
+}
+
+/// Counts since the last summary, by port and kind
+pub struct ScanStats {
+    counts: Mutex<BTreeMap<(u16, ProbeKind), u64>>,
+    total: AtomicU64
+}
+
+pub static SCANS : ScanStats = ScanStats {
+    counts: Mutex::new(BTreeMap::new()),
+    total: AtomicU64::new(0)
+};
+
+impl ScanStats {
+    pub fn record(&self, port: u16, kind: ProbeKind) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        let mut counts = self.counts.lock().unwrap();
+        let kind = match kind {
+            ProbeKind::Http { method, .. } if counts.len() >= MAX_KINDS => ProbeKind::Http { method, path: String::new() },
+            kind => kind
+        };
+        *counts.entry((port, kind)).or_insert(0) += 1;
+    }
+
+    /// One line for everything counted since the last summary, None if there was nothing
+    fn summary(&self, control_port: u16) -> Option<String> {
+        let counts = std::mem::take(&mut *self.counts.lock().unwrap());
+        if counts.is_empty() {
+            return None;
+        }
+        let mut ports : Vec<String> = Vec::new();
+        let mut current = None;
+        for ((port, kind), count) in &counts {
+            if current != Some(*port) {
+                current = Some(*port);
+                let name = if *port == control_port { format!("control port {port}") } else { format!("port {port}") };
+                ports.push(format!("{name}:"));
+            }
+            ports.last_mut().unwrap().push_str(&format!(" {kind}={count}"));
+        }
+        let count : u64 = counts.values().sum();
+        Some(format!("{count} probes ({} since start); {}", self.total.load(Ordering::Relaxed), ports.join("; ")))
+    }
+}
+
+/// Prints the summary every interval in which something was probed
+pub fn spawn_summary(interval: Duration, control_port: u16) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Some(summary) = SCANS.summary(control_port) {
+            info!("Scans of the last {}s: {summary}", interval.as_secs());
+        }
+    });
+}
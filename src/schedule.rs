@@ -0,0 +1,261 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+use anyhow::{anyhow, Result, Context};
+
+pub const SCHEDULE_LENGTH : usize = 7;
+const DAY_NAMES : [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Time window during which a redirect accepts connections.
+/// Minutes are counted from midnight, in the timezone of the schedule
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    start: u16,
+    end: u16,
+    days: u8, // Bit 0 is monday
+    offset: i16 // Minutes east of UTC
+}
+
+fn parse_time(x: &str) -> Result<u16> {
+    let (h, m) = x.trim().split_once(':').with_context(|| format!("{x} should be of the form HH:MM"))?;
+    let h : u16 = h.parse().with_context(|| format!("Invalid hour in {x}"))?;
+    let m : u16 = m.parse().with_context(|| format!("Invalid minute in {x}"))?;
+    if h > 24 || m > 59 || (h == 24 && m != 0) {
+        return Err(anyhow!("{x} is not a valid time of day"));
+    }
+    Ok(h*60+m)
+}
+
+fn parse_day(x: &str) -> Result<u8> {
+    let x = x.trim().to_lowercase();
+    DAY_NAMES.iter().position(|d| *d == x)
+        .map(|i| i as u8)
+        .with_context(|| format!("{x} is not a valid day of the week"))
+}
+
+/* "mon-fri", "sat,sun", "mon-wed,fri" */
+fn parse_days(x: &str) -> Result<u8> {
+    let mut days = 0u8;
+    for part in x.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let first = parse_day(first)?;
+                let last = parse_day(last)?;
+                let mut day = first;
+                loop {
+                    days |= 1 << day;
+                    if day == last {
+                        break;
+                    }
+                    day = (day+1)%7;
+                }
+            }
+            None => days |= 1 << parse_day(part)?
+        }
+    }
+    Ok(days)
+}
+
+/* "UTC", "+02:00", "-05:30" */
+fn parse_offset(x: &str) -> Result<i16> {
+    if x.eq_ignore_ascii_case("utc") || x.eq_ignore_ascii_case("z") {
+        return Ok(0);
+    }
+    let (sign, rest) = match x.split_at_checked(1) {
+        Some(("+", rest)) => (1, rest),
+        Some(("-", rest)) => (-1, rest),
+        _ => return Err(anyhow!("timezone should be \"UTC\" or an offset like \"+02:00\""))
+    };
+    let offset = parse_time(rest).context("Invalid timezone offset")? as i16;
+    if offset > 14*60 {
+        return Err(anyhow!("{x} is not a valid timezone offset"));
+    }
+    Ok(sign*offset)
+}
+
+impl Schedule {
+    pub fn parse(hours: &str, days: Option<&str>, timezone: Option<&str>) -> Result<Schedule> {
+        let (start, end) = hours.split_once('-').context("active_hours should be of the form \"HH:MM-HH:MM\"")?;
+        let schedule = Schedule {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            days: match days {
+                Some(days) => parse_days(days)?,
+                None => 0x7f
+            },
+            offset: match timezone {
+                Some(timezone) => parse_offset(timezone)?,
+                None => 0
+            }
+        };
+        if schedule.start == schedule.end {
+            return Err(anyhow!("active_hours should not be empty"));
+        }
+        Ok(schedule)
+    }
+
+    /// Whether the redirect is active at the given time (seconds since the UNIX epoch)
+    pub fn is_active(&self, unix_time: u64) -> bool {
+        let local = unix_time as i64 + self.offset as i64 * 60;
+        let day = local.div_euclid(86400);
+        let minute = (local.rem_euclid(86400) / 60) as u16;
+        let weekday = |day: i64| ((day + 3).rem_euclid(7)) as u8; // 1970-01-01 was a thursday
+        if self.start < self.end {
+            self.days & (1 << weekday(day)) != 0 && minute >= self.start && minute < self.end
+        } else if minute >= self.start {
+            // The window goes past midnight, it belongs to the day it started
+            self.days & (1 << weekday(day)) != 0
+        } else {
+            minute < self.end && self.days & (1 << weekday(day-1)) != 0
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; SCHEDULE_LENGTH] {
+        let mut ret = [0u8; SCHEDULE_LENGTH];
+        ret[0..2].copy_from_slice(&self.start.to_be_bytes());
+        ret[2..4].copy_from_slice(&self.end.to_be_bytes());
+        ret[4] = self.days;
+        ret[5..7].copy_from_slice(&self.offset.to_be_bytes());
+        ret
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Schedule> {
+        let buf : &[u8; SCHEDULE_LENGTH] = buf.try_into().context("Malformed schedule")?;
+        let schedule = Schedule {
+            start: u16::from_be_bytes([buf[0], buf[1]]),
+            end: u16::from_be_bytes([buf[2], buf[3]]),
+            days: buf[4],
+            offset: i16::from_be_bytes([buf[5], buf[6]])
+        };
+        if schedule.start > 24*60 || schedule.end > 24*60 {
+            return Err(anyhow!("Malformed schedule"));
+        }
+        Ok(schedule)
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}-{:02}:{:02}", self.start/60, self.start%60, self.end/60, self.end%60)?;
+        if self.days != 0x7f {
+            let days : Vec<&str> = (0..7).filter(|d| self.days & (1 << d) != 0).map(|d| DAY_NAMES[d]).collect();
+            write!(f, " on {}", days.join(","))?;
+        }
+        let offset = self.offset.abs();
+        write!(f, " UTC{}{:02}:{:02}", if self.offset < 0 { '-' } else { '+' }, offset/60, offset%60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* 2024-01-01 was a monday */
+    const MONDAY : u64 = 1_704_067_200;
+
+    /* The UNIX time of a day of the week after that monday (0 is monday), at a UTC time of day */
+    fn at(day: u64, hours: u64, minutes: u64) -> u64 {
+        MONDAY + day * 86400 + hours * 3600 + minutes * 60
+    }
+
+    #[test]
+    fn malformed_schedules_are_refused() {
+        for (hours, days, timezone) in [
+            ("08-18", None, None),
+            ("08:00", None, None),
+            ("25:00-26:00", None, None),
+            ("08:60-09:00", None, None),
+            ("24:30-01:00", None, None),
+            ("08:00-08:00", None, None),
+            ("08:00-18:00", Some("mon-xyz"), None),
+            ("08:00-18:00", Some(""), None),
+            ("08:00-18:00", None, Some("CET")),
+            ("08:00-18:00", None, Some("+15:00")),
+            ("08:00-18:00", None, Some("02:00"))
+        ] {
+            assert!(Schedule::parse(hours, days, timezone).is_err(), "{hours} {days:?} {timezone:?}");
+        }
+    }
+
+    #[test]
+    fn windows_of_a_day() {
+        let schedule = Schedule::parse("09:00-17:00", Some("mon-fri"), None).unwrap();
+        assert!(!schedule.is_active(at(0, 8, 59)));
+        assert!(schedule.is_active(at(0, 9, 0)));
+        assert!(schedule.is_active(at(4, 16, 59)));
+        assert!(!schedule.is_active(at(4, 17, 0)), "the end is excluded");
+        assert!(!schedule.is_active(at(5, 10, 0)), "saturday");
+        let whole = Schedule::parse("00:00-24:00", Some("sun"), None).unwrap();
+        assert!(whole.is_active(at(6, 0, 0)));
+        assert!(whole.is_active(at(6, 23, 59)));
+        assert!(!whole.is_active(at(7, 0, 0)), "the next monday");
+        assert!(!whole.is_active(at(5, 23, 59)));
+    }
+
+    #[test]
+    fn windows_across_midnight_belong_to_the_day_they_start() {
+        let schedule = Schedule::parse("22:00-02:00", Some("fri"), None).unwrap();
+        assert!(!schedule.is_active(at(4, 21, 59)));
+        assert!(schedule.is_active(at(4, 22, 0)));
+        assert!(schedule.is_active(at(4, 23, 59)));
+        assert!(schedule.is_active(at(5, 0, 0)), "saturday night is friday's");
+        assert!(schedule.is_active(at(5, 1, 59)));
+        assert!(!schedule.is_active(at(5, 2, 0)));
+        assert!(!schedule.is_active(at(5, 22, 0)), "saturday's own night");
+        assert!(!schedule.is_active(at(4, 1, 0)), "friday morning is thursday's");
+    }
+
+    #[test]
+    fn day_ranges_wrap_around_the_week() {
+        let schedule = Schedule::parse("00:00-24:00", Some("fri-mon"), None).unwrap();
+        for (day, active) in [(0, true), (1, false), (2, false), (3, false), (4, true), (5, true), (6, true)] {
+            assert_eq!(schedule.is_active(at(day, 12, 0)), active, "day {day}");
+        }
+        let list = Schedule::parse("00:00-24:00", Some("mon-tue,thu, SUN"), None).unwrap();
+        for (day, active) in [(0, true), (1, true), (2, false), (3, true), (4, false), (5, false), (6, true)] {
+            assert_eq!(list.is_active(at(day, 12, 0)), active, "day {day}");
+        }
+        // The epoch was a thursday, and times before a week fit too
+        assert!(list.is_active(0));
+        assert!(!list.is_active(86400));
+    }
+
+    #[test]
+    fn timezones_shift_the_window() {
+        let east = Schedule::parse("09:00-17:00", None, Some("+02:00")).unwrap();
+        assert!(!east.is_active(at(0, 6, 59)));
+        assert!(east.is_active(at(0, 7, 0)));
+        assert!(!east.is_active(at(0, 15, 0)));
+        // 20:00 on monday in UTC-05:00 is 01:00 on tuesday in UTC
+        let west = Schedule::parse("18:00-22:00", Some("mon"), Some("-05:00")).unwrap();
+        assert!(west.is_active(at(1, 1, 0)));
+        assert!(!west.is_active(at(0, 20, 0)));
+        assert_eq!(Schedule::parse("09:00-17:00", None, Some("UTC")).unwrap(), Schedule::parse("09:00-17:00", None, None).unwrap());
+    }
+
+    #[test]
+    fn schedules_round_trip() {
+        for (hours, days, timezone) in [
+            ("09:00-17:00", None, None),
+            ("22:30-02:15", Some("fri-mon"), Some("-05:30")),
+            ("00:00-24:00", Some("sun"), Some("+14:00"))
+        ] {
+            let schedule = Schedule::parse(hours, days, timezone).unwrap();
+            assert_eq!(Schedule::from_bytes(&schedule.to_bytes()).unwrap(), schedule);
+        }
+        let bytes = Schedule::parse("09:00-17:00", None, None).unwrap().to_bytes();
+        assert!(Schedule::from_bytes(&bytes[..SCHEDULE_LENGTH - 1]).is_err());
+        assert!(Schedule::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        let mut late = bytes;
+        late[0..2].copy_from_slice(&(24u16*60 + 1).to_be_bytes());
+        assert!(Schedule::from_bytes(&late).is_err());
+    }
+}
@@ -9,103 +9,1165 @@ You should have received a copy of the GNU General Public License along with thi
 If not, see <https://www.gnu.org/licenses/>. 
 */
 
-use crate::config::{CommonConfig, Port, ServerConfig};
-use crate::common::{spawn_pipes, MAGIC1, TCP_CHALLENGE_LENGTH};
-use crate::crypto::{self, AEAD_LENGTH};
+use crate::accounting;
+use crate::activation;
+use crate::admin;
+use crate::cidr::{AccessRules, Cidr};
+use crate::config::{parse_compact_redirect, persist_redirect, CommonConfig, Heartbeat, Keepalive, Port, Protocol, Redirect, ServerConfig, SpecificConfig};
+use crate::error::Failure;
+use crate::connector::{Connectors, Resolver, Stream, UNIX_TARGET_PREFIX};
+use crate::pool::LocalPool;
+use crate::transport::{Transport, Transports};
+use crate::common::{self, port_label, spawn_pipes, BufferConfig, PipeOptions, PipeRegistry, PipeWorkers, MEMORY, TCP_CHALLENGE_LENGTH};
+use crate::control::{self, BindError, ClientInfo, ControlMessage, ControlSender, ErrorCode, Registration, StreamFrame};
+use crate::crypto::{self, Channel, Cipher};
+use crate::datagram::DatagramStream;
+use crate::integrity::{self, DigestReport, Pending};
+use crate::knock;
+use crate::log;
+use crate::mirror::MirrorSink;
+use crate::mux::Mux;
+use crate::signal::Hangups;
+use crate::throttle::RateLimiter;
+use crate::{debug, error, info, warn};
 use anyhow::{anyhow, Result, Context};
-use std::net::{SocketAddr, TcpStream};
-use std::io::{Read, Write};
-use std::time::Duration;
+use rand::{RngCore, rngs::OsRng};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::io::Write;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::thread;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-const RETRY_DELAY : u64 = 60;
-const RESPONSE_BUFFER_SIZE : usize = 1024;
-const RESPONSE_MAX_SIZE : usize = 1048576;
+const MAX_AUTH_FAILURES : u32 = 3;
+const TEST_TIMEOUT : u64 = 5;
+const INITIAL_RETRY_DELAY : Duration = Duration::from_secs(2);
+// A session that lasted this long starts the waits over
+const STABLE_SESSION : Duration = Duration::from_secs(60);
+// Before the next session, once an established one ended, however long it lasted
+const RECONNECT_DELAY : Duration = Duration::from_secs(1);
+// How soon a session established while shutting down is closed
+const SHUTDOWN_POLL_INTERVAL : Duration = Duration::from_millis(100);
+// The attempts to connect back for a client, before telling the gateway it is refused
+const DIAL_BACK_ATTEMPTS : u32 = 2;
 
-/* Establish a new TCP connection, taking into account http_proxy if required */
-fn connect(scfg: &ServerConfig) -> Result<TcpStream> {
-    match &scfg.proxy {
-        None => {
-            TcpStream::connect(&scfg.gateway_address).context("Failed to connect to gateway")
-        }
-        Some(proxy) => {
-            println!("Connecting through http proxy");
-            let mut stream = TcpStream::connect(&proxy).context("Failed to connect to http proxy")?;
-            stream.write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", &scfg.gateway_address, &scfg.gateway_address).as_bytes())
-                .context("Failed to write HTTP connect to proxy")?;
-            stream.flush().context("Failed to flush HTTP connect to proxy")?;
-            
-            let mut response = Vec::new();
-            let mut buf = [0u8; RESPONSE_BUFFER_SIZE];
-            loop { // We first need to read an HTTP response, which ends with an empty line
-                let size = stream.read(&mut buf).context("Failed to read HTTP CONNECT respone")?;
-                if size == 0 {
-                    let response = String::from_utf8(response).context("Malformed UTF8 HTTP CONNECT response")?;
-                    println!("Stream ended early with response:\n{response}\n");
-                    return Err(anyhow!("Unexpected end of stream"));
-                } else if size+response.len() > RESPONSE_MAX_SIZE {
-                    let response = String::from_utf8(response).context("Malformed UTF8 partial HTTP CONNECT response")?;
-                    println!("HTTP connect partial response:\n{response}\n");
-                    return Err(anyhow!("Response too big"));
-                }
-                response.extend_from_slice(&buf[0..size]);
-                if response.len() >= 4 && (&response[response.len()-4..response.len()] == b"\r\n\r\n" || &response[response.len()-2..response.len()] == b"\n\n")  {
-                    break;        
+/// The waits between the attempts to reach the gateway double up to `retry_delay`, each drawn between half and all
+/// of it so that the servers of a gateway that comes back don't all reconnect at once
+struct Backoff {
+    max: Duration,
+    attempts: u32
+}
+
+impl Backoff {
+    fn next(&mut self) -> Duration {
+        let delay = INITIAL_RETRY_DELAY.saturating_mul(1 << self.attempts.min(16)).min(self.max);
+        self.attempts = self.attempts.saturating_add(1);
+        let half = delay.as_millis() as u64 / 2;
+        delay - Duration::from_millis(OsRng.next_u64() % (half + 1))
+    }
+
+    /// Before the next attempt: only the attempts that didn't establish a session back off, the gateway most
+    /// likely restarted when an established one ended
+    fn after(&mut self, result: &Result<Ended>) -> Duration {
+        match result {
+            Ok(Ended::Expired(_)) => Duration::ZERO,
+            Ok(Ended::Closed | Ended::Dropped(_)) => RECONNECT_DELAY,
+            Err(_) => self.next()
+        }
+    }
+}
+
+/* Run a step of the connection test, explaining what its failure usually means */
+fn test_step<T>(name: &str, failure: Failure, explanation: &str, step: impl FnOnce() -> Result<T>) -> Result<T> {
+    let start = Instant::now();
+    match step() {
+        Ok(x) => {
+            println!("[ ok ] {name} ({}ms)", start.elapsed().as_millis());
+            Ok(x)
+        }
+        Err(err) => {
+            println!("[fail] {name} ({}ms)\n{explanation}", start.elapsed().as_millis());
+            // A more specific classification, like a wrong key, wins
+            match Failure::of(&err) {
+                Failure::Transient => Err(err).context(failure),
+                _ => Err(err)
+            }
+        }
+    }
+}
+
+/// `smugglrs test-connection`: pair with each gateway without registering any port
+pub fn test_connection(ccfg: CommonConfig, scfg: ServerConfig) -> Result<()> {
+    let transport = Transports::default().get(&scfg).context(Failure::Config)?;
+    let mut result = Ok(());
+    for (i, gateway) in scfg.gateway_addresses.iter().enumerate() {
+        if scfg.gateway_addresses.len() > 1 {
+            println!("{}Gateway {gateway}:", if i > 0 { "\n" } else { "" });
+        }
+        // The first failure is the one returned, but every gateway is tested
+        if let Err(err) = test_gateway(&ccfg, &scfg, transport.as_ref(), gateway) {
+            result = result.and(Err(err));
+        }
+    }
+    result
+}
+
+fn test_gateway(ccfg: &CommonConfig, scfg: &ServerConfig, transport: &dyn Transport, gateway: &str) -> Result<()> {
+    let name = match (scfg.transport.as_str(), &scfg.proxy, &scfg.socks5_proxy) {
+        ("tcp", Some(proxy), _) => format!("TCP connect to {gateway} through the http proxy {proxy}"),
+        ("tcp", _, Some(proxy)) => format!("TCP connect to {gateway} through the SOCKS proxy {}", proxy.address),
+        ("tcp", None, None) => format!("TCP connect to {gateway}"),
+        (x, _, _) => format!("Connect to {gateway} over {x}")
+    };
+    if !scfg.knock.is_empty() {
+        test_step("Knock sequence sent", Failure::Transient, "The gateway's address can't be resolved, or the knocks can't be sent.",
+            || knock::send(gateway, scfg.address_family, &scfg.knock))?;
+    }
+    let mut control = test_step(&name, Failure::Transient,
+        "The gateway can't be reached: check gateway_address, port and the proxy options in config.toml and that the gateway is running.",
+        || transport.connect(gateway))?;
+    let hello = test_step("MAGIC1 sent", Failure::Transient, "The connection was closed right away.",
+        || crypto::send_hello(&ccfg.magics, &mut control))?;
+    control.set_read_timeout(Some(Duration::from_secs(TEST_TIMEOUT))).context("Failed to set read timeout")?;
+    let challenge = test_step("Challenge received", Failure::Config,
+        "Something answered, but not a smugglrs gateway with our key: check the port and that aeskey.bin is the gateway's. The gateway may also be busy with another server, expect another knock sequence, or be older than the server.",
+        || crypto::receive_challenge(&mut control))?;
+    let solved = test_step("Challenge solved", Failure::Authentication,
+        "The key doesn't match the gateway's: copy aeskey.bin from the gateway again.",
+        || crypto::solve_challenge(&ccfg.key, &ccfg.magics, &hello, &challenge, &mut control))?;
+    let (cipher, confirmed) = test_step("Gateway authenticated", Failure::Authentication,
+        "The gateway sent a challenge with our key but failed to prove that it has it: something between the server and the gateway may be relaying the connection.",
+        || crypto::confirm_gateway(&ccfg.magics, solved, &mut control))?;
+    if !confirmed {
+        println!("The gateway is older than the server and doesn't prove that it has the key: it should be updated.");
+    }
+    test_step("Session established", Failure::Config,
+        "The gateway closed the session: it may be too old to answer connection tests.", || {
+        control::write_message(&mut control, &mut cipher.channel(Channel::ToGateway), &ControlMessage::Probe)?;
+        match control::read_message(&mut control, &mut cipher.channel(Channel::ToServer))? {
+            ControlMessage::Probe => Ok(()),
+            _ => Err(anyhow!("Unexpected answer to the connection test"))
+        }
+    })?;
+    println!("The connection to the gateway works.");
+    Ok(())
+}
+
+/// State that outlives sessions, shared with the admin socket
+struct ServerState {
+    redirects: Mutex<HashMap<Port, Redirect>>,
+    /// Set while a session is established
+    control: Mutex<Option<ControlSender>>,
+    pipes: Arc<PipeRegistry>,
+    /// Ports that are kept in the redirects, but not registered on the gateway
+    paused: Mutex<HashSet<Port>>,
+    stats: Mutex<HashMap<Port, Arc<PortStats>>>,
+    /// Gateway address and pairing time of the current session
+    session: Mutex<Option<(SocketAddr, Instant)>>,
+    /// Sessions established so far, with any of the gateways
+    sessions: AtomicU64,
+    /// For the redirects with `preconnect`
+    pools: Mutex<HashMap<Port, Arc<LocalPool>>>,
+    connectors: Connectors,
+    transport: Arc<dyn Transport>,
+    /// For the redirects with `verify_integrity`
+    integrity: Arc<Pending>,
+    /// For the redirects with `rate`, shared by all their connections
+    limiters: Mutex<HashMap<Port, Arc<RateLimiter>>>,
+    config_path: PathBuf,
+}
+
+/// What library users can plug into the server
+#[derive(Default, Clone)]
+pub struct Extensions {
+    pub connectors: Connectors,
+    pub transports: Transports,
+    pub shutdown: Shutdown
+}
+
+/// Stops the server from the outside, e.g. when the Windows service manager stops it: the session is closed
+/// and `run` returns
+#[derive(Default, Clone)]
+pub struct Shutdown(Arc<(Mutex<bool>, Condvar)>);
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        *self.0.0.lock().unwrap() = true;
+        self.0.1.notify_all();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.0.0.lock().unwrap()
+    }
+
+    /// Whether it was triggered within `timeout`
+    fn wait(&self, timeout: Duration) -> bool {
+        let triggered = self.0.0.lock().unwrap();
+        *self.0.1.wait_timeout_while(triggered, timeout, |triggered| !*triggered).unwrap().0
+    }
+}
+
+/// Counters of a redirect, since the server started
+#[derive(Default)]
+struct PortStats {
+    connections: AtomicU64,
+    bytes: Arc<AtomicU64>
+}
+
+/* Paused ports are only registered when they have maintenance, to keep them bound */
+fn registration(port: Port, redirect: &Redirect, paused: bool) -> Registration {
+    Registration {
+        port,
+        schedule: redirect.schedule,
+        hide_client: !redirect.forward_client_addr,
+        access: redirect.access.clone(),
+        first_byte_timeout: redirect.first_byte_timeout,
+        maintenance: redirect.maintenance.clone(),
+        paused,
+        verify_integrity: redirect.verify_integrity,
+        encrypt: redirect.encrypt,
+        name: redirect.name.clone(),
+        pipe_buffer: redirect.pipe_buffer,
+        nodelay: redirect.nodelay
+    }
+}
+
+/* Clears the control sender and stops the pipes once the session is over */
+struct SessionGuard<'a>(&'a ServerState);
+
+impl Drop for SessionGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.control.lock().unwrap() = None;
+        *self.0.session.lock().unwrap() = None;
+        let count = self.0.pipes.close();
+        if count > 0 {
+            info!("Cancelled the {count} active connections of the session");
+        }
+    }
+}
+
+/* Pings the gateway while the session lasts; its answers keep the read timeout of the control socket from expiring */
+struct Pinger(Arc<AtomicBool>);
+
+impl Pinger {
+    fn spawn(state: Arc<ServerState>, heartbeat: Heartbeat) -> Pinger {
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = stop.clone();
+            let ping = ControlMessage::Ping { interval: heartbeat.interval, misses: heartbeat.misses };
+            thread::spawn(move || while !stop.load(Ordering::Relaxed) {
+                // A failed write ends the session by itself
+                if let Err(err) = notify_gateway(&state, &ping) {
+                    debug!("Failed to ping the gateway, reason: {err:#}");
+                    return;
                 }
+                thread::sleep(heartbeat.interval);
+            });
+        }
+        Pinger(stop)
+    }
+}
+
+impl Drop for Pinger {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// How an established session ended, the errors are the ones before or besides the control connection dropping
+enum Ended {
+    /// By the gateway
+    Closed,
+    /// By the gateway, once it lasted its max_session_duration: the server pairs again right away
+    Expired(String),
+    /// The control connection failed: the gateway likely restarted, and may be back already
+    Dropped(anyhow::Error)
+}
+
+impl Ended {
+    fn into_result(self) -> Result<()> {
+        match self {
+            Ended::Closed | Ended::Expired(_) => Ok(()),
+            Ended::Dropped(err) => Err(err)
+        }
+    }
+}
+
+/* The gateways of the configuration, tried from the last one a session was established with */
+struct Failover {
+    preferred: usize
+}
+
+impl Failover {
+    /// Tries the gateways in turn until a session is established with one of them: the result is that session's,
+    /// or the failure of the last gateway
+    fn session(&mut self, ccfg: &CommonConfig, scfg: &ServerConfig, state: &Arc<ServerState>, mirrors: &HashMap<Port, MirrorSink>, shutdown: &Shutdown) -> Result<Ended> {
+        let count = scfg.gateway_addresses.len();
+        let mut tried = 0;
+        loop {
+            let i = (self.preferred + tried) % count;
+            let gateway = &scfg.gateway_addresses[i];
+            let sessions = state.sessions.load(Ordering::Relaxed);
+            let result = server(ccfg, scfg, state, mirrors, gateway);
+            if state.sessions.load(Ordering::Relaxed) != sessions {
+                self.preferred = i;
+                return result;
             }
-            let response = String::from_utf8(response).context("Received bad HTTP response")?;
-            print!("http proxy response:\n{response}");
-            Ok(stream)
+            let err = match result {
+                Ok(ended) => return Ok(ended),
+                Err(err) => err
+            };
+            tried += 1;
+            // Another gateway won't fix the configuration
+            if tried == count || shutdown.is_triggered() || !matches!(Failure::of(&err), Failure::Transient | Failure::Authentication) {
+                return Err(err);
+            }
+            warn!("Failed to reach the gateway {gateway}, trying {} instead, reason: {err:#}", scfg.gateway_addresses[(i + 1) % count]);
         }
     }
 }
 
-fn server(ccfg: &CommonConfig, scfg: &ServerConfig) -> Result<()> {
-    let mut control = connect(&scfg).context("Failed to connect to gateway")?;
-    control.write(MAGIC1).context("Failed to write MAGIC1")?;
-    control.flush().context("Failed to flush MAGIC1")?;
-    let mut cipher = crypto::answer_challenge(&ccfg.key, &mut control).context("Failed to solve server's challenge")?;
+/* A session with one of the gateways, `gateway`; its data connections go to the same one */
+fn server(ccfg: &CommonConfig, scfg: &ServerConfig, state: &Arc<ServerState>, mirrors: &HashMap<Port, MirrorSink>, gateway: &str) -> Result<Ended> {
+    if !scfg.knock.is_empty() {
+        knock::send(gateway, scfg.address_family, &scfg.knock).context("Failed to knock on the gateway")?;
+    }
+    let mut control = state.transport.connect(gateway).context("Failed to connect to gateway")?;
+    common::set_keepalive(&control, ccfg.keepalive);
+    // Something that accepted the connection but never answers must not hang us, retrying takes care of it
+    control.set_read_timeout(Some(scfg.handshake_timeout)).context("Failed to set the handshake timeout")?;
+    control.set_write_timeout(Some(scfg.handshake_timeout)).context("Failed to set the handshake timeout")?;
+    let hello = crypto::send_hello(&ccfg.magics, &mut control)?;
+    let (cipher, confirmed) = crypto::answer_challenge(&ccfg.key, &ccfg.magics, &hello, &mut control).map_err(|err| match control::is_timeout(&err) {
+        true => err.context(format!("The gateway accepted the connection but didn't answer the handshake within {}s (handshake_timeout)", scfg.handshake_timeout.as_secs())),
+        false => err.context("Failed to solve server's challenge")
+    })?;
     control.set_read_timeout(None).context("Failed to disable timeout on control socket")?; //We will be waiting for new connections, disable read timeout
-    println!("Challenge solved, connection established. Sending ports to bind...");
+    let session = log::new_session();
+    let _tag = log::tagged(log::Tag { session: Some(session), conn: None });
+    match confirmed {
+        true => info!("Gateway {gateway} authenticated as a peer (key fingerprint {})", crypto::fingerprint(&ccfg.key)),
+        false => warn!("Gateway {gateway} is older than the server and doesn't prove that it has the key, it should be updated")
+    }
+    info!("Challenge solved, connection established with the gateway {gateway}. Sending ports to bind...");
+    let mut receiver = cipher.channel(Channel::ToServer);
+    let data_cipher = cipher.channel(Channel::DataChallenge);
+    // Ready before the registration, the gateway opens streams as soon as it answered it
+    let mut mux = scfg.multiplex.then(|| {
+        let state = state.clone();
+        Mux::new(Box::new(move |msg| match state.control.lock().unwrap().as_mut() {
+            Some(sender) => sender.send(msg),
+            None => Err(anyhow!("The session is over"))
+        }))
+    });
     {
-        let mut ports = Vec::new();
-        for (port, _) in &scfg.redirects {
-            ports.extend_from_slice(&port.to_bytes());
-        }
-        let length : u8 = (scfg.redirects.len()*3+AEAD_LENGTH).try_into().context("Too many forwarded port, should be less than 78")?; 
-        let encrypted_length = cipher.encrypt(&[length]);
-        control.write_all(&encrypted_length).context("Failed to write encrypted length")?;
-        let encrypted_ports = cipher.encrypt(&ports);
-        control.write_all(&encrypted_ports).context("Failed to write encrypted ports")?;
-        control.flush().context("Failed to flush encrypted length+ports")?;
-    }
-    println!("Done. Waiting for new connections...");
+        // Hold the lock until the sender is available, so that no redirect added meanwhile is missed
+        let redirects = state.redirects.lock().unwrap();
+        let paused = state.paused.lock().unwrap();
+        let mut sender = ControlSender::new(control.try_clone().context("Failed to clone control socket")?, cipher.channel(Channel::ToGateway));
+        let registrations = redirects.iter()
+            .filter(|(port, redirect)| !paused.contains(port) || redirect.maintenance.is_some())
+            .map(|(port, redirect)| registration(*port, redirect, paused.contains(port))).collect();
+        sender.send(&ControlMessage::Register { registrations, bind_status: true, rekey: true, multiplex: scfg.multiplex, session: Some(session) }).map_err(|err| match control::is_timeout(&err) {
+            true => err.context(format!("The gateway stopped reading the ports for {}s (handshake_timeout)", scfg.handshake_timeout.as_secs())),
+            false => err.context("Failed to send ports")
+        })?;
+        // Past the registration, the heartbeat notices a gateway that is gone
+        control.set_write_timeout(None).context("Failed to disable timeout on control socket")?;
+        *state.control.lock().unwrap() = Some(sender);
+        *state.session.lock().unwrap() = Some((control.peer_addr().context("Failed to get gateway address")?, Instant::now()));
+    }
+    state.pipes.reopen();
+    let _session = SessionGuard(state);
+    // The gateway answers every ping, so nothing coming for a few of them means it's gone
+    if let Some(heartbeat) = scfg.heartbeat {
+        control.set_read_timeout(Some(heartbeat.timeout())).context("Failed to set the heartbeat timeout")?;
+    }
+    let _pinger = scfg.heartbeat.map(|heartbeat| Pinger::spawn(state.clone(), heartbeat));
+    info!("Done. Waiting for new connections...");
+    state.sessions.fetch_add(1, Ordering::Relaxed);
+    activation::ready(&format!("Connected to the gateway {gateway}"));
+    // Until the gateway reported the ports of the registration
+    let mut registering = true;
+    // Older gateways would end the session on a `Refused`
+    let mut refusals = false;
     loop {
-        let mut msg = [0u8; 2 + TCP_CHALLENGE_LENGTH + AEAD_LENGTH];
-        control.read_exact(&mut msg).context("Failed to read control message")?;
-        let msg = cipher.decrypt(&msg).context("Failed to decrypt control message")?;
-        let port = u16::from_be_bytes(msg[0..2].try_into().unwrap());
-        let mut gateway_socket = connect(&scfg).context("Failed to establish a new connection to the gateway")?;
-        gateway_socket.write_all(&cipher.encrypt(&msg[2..])).context("Failed to write new connection challenge")?;
-        gateway_socket.flush().context("Failed to flush new connection challenge")?;
-        let local_port = match scfg.redirects.get(&Port::new_tcp(port)) {
-            Some(port) => *port,
-            None => {
-                return Err(anyhow!("Server sent an invalid port"));
+        let msg = match control::try_read_message(&mut control, &mut receiver) {
+            Ok(Some(msg)) => msg,
+            Ok(None) => {
+                info!("Gateway closed the session");
+                return Ok(Ended::Closed);
+            }
+            Err(err) if control::is_timeout(&err) => {
+                let timeout = scfg.heartbeat.map_or(0, |heartbeat| heartbeat.timeout().as_secs());
+                return Ok(Ended::Dropped(anyhow!("Nothing came from the gateway for {timeout}s, it is gone")));
             }
+            Err(err) => return Ok(Ended::Dropped(err))
         };
-        let local_socket = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], local_port))).context("Failed to connect to the local server")?;
-        spawn_pipes(gateway_socket, local_socket).context("Failed to spawn pipes")?;
+        let (port, challenge, client, stream) = match msg {
+            ControlMessage::NewConnection { port, challenge, client } => (port, challenge, client, None),
+            ControlMessage::Stream { id, frame: StreamFrame::Open { port, token, client } } => match &mux {
+                Some(mux) => (port, token, client, Some(mux.accept(id))),
+                None => return Err(anyhow!("Gateway opened a stream, but the connections aren't multiplexed"))
+            },
+            ControlMessage::Stream { id, frame } => {
+                if let Some(mux) = &mux {
+                    mux.dispatch(id, frame);
+                }
+                continue;
+            }
+            ControlMessage::Error { code: ErrorCode::SessionRefused, message } => {
+                return Err(anyhow!("Gateway refused the session: {message}"));
+            }
+            ControlMessage::Error { code: ErrorCode::SessionExpired, message } => return Ok(Ended::Expired(message)),
+            ControlMessage::Error { code, message } => {
+                warn!("Gateway reported an error ({code:?}): {message}");
+                continue;
+            }
+            ControlMessage::Integrity { id, digests } => {
+                state.integrity.gateway(id, digests);
+                continue;
+            }
+            ControlMessage::Pong => continue,
+            ControlMessage::BindStatus { statuses, rekey, multiplex, refused } => {
+                let failed = bind_failures(&statuses);
+                if registering {
+                    registering = false;
+                    refusals = refused;
+                    // Older gateways can't follow a new key
+                    if let Some(sender) = state.control.lock().unwrap().as_mut() {
+                        sender.set_rekey_after(rekey.then_some(ccfg.rekey_after));
+                    }
+                    if mux.is_some() && !multiplex {
+                        warn!("The gateway is older than the server and can't multiplex, each connection gets its own");
+                        mux = None;
+                    }
+                    info!("The gateway bound {} of the {} ports", statuses.len() - failed, statuses.len());
+                    if failed > 0 && scfg.require_all_ports {
+                        return Err(anyhow!("The gateway failed to bind {failed} of the {} ports and require_all_ports is set", statuses.len())).context(Failure::Config);
+                    }
+                }
+                continue;
+            }
+            ControlMessage::Register { .. } | ControlMessage::AddPort(_) | ControlMessage::RemovePort(_) | ControlMessage::UpdatePort(_) | ControlMessage::Probe | ControlMessage::Refused { .. } => {
+                return Err(anyhow!("Gateway sent an unexpected registration")).context(Failure::Config);
+            }
+            ControlMessage::Ping { .. } => return Err(anyhow!("Gateway sent an unexpected ping"))
+        };
+        // Each connection is dialed back on its own, so that the clients arriving together wait a single round trip
+        let (buffers, state, mirror, data_cipher, gateway) = (ccfg.buffers, state.clone(), mirrors.get(&port).cloned(), data_cipher.clone(), gateway.to_string());
+        let keepalive = ccfg.keepalive.filter(|x| x.data);
+        log::spawn(move || {
+            let tunnel : Box<dyn Stream> = match stream {
+                Some(stream) => Box::new(stream),
+                None => match dial_back(keepalive, &state, &gateway, &data_cipher, &challenge) {
+                    Ok(tunnel) => tunnel,
+                    // Only this client is lost, the session goes on
+                    Err(err) => {
+                        let _tag = log::connection(client.conn);
+                        warn!("Failed to connect back to the gateway for {client} on port {}, reason: {err:#}", port.port);
+                        if refusals {
+                            if let Err(err) = notify_gateway(&state, &ControlMessage::Refused { token: challenge, reason: format!("{err:#}") }) {
+                                warn!("{err:#}");
+                            }
+                        }
+                        return;
+                    }
+                }
+            };
+            if let Err(err) = forward(buffers, &state, mirror.as_ref(), &data_cipher, Request { port, challenge, client, tunnel }) {
+                warn!("Failed to forward a connection of port {}, reason: {err:#}", port.port);
+            }
+        });
     }    
 }
 
+/* The connection for a client, presenting its token. A failed attempt is tried again at once, as the gateway
+   waits for it up to its connect_timeout anyway */
+fn dial_back(keepalive: Option<Keepalive>, state: &ServerState, gateway: &str, data_cipher: &Cipher, challenge: &[u8]) -> Result<Box<dyn Stream>> {
+    let mut attempt = 1;
+    loop {
+        let connected = state.transport.connect(gateway).context("Failed to establish a new connection to the gateway").and_then(|mut gateway_socket| {
+            common::set_keepalive(&gateway_socket, keepalive);
+            gateway_socket.write_all(&data_cipher.seal_token(challenge).context("Failed to seal new connection challenge")?).context("Failed to write new connection challenge")?;
+            gateway_socket.flush().context("Failed to flush new connection challenge")?;
+            Ok(Box::new(gateway_socket) as Box<dyn Stream>)
+        });
+        match connected {
+            Err(err) if attempt < DIAL_BACK_ATTEMPTS => debug!("Attempt {attempt} to connect back to the gateway failed, retrying, reason: {err:#}"),
+            connected => return connected
+        }
+        attempt += 1;
+    }
+}
+
+/* A connection the gateway asked for, with the tunnel side it comes through */
+struct Request {
+    port: Port,
+    challenge: [u8; TCP_CHALLENGE_LENGTH],
+    client: ClientInfo,
+    tunnel: Box<dyn Stream>
+}
+
+/* Connects the local side of a connection, and pipes it to the tunnel */
+fn forward(buffers: BufferConfig, state: &ServerState, mirror: Option<&MirrorSink>, data_cipher: &Cipher, request: Request) -> Result<()> {
+    let Request { port, challenge, client, tunnel } = request;
+    let _tag = log::connection(client.conn);
+    let redirect = match state.redirects.lock().unwrap().get(&port) {
+        Some(redirect) => redirect.clone(),
+        None => {
+            // It may have been removed while the gateway was notifying us
+            warn!("Gateway requested port {} which is not redirected, closing", port.port);
+            return Ok(());
+        }
+    };
+    let label = port_label(port.port, redirect.name.as_deref());
+    match client.requested_at {
+        0 => debug!("Piping new stream from {client} on port {label}"),
+        // Both clocks may not agree, this is only a hint
+        requested_at => debug!("Piping new stream from {client} on port {label}, requested by the gateway {}ms ago",
+            unix_time_ms().saturating_sub(requested_at))
+    }
+    let pool = state.pools.lock().unwrap().get(&port).cloned();
+    let connected : Result<Box<dyn Stream>> = match (pool, port.protocol) {
+        (_, Protocol::UDP) => connect_udp(redirect.local_port),
+        (Some(pool), _) => pool.take().context("Failed to connect to the local server").map(|x| Box::new(x) as Box<dyn Stream>),
+        (None, _) => state.connectors.get(&redirect)?.connect(&redirect)
+    };
+    // Only this connection is lost, the gateway sees it closed
+    let local_socket = match connected {
+        Ok(socket) => socket,
+        Err(err) => {
+            warn!("Failed to reach the local side of port {label}, closing, reason: {err:#}");
+            let _ = tunnel.shutdown_stream();
+            return Ok(());
+        }
+    };
+    let stats = state.stats.lock().unwrap().entry(port).or_default().clone();
+    let options = PipeOptions {
+        mirror: redirect.mirror.and(mirror).map(MirrorSink::tap),
+        counter: Some(stats.bytes.clone()),
+        registry: Some(state.pipes.clone()),
+        port: Some(port.port),
+        name: redirect.name.clone(),
+        peer: client.addr,
+        buffers: buffers.sized(redirect.pipe_buffer),
+        nodelay: redirect.nodelay,
+        integrity: redirect.verify_integrity.then(|| {
+            let (pending, id) = (state.integrity.clone(), integrity::connection_id(&challenge));
+            Arc::new(move |digests| pending.local(id, port.port, digests)) as DigestReport
+        }),
+        // What goes towards the gateway is sealed, what comes from it opened. The streams of the control connection
+        // are encrypted with it already
+        encryption: (redirect.encrypt && tunnel.as_tcp().is_some()).then(|| data_cipher.connection_ciphers(&challenge)).transpose()
+            .context("Failed to derive the keys of the connection")?
+            .map(|(to_server, to_gateway)| (to_gateway, to_server)),
+        rate: redirect.rate.map(|rate| {
+            let mut limiters = state.limiters.lock().unwrap();
+            let limiter = limiters.entry(port).or_insert_with(|| RateLimiter::new(rate));
+            // The redirect may have been replaced with another rate
+            if limiter.rate() != rate {
+                *limiter = RateLimiter::new(rate);
+            }
+            limiter.clone()
+        }),
+        ..Default::default()
+    };
+    // Not counted when refused for lack of memory
+    spawn_pipes(tunnel, local_socket, options).context("Failed to spawn pipes")?;
+    stats.connections.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+/* The redirects of the ports the gateway couldn't bind do nothing, which is worth more than a warning */
+fn bind_failures(statuses: &[(Port, Option<BindError>)]) -> usize {
+    let mut failed = 0;
+    for (port, error) in statuses {
+        if let Some(error) = error {
+            error!("The gateway failed to bind port {port}, its redirect does nothing, reason: {error}");
+            failed += 1;
+        }
+    }
+    failed
+}
+
+/* Each client peer gets its own socket, so that the answers of the local service go back to it */
+fn connect_udp(local_port: u16) -> Result<Box<dyn Stream>> {
+    let socket = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).context("Failed to bind a UDP socket")?;
+    socket.connect(SocketAddr::from(([127, 0, 0, 1], local_port))).context("Failed to connect the UDP socket to the local port")?;
+    Ok(Box::new(DatagramStream::connected(socket).context("Failed to set up the UDP socket")?))
+}
+
+fn unix_time_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/* Send a message to the gateway if a session is established */
+fn notify_gateway(state: &ServerState, msg: &ControlMessage) -> Result<String> {
+    match state.control.lock().unwrap().as_mut() {
+        Some(sender) => {
+            sender.send(msg).context("Failed to notify the gateway")?;
+            Ok("sent to the gateway".to_string())
+        }
+        None => Ok("no session is established, the gateway will be notified when it is".to_string())
+    }
+}
+
+/// `port add <redirect> [--persist]` and `port remove <redirect> [--persist]` admin commands.
+/// Without `--persist`, changes are lost when smugglrs restarts
+fn port_command(state: &ServerState, args: &[&str]) -> Result<String> {
+    if let Some((&"rules", args)) = args.split_first() {
+        return rules_command(state, args);
+    }
+    let (action, spec, persist) = match args {
+        [action, spec] => (*action, *spec, false),
+        [action, spec, "--persist"] => (*action, *spec, true),
+        _ => return Err(anyhow!("usage: port add|remove|pause|resume <port>[:<local port>][/<protocol>] [--persist], or port rules <port>[/<protocol>] [allow=<cidr>,...] [deny=<cidr>,...]"))
+    };
+    let (port, redirect) = parse_compact_redirect(spec)?;
+    // Hold the lock while notifying the gateway, so that messages are sent in the same order as the changes
+    let mut redirects = state.redirects.lock().unwrap();
+    match action {
+        "add" => {
+            if redirects.contains_key(&port) {
+                return Err(anyhow!("Duplicate port detected, {} is bound at least twice", port.port));
+            }
+            if redirects.len() >= u16::MAX as usize {
+                return Err(anyhow!("Too many redirects"));
+            }
+            if persist {
+                persist_redirect(&state.config_path, port, Some(&redirect))?;
+            }
+            let msg = ControlMessage::AddPort(registration(port, &redirect, false));
+            redirects.insert(port, redirect);
+            info!("Redirect {spec} added from the admin socket");
+            notify_gateway(state, &msg)
+        }
+        "remove" => {
+            if !redirects.contains_key(&port) {
+                return Err(anyhow!("Port {} is not redirected", port.port));
+            }
+            // Kept when the configuration file can't be updated
+            if persist {
+                persist_redirect(&state.config_path, port, None)?;
+            }
+            let redirect = redirects.remove(&port).unwrap();
+            info!("Redirect {spec} removed from the admin socket");
+            state.pools.lock().unwrap().remove(&port);
+            if state.paused.lock().unwrap().remove(&port) && redirect.maintenance.is_none() {
+                return Ok("removed a paused port".to_string());
+            }
+            notify_gateway(state, &ControlMessage::RemovePort(port))
+        }
+        "pause" | "resume" if persist => Err(anyhow!("Pausing a port can't be persisted")),
+        "pause" => {
+            let redirect = redirects.get(&port).with_context(|| format!("Port {} is not redirected", port.port))?;
+            if !state.paused.lock().unwrap().insert(port) {
+                return Err(anyhow!("Port {} is already paused", port.port));
+            }
+            info!("Redirect {port} paused from the admin socket");
+            match redirect.maintenance {
+                Some(_) => notify_gateway(state, &ControlMessage::UpdatePort(registration(port, redirect, true))),
+                None => notify_gateway(state, &ControlMessage::RemovePort(port))
+            }
+        }
+        "resume" => {
+            let redirect = redirects.get(&port).with_context(|| format!("Port {} is not redirected", port.port))?;
+            if !state.paused.lock().unwrap().remove(&port) {
+                return Err(anyhow!("Port {} is not paused", port.port));
+            }
+            info!("Redirect {port} resumed from the admin socket");
+            match redirect.maintenance {
+                Some(_) => notify_gateway(state, &ControlMessage::UpdatePort(registration(port, redirect, false))),
+                None => notify_gateway(state, &ControlMessage::AddPort(registration(port, redirect, false)))
+            }
+        }
+        x => Err(anyhow!("{x} is not a valid port action, expected add, remove, pause or resume"))
+    }
+}
+
+/* The idle connections kept ready for a redirect with `preconnect` */
+fn local_pool(redirect: &Redirect) -> Option<Arc<LocalPool>> {
+    (redirect.preconnect > 0).then(|| LocalPool::new(SocketAddr::from(([127, 0, 0, 1], redirect.local_port)), redirect.preconnect))
+}
+
+/// On SIGHUP, the redirects of the configuration file replace ours, including the ones added from the admin socket
+/// without `--persist`. The other options only change once smugglrs restarts
+fn reload(state: &ServerState) -> Result<String> {
+    let scfg = match CommonConfig::new(Some(&state.config_path))? {
+        (_, SpecificConfig::Server(scfg)) => scfg,
+        (_, SpecificConfig::Gateway(_)) => return Err(anyhow!("The configuration is now a gateway's, restart smugglrs to change its mode"))
+    };
+    for redirect in scfg.redirects.values() {
+        state.connectors.get(redirect)?;
+    }
+    // Hold the lock while notifying the gateway, so that messages are sent in the same order as the changes
+    let mut redirects = state.redirects.lock().unwrap();
+    let mut messages = Vec::new();
+    let (mut added, mut removed, mut changed) = (0, 0, 0);
+    for port in redirects.keys().filter(|port| !scfg.redirects.contains_key(port)).copied().collect::<Vec<Port>>() {
+        let redirect = redirects.remove(&port).unwrap();
+        state.pools.lock().unwrap().remove(&port);
+        state.limiters.lock().unwrap().remove(&port);
+        if !state.paused.lock().unwrap().remove(&port) || redirect.maintenance.is_some() {
+            messages.push(ControlMessage::RemovePort(port));
+        }
+        removed += 1;
+    }
+    for (port, redirect) in scfg.redirects {
+        let paused = state.paused.lock().unwrap().contains(&port);
+        let message = match redirects.get(&port) {
+            Some(current) if *current == redirect => continue,
+            Some(current) => {
+                if current.mirror != redirect.mirror {
+                    warn!("The mirror of redirect {port} changes once smugglrs restarts");
+                }
+                changed += 1;
+                (!paused || redirect.maintenance.is_some()).then(|| ControlMessage::UpdatePort(registration(port, &redirect, paused)))
+            }
+            None => {
+                if redirect.mirror.is_some() {
+                    warn!("Redirect {port} is mirrored once smugglrs restarts");
+                }
+                added += 1;
+                Some(ControlMessage::AddPort(registration(port, &redirect, false)))
+            }
+        };
+        let mut pools = state.pools.lock().unwrap();
+        pools.remove(&port);
+        pools.extend(local_pool(&redirect).map(|pool| (port, pool)));
+        state.limiters.lock().unwrap().remove(&port);
+        messages.extend(message);
+        redirects.insert(port, redirect);
+    }
+    for message in &messages {
+        notify_gateway(state, message).context("The gateway will get the redirects at the next session")?;
+    }
+    Ok(format!("{added} redirects added, {removed} removed and {changed} changed"))
+}
+
+/// `port rules <port> [allow=<cidr>,...] [deny=<cidr>,...]` replaces the client rules of a redirect,
+/// without any list everyone is allowed again (as far as the gateway's own rules go)
+fn rules_command(state: &ServerState, args: &[&str]) -> Result<String> {
+    let (spec, lists) = args.split_first().context("usage: port rules <port>[/<protocol>] [allow=<cidr>,...] [deny=<cidr>,...]")?;
+    let (port, _) = parse_compact_redirect(spec)?;
+    let mut access = AccessRules::default();
+    for list in lists {
+        let parse = |x: &str| x.split(',').map(Cidr::parse).collect::<Result<Vec<Cidr>>>();
+        match list.split_once('=') {
+            Some(("allow", x)) => access.allow = parse(x)?,
+            Some(("deny", x)) => access.deny = parse(x)?,
+            _ => return Err(anyhow!("{list} should be of the form allow=<cidr>,... or deny=<cidr>,..."))
+        }
+    }
+    let mut redirects = state.redirects.lock().unwrap();
+    let redirect = redirects.get_mut(&port).with_context(|| format!("Port {} is not redirected", port.port))?;
+    info!("Client rules of redirect {port} set to {access} from the admin socket");
+    redirect.access = access;
+    let paused = state.paused.lock().unwrap().contains(&port);
+    if paused && redirect.maintenance.is_none() {
+        return Ok("the port is paused, the gateway will be notified when it is resumed".to_string());
+    }
+    notify_gateway(state, &ControlMessage::UpdatePort(registration(port, redirect, paused)))
+}
+
+/* A single line, made of segments separated by "; ", each made of space separated key=value fields.
+   New fields may be added, so readers should ignore the ones they don't know */
+fn status_command(state: &ServerState) -> Result<String> {
+    let mut segments = Vec::new();
+    segments.push(match *state.session.lock().unwrap() {
+        Some((peer, since)) => format!("session peer={peer} uptime={}", since.elapsed().as_secs()),
+        None => "session down".to_string()
+    });
+    let pipes = state.pipes.list();
+    {
+        let redirects = state.redirects.lock().unwrap();
+        let paused = state.paused.lock().unwrap();
+        let stats = state.stats.lock().unwrap();
+        let mut ports : Vec<&Port> = redirects.keys().collect();
+        ports.sort_by_key(|port| port.port);
+        for port in ports {
+            let (connections, bytes) = stats.get(port)
+                .map_or((0, 0), |stats| (stats.connections.load(Ordering::Relaxed), stats.bytes.load(Ordering::Relaxed)));
+            let local = match &redirects[port].unix {
+                Some(path) => format!("{UNIX_TARGET_PREFIX}{}", path.display()),
+                None => redirects[port].local_port.to_string()
+            };
+            let mut segment = format!("port={port} local={local} pipes={} connections={connections} bytes={bytes} paused={}",
+                pipes.iter().filter(|pipe| pipe.port == Some(port.port)).count(), paused.contains(port));
+            if let Some(name) = &redirects[port].name {
+                segment.push_str(&format!(" name={name}"));
+            }
+            if let Some(host) = &redirects[port].host {
+                segment.push_str(&format!(" host={host}"));
+            }
+            if redirects[port].encrypt {
+                segment.push_str(" encrypted");
+            }
+            if let Some(rate) = redirects[port].rate {
+                segment.push_str(&format!(" rate={rate}"));
+            }
+            if !redirects[port].access.is_empty() {
+                segment.push_str(&format!(" {}", redirects[port].access));
+            }
+            if let Some(pool) = state.pools.lock().unwrap().get(port) {
+                let (idle, size, hits, misses) = pool.stats();
+                segment.push_str(&format!(" pool={idle}/{size} pool_hits={hits} pool_misses={misses}"));
+            }
+            segments.push(segment);
+        }
+    }
+    let memory = MEMORY.stats();
+    segments.push(format!("memory used={} budget={} downsized={} rejected={}", memory.used,
+        memory.limit.map_or("none".to_string(), |limit| limit.to_string()), memory.downsized, memory.rejected));
+    segments.extend(pipes.iter().map(admin::pipe_segment));
+    Ok(segments.join("; "))
+}
+
+fn pipe_command(state: &ServerState, args: &[&str]) -> Result<String> {
+    match args {
+        ["kill", id] => {
+            let id : u64 = id.parse().with_context(|| format!("{id} is not a valid pipe ID"))?;
+            if !state.pipes.kill(id) {
+                return Err(anyhow!("There is no pipe {id}"));
+            }
+            info!("Pipe {id} killed from the admin socket");
+            Ok(format!("pipe {id} killed"))
+        }
+        _ => Err(anyhow!("usage: pipe kill <id>"))
+    }
+}
+
 pub fn main(ccfg: CommonConfig, scfg: ServerConfig) -> Result<()> {
-    let retry = Duration::from_secs(RETRY_DELAY);
-    println!("Server started.");
+    run(ccfg, scfg, Extensions::default())
+}
+
+/// Run the server, with custom connectors for the redirects with `target = "custom:<name>"`,
+/// and custom transports for the schemes of `gateway_address`
+pub fn run(ccfg: CommonConfig, scfg: ServerConfig, extensions: Extensions) -> Result<()> {
+    let hangups = Hangups::block()?;
+    let _accounting = accounting::start(&ccfg.accounting)?;
+    let Extensions { mut connectors, transports, shutdown } = extensions;
+    connectors.set_resolver(Resolver::new(scfg.resolve_ttl, scfg.resolve_negative_ttl));
+    for redirect in scfg.redirects.values() {
+        connectors.get(redirect).context(Failure::Config)?;
+    }
+    let transport = transports.get(&scfg).context(Failure::Config)?;
+    // Every redirect is registered in a single message, better to find out now that they don't fit
+    let registrations = scfg.redirects.iter().map(|(port, redirect)| registration(*port, redirect, false)).collect();
+    let length = ControlMessage::Register { registrations, bind_status: true, rekey: true, multiplex: true, session: Some(0) }.encoded_length().context(Failure::Config)?;
+    if length > control::MAX_MESSAGE_LENGTH {
+        return Err(anyhow!("The {} redirects don't fit in the registration sent to the gateway ({length} bytes, at most {}), use fewer ports or shorter options",
+            scfg.redirects.len(), control::MAX_MESSAGE_LENGTH)).context(Failure::Config);
+    }
+    MEMORY.set_limit(ccfg.buffers.budget);
+    PipeWorkers::start(ccfg.pipe_workers).context("Failed to start the pipe workers")?;
+    // Mirror sinks outlive sessions, so that connection IDs keep increasing across reconnections
+    let mirrors : HashMap<Port, MirrorSink> = scfg.redirects.iter()
+        .filter_map(|(port, redirect)| redirect.mirror.clone().map(|target| (*port, MirrorSink::new(target))))
+        .collect();
+    let state = Arc::new(ServerState {
+        redirects: Mutex::new(scfg.redirects.clone()),
+        control: Mutex::new(None),
+        pipes: Arc::new(PipeRegistry::default()),
+        paused: Mutex::new(HashSet::new()),
+        stats: Mutex::new(HashMap::new()),
+        session: Mutex::new(None),
+        sessions: AtomicU64::new(0),
+        pools: Mutex::new(scfg.redirects.iter()
+            .filter_map(|(port, redirect)| local_pool(redirect).map(|pool| (*port, pool)))
+            .collect()),
+        connectors,
+        transport,
+        integrity: Arc::new(Pending::default()),
+        limiters: Mutex::new(HashMap::new()),
+        config_path: scfg.config_path.clone()
+    });
+    state.pipes.spawn_reaper(ccfg.reaper);
+    {
+        let state = state.clone();
+        hangups.handle(move || match reload(&state) {
+            Ok(summary) => info!("Configuration reloaded, {summary}"),
+            Err(err) => error!("Failed to reload the configuration, keeping the current one, reason: {err:#}")
+        });
+    }
+    {
+        let state = state.clone();
+        admin::serve(&scfg.admin_socket, move |command| {
+            let args : Vec<&str> = command.split_whitespace().collect();
+            match args.split_first() {
+                Some((&"port", args)) => port_command(&state, args),
+                Some((&"pipe", args)) => pipe_command(&state, args),
+                Some((&"status", [])) => status_command(&state),
+                Some((&"connections", [])) => Ok(state.pipes.list().iter().map(admin::pipe_segment).collect::<Vec<String>>().join("; ")),
+                Some((&"kick", [id])) => pipe_command(&state, &["kill", id]),
+                Some((&"traffic", [])) => accounting::traffic_command(),
+                _ => Err(anyhow!("Unknown command {command}"))
+            }
+        })?;
+    }
+    {
+        // Also closes the sessions that were still being established when it was triggered
+        let state = state.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || loop {
+            if shutdown.wait(SHUTDOWN_POLL_INTERVAL) {
+                if let Some(sender) = state.control.lock().unwrap().as_ref() {
+                    sender.close();
+                }
+                thread::sleep(SHUTDOWN_POLL_INTERVAL);
+            }
+        });
+    }
+    info!("Server started.");
+    let mut failover = Failover { preferred: 0 };
+    if !scfg.retry {
+        let result = failover.session(&ccfg, &scfg, &state, &mirrors, &shutdown);
+        if shutdown.is_triggered() {
+            info!("Shutting down");
+            return Ok(());
+        }
+        return result.and_then(Ended::into_result);
+    }
+    // A single authentication failure may come from something else listening on the gateway's address
+    let mut auth_failures = 0;
+    let mut backoff = Backoff { max: scfg.retry_delay, attempts: 0 };
     loop {
-        if let Err(err) = server(&ccfg, &scfg) {
-            println!("Server error.\nReason:\n{err:?}\nWaiting {RETRY_DELAY}s before retrying...");
+        let start = Instant::now();
+        let result = failover.session(&ccfg, &scfg, &state, &mirrors, &shutdown);
+        if shutdown.is_triggered() {
+            info!("Shutting down");
+            return Ok(());
+        }
+        if start.elapsed() >= STABLE_SESSION {
+            backoff.attempts = 0;
+        }
+        let delay = backoff.after(&result);
+        let failure = result.as_ref().err().map(Failure::of);
+        auth_failures = match failure {
+            Some(Failure::Authentication) => auth_failures + 1,
+            _ => 0
+        };
+        match result {
+            Ok(Ended::Closed) => info!("Session ended, waiting {:.1}s before reconnecting...", delay.as_secs_f64()),
+            Ok(Ended::Expired(message)) => info!("Gateway ended the session: {message}, pairing again right away"),
+            Ok(Ended::Dropped(err)) => warn!("Lost the gateway, waiting {:.1}s before reconnecting, reason: {err:#}", delay.as_secs_f64()),
+            Err(err) if auth_failures >= MAX_AUTH_FAILURES => {
+                return Err(err).context(format!("Authentication failed {MAX_AUTH_FAILURES} times in a row, giving up"));
+            },
+            Err(err) if failure.is_some_and(|failure| !matches!(failure, Failure::Transient | Failure::Authentication)) => {
+                return Err(err).context("Permanent failure, giving up");
+            },
+            Err(err) => warn!("Server error, waiting {:.1}s before retrying, reason: {err:#}", delay.as_secs_f64())
+        }
+        if shutdown.wait(delay) {
+            info!("Shutting down");
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::fixtures::{temp_config, TempDir};
+    use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+    /* For the server to notice, beyond the waits of its configuration */
+    const MARGIN : Duration = Duration::from_millis(500);
+
+    /* A server reaching its gateway through `transport = "mock"`, with a key of its own and waits of a second unless
+       `options` give another retry_delay_s */
+    fn config(name: &str, options: &str) -> (TempDir, CommonConfig, ServerConfig) {
+        let retry_delay = if options.contains("retry_delay_s") { "" } else { "retry_delay_s = 1\n" };
+        let toml = format!("mode = \"server\"\nport = 1\ngateway_address = \"mock://gateway\"\nredirects = [[5333, 8000, \"TCP\"]]\n{retry_delay}{options}");
+        match temp_config(name, &toml) {
+            (dir, ccfg, SpecificConfig::Server(scfg)) => (dir, ccfg, scfg),
+            _ => unreachable!()
+        }
+    }
+
+    /* Counts the attempts to reach the gateway, `connect` is given the number of each */
+    struct MockTransport<F> {
+        attempts: Arc<AtomicU64>,
+        connect: F
+    }
+
+    impl<F: Fn(u64) -> Result<TcpStream> + Send + Sync> Transport for MockTransport<F> {
+        fn connect(&self, _address: &str) -> Result<TcpStream> {
+            (self.connect)(self.attempts.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+    }
+
+    fn extensions<F: Fn(u64) -> Result<TcpStream> + Send + Sync + 'static>(connect: F) -> (Extensions, Arc<AtomicU64>) {
+        let attempts = Arc::new(AtomicU64::new(0));
+        let mut extensions = Extensions::default();
+        extensions.transports.register("mock", MockTransport { attempts: attempts.clone(), connect });
+        (extensions, attempts)
+    }
+
+    /* Accepts connections, and never sends anything on them */
+    fn silent_listener() -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut accepted = Vec::new();
+            for stream in listener.incoming() {
+                accepted.extend(stream);
+            }
+        });
+        address
+    }
+
+    /* Until `attempts` reached `count` */
+    fn attempted_within(attempts: &AtomicU64, count: u64, bound: Duration) -> bool {
+        let deadline = Instant::now() + bound;
+        while attempts.load(Ordering::Relaxed) < count && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        attempts.load(Ordering::Relaxed) >= count
+    }
+
+    /* Challenges the servers with a key of its own, which theirs can't open */
+    fn gateway_with_another_key() -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let key = crypto::random_key();
+        let keys = [(key, crypto::Magics::derive(&key))];
+        thread::spawn(move || for mut stream in listener.incoming().flatten() {
+            let mut magic1 = [0u8; common::MAGIC1_LENGTH];
+            if std::io::Read::read_exact(&mut stream, &mut magic1).is_ok() {
+                let _ = crypto::challenge(&keys, &magic1, &mut stream);
+            }
+        });
+        address
+    }
+
+    /* Pairs with the servers of the key of `ccfg` as a gateway does, reads their ports, then resets the connection */
+    fn gateway_that_drops(ccfg: &CommonConfig) -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let keys = [(ccfg.key, ccfg.magics)];
+        thread::spawn(move || for mut stream in listener.incoming().flatten() {
+            let mut magic1 = [0u8; common::MAGIC1_LENGTH];
+            if std::io::Read::read_exact(&mut stream, &mut magic1).is_ok() {
+                if let Ok((cipher, _)) = crypto::challenge(&keys, &magic1, &mut stream) {
+                    let _ = control::read_message(&mut stream, &mut cipher.channel(Channel::ToGateway));
+                    let _ = crate::sockopt::set_linger_zero(&stream);
+                }
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn dropped_sessions_reconnect_sooner_than_failed_handshakes() {
+        let (_dir, ccfg, scfg) = config("dropped", "retry_delay_s = 10\n");
+        let (failing, dropping) = (gateway_with_another_key(), gateway_that_drops(&ccfg));
+        let times = Arc::new(Mutex::new(Vec::new()));
+        let (extensions, attempts) = {
+            let times = times.clone();
+            // Two failed handshakes, then sessions that are established and dropped
+            extensions(move |attempt| {
+                times.lock().unwrap().push(Instant::now());
+                Ok(TcpStream::connect(if attempt <= 2 { failing } else { dropping })?)
+            })
+        };
+        let shutdown = extensions.shutdown.clone();
+        let server = thread::spawn(move || run(ccfg, scfg, extensions));
+        let reached = attempted_within(&attempts, 4, INITIAL_RETRY_DELAY * 3 + RECONNECT_DELAY + MARGIN);
+        shutdown.trigger();
+        server.join().unwrap().unwrap();
+        assert!(reached, "the server stopped reconnecting");
+        let times = times.lock().unwrap();
+        let (backoff, reconnect) = (times[2] - times[1], times[3] - times[2]);
+        assert!(backoff >= INITIAL_RETRY_DELAY, "the second failed handshake should back off at least {INITIAL_RETRY_DELAY:?}, the server waited {backoff:?}");
+        assert!(reconnect < RECONNECT_DELAY + MARGIN, "the dropped session should be followed by a new attempt after {RECONNECT_DELAY:?}, the server waited {reconnect:?}");
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        let (_dir, ccfg, scfg) = config("transient", "");
+        let (extensions, attempts) = extensions(|_| Err(anyhow!("Connection refused")));
+        let shutdown = extensions.shutdown.clone();
+        let server = thread::spawn(move || run(ccfg, scfg, extensions));
+        assert!(attempted_within(&attempts, 3, Duration::from_secs(2) + MARGIN), "the server stopped retrying");
+        shutdown.trigger();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn permanent_failures_give_up() {
+        let (_dir, ccfg, scfg) = config("permanent", "");
+        let (extensions, attempts) = extensions(|_| Err(anyhow!("The SOCKS proxy rejected the username and password")).context(Failure::Config));
+        let err = run(ccfg, scfg, extensions).unwrap_err();
+        assert!(matches!(Failure::of(&err), Failure::Config), "{err:#}");
+        assert!(format!("{err:#}").starts_with("Permanent failure, giving up"), "{err:#}");
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn authentication_failures_give_up_once_repeated() {
+        let (_dir, ccfg, scfg) = config("authentication", "");
+        let address = gateway_with_another_key();
+        // A failure of another kind in between starts the count over
+        let (extensions, attempts) = extensions(move |attempt| match attempt {
+            2 => Err(anyhow!("Connection refused")),
+            _ => Ok(TcpStream::connect(address)?)
+        });
+        let err = run(ccfg, scfg, extensions).unwrap_err();
+        assert!(matches!(Failure::of(&err), Failure::Authentication), "{err:#}");
+        assert!(format!("{err:#}").starts_with(&format!("Authentication failed {MAX_AUTH_FAILURES} times in a row")), "{err:#}");
+        assert_eq!(attempts.load(Ordering::Relaxed), 2 + u64::from(MAX_AUTH_FAILURES), "a transient failure starts the count over");
+    }
+
+    #[test]
+    fn silent_gateways_time_the_handshake_out() {
+        let (_dir, ccfg, scfg) = config("silent-once", "handshake_timeout = 1\nretry = false\n");
+        let address = silent_listener();
+        let (extensions, _) = extensions(move |_| Ok(TcpStream::connect(address)?));
+        let started = Instant::now();
+        let err = run(ccfg, scfg, extensions).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(1) + MARGIN, "the handshake took {:?}", started.elapsed());
+        assert!(format!("{err:#}").contains("handshake_timeout"), "{err:#}");
+        assert!(matches!(Failure::of(&err), Failure::Transient), "{err:#}");
+    }
+
+    #[test]
+    fn servers_recover_from_silent_gateways() {
+        let (_dir, ccfg, scfg) = config("silent-retry", "handshake_timeout = 1\n");
+        let address = silent_listener();
+        let (extensions, attempts) = extensions(move |_| Ok(TcpStream::connect(address)?));
+        let shutdown = extensions.shutdown.clone();
+        let server = thread::spawn(move || run(ccfg, scfg, extensions));
+        // The handshake times out, then the server waits at most retry_delay_s before trying again
+        assert!(attempted_within(&attempts, 2, Duration::from_secs(2) + MARGIN), "the server hangs on the silent gateway");
+        shutdown.trigger();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn ended_sessions_reconnect_right_away() {
+        // Once failures pushed the waits up to retry_delay
+        let mut backoff = Backoff { max: Duration::from_secs(60), attempts: 10 };
+        assert_eq!(backoff.after(&Ok(Ended::Closed)), RECONNECT_DELAY);
+        assert_eq!(backoff.after(&Ok(Ended::Dropped(anyhow!("Connection reset by peer")))), RECONNECT_DELAY);
+        assert_eq!(backoff.after(&Ok(Ended::Expired("The session lasted the 60s of max_session_duration".to_string()))), Duration::ZERO);
+        assert_eq!(backoff.attempts, 10, "an established session isn't a failed attempt");
+    }
+
+    #[test]
+    fn failed_attempts_back_off() {
+        let mut backoff = Backoff { max: Duration::from_secs(60), attempts: 0 };
+        let mut full = INITIAL_RETRY_DELAY;
+        for _ in 0..10 {
+            let delay = backoff.after(&Err(anyhow!("Connection refused")));
+            assert!(delay >= full / 2 && delay <= full, "{delay:?} should be between half and all of {full:?}");
+            full = (full * 2).min(backoff.max);
         }
-        thread::sleep(retry);
+        assert_eq!(full, backoff.max);
+        assert_eq!(backoff.attempts, 10);
     }
 }
@@ -0,0 +1,264 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! The server as a Windows service: `smugglrs --install-service` registers `smugglrs --service --config <path>`
+//! with the service control manager, which starts it at boot. Stopping the service, or shutting Windows down,
+//! goes through `server::Shutdown`. A service has no console, so it logs to `smugglrs.log`, next to its configuration,
+//! which is also where its relative paths are.
+
+use crate::config::{CommonConfig, SpecificConfig, CONFIG_PATH};
+use crate::error::Failure;
+use crate::server::{self, Extensions, Shutdown};
+use crate::{error, info, log};
+use anyhow::{anyhow, Context, Result};
+use std::ffi::{c_void, OsStr};
+use std::os::windows::ffi::OsStrExt;
+use std::path::{self, Path, PathBuf};
+use std::sync::OnceLock;
+use std::{env, io, ptr};
+
+pub const SERVICE_NAME : &str = "smugglrs";
+const LOG_FILE : &str = "smugglrs.log";
+
+const SERVICE_WIN32_OWN_PROCESS : u32 = 0x10;
+const SERVICE_AUTO_START : u32 = 2;
+const SERVICE_ERROR_NORMAL : u32 = 1;
+const SERVICE_STOPPED : u32 = 1;
+const SERVICE_START_PENDING : u32 = 2;
+const SERVICE_STOP_PENDING : u32 = 3;
+const SERVICE_RUNNING : u32 = 4;
+const SERVICE_ACCEPT_STOP : u32 = 1;
+const SERVICE_ACCEPT_SHUTDOWN : u32 = 4;
+const SERVICE_CONTROL_STOP : u32 = 1;
+const SERVICE_CONTROL_INTERROGATE : u32 = 4;
+const SERVICE_CONTROL_SHUTDOWN : u32 = 5;
+const SC_MANAGER_CONNECT : u32 = 1;
+const SC_MANAGER_CREATE_SERVICE : u32 = 2;
+const SERVICE_CHANGE_CONFIG : u32 = 2;
+const SERVICE_QUERY_STATUS : u32 = 4;
+const SERVICE_STOP : u32 = 0x20;
+const DELETE : u32 = 0x10000;
+const NO_ERROR : u32 = 0;
+const ERROR_CALL_NOT_IMPLEMENTED : u32 = 120;
+const ERROR_SERVICE_SPECIFIC_ERROR : u32 = 1066;
+// Milliseconds the service manager waits before it considers a pending service stuck
+const STOP_WAIT_HINT : u32 = 5000;
+
+type Handle = *mut c_void;
+type ServiceMain = extern "system" fn(u32, *mut *mut u16);
+type HandlerEx = extern "system" fn(u32, u32, *mut c_void, *mut c_void) -> u32;
+
+#[repr(C)]
+#[derive(Default)]
+struct ServiceStatus {
+    service_type: u32,
+    current_state: u32,
+    controls_accepted: u32,
+    win32_exit_code: u32,
+    service_specific_exit_code: u32,
+    check_point: u32,
+    wait_hint: u32
+}
+
+#[repr(C)]
+struct ServiceTableEntry {
+    name: *const u16,
+    main: Option<ServiceMain>
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn StartServiceCtrlDispatcherW(table: *const ServiceTableEntry) -> i32;
+    fn RegisterServiceCtrlHandlerExW(name: *const u16, handler: HandlerEx, context: *mut c_void) -> Handle;
+    fn SetServiceStatus(handle: Handle, status: *const ServiceStatus) -> i32;
+    fn OpenSCManagerW(machine: *const u16, database: *const u16, access: u32) -> Handle;
+    fn CreateServiceW(manager: Handle, name: *const u16, display_name: *const u16, access: u32, service_type: u32,
+        start_type: u32, error_control: u32, binary_path: *const u16, load_order_group: *const u16, tag_id: *mut u32,
+        dependencies: *const u16, account: *const u16, password: *const u16) -> Handle;
+    fn OpenServiceW(manager: Handle, name: *const u16, access: u32) -> Handle;
+    fn ControlService(service: Handle, control: u32, status: *mut ServiceStatus) -> i32;
+    fn DeleteService(service: Handle) -> i32;
+    fn CloseServiceHandle(handle: Handle) -> i32;
+}
+
+/* NUL terminated UTF-16, as the wide functions take it */
+fn wide(x: &OsStr) -> Vec<u16> {
+    x.encode_wide().chain(Some(0)).collect()
+}
+
+/* Of the service manager or of a service, closed once dropped */
+struct ScHandle(Handle);
+
+impl ScHandle {
+    fn new(handle: Handle, what: &str) -> Result<ScHandle> {
+        match handle.is_null() {
+            true => Err(io::Error::last_os_error()).context(format!("Failed to open {what}")),
+            false => Ok(ScHandle(handle))
+        }
+    }
+
+    fn manager(access: u32) -> Result<ScHandle> {
+        // SAFETY: null names are the local machine and its active database
+        ScHandle::new(unsafe { OpenSCManagerW(ptr::null(), ptr::null(), access) }, "the service control manager, is this an administrator prompt?")
+    }
+}
+
+impl Drop for ScHandle {
+    fn drop(&mut self) {
+        // SAFETY: the handle is valid until now
+        unsafe { CloseServiceHandle(self.0) };
+    }
+}
+
+/* Set once the dispatcher started the service, only used from its threads */
+struct Service {
+    config: PathBuf,
+    shutdown: Shutdown
+}
+
+struct StatusHandle(Handle);
+
+// SAFETY: the handle is only passed to SetServiceStatus, which may be called from any thread
+unsafe impl Send for StatusHandle {}
+unsafe impl Sync for StatusHandle {}
+
+static SERVICE : OnceLock<Service> = OnceLock::new();
+static STATUS : OnceLock<StatusHandle> = OnceLock::new();
+
+fn set_status(state: u32, exit_code: u32) {
+    let Some(handle) = STATUS.get() else {
+        return;
+    };
+    let status = ServiceStatus {
+        service_type: SERVICE_WIN32_OWN_PROCESS,
+        current_state: state,
+        controls_accepted: if state == SERVICE_RUNNING { SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN } else { 0 },
+        win32_exit_code: if exit_code == 0 { NO_ERROR } else { ERROR_SERVICE_SPECIFIC_ERROR },
+        service_specific_exit_code: exit_code,
+        check_point: 0,
+        wait_hint: if state == SERVICE_START_PENDING || state == SERVICE_STOP_PENDING { STOP_WAIT_HINT } else { 0 }
+    };
+    // SAFETY: the handle was returned by RegisterServiceCtrlHandlerExW, the status outlives the call
+    if unsafe { SetServiceStatus(handle.0, &status) } == 0 {
+        error!("Failed to report the state of the service, reason: {}", io::Error::last_os_error());
+    }
+}
+
+extern "system" fn handler(control: u32, _event_type: u32, _event_data: *mut c_void, _context: *mut c_void) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            info!("Stopped by the service manager");
+            set_status(SERVICE_STOP_PENDING, 0);
+            if let Some(service) = SERVICE.get() {
+                service.shutdown.trigger();
+            }
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED
+    }
+}
+
+extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+    let name = wide(OsStr::new(SERVICE_NAME));
+    // SAFETY: the name is NUL terminated, the handler has the signature of a HandlerEx
+    let handle = unsafe { RegisterServiceCtrlHandlerExW(name.as_ptr(), handler, ptr::null_mut()) };
+    if handle.is_null() {
+        error!("Failed to register the handler of the service, reason: {}", io::Error::last_os_error());
+        return;
+    }
+    let _ = STATUS.set(StatusHandle(handle));
+    set_status(SERVICE_START_PENDING, 0);
+    let code = match run_server() {
+        Ok(()) => 0,
+        Err(err) => {
+            error!("The service stopped, reason: {err:#}");
+            Failure::of(&err).exit_code() as u32
+        }
+    };
+    set_status(SERVICE_STOPPED, code);
+}
+
+fn run_server() -> Result<()> {
+    let service = SERVICE.get().context("The service was started without its configuration")?;
+    let (ccfg, scfg) = match CommonConfig::new(Some(&service.config)).context(Failure::Config)? {
+        (ccfg, SpecificConfig::Server(scfg)) => (ccfg, scfg),
+        (_, SpecificConfig::Gateway(_)) => return Err(anyhow!("Only the server can run as a Windows service")).context(Failure::Config)
+    };
+    set_status(SERVICE_RUNNING, 0);
+    server::run(ccfg, scfg, Extensions { shutdown: service.shutdown.clone(), ..Default::default() })
+}
+
+/* The service doesn't start where we are run from */
+fn config_path(config: Option<&Path>) -> Result<PathBuf> {
+    let config = config.unwrap_or(Path::new(CONFIG_PATH));
+    path::absolute(config).with_context(|| format!("Failed to find the absolute path of {}", config.display()))
+}
+
+/// `--service`: what the service manager runs, returns once the service stopped
+pub fn run(config: Option<&Path>) -> Result<()> {
+    let config = config_path(config)?;
+    if let Some(directory) = config.parent() {
+        env::set_current_dir(directory).with_context(|| format!("Failed to change directory to {}", directory.display()))?;
+    }
+    log::to_file(Path::new(LOG_FILE)).with_context(|| format!("Failed to open the log file {LOG_FILE}"))?;
+    let _ = SERVICE.set(Service { config, shutdown: Shutdown::default() });
+    let name = wide(OsStr::new(SERVICE_NAME));
+    let table = [
+        ServiceTableEntry { name: name.as_ptr(), main: Some(service_main) },
+        ServiceTableEntry { name: ptr::null(), main: None }
+    ];
+    // SAFETY: the table ends with a null entry, and outlives the dispatcher which returns once the service stopped
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        return Err(io::Error::last_os_error())
+            .context("Failed to reach the service control manager, --service is only for Windows to run smugglrs as a service")
+            .context(Failure::Usage);
+    }
+    Ok(())
+}
+
+/// `--install-service`: starts at boot, as LocalSystem, with the configuration given now
+pub fn install(config: Option<&Path>) -> Result<()> {
+    let config = config_path(config)?;
+    match CommonConfig::new(Some(&config)).context(Failure::Config)? {
+        (_, SpecificConfig::Server(_)) => {}
+        (_, SpecificConfig::Gateway(_)) => return Err(anyhow!("Only the server can run as a Windows service")).context(Failure::Config)
+    }
+    let executable = env::current_exe().context("Failed to find the path of smugglrs")?;
+    let command = format!("\"{}\" --service --config \"{}\"", executable.display(), config.display());
+    let manager = ScHandle::manager(SC_MANAGER_CREATE_SERVICE)?;
+    let (name, command) = (wide(OsStr::new(SERVICE_NAME)), wide(OsStr::new(&command)));
+    // SAFETY: the strings are NUL terminated, the null ones are the defaults
+    let service = unsafe {
+        CreateServiceW(manager.0, name.as_ptr(), name.as_ptr(), SERVICE_CHANGE_CONFIG, SERVICE_WIN32_OWN_PROCESS, SERVICE_AUTO_START,
+            SERVICE_ERROR_NORMAL, command.as_ptr(), ptr::null(), ptr::null_mut(), ptr::null(), ptr::null(), ptr::null())
+    };
+    ScHandle::new(service, "the new service").context("Failed to install the service, it may be installed already")?;
+    println!("Service {SERVICE_NAME} installed with {}, start it with `sc start {SERVICE_NAME}`", config.display());
+    Ok(())
+}
+
+/// `--uninstall-service`: stopped first if it runs
+pub fn uninstall() -> Result<()> {
+    let manager = ScHandle::manager(SC_MANAGER_CONNECT)?;
+    let name = wide(OsStr::new(SERVICE_NAME));
+    // SAFETY: the name is NUL terminated
+    let service = ScHandle::new(unsafe { OpenServiceW(manager.0, name.as_ptr(), SERVICE_STOP | SERVICE_QUERY_STATUS | DELETE) }, "the service")?;
+    let mut status = ServiceStatus::default();
+    // SAFETY: the status is written to by the call. Failing only means it wasn't running
+    unsafe { ControlService(service.0, SERVICE_CONTROL_STOP, &mut status) };
+    // SAFETY: the handle was opened with DELETE
+    if unsafe { DeleteService(service.0) } == 0 {
+        return Err(io::Error::last_os_error()).context("Failed to uninstall the service");
+    }
+    println!("Service {SERVICE_NAME} uninstalled");
+    Ok(())
+}
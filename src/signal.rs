@@ -0,0 +1,102 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! SIGHUP reloads the configuration. It's blocked in every thread and waited for by one of its own, so that nothing
+//! runs in a signal handler. SIGTERM and SIGINT are handled the same way once something needs to run before exiting
+
+#[cfg(unix)]
+mod unix {
+    use anyhow::{Context, Result};
+    use std::sync::Mutex;
+    use std::{io, mem, process, ptr, thread};
+
+    type Hook = Box<dyn Fn() + Send>;
+
+    /* Waited for by a thread of their own once there's one */
+    static TERMINATION_HOOKS : Mutex<Vec<Hook>> = Mutex::new(Vec::new());
+
+    fn block(signals: &[libc::c_int]) -> Result<libc::sigset_t> {
+        // SAFETY: the set is initialized by sigemptyset before it's used
+        unsafe {
+            let mut set : libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for signal in signals {
+                libc::sigaddset(&mut set, *signal);
+            }
+            match libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) {
+                0 => Ok(set),
+                err => Err(io::Error::from_raw_os_error(err).into())
+            }
+        }
+    }
+
+    /// Run `hook` on SIGTERM or SIGINT, after the ones registered before it, then exit. The first one blocks both
+    /// signals like `Hangups::block`, before anything spawns a thread
+    pub fn on_termination<F: Fn() + Send + 'static>(hook: F) -> Result<()> {
+        let mut hooks = TERMINATION_HOOKS.lock().unwrap();
+        if hooks.is_empty() {
+            let set = block(&[libc::SIGTERM, libc::SIGINT]).context("Failed to block SIGTERM")?;
+            thread::spawn(move || loop {
+                let mut signal = 0;
+                // SAFETY: the set is blocked, sigwait only reads it
+                if unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+                    for hook in TERMINATION_HOOKS.lock().unwrap().iter() {
+                        hook();
+                    }
+                    process::exit(0);
+                }
+            });
+        }
+        hooks.push(Box::new(hook));
+        Ok(())
+    }
+
+    pub struct Hangups(libc::sigset_t);
+
+    impl Hangups {
+        /// Blocks SIGHUP in this thread and the ones it spawns from now on: those spawned before would be killed by it
+        pub fn block() -> Result<Hangups> {
+            block(&[libc::SIGHUP]).map(Hangups).context("Failed to block SIGHUP")
+        }
+
+        /// Calls `f` for each SIGHUP, from a thread of its own
+        pub fn handle<F: Fn() + Send + 'static>(self, f: F) {
+            let set = self.0;
+            thread::spawn(move || loop {
+                let mut signal = 0;
+                // SAFETY: the set is blocked, sigwait only reads it
+                if unsafe { libc::sigwait(&set, &mut signal) } == 0 && signal == libc::SIGHUP {
+                    f();
+                }
+            });
+        }
+    }
+}
+
+#[cfg(unix)]
+pub use unix::{on_termination, Hangups};
+
+#[cfg(not(unix))]
+pub struct Hangups;
+
+#[cfg(not(unix))]
+impl Hangups {
+    pub fn block() -> anyhow::Result<Hangups> {
+        Ok(Hangups)
+    }
+
+    pub fn handle<F: Fn() + Send + 'static>(self, _f: F) {}
+}
+
+#[cfg(not(unix))]
+pub fn on_termination<F: Fn() + Send + 'static>(_hook: F) -> anyhow::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,472 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+/* Socket options that the standard library doesn't expose */
+
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::time::Duration;
+
+#[derive(Copy, Clone)]
+pub enum BufferKind {
+    Receive, Send
+}
+
+/// How the gateway binds its listeners, see `[socket]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenOptions {
+    /// SO_REUSEADDR, so that a restarted gateway binds its ports again while the connections of the old one linger
+    pub reuse_address: bool,
+    /// Connections waiting to be accepted, capped by the system (`net.core.somaxconn` on Linux)
+    pub backlog: u32,
+    /// SO_BINDTODEVICE, Linux only
+    pub device: Option<String>,
+    /// IP_FREEBIND: bind an address the machine doesn't have (yet), Linux only
+    pub freebind: bool
+}
+
+pub const DEFAULT_BACKLOG : u32 = 1024;
+
+impl Default for ListenOptions {
+    fn default() -> ListenOptions {
+        ListenOptions { reuse_address: true, backlog: DEFAULT_BACKLOG, device: None, freebind: false }
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use std::iter;
+    use std::mem::{size_of, zeroed};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    fn option(kind: BufferKind) -> libc::c_int {
+        match kind {
+            BufferKind::Receive => libc::SO_RCVBUF,
+            BufferKind::Send => libc::SO_SNDBUF
+        }
+    }
+
+    pub fn buffer_size(stream: &TcpStream, kind: BufferKind) -> io::Result<usize> {
+        let mut value : libc::c_int = 0;
+        let mut length = size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: `value` and `length` outlive the call, and `length` is the size of `value`
+        let ret = unsafe {
+            libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, option(kind), &mut value as *mut _ as *mut libc::c_void, &mut length)
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(value.max(0) as usize)
+    }
+
+    pub fn set_buffer_size(stream: &TcpStream, kind: BufferKind, size: usize) -> io::Result<()> {
+        let value = libc::c_int::try_from(size).unwrap_or(libc::c_int::MAX);
+        // SAFETY: `value` outlives the call, and the length passed is its size
+        let ret = unsafe {
+            libc::setsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, option(kind), &value as *const _ as *const libc::c_void, size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn set_linger_zero(stream: &TcpStream) -> io::Result<()> {
+        let linger = libc::linger { l_onoff: 1, l_linger: 0 };
+        // SAFETY: `linger` outlives the call, and the length passed is its size
+        let ret = unsafe {
+            libc::setsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_LINGER, &linger as *const _ as *const libc::c_void, size_of::<libc::linger>() as libc::socklen_t)
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn set_int_option(fd: &impl AsRawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        // SAFETY: `value` outlives the call, and the length passed is its size
+        let ret = unsafe {
+            libc::setsockopt(fd.as_raw_fd(), level, name, &value as *const _ as *const libc::c_void, size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    const TCP_KEEPIDLE : libc::c_int = libc::TCP_KEEPALIVE;
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    const TCP_KEEPIDLE : libc::c_int = libc::TCP_KEEPIDLE;
+
+    pub fn set_keepalive(stream: &TcpStream, idle: Duration, interval: Duration, count: u32) -> io::Result<()> {
+        let secs = |x: Duration| libc::c_int::try_from(x.as_secs()).unwrap_or(libc::c_int::MAX);
+        set_int_option(stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+        set_int_option(stream, libc::IPPROTO_TCP, TCP_KEEPIDLE, secs(idle))?;
+        set_int_option(stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, secs(interval))?;
+        set_int_option(stream, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, libc::c_int::try_from(count).unwrap_or(libc::c_int::MAX))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_device_options(fd: &OwnedFd, options: &ListenOptions) -> io::Result<()> {
+        if let Some(device) = &options.device {
+            // SAFETY: `device` is borrowed for the whole call, and the length passed is its own
+            let ret = unsafe {
+                libc::setsockopt(fd.as_raw_fd(), libc::SOL_SOCKET, libc::SO_BINDTODEVICE, device.as_ptr() as *const libc::c_void, device.len() as libc::socklen_t)
+            };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        // Of the socket whatever its family
+        if options.freebind {
+            set_int_option(fd, libc::IPPROTO_IP, libc::IP_FREEBIND, 1)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn set_device_options(_fd: &OwnedFd, options: &ListenOptions) -> io::Result<()> {
+        match options.device.is_some() || options.freebind {
+            true => Err(io::Error::new(io::ErrorKind::Unsupported, "bind_device and freebind are only supported on Linux")),
+            false => Ok(())
+        }
+    }
+
+    /* What std does when binding, except that IPV6_V6ONLY is set before, rather than left to the system's default */
+    fn bound_socket(addr: SocketAddr, kind: libc::c_int, v6only: bool, options: &ListenOptions) -> io::Result<OwnedFd> {
+        let family = match addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6
+        };
+        // SAFETY: no pointers are passed, the descriptor returned is checked below
+        let fd = unsafe { libc::socket(family, kind, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a new descriptor that nothing else owns
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // SAFETY: `fd` is owned, and F_SETFD takes an int
+        if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Not for UDP, where it lets other sockets bind the same port
+        if kind == libc::SOCK_STREAM && options.reuse_address {
+            set_int_option(&fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+        }
+        set_device_options(&fd, options)?;
+        // SAFETY: sockaddr_storage is plain data, for which zeroes are valid
+        let mut storage : libc::sockaddr_storage = unsafe { zeroed() };
+        let length = match addr {
+            SocketAddr::V4(addr) => {
+                // SAFETY: sockaddr_storage is large and aligned enough for any sockaddr, sockaddr_in included
+                let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(addr.ip().octets()) };
+                size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                set_int_option(&fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, v6only as libc::c_int)?;
+                // SAFETY: sockaddr_storage is large and aligned enough for any sockaddr, sockaddr_in6 included
+                let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_addr = libc::in6_addr { s6_addr: addr.ip().octets() };
+                sin6.sin6_scope_id = addr.scope_id();
+                size_of::<libc::sockaddr_in6>()
+            }
+        };
+        // SAFETY: `storage` outlives the call, and `length` is the size of the sockaddr written into it
+        if unsafe { libc::bind(fd.as_raw_fd(), &storage as *const _ as *const libc::sockaddr, length as libc::socklen_t) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(fd)
+    }
+
+    pub fn bind_tcp(addr: SocketAddr, v6only: bool, options: &ListenOptions) -> io::Result<TcpListener> {
+        let fd = bound_socket(addr, libc::SOCK_STREAM, v6only, options)?;
+        let backlog = libc::c_int::try_from(options.backlog).unwrap_or(libc::c_int::MAX);
+        // SAFETY: `fd` is owned, and no pointers are passed
+        if unsafe { libc::listen(fd.as_raw_fd(), backlog) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(TcpListener::from(fd))
+    }
+
+    pub fn bind_udp(addr: SocketAddr, v6only: bool, options: &ListenOptions) -> io::Result<UdpSocket> {
+        Ok(UdpSocket::from(bound_socket(addr, libc::SOCK_DGRAM, v6only, options)?))
+    }
+
+    fn poll_readable(fd: libc::c_int, timeout: Duration) -> io::Result<bool> {
+        let mut fd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        let timeout = libc::c_int::try_from(timeout.as_millis()).unwrap_or(libc::c_int::MAX);
+        loop {
+            // SAFETY: `fd` is one pollfd, borrowed for the whole call
+            match unsafe { libc::poll(&mut fd, 1, timeout) } {
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+                -1 => return Err(io::Error::last_os_error()),
+                ready => return Ok(ready > 0)
+            }
+        }
+    }
+
+    pub fn wait_acceptable(listener: &TcpListener, timeout: Duration) -> io::Result<bool> {
+        poll_readable(listener.as_raw_fd(), timeout)
+    }
+
+    pub fn wait_clients(listener: &TcpListener, streams: &[&TcpStream], timeout: Duration) -> io::Result<Vec<bool>> {
+        let mut fds : Vec<libc::pollfd> = iter::once(listener.as_raw_fd()).chain(streams.iter().map(|stream| stream.as_raw_fd()))
+            .map(|fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 }).collect();
+        let timeout = libc::c_int::try_from(timeout.as_millis()).unwrap_or(libc::c_int::MAX);
+        loop {
+            // SAFETY: `fds` is a vector of `fds.len()` pollfd, of descriptors borrowed for the whole call
+            match unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } {
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+                -1 => return Err(io::Error::last_os_error()),
+                // Hung up or failed streams too, their clients find out when they're read
+                _ => return Ok(fds[1..].iter().map(|fd| fd.revents != 0).collect())
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::*;
+
+    pub fn buffer_size(_stream: &TcpStream, _kind: BufferKind) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "socket buffer sizes are not supported on this platform"))
+    }
+
+    pub fn set_buffer_size(_stream: &TcpStream, _kind: BufferKind, _size: usize) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "socket buffer sizes are not supported on this platform"))
+    }
+
+    pub fn set_linger_zero(_stream: &TcpStream) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "SO_LINGER is not supported on this platform"))
+    }
+
+    pub fn set_keepalive(_stream: &TcpStream, _idle: Duration, _interval: Duration, _count: u32) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "TCP keepalive is not supported on this platform"))
+    }
+
+    /* Left to the system's default for IPV6_V6ONLY, SO_REUSEADDR and the backlog */
+    pub fn bind_tcp(addr: SocketAddr, _v6only: bool, _options: &ListenOptions) -> io::Result<TcpListener> {
+        TcpListener::bind(addr)
+    }
+
+    pub fn bind_udp(addr: SocketAddr, _v6only: bool, _options: &ListenOptions) -> io::Result<UdpSocket> {
+        UdpSocket::bind(addr)
+    }
+
+    /* Without poll, the caller's non-blocking accept is only retried every few milliseconds */
+    pub fn wait_acceptable(_listener: &TcpListener, timeout: Duration) -> io::Result<bool> {
+        std::thread::sleep(timeout.min(Duration::from_millis(15)));
+        Ok(true)
+    }
+
+    /* The streams are peeked once that wait is over, which doesn't take anything from them either */
+    pub fn wait_clients(listener: &TcpListener, streams: &[&TcpStream], timeout: Duration) -> io::Result<Vec<bool>> {
+        wait_acceptable(listener, timeout)?;
+        streams.iter().map(|stream| {
+            stream.set_nonblocking(true)?;
+            let ready = !matches!(stream.peek(&mut [0u8; 1]), Err(err) if err.kind() == io::ErrorKind::WouldBlock);
+            stream.set_nonblocking(false)?;
+            Ok(ready)
+        }).collect()
+    }
+}
+
+/* A pipe between two sockets, which splice(2) moves the bytes through without copying them to user space */
+#[cfg(target_os = "linux")]
+mod splice {
+    use std::io;
+    use std::net::TcpStream;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::ptr::null_mut;
+
+    pub struct SplicePipe {
+        read: OwnedFd,
+        write: OwnedFd
+    }
+
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        // SAFETY: null offsets mean the current ones, the caller owns both descriptors for the whole call
+        let ret = unsafe { libc::splice(from, null_mut(), to, null_mut(), len, libc::SPLICE_F_MOVE) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+
+    impl SplicePipe {
+        pub fn new(size: usize) -> io::Result<SplicePipe> {
+            let mut fds = [0 as libc::c_int; 2];
+            // SAFETY: `fds` has room for the two descriptors pipe2 writes
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: pipe2 succeeded, so both descriptors are new and nothing else owns them
+            let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+            // Past /proc/sys/fs/pipe-max-size it keeps its default size, and less is moved at once
+            let size = libc::c_int::try_from(size).unwrap_or(libc::c_int::MAX);
+            // SAFETY: `write` is owned, and F_SETPIPE_SZ takes an int
+            unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, size) };
+            Ok(SplicePipe { read, write })
+        }
+
+        /// Moves at most `len` bytes of `src` into the pipe, which should be empty. 0 once `src` is over
+        pub fn fill(&self, src: &TcpStream, len: usize) -> io::Result<usize> {
+            splice(src.as_raw_fd(), self.write.as_raw_fd(), len)
+        }
+
+        /// Moves the `len` bytes in the pipe to `dst`
+        pub fn drain(&self, dst: &TcpStream, mut len: usize) -> io::Result<()> {
+            while len > 0 {
+                match splice(self.read.as_raw_fd(), dst.as_raw_fd(), len) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(moved) => len -= moved,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+                    Err(err) => return Err(err)
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod splice {
+    use std::io;
+    use std::net::TcpStream;
+
+    pub struct SplicePipe;
+
+    impl SplicePipe {
+        pub fn new(_size: usize) -> io::Result<SplicePipe> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "splice is only supported on Linux"))
+        }
+
+        pub fn fill(&self, _src: &TcpStream, _len: usize) -> io::Result<usize> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        pub fn drain(&self, _dst: &TcpStream, _len: usize) -> io::Result<()> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+}
+
+pub use splice::SplicePipe;
+
+/* Edge-triggered epoll, for the pipe workers: a socket is reported once each time it becomes readable or writable,
+   and should then be read and written until it would block */
+#[cfg(target_os = "linux")]
+mod poll {
+    use std::io;
+    use std::net::TcpStream;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::time::Duration;
+
+    const MAX_EVENTS : usize = 256;
+
+    pub struct Poller(OwnedFd);
+
+    impl Poller {
+        pub fn new() -> io::Result<Poller> {
+            // SAFETY: no pointers are passed, the descriptor returned is checked below
+            let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: `fd` is a new descriptor that nothing else owns
+            Ok(Poller(unsafe { OwnedFd::from_raw_fd(fd) }))
+        }
+
+        pub fn add(&self, stream: &TcpStream, token: u64) -> io::Result<()> {
+            let mut event = libc::epoll_event {
+                events: (libc::EPOLLIN | libc::EPOLLOUT | libc::EPOLLRDHUP | libc::EPOLLET) as u32,
+                u64: token
+            };
+            // SAFETY: `event` outlives the call, and both descriptors are borrowed for it
+            if unsafe { libc::epoll_ctl(self.0.as_raw_fd(), libc::EPOLL_CTL_ADD, stream.as_raw_fd(), &mut event) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        /// Needed before the stream is dropped, as long as other descriptors of the same socket are open
+        pub fn remove(&self, stream: &TcpStream) -> io::Result<()> {
+            let mut event = libc::epoll_event { events: 0, u64: 0 };
+            // SAFETY: `event` outlives the call, and both descriptors are borrowed for it
+            if unsafe { libc::epoll_ctl(self.0.as_raw_fd(), libc::EPOLL_CTL_DEL, stream.as_raw_fd(), &mut event) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        /// The tokens of the streams that are ready, none after the timeout
+        pub fn wait(&self, ready: &mut Vec<u64>, timeout: Duration) -> io::Result<()> {
+            let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+            let timeout = libc::c_int::try_from(timeout.as_millis()).unwrap_or(libc::c_int::MAX);
+            // SAFETY: `events` has room for the MAX_EVENTS events passed as its length
+            let count = unsafe { libc::epoll_wait(self.0.as_raw_fd(), events.as_mut_ptr(), MAX_EVENTS as libc::c_int, timeout) };
+            match count {
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => Ok(()),
+                -1 => Err(io::Error::last_os_error()),
+                count => {
+                    ready.extend(events[..count as usize].iter().map(|event| event.u64));
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod poll {
+    use std::io;
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    pub struct Poller;
+
+    impl Poller {
+        pub fn new() -> io::Result<Poller> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "pipe workers are only supported on Linux"))
+        }
+
+        pub fn add(&self, _stream: &TcpStream, _token: u64) -> io::Result<()> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        pub fn remove(&self, _stream: &TcpStream) -> io::Result<()> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        pub fn wait(&self, _ready: &mut Vec<u64>, _timeout: Duration) -> io::Result<()> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+}
+
+pub use poll::Poller;
+
+/* wait_acceptable waits for a connection to accept on a non-blocking listener, which may still be gone by the time
+   it's accepted, and returns false on timeout. wait_clients waits for either that or something to read (or the peer
+   closing) on one of `streams`, without taking it, and tells which of them are readable. set_linger_zero makes closing the stream reset the connection.
+   set_keepalive probes the peer after `idle` without traffic, every `interval`, and drops the connection after `count`
+   probes went unanswered. bind_tcp and bind_udp bind an IPv6 address either to IPv6 only or to both IPv6 and IPv4, whatever the system's default,
+   with the options of `ListenOptions` */
+pub use imp::{bind_tcp, bind_udp, buffer_size, set_buffer_size, set_keepalive, set_linger_zero, wait_acceptable, wait_clients};
@@ -0,0 +1,83 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! Bandwidth limits of the redirects with `rate`: every connection of the port takes what it forwards, in both
+//! directions, from the same token bucket, and waits once it's empty.
+
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SLICES_PER_SECOND : u64 = 10; // Reads take at most this fraction of the rate, so that waits stay short
+
+pub struct RateLimiter {
+    /// Bytes per second, which is also as much as the bucket holds
+    rate: u64,
+    bucket: Mutex<Bucket>
+}
+
+struct Bucket {
+    /// Negative once connections took more than there was, which they wait for
+    tokens: f64,
+    refilled: Instant
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter { rate, bucket: Mutex::new(Bucket { tokens: rate as f64, refilled: Instant::now() }) })
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    fn slice(&self) -> usize {
+        usize::try_from(self.rate / SLICES_PER_SECOND).unwrap_or(usize::MAX).max(1)
+    }
+
+    /* The bytes were read already, so they are taken even if they aren't there, and the debt waited for */
+    fn take(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let rate = self.rate as f64;
+            bucket.tokens = (bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * rate).min(rate) - bytes as f64;
+            bucket.refilled = now;
+            (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / rate))
+        };
+        if let Some(wait) = wait {
+            thread::sleep(wait);
+        }
+    }
+}
+
+struct Throttled<R> {
+    src: R,
+    limiter: Arc<RateLimiter>
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.limiter.slice());
+        let read = self.src.read(&mut buf[..len])?;
+        self.limiter.take(read);
+        Ok(read)
+    }
+}
+
+/// Unlimited sources are read as they are
+pub fn throttle(src: Box<dyn Read + Send>, limiter: Option<Arc<RateLimiter>>) -> Box<dyn Read + Send> {
+    match limiter {
+        Some(limiter) => Box::new(Throttled { src, limiter }),
+        None => src
+    }
+}
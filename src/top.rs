@@ -0,0 +1,318 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! `smugglrs top`: a live view of the server over the admin socket.
+//! It only relies on the `status` command, and shows "-" for the fields an older server doesn't send.
+
+use anyhow::{Result, Context};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::admin;
+
+const REFRESH_DELAY : u64 = 1;
+const HISTORY : usize = 60; // Samples, one per refresh
+const SPARKLINE : &[char] = &['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+enum Key {
+    Up, Down, Pause, Kill, Freeze, Quit
+}
+
+/* One segment of the status line */
+struct Segment {
+    kind: String,
+    name: String,
+    fields: HashMap<String, String>
+}
+
+impl Segment {
+    fn parse(segment: &str) -> Option<Segment> {
+        let mut tokens = segment.split_whitespace();
+        let (kind, name) = match tokens.next()?.split_once('=') {
+            Some((kind, name)) => (kind.to_string(), name.to_string()),
+            None => (segment.split_whitespace().next()?.to_string(), String::new())
+        };
+        let mut fields = HashMap::new();
+        for token in tokens {
+            match token.split_once('=') {
+                Some((key, value)) => fields.insert(key.to_string(), value.to_string()),
+                None => fields.insert(token.to_string(), String::new())
+            };
+        }
+        Some(Segment { kind, name, fields })
+    }
+
+    fn get(&self, key: &str) -> &str {
+        self.fields.get(key).map_or("-", String::as_str)
+    }
+
+    fn number(&self, key: &str) -> Option<u64> {
+        self.fields.get(key)?.parse().ok()
+    }
+}
+
+/* (when, connections, bytes) */
+type Sample = (Instant, u64, u64);
+
+#[derive(Default)]
+struct Dashboard {
+    segments: Vec<Segment>,
+    history: HashMap<String, VecDeque<Sample>>,
+    selected: usize,
+    frozen: bool,
+    message: String
+}
+
+impl Dashboard {
+    fn update(&mut self, status: &str) {
+        self.segments = status.split("; ").filter_map(Segment::parse).collect();
+        let now = Instant::now();
+        for segment in self.segments.iter().filter(|segment| segment.kind == "port") {
+            let history = self.history.entry(segment.name.clone()).or_default();
+            if let (Some(connections), Some(bytes)) = (segment.number("connections"), segment.number("bytes")) {
+                history.push_back((now, connections, bytes));
+                if history.len() > HISTORY {
+                    history.pop_front();
+                }
+            }
+        }
+        self.selected = self.selected.min(self.rows().len().saturating_sub(1));
+    }
+
+    /* Ports and pipes, in the order they are shown */
+    fn rows(&self) -> Vec<&Segment> {
+        let ports = self.segments.iter().filter(|segment| segment.kind == "port");
+        let pipes = self.segments.iter().filter(|segment| segment.kind == "pipe");
+        ports.chain(pipes).collect()
+    }
+
+    fn connections_per_minute(&self, port: &str) -> String {
+        match self.history.get(port) {
+            Some(history) if history.len() >= 2 => {
+                let (first, last) = (history.front().unwrap(), history.back().unwrap());
+                let elapsed = last.0.duration_since(first.0).as_secs_f64();
+                format!("{:.1}", (last.1 - first.1) as f64 * 60.0 / elapsed)
+            }
+            _ => "-".to_string()
+        }
+    }
+
+    fn sparkline(&self, port: &str) -> (String, String) {
+        let history = match self.history.get(port) {
+            Some(history) if history.len() >= 2 => history,
+            _ => return ("-".to_string(), String::new())
+        };
+        let rates : Vec<f64> = history.iter().zip(history.iter().skip(1))
+            .map(|(a, b)| b.2.saturating_sub(a.2) as f64 / b.0.duration_since(a.0).as_secs_f64())
+            .collect();
+        let max = rates.iter().copied().fold(0.0, f64::max);
+        let line = rates.iter().rev().take(30).rev()
+            .map(|rate| if max > 0.0 { SPARKLINE[((rate / max) * (SPARKLINE.len() - 1) as f64).round() as usize] } else { SPARKLINE[0] })
+            .collect();
+        (human_bytes(*rates.last().unwrap()), line)
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::from("\x1b[H\x1b[2J");
+        match self.segments.iter().find(|segment| segment.kind == "session") {
+            Some(session) if session.fields.contains_key("down") => out.push_str("Session: down\r\n"),
+            Some(session) => out.push_str(&format!("Session: gateway {}, up for {}, RTT {}\r\n",
+                session.get("peer"), session.number("uptime").map_or("-".to_string(), human_duration), session.get("rtt"))),
+            None => out.push_str("Session: -\r\n")
+        }
+        if let Some(memory) = self.segments.iter().find(|segment| segment.kind == "memory" && segment.get("budget") != "none") {
+            let used = memory.number("used").map_or("-".to_string(), |x| human_bytes(x as f64));
+            let budget = memory.number("budget").map_or("-".to_string(), |x| human_bytes(x as f64));
+            out.push_str(&format!("Memory: {used} of {budget}, {} downsized, {} refused\r\n", memory.get("downsized"), memory.get("rejected")));
+        }
+        out.push_str(&format!("\r\n{:<12} {:<8} {:>6} {:>8} {:>10}  {}\r\n", "PORT", "LOCAL", "PIPES", "CONN/MIN", "BYTES/S", "TRAFFIC"));
+        let rows = self.rows();
+        let mut pipes_header = false;
+        for (i, segment) in rows.iter().enumerate() {
+            let line = match segment.kind.as_str() {
+                "port" => {
+                    let (rate, sparkline) = self.sparkline(&segment.name);
+                    let paused = if segment.get("paused") == "true" { " (paused)" } else { "" };
+                    let pool = segment.fields.get("pool").map_or(String::new(), |pool| format!(" (pool {pool})"));
+                    let name = segment.fields.get("name").map_or(String::new(), |name| format!(" {name}"));
+                    format!("{:<12} {:<8} {:>6} {:>8} {:>10}  {sparkline}{paused}{pool}{name}", segment.name, segment.get("local"),
+                        segment.get("pipes"), self.connections_per_minute(&segment.name), rate)
+                }
+                _ => {
+                    if !pipes_header {
+                        pipes_header = true;
+                        out.push_str(&format!("\r\n{:<12} {:<8} {:>6}\r\n", "PIPE", "PORT", "AGE"));
+                    }
+                    format!("{:<12} {:<8} {:>6}", segment.name, segment.get("port"), segment.number("age").map_or("-".to_string(), human_duration))
+                }
+            };
+            if i == self.selected {
+                out.push_str(&format!("\x1b[7m{line}\x1b[0m\r\n"));
+            } else {
+                out.push_str(&format!("{line}\r\n"));
+            }
+        }
+        out.push_str(&format!("\r\n{}\r\n", self.message));
+        out.push_str(&format!("up/down: select  p: pause/resume port  x: kill pipe  space: {}  q: quit\r\n",
+            if self.frozen { "resume refresh" } else { "freeze refresh" }));
+        out
+    }
+
+    fn act(&mut self, path: &Path, key: Key) {
+        let rows = self.rows();
+        let selected = rows.get(self.selected);
+        let command = match (key, selected) {
+            (Key::Pause, Some(row)) if row.kind == "port" => {
+                let action = if row.get("paused") == "true" { "resume" } else { "pause" };
+                format!("port {action} {}", row.name)
+            }
+            (Key::Kill, Some(row)) if row.kind == "pipe" => format!("pipe kill {}", row.name),
+            (Key::Pause, _) => {
+                self.message = "Select a port to pause it".to_string();
+                return;
+            }
+            (Key::Kill, _) => {
+                self.message = "Select a pipe to kill it".to_string();
+                return;
+            }
+            _ => return
+        };
+        self.message = match admin::request(path, &command) {
+            Ok(response) => format!("{command}: {response}"),
+            Err(err) => format!("{command}: {err:#}")
+        };
+    }
+}
+
+fn human_bytes(x: f64) -> String {
+    match x {
+        x if x >= 1e9 => format!("{:.1}GB", x / 1e9),
+        x if x >= 1e6 => format!("{:.1}MB", x / 1e6),
+        x if x >= 1e3 => format!("{:.1}KB", x / 1e3),
+        x => format!("{x:.0}B")
+    }
+}
+
+fn human_duration(secs: u64) -> String {
+    match secs {
+        x if x >= 86400 => format!("{}d{}h", x / 86400, x % 86400 / 3600),
+        x if x >= 3600 => format!("{}h{}m", x / 3600, x % 3600 / 60),
+        x if x >= 60 => format!("{}m{}s", x / 60, x % 60),
+        x => format!("{x}s")
+    }
+}
+
+#[cfg(unix)]
+mod terminal {
+    use std::io::{self, Write};
+
+    /// Raw mode for as long as it lives, so that keys are read one by one
+    pub struct RawMode(libc::termios);
+
+    impl RawMode {
+        pub fn enable() -> io::Result<RawMode> {
+            // SAFETY: termios is plain data, for which zeroes are valid, and it outlives both calls that are passed it
+            unsafe {
+                let mut termios : libc::termios = std::mem::zeroed();
+                if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let original = termios;
+                termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+                termios.c_cc[libc::VMIN] = 1;
+                termios.c_cc[libc::VTIME] = 0;
+                if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                print!("\x1b[?25l"); // Hide the cursor
+                io::stdout().flush()?;
+                Ok(RawMode(original))
+            }
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            // SAFETY: `self.0` outlives the call, it is what tcgetattr wrote
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0);
+            }
+            print!("\x1b[?25h\r\n");
+            let _ = io::stdout().flush();
+        }
+    }
+}
+
+#[cfg(unix)]
+pub fn main(path: &Path) -> Result<()> {
+    // Fail before touching the terminal if the server isn't there
+    admin::request(path, "status")?;
+    let _raw = terminal::RawMode::enable().context("Failed to set the terminal in raw mode")?;
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut stdin = io::stdin();
+        let mut buf = [0u8; 3];
+        loop {
+            let key = match stdin.read(&mut buf) {
+                Ok(0) | Err(_) => Key::Quit,
+                Ok(len) => match &buf[..len] {
+                    b"\x1b[A" => Key::Up,
+                    b"\x1b[B" => Key::Down,
+                    b"p" => Key::Pause,
+                    b"x" => Key::Kill,
+                    b" " => Key::Freeze,
+                    b"q" | b"\x03" => Key::Quit,
+                    _ => continue
+                }
+            };
+            if tx.send(key).is_err() {
+                return;
+            }
+        }
+    });
+    let refresh = Duration::from_secs(REFRESH_DELAY);
+    let mut dashboard = Dashboard::default();
+    let mut next_refresh = Instant::now();
+    loop {
+        if !dashboard.frozen && Instant::now() >= next_refresh {
+            next_refresh = Instant::now() + refresh;
+            match admin::request(path, "status") {
+                Ok(status) => match status.strip_prefix("ok: ") {
+                    Some(status) => dashboard.update(status),
+                    None => dashboard.message = status
+                },
+                Err(err) => dashboard.message = format!("{err:#}")
+            }
+        }
+        print!("{}", dashboard.render());
+        io::stdout().flush()?;
+        match rx.recv_timeout(next_refresh.saturating_duration_since(Instant::now()).max(Duration::from_millis(50))) {
+            Ok(Key::Quit) => return Ok(()),
+            Ok(Key::Up) => dashboard.selected = dashboard.selected.saturating_sub(1),
+            Ok(Key::Down) => dashboard.selected = (dashboard.selected + 1).min(dashboard.rows().len().saturating_sub(1)),
+            Ok(Key::Freeze) => dashboard.frozen = !dashboard.frozen,
+            Ok(key) => {
+                dashboard.act(path, key);
+                next_refresh = Instant::now(); // Show the effect right away
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(())
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn main(_path: &Path) -> Result<()> {
+    Err(anyhow::anyhow!("smugglrs top is not supported on this platform"))
+}
@@ -0,0 +1,248 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! How the server reaches the gateway. The scheme of `gateway_address` (`tcp://` by default) picks the
+//! transport; the link itself is always a TCP socket, possibly established through a proxy, or the relay of one.
+
+use anyhow::{anyhow, Result, Context};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use crate::config::{AddressFamily, ServerConfig, SocksProxy};
+use crate::error::Failure;
+use crate::websocket;
+use crate::{debug, warn};
+
+const RESPONSE_MAX_SIZE : usize = 65536; // Of the status line and headers of the http proxy
+pub const DEFAULT_SOCKS_PROXY : &str = "127.0.0.1:9050"; // Tor's
+
+pub trait Transport: Send + Sync {
+    /// Open a connection to the gateway at `address` (`host:port`), for the control connection
+    /// as well as for every new tunnelled connection
+    fn connect(&self, address: &str) -> Result<TcpStream>;
+}
+
+/// Tries every address of the gateway of the family, in the order of the resolution
+pub struct Tcp(pub AddressFamily);
+
+impl Transport for Tcp {
+    fn connect(&self, address: &str) -> Result<TcpStream> {
+        let addrs : Vec<SocketAddr> = address.to_socket_addrs().with_context(|| format!("Failed to resolve {address}"))?
+            .filter(|addr| self.0.permits(addr.ip()))
+            .collect();
+        if addrs.is_empty() {
+            return Err(anyhow!("{address} has no {} address", self.0));
+        }
+        TcpStream::connect(&addrs[..]).context("Failed to connect to gateway")
+    }
+}
+
+/// Used by `tcp://` when `http_proxy` is set: every connection to the gateway is tunnelled with a CONNECT
+pub struct HttpProxy(pub String);
+
+impl Transport for HttpProxy {
+    fn connect(&self, address: &str) -> Result<TcpStream> {
+        debug!("Connecting to {address} through the http proxy {}", self.0);
+        let mut stream = TcpStream::connect(&self.0).with_context(|| format!("The http proxy {} is unreachable", self.0))?;
+        stream.write_all(format!("CONNECT {address} HTTP/1.1\r\nHost: {address}\r\n\r\n").as_bytes())
+            .context("Failed to write HTTP connect to proxy")?;
+        stream.flush().context("Failed to flush HTTP connect to proxy")?;
+        let head = read_head(&mut stream)?;
+        debug!("http proxy response {head:?}");
+        let ResponseHead { status, reason, headers } = parse_head(&head)?;
+        match status {
+            200..=299 => Ok(stream),
+            407 => {
+                let scheme = headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("Proxy-Authenticate")).map_or("", |(_, value)| value);
+                Err(anyhow!("The http proxy {} refused to CONNECT to {address}: it requires an authentication, which isn't supported ({status} {reason}, {scheme:?})", self.0))
+                    .context(Failure::Config)
+            }
+            _ => Err(anyhow!("The http proxy {} refused to CONNECT to {address} ({status} {reason})", self.0))
+        }
+    }
+}
+
+/* The status line and headers of the proxy's response, read a byte at a time so that nothing of the tunnel
+   behind them is consumed */
+fn read_head(stream: &mut TcpStream) -> Result<String> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !(head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n")) {
+        if head.len() >= RESPONSE_MAX_SIZE {
+            return Err(anyhow!("The response of the http proxy is too big"));
+        }
+        match stream.read(&mut byte).context("Failed to read HTTP CONNECT response")? {
+            0 => {
+                warn!("Stream ended early with response {:?}", String::from_utf8_lossy(&head));
+                return Err(anyhow!("The http proxy closed the connection before answering the CONNECT"));
+            }
+            _ => head.push(byte[0])
+        }
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+struct ResponseHead<'a> {
+    status: u16,
+    reason: &'a str,
+    headers: Vec<(&'a str, String)>
+}
+
+/// The status, reason and headers of a response, with the folded headers unfolded
+fn parse_head(head: &str) -> Result<ResponseHead<'_>> {
+    let mut lines = head.split('\n').map(|x| x.strip_suffix('\r').unwrap_or(x)).take_while(|x| !x.is_empty());
+    let status_line = lines.next().unwrap_or_default();
+    let mut parts = status_line.splitn(3, ' ');
+    if !parts.next().is_some_and(|version| version.starts_with("HTTP/")) {
+        return Err(anyhow!("The http proxy didn't answer with HTTP: {status_line:?}"));
+    }
+    let status = parts.next().and_then(|x| x.parse::<u16>().ok()).filter(|x| (100..1000).contains(x))
+        .with_context(|| format!("Malformed status line from the http proxy: {status_line:?}"))?;
+    let reason = parts.next().unwrap_or_default();
+    let mut headers : Vec<(&str, String)> = Vec::new();
+    for line in lines {
+        // Obsolete line folding: the line continues the value of the previous header
+        if line.starts_with([' ', '\t']) {
+            let (_, value) = headers.last_mut().with_context(|| format!("Malformed header from the http proxy: {line:?}"))?;
+            value.push(' ');
+            value.push_str(line.trim());
+            continue;
+        }
+        let (name, value) = line.split_once(':').with_context(|| format!("Malformed header from the http proxy: {line:?}"))?;
+        headers.push((name.trim(), value.trim().to_string()));
+    }
+    Ok(ResponseHead { status, reason, headers })
+}
+
+/// `tor+socks5://`, and `tcp://` when `socks5_proxy` is set: the gateway's address is resolved by the proxy, so that
+/// it can be an onion address
+pub struct Socks5(pub SocksProxy);
+
+impl Transport for Socks5 {
+    fn connect(&self, address: &str) -> Result<TcpStream> {
+        let proxy = &self.0.address;
+        let (host, port) = address.rsplit_once(':').context("The gateway address should be host:port")?;
+        let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+        let port : u16 = port.parse().context("Invalid gateway port")?;
+        let host_length = u8::try_from(host.len()).context("Gateway host name too long")?;
+        debug!("Connecting to {address} through the SOCKS proxy {proxy}");
+        let mut stream = TcpStream::connect(proxy).with_context(|| format!("The SOCKS proxy {proxy} is unreachable"))?;
+        // No authentication, or username and password (RFC 1929) when we have them
+        let greeting : &[u8] = match self.0.credentials {
+            Some(_) => &[5, 2, 0, 2],
+            None => &[5, 1, 0]
+        };
+        stream.write_all(greeting).context("Failed to write SOCKS greeting")?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).context("Failed to read SOCKS greeting reply")?;
+        match (reply, &self.0.credentials) {
+            ([5, 0], _) => {}
+            ([5, 2], Some((username, password))) => {
+                let mut request = vec![1, username.len() as u8];
+                request.extend_from_slice(username.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                stream.write_all(&request).context("Failed to write SOCKS authentication")?;
+                stream.read_exact(&mut reply).context("Failed to read SOCKS authentication reply")?;
+                if reply[1] != 0 {
+                    return Err(anyhow!("The SOCKS proxy {proxy} rejected the username and password")).context(Failure::Config);
+                }
+            }
+            ([5, _], None) => return Err(anyhow!("The SOCKS proxy {proxy} requires an authentication")).context(Failure::Config),
+            ([5, _], Some(_)) => return Err(anyhow!("The SOCKS proxy {proxy} accepts neither a username and password nor no authentication")).context(Failure::Config),
+            _ => return Err(anyhow!("{proxy} is not a SOCKS5 proxy"))
+        }
+        let mut request = vec![5, 1, 0, 3, host_length];
+        request.extend_from_slice(host.as_bytes());
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).context("Failed to write SOCKS connect request")?;
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).context("Failed to read SOCKS connect reply")?;
+        if reply[1] != 0 {
+            return Err(anyhow!("The SOCKS proxy {proxy} refused to connect to {address} ({})", socks_error(reply[1])));
+        }
+        let bound_length = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut length = [0u8; 1];
+                stream.read_exact(&mut length).context("Failed to read SOCKS connect reply")?;
+                length[0] as usize
+            }
+            x => return Err(anyhow!("Malformed SOCKS connect reply, address type {x}"))
+        };
+        // The bound address and port are of no use to us
+        stream.read_exact(&mut vec![0u8; bound_length + 2]).context("Failed to read SOCKS connect reply")?;
+        Ok(stream)
+    }
+}
+
+/* The replies of RFC 1928 */
+fn socks_error(code: u8) -> String {
+    match code {
+        1 => "general failure".to_string(),
+        2 => "not allowed by the ruleset".to_string(),
+        3 => "network unreachable".to_string(),
+        4 => "host unreachable".to_string(),
+        5 => "connection refused".to_string(),
+        6 => "TTL expired".to_string(),
+        7 => "command not supported".to_string(),
+        8 => "address type not supported".to_string(),
+        x => format!("error {x}")
+    }
+}
+
+/// `ws://`: every connection to the gateway, established like `tcp://` ones, is upgraded to a WebSocket
+pub struct WebSocket {
+    pub inner: Arc<dyn Transport>,
+    /// The `Host` header, the gateway's address if None
+    pub host: Option<String>,
+    pub path: String
+}
+
+impl Transport for WebSocket {
+    fn connect(&self, address: &str) -> Result<TcpStream> {
+        let stream = self.inner.connect(address)?;
+        websocket::upgrade(stream, self.host.as_deref().unwrap_or(address), &self.path)
+    }
+}
+
+/// Custom transports, by scheme. They take precedence over the built-in ones.
+#[derive(Default, Clone)]
+pub struct Transports(HashMap<String, Arc<dyn Transport>>);
+
+impl Transports {
+    pub fn register<T: Transport + 'static>(&mut self, scheme: &str, transport: T) -> &mut Transports {
+        self.0.insert(scheme.to_string(), Arc::new(transport));
+        self
+    }
+
+    pub fn get(&self, scfg: &ServerConfig) -> Result<Arc<dyn Transport>> {
+        if let Some(transport) = self.0.get(&scfg.transport) {
+            return Ok(transport.clone());
+        }
+        let tcp : Arc<dyn Transport> = match (&scfg.proxy, &scfg.socks5_proxy) {
+            (Some(proxy), _) => Arc::new(HttpProxy(proxy.clone())),
+            (_, Some(proxy)) => Arc::new(Socks5(proxy.clone())),
+            (None, None) => Arc::new(Tcp(scfg.address_family))
+        };
+        match scfg.transport.as_str() {
+            "tcp" => Ok(tcp),
+            "ws" => Ok(Arc::new(WebSocket { inner: tcp, host: scfg.websocket_host.clone(), path: scfg.websocket_path.clone() })),
+            "tor+socks5" => Ok(Arc::new(Socks5(SocksProxy {
+                address: scfg.socks_proxy.clone().unwrap_or(DEFAULT_SOCKS_PROXY.to_string()),
+                credentials: None
+            }))),
+            x => Err(anyhow!("{x} is not a known transport, expected tcp, ws or tor+socks5"))
+        }
+    }
+}
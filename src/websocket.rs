@@ -0,0 +1,361 @@
+/*
+Copyright (C) 2024 David Hamelin
+This program is free software: you can redistribute it and/or modify it under the terms of the 
+GNU General Public License as published by the Free Software Foundation, version 3.
+This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; 
+without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. 
+See the GNU General Public License for more details. 
+You should have received a copy of the GNU General Public License along with this program. 
+If not, see <https://www.gnu.org/licenses/>. 
+*/
+
+//! `ws://`: for networks where only an HTTP reverse proxy gets out, which forwards WebSocket upgrades. The server
+//! upgrades each of its connections to the gateway (`websocket_path`, `websocket_host`), the gateway answers the
+//! upgrades of its own `websocket_path`, then both send what they would have sent over TCP in binary messages.
+//!
+//! Everything else works on `TcpStream`s, so each upgraded connection is relayed to a loopback connection: the
+//! transport returns it to the server, and the gateway sorts it like any other candidate. A close frame only ends its
+//! direction, like a half-close: it is answered once the other direction is done too.
+
+use crate::log;
+use crate::sockopt;
+use anyhow::{anyhow, Context, Result};
+use rand::{RngCore, rngs::OsRng};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const GUID : &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const HEAD_MAX_SIZE : usize = 8192; // Of the upgrade request and response
+const MAX_FRAME : u64 = 1048576;
+const RELAY_BUFFER : usize = 65536;
+/* Reverse proxies close the connections that stay silent, the ones of idle clients aren't */
+const PING_INTERVAL : Duration = Duration::from_secs(30);
+
+const OPCODE_CONTINUATION : u8 = 0;
+const OPCODE_TEXT : u8 = 1;
+const OPCODE_BINARY : u8 = 2;
+const OPCODE_CLOSE : u8 = 8;
+const OPCODE_PING : u8 = 9;
+const OPCODE_PONG : u8 = 10;
+
+/* Only for the handshake, SHA-1 isn't relied upon for anything else */
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h : [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6)
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, temp);
+        }
+        for (x, y) in h.iter_mut().zip([a, b, c, d, e]) {
+            *x = x.wrapping_add(y);
+        }
+    }
+    let mut digest = [0u8; 20];
+    for (i, x) in h.iter().enumerate() {
+        digest[4 * i..4 * i + 4].copy_from_slice(&x.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET : &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut ret = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                ret.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                ret.push('=');
+            }
+        }
+    }
+    ret
+}
+
+/* What the gateway answers to the key of the server, which proves it understood the upgrade */
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+/* The request or status line and the headers, read a byte at a time so that no frame behind them is consumed.
+   `head` may already hold its first bytes */
+fn read_head(stream: &mut TcpStream, mut head: Vec<u8>) -> Result<String> {
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= HEAD_MAX_SIZE {
+            return Err(anyhow!("The WebSocket upgrade is more than {HEAD_MAX_SIZE} bytes long"));
+        }
+        match stream.read(&mut byte).context("Failed to read the WebSocket upgrade")? {
+            0 => return Err(anyhow!("The connection was closed during the WebSocket upgrade")),
+            _ => head.push(byte[0])
+        }
+    }
+    String::from_utf8(head).context("The WebSocket upgrade isn't text")
+}
+
+/* The first line, and the value of a header, by its case-insensitive name */
+fn first_line(head: &str) -> &str {
+    head.split("\r\n").next().unwrap_or_default()
+}
+
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.split("\r\n").skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(x, _)| x.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+fn has_token(head: &str, name: &str, token: &str) -> bool {
+    header(head, name).is_some_and(|value| value.split(',').any(|x| x.trim().eq_ignore_ascii_case(token)))
+}
+
+/// The server's side of the upgrade, then the relay of the connection
+pub fn upgrade(mut stream: TcpStream, host: &str, path: &str) -> Result<TcpStream> {
+    let mut nonce = [0u8; 16];
+    OsRng.fill_bytes(&mut nonce);
+    let key = base64(&nonce);
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n");
+    stream.write_all(request.as_bytes()).context("Failed to write the WebSocket upgrade")?;
+    let head = read_head(&mut stream, Vec::new())?;
+    let status = first_line(&head);
+    if status.split(' ').nth(1) != Some("101") {
+        return Err(anyhow!("The gateway refused to upgrade {path} to a WebSocket: {status:?}"));
+    }
+    if header(&head, "Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+        return Err(anyhow!("The answer to the WebSocket upgrade doesn't match its key, something else than the gateway answered"));
+    }
+    relay(stream, true).context("Failed to relay the WebSocket")
+}
+
+/// The gateway's side of the upgrade, once it read the `first` bytes of the request. Returns None when it is no
+/// upgrade to `path`, which is left unanswered
+pub fn accept(mut stream: TcpStream, first: &[u8], path: &str) -> Result<Option<TcpStream>> {
+    let head = read_head(&mut stream, first.to_vec())?;
+    let mut request = first_line(&head).split(' ');
+    let upgrade = request.next() == Some("GET") && request.next() == Some(path)
+        && has_token(&head, "Upgrade", "websocket") && has_token(&head, "Connection", "Upgrade")
+        && header(&head, "Sec-WebSocket-Version") == Some("13");
+    let Some(key) = header(&head, "Sec-WebSocket-Key").filter(|_| upgrade) else {
+        return Ok(None);
+    };
+    let response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept_key(key));
+    stream.write_all(response.as_bytes()).context("Failed to answer the WebSocket upgrade")?;
+    relay(stream, false).context("Failed to relay the WebSocket").map(Some)
+}
+
+/* A connected pair of loopback sockets. Another process may connect to the listener meanwhile, it's not accepted */
+fn loopback_pair() -> io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let ours = TcpStream::connect(listener.local_addr()?)?;
+    loop {
+        let (theirs, addr) = listener.accept()?;
+        if addr == ours.local_addr()? {
+            return Ok((theirs, ours));
+        }
+    }
+}
+
+/* Frames sent by the server are masked, the ones sent by the gateway aren't */
+struct Writer {
+    stream: TcpStream,
+    masked: bool,
+    sent_close: bool,
+    received_close: bool
+}
+
+impl Writer {
+    fn send(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(0x80 | opcode);
+        let mask_bit = if self.masked { 0x80 } else { 0 };
+        match payload.len() {
+            len if len < 126 => frame.push(mask_bit | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        if self.masked {
+            let mut mask = [0u8; 4];
+            OsRng.fill_bytes(&mut mask);
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, x)| x ^ mask[i % 4]));
+        } else {
+            frame.extend_from_slice(payload);
+        }
+        self.stream.write_all(&frame)
+    }
+
+    /* Once both sides sent theirs, the connection is over */
+    fn close(&mut self) -> io::Result<()> {
+        if !self.sent_close {
+            self.sent_close = true;
+            self.send(OPCODE_CLOSE, &[])?;
+        }
+        if self.received_close {
+            self.stream.shutdown(Shutdown::Both)?;
+        }
+        Ok(())
+    }
+}
+
+/* Resets both connections, neither peer must take a truncated stream as complete */
+fn reset(ws: &TcpStream, ours: &TcpStream) {
+    for stream in [ws, ours] {
+        let _ = sockopt::set_linger_zero(stream);
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+}
+
+struct Frame {
+    opcode: u8,
+    payload: Vec<u8>
+}
+
+/* Waits for the next frame, pinging the other side while nothing comes. None once the connection is closed */
+fn read_frame(stream: &mut TcpStream, writer: &Mutex<Writer>, masked: bool) -> io::Result<Option<Frame>> {
+    let mut header = [0u8; 2];
+    if !fill(stream, &mut header, writer, true)? {
+        return Ok(None);
+    }
+    let opcode = header[0] & 0x0f;
+    if (header[1] & 0x80 != 0) != masked {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "WebSocket frame masked the wrong way"));
+    }
+    let length = match header[1] & 0x7f {
+        126 => {
+            let mut length = [0u8; 2];
+            fill(stream, &mut length, writer, false)?;
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0u8; 8];
+            fill(stream, &mut length, writer, false)?;
+            u64::from_be_bytes(length)
+        }
+        length => length as u64
+    };
+    if length > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("WebSocket frame of {length} bytes")));
+    }
+    let mut mask = [0u8; 4];
+    if masked {
+        fill(stream, &mut mask, writer, false)?;
+    }
+    let mut payload = vec![0u8; length as usize];
+    fill(stream, &mut payload, writer, false)?;
+    if masked {
+        for (i, x) in payload.iter_mut().enumerate() {
+            *x ^= mask[i % 4];
+        }
+    }
+    Ok(Some(Frame { opcode, payload }))
+}
+
+/* Like read_exact, through the read timeouts. Returns false if the connection ended before the first byte, when
+   that's allowed */
+fn fill(stream: &mut TcpStream, buf: &mut [u8], writer: &Mutex<Writer>, first: bool) -> io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match stream.read(&mut buf[read..]) {
+            Ok(0) if first && read == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => read += len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                let mut writer = writer.lock().unwrap();
+                if !writer.sent_close {
+                    writer.send(OPCODE_PING, &[])?;
+                }
+            }
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(true)
+}
+
+/// The socket the upgraded connection `ws` is relayed to, as if it was that connection. `client` is the server's side
+pub fn relay(ws: TcpStream, client: bool) -> io::Result<TcpStream> {
+    let (theirs, ours) = loopback_pair()?;
+    ws.set_read_timeout(Some(PING_INTERVAL))?;
+    let writer = Arc::new(Mutex::new(Writer { stream: ws.try_clone()?, masked: client, sent_close: false, received_close: false }));
+    {
+        // What is written to the relayed socket goes in binary messages
+        let (mut ours, ws, writer) = (ours.try_clone()?, ws.try_clone()?, writer.clone());
+        log::spawn(move || {
+            let mut buf = vec![0u8; RELAY_BUFFER];
+            loop {
+                let sent = match ours.read(&mut buf) {
+                    Ok(0) => {
+                        let _ = writer.lock().unwrap().close();
+                        return;
+                    }
+                    Ok(len) => writer.lock().unwrap().send(OPCODE_BINARY, &buf[..len]),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => Err(err)
+                };
+                if sent.is_err() {
+                    reset(&ws, &ours);
+                    return;
+                }
+            }
+        });
+    }
+    let mut ws = ws;
+    let mut ours = ours;
+    log::spawn(move || loop {
+        let frame = match read_frame(&mut ws, &writer, !client) {
+            Ok(Some(frame)) => frame,
+            // Closed without a close frame
+            Ok(None) | Err(_) => return reset(&ws, &ours)
+        };
+        let handled = match frame.opcode {
+            OPCODE_BINARY | OPCODE_CONTINUATION => ours.write_all(&frame.payload),
+            OPCODE_PING => writer.lock().unwrap().send(OPCODE_PONG, &frame.payload),
+            OPCODE_PONG => Ok(()),
+            OPCODE_CLOSE => {
+                let _ = ours.shutdown(Shutdown::Write);
+                let mut writer = writer.lock().unwrap();
+                writer.received_close = true;
+                if writer.sent_close {
+                    let _ = writer.close();
+                }
+                return;
+            }
+            OPCODE_TEXT => Err(io::Error::new(io::ErrorKind::InvalidData, "Text WebSocket message")),
+            x => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown WebSocket opcode {x}")))
+        };
+        if handled.is_err() {
+            return reset(&ws, &ours);
+        }
+    });
+    Ok(theirs)
+}